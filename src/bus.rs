@@ -0,0 +1,52 @@
+// The memory side of the system bus. The CPU only ever touches memory through
+// this trait, so each machine can describe its own memory map: ROM regions
+// that ignore writes, mirrored RAM, memory-mapped devices, and whatever
+// unmapped addresses should do on that particular board. Returning
+// `UnmappedAddress` means nothing responds at all, which the CPU reports as an
+// error.
+pub trait Bus {
+    fn read(&self, address: u16) -> Result<u8, UnmappedAddress>;
+    fn write(&mut self, address: u16, value: u8) -> Result<(), UnmappedAddress>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnmappedAddress(pub u16);
+
+// Plain RAM starting at address 0. This is what the unit tests and the
+// diagnostic setup use. The vector doesn't have to cover the whole 64K
+// address space; anything past the end of it is unmapped.
+impl Bus for Vec<u8> {
+    fn read(&self, address: u16) -> Result<u8, UnmappedAddress> {
+        self.get(address as usize).copied().ok_or(UnmappedAddress(address))
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), UnmappedAddress> {
+        match self.get_mut(address as usize) {
+            Some(byte) => {
+                *byte = value;
+                Ok(())
+            },
+            None => Err(UnmappedAddress(address)),
+        }
+    }
+}
+
+mod test {
+    #[allow(unused)] use super::*;
+
+    #[test]
+    fn test_vec_read_write() {
+        let mut memory = vec![0x00; 0x10];
+        assert_eq!(memory.write(0x0f, 0xab), Ok(()));
+        assert_eq!(memory.read(0x0f), Ok(0xab));
+        assert_eq!(memory[0x0f], 0xab);
+    }
+
+    #[test]
+    fn test_vec_unmapped() {
+        let mut memory = vec![0x00; 0x10];
+        assert_eq!(memory.read(0x10), Err(UnmappedAddress(0x10)));
+        assert_eq!(memory.write(0x10, 0xab), Err(UnmappedAddress(0x10)));
+        assert_eq!(memory.len(), 0x10);
+    }
+}
@@ -0,0 +1,203 @@
+use std::fmt;
+use std::io::{self, Write};
+
+// A shadow of the real stack, kept from calls and returns as they happen so a
+// backtrace doesn't have to guess which words on the stack are return
+// addresses. Programs are free to play with the stack behind its back (POP a
+// return address and jump through it, SPHL to somewhere else, PUSH an address
+// and RET to it), so it's checked against the stack pointer as it goes: a
+// frame whose return address is no longer on the stack is thrown away, and a
+// return that doesn't match a frame is taken as a jump.
+
+// Runaway recursion shouldn't eat all the memory
+const MAX_DEPTH: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    // Where the call went
+    pub subroutine: u16,
+    pub return_address: u16,
+    // Where the return address was pushed
+    pub sp: u16,
+    // Entered by an interrupt rather than a CALL or RST in the program
+    pub interrupt: bool,
+}
+
+// Frames are kept outermost first, and each one sits lower on the stack than
+// the one before it
+#[derive(Debug, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+}
+
+// One line of a backtrace: where execution is in a subroutine, or where it
+// will carry on once the subroutine above returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BacktraceEntry {
+    pub address: u16,
+    // `None` for the top level, below any call
+    pub subroutine: Option<u16>,
+    pub interrupt: bool,
+    // The return address that was pushed, if something like XTHL has swapped
+    // it for another one since
+    pub changed_from: Option<u16>,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack::default()
+    }
+
+    // Called once the return address has been pushed
+    pub fn call(&mut self, frame: Frame) {
+        // Anything at or below the new return address has already been
+        // popped, one way or another
+        self.discard_below(frame.sp.wrapping_add(1));
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame);
+    }
+
+    // Called with the stack pointer as it is before the return address is
+    // popped
+    pub fn ret(&mut self, sp: u16) {
+        self.discard_below(sp);
+        if matches!(self.frames.last(), Some(frame) if frame.sp == sp) {
+            self.frames.pop();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    fn discard_below(&mut self, sp: u16) {
+        while matches!(self.frames.last(), Some(frame) if frame.sp < sp) {
+            self.frames.pop();
+        }
+    }
+
+    // Every frame that hasn't been returned from or thrown away, outermost
+    // first. Unlike `frames` this keeps one whose return address was popped
+    // some other way, until the next call or return finds it.
+    pub fn open_frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    // Frames still on the stack with the stack pointer at `sp`, innermost
+    // first. Whatever was popped since the last call or return is left out.
+    pub fn frames(&self, sp: u16) -> impl Iterator<Item = &Frame> {
+        self.frames.iter().rev().skip_while(move |frame| frame.sp < sp)
+    }
+
+    // Innermost first, starting at `pc`. `read_word` gets the return address
+    // that's on the stack now, which is where a RET would really go.
+    pub fn backtrace<F: Fn(u16) -> Option<u16>>(&self, pc: u16, sp: u16, read_word: F) -> Vec<BacktraceEntry> {
+        let mut entries = Vec::new();
+        let mut address = pc;
+        let mut changed_from = None;
+        for frame in self.frames(sp) {
+            entries.push(BacktraceEntry { address, subroutine: Some(frame.subroutine), interrupt: frame.interrupt, changed_from });
+            let live = read_word(frame.sp).unwrap_or(frame.return_address);
+            changed_from = if live == frame.return_address { None } else { Some(frame.return_address) };
+            address = live;
+        }
+        entries.push(BacktraceEntry { address, subroutine: None, interrupt: false, changed_from });
+        entries
+    }
+}
+
+impl fmt::Display for BacktraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.subroutine {
+            Some(subroutine) => write!(f, "{:04x}  in {:04x}", self.address, subroutine)?,
+            None => write!(f, "{:04x}  at top level", self.address)?,
+        }
+        if self.interrupt {
+            write!(f, " (interrupt handler)")?;
+        }
+        if let Some(pushed) = self.changed_from {
+            write!(f, " (return address changed from {:04x})", pushed)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn write_backtrace<W: Write>(out: &mut W, entries: &[BacktraceEntry]) -> io::Result<()> {
+    for (depth, entry) in entries.iter().enumerate() {
+        writeln!(out, "#{}  {}", depth, entry)?;
+    }
+    Ok(())
+}
+
+mod test {
+    #[allow(unused)] use super::*;
+
+    #[allow(unused)]
+    fn frame(subroutine: u16, return_address: u16, sp: u16) -> Frame {
+        Frame { subroutine, return_address, sp, interrupt: false }
+    }
+
+    #[allow(unused)]
+    fn addresses(call_stack: &CallStack, pc: u16, sp: u16) -> Vec<u16> {
+        call_stack.backtrace(pc, sp, |_| None).iter().map(|entry| entry.address).collect()
+    }
+
+    #[test]
+    fn test_call_and_return() {
+        let mut call_stack = CallStack::new();
+        call_stack.call(frame(0x0100, 0x0003, 0x00fe));
+        call_stack.call(frame(0x0200, 0x0103, 0x00fc));
+        assert_eq!(addresses(&call_stack, 0x0205, 0x00fc), vec![0x0205, 0x0103, 0x0003]);
+        call_stack.ret(0x00fc);
+        assert_eq!(addresses(&call_stack, 0x0103, 0x00fe), vec![0x0103, 0x0003]);
+        call_stack.ret(0x00fe);
+        assert_eq!(addresses(&call_stack, 0x0003, 0x0100), vec![0x0003]);
+    }
+
+    #[test]
+    fn test_popped_return_address_is_discarded() {
+        // POP H; PCHL leaves the frame behind, and the next call or return
+        // finds it above the stack pointer
+        let mut call_stack = CallStack::new();
+        call_stack.call(frame(0x0100, 0x0003, 0x00fe));
+        call_stack.call(frame(0x0200, 0x0103, 0x00fc));
+        assert_eq!(addresses(&call_stack, 0x0104, 0x00fe), vec![0x0104, 0x0003]);
+        call_stack.call(frame(0x0300, 0x0107, 0x00fc));
+        assert_eq!(addresses(&call_stack, 0x0300, 0x00fc), vec![0x0300, 0x0107, 0x0003]);
+    }
+
+    #[test]
+    fn test_return_without_a_frame_is_a_jump() {
+        // PUSH H; RET inside a subroutine doesn't return from it
+        let mut call_stack = CallStack::new();
+        call_stack.call(frame(0x0100, 0x0003, 0x00fe));
+        call_stack.ret(0x00fc);
+        assert_eq!(addresses(&call_stack, 0x0150, 0x00fe), vec![0x0150, 0x0003]);
+    }
+
+    #[test]
+    fn test_changed_return_address() {
+        let mut call_stack = CallStack::new();
+        call_stack.call(frame(0x0100, 0x0003, 0x00fe));
+        let entries = call_stack.backtrace(0x0105, 0x00fe, |_| Some(0x0004));
+        assert_eq!(entries[1].address, 0x0004);
+        assert_eq!(entries[1].changed_from, Some(0x0003));
+        let mut out = Vec::new();
+        write_backtrace(&mut out, &entries).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "#0  0105  in 0100\n#1  0004  at top level (return address changed from 0003)\n",
+        );
+    }
+
+    #[test]
+    fn test_depth_is_limited() {
+        let mut call_stack = CallStack::new();
+        for depth in 0..MAX_DEPTH as u16 + 10 {
+            call_stack.call(frame(0x0100, 0x0103, 0xfffe - depth * 2));
+        }
+        assert_eq!(call_stack.frames(0).count(), MAX_DEPTH);
+    }
+}
@@ -0,0 +1,430 @@
+use std::fmt;
+use std::io::prelude::*;
+use std::fs::File;
+use std::path::Path;
+
+use crate::debugger::Debuggable;
+use crate::emulator;
+use crate::error::EmulatorError;
+use crate::io_ports::NoPorts;
+use crate::state_8080::State8080;
+
+// Just enough of CP/M to run the well known CPU exercisers (TST8080, CPUTEST,
+// 8080PRE and 8080EXM) without patching them. Programs are loaded at 0x100
+// like a .COM file, calls to the BDOS entry point at 0x0005 are handled here
+// instead of in emulated code, and jumping to 0x0000 (warm boot) ends the run.
+const LOAD_ADDRESS: u16 = 0x0100;
+const WARM_BOOT: u16 = 0x0000;
+const BDOS_ENTRY: u16 = 0x0005;
+// Where the JMP at 0x0005 points. Programs read it to find the top of the
+// transient program area, and usually put their stack just below it.
+const BDOS_ADDRESS: u16 = 0xfe00;
+
+pub struct TestRom {
+    pub name: &'static str,
+    // Generous limits on how many instructions each one needs, so an
+    // exerciser stuck in a loop fails instead of hanging the whole run.
+    // 8080EXM really does take billions.
+    pub step_limit: u64,
+    // What the exerciser prints on a line for a passing or failing test
+    // group. Anything else is progress output, and a run that never prints
+    // a verdict counts as a failure.
+    pub passed: &'static [&'static str],
+    pub failed: &'static [&'static str],
+}
+
+// 8080EXM prints a line per instruction group, the others a single verdict
+// at the end
+const TEST_ROMS: [TestRom; 4] = [
+    TestRom {
+        name: "TST8080.COM",
+        step_limit: 10_000_000,
+        passed: &["CPU IS OPERATIONAL"],
+        failed: &["CPU HAS FAILED"],
+    },
+    TestRom {
+        name: "CPUTEST.COM",
+        step_limit: 1_000_000_000,
+        passed: &["CPU TESTS OK"],
+        failed: &["CPU FAILED", "ERROR"],
+    },
+    TestRom {
+        name: "8080PRE.COM",
+        step_limit: 10_000_000,
+        passed: &["Preliminary tests complete"],
+        failed: &["Preliminary tests failed", "ERROR"],
+    },
+    TestRom {
+        name: "8080EXM.COM",
+        step_limit: 20_000_000_000,
+        passed: &["PASSED!"],
+        failed: &["ERROR ****"],
+    },
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RunError {
+    Emulator(EmulatorError),
+    // Still running after this many steps
+    OutOfSteps(u64),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::Emulator(err) => write!(f, "emulator error: {}", err),
+            RunError::OutOfSteps(steps) => write!(f, "still running after {} steps", steps),
+        }
+    }
+}
+
+pub struct CpmMachine {
+    pub state: State8080,
+}
+
+impl CpmMachine {
+    pub fn new(program: &[u8]) -> CpmMachine {
+        let mut memory = vec![0; 0x10000];
+        let end = (LOAD_ADDRESS as usize + program.len()).min(memory.len());
+        memory[LOAD_ADDRESS as usize..end].copy_from_slice(&program[..end - LOAD_ADDRESS as usize]);
+        // HLT at the warm boot vector in case anything executes it directly
+        memory[WARM_BOOT as usize] = 0x76;
+        memory[BDOS_ENTRY as usize] = 0xc3; // JMP BDOS_ADDRESS
+        memory[BDOS_ENTRY as usize + 1] = (BDOS_ADDRESS & 0xff) as u8;
+        memory[BDOS_ENTRY as usize + 2] = (BDOS_ADDRESS >> 8) as u8;
+        memory[BDOS_ADDRESS as usize] = 0xc9; // RET
+
+        let mut state = State8080::empty_state();
+        state.memory = memory;
+        state.sp = BDOS_ADDRESS;
+        // The CCP calls into the program, so returning from it warm boots
+        state.push(0x00, 0x00);
+        state.set_program_counter(LOAD_ADDRESS);
+        CpmMachine { state }
+    }
+
+    // Runs until the program warm boots or halts. Console output from the
+    // BDOS is written to `console` as it happens, since some of the exercisers
+    // run for a long time. Console output is best effort; failing to write it
+    // doesn't stop the program.
+    pub fn run<W: Write>(&mut self, console: &mut W) -> Result<(), EmulatorError> {
+        while self.step(console)? {}
+        Ok(())
+    }
+
+    // Same as `run`, but gives up after `max_steps` steps
+    pub fn run_for<W: Write>(&mut self, console: &mut W, max_steps: u64) -> Result<(), RunError> {
+        for _ in 0..max_steps {
+            if !self.step(console).map_err(RunError::Emulator)? {
+                return Ok(());
+            }
+        }
+        Err(RunError::OutOfSteps(max_steps))
+    }
+
+    // Runs one instruction, or one BDOS call if that's where the program
+    // counter is. Returns false once the program has finished.
+    pub fn step<W: Write>(&mut self, console: &mut W) -> Result<bool, EmulatorError> {
+        match self.state.program_counter() {
+            WARM_BOOT => Ok(false),
+            BDOS_ENTRY => {
+                if !self.bdos(console) {
+                    return Ok(false);
+                }
+                self.state.ret();
+                Ok(true)
+            },
+            _ => {
+                emulator::emulate_8080_op(&mut self.state)?;
+                Ok(!self.state.halted())
+            },
+        }
+    }
+
+    // Returns false if the program asked to exit
+    fn bdos<W: Write>(&mut self, console: &mut W) -> bool {
+        match self.state.c {
+            0 => return false, // System reset
+            2 => { // Console output
+                let _ = console.write_all(&[self.state.e]);
+            },
+            9 => { // Print string, terminated by '$'
+                let mut address = self.state.de();
+                let mut text = Vec::new();
+                // Without a '$' anywhere this would loop forever, so it gives
+                // up once it's been all the way round memory
+                for _ in 0..0x10000 {
+                    let character = self.state.read_memory(address);
+                    if character == b'$' {
+                        break;
+                    }
+                    text.push(character);
+                    address = address.wrapping_add(1);
+                }
+                let _ = console.write_all(&text);
+            },
+            _ => {},
+        }
+        let _ = console.flush();
+        true
+    }
+}
+
+// Console output goes straight to stdout while debugging
+impl Debuggable for CpmMachine {
+    type Memory = Vec<u8>;
+    type Io = NoPorts;
+
+    fn state(&self) -> &State8080 {
+        &self.state
+    }
+
+    fn state_mut(&mut self) -> &mut State8080 {
+        &mut self.state
+    }
+
+    fn step(&mut self) -> Result<bool, EmulatorError> {
+        CpmMachine::step(self, &mut std::io::stdout())
+    }
+}
+
+// Puts the console on stderr, for when stdout is carrying something else like
+// the GDB stub's packets
+pub struct StderrConsole(pub CpmMachine);
+
+impl Debuggable for StderrConsole {
+    type Memory = Vec<u8>;
+    type Io = NoPorts;
+
+    fn state(&self) -> &State8080 {
+        &self.0.state
+    }
+
+    fn state_mut(&mut self) -> &mut State8080 {
+        &mut self.0.state
+    }
+
+    fn step(&mut self) -> Result<bool, EmulatorError> {
+        self.0.step(&mut std::io::stderr())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct TestGroup {
+    pub name: String,
+    pub passed: bool,
+}
+
+// Picks the pass/fail lines for `rom` out of its console output. A failure
+// string wins if a line somehow has both.
+pub fn test_groups(rom: &TestRom, output: &str) -> Vec<TestGroup> {
+    let mut groups = Vec::new();
+    for line in output.lines() {
+        let line = line.trim_matches(|c: char| c.is_whitespace() || c == '\r');
+        let passed = if rom.failed.iter().any(|text| line.contains(text)) {
+            false
+        } else if rom.passed.iter().any(|text| line.contains(text)) {
+            true
+        } else {
+            continue;
+        };
+        let name = match line.find("..") {
+            Some(index) => line[..index].trim(),
+            None => line,
+        };
+        groups.push(TestGroup { name: name.to_string(), passed });
+    }
+    groups
+}
+
+// Writes console output to stdout while also keeping a copy to look for test
+// results in
+struct Tee {
+    captured: Vec<u8>,
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.captured.extend_from_slice(buf);
+        std::io::stdout().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stdout().flush()
+    }
+}
+
+// Runs whichever of the standard exercisers are in `directory` and prints a
+// pass/fail report for each test group. Returns true if everything that ran
+// passed.
+pub fn run_test_roms(directory: &Path) -> bool {
+    let mut all_passed = true;
+    let mut found_any = false;
+    for rom in TEST_ROMS.iter() {
+        let rom_name = rom.name;
+        let path = match find_file(directory, rom_name) {
+            Some(path) => path,
+            None => {
+                println!("{}: not found, skipping", rom_name);
+                continue;
+            },
+        };
+        found_any = true;
+        let mut program = Vec::new();
+        if let Err(err) = File::open(&path).and_then(|mut file| file.read_to_end(&mut program)) {
+            println!("{}: unable to read {}: {}", rom_name, path.display(), err);
+            all_passed = false;
+            continue;
+        }
+
+        println!("==> {}", rom_name);
+        let mut machine = CpmMachine::new(&program);
+        let mut console = Tee { captured: Vec::new() };
+        let result = machine.run_for(&mut console, rom.step_limit);
+        println!();
+
+        let groups = test_groups(rom, &String::from_utf8_lossy(&console.captured));
+        for group in groups.iter() {
+            println!("{}: {}: {}", rom_name, group.name, if group.passed { "pass" } else { "FAIL" });
+        }
+        let rom_passed = match result {
+            Ok(()) => !groups.is_empty() && groups.iter().all(|group| group.passed),
+            Err(err) => {
+                println!("{}: {}", rom_name, err);
+                false
+            },
+        };
+        println!("{}: {}", rom_name, if rom_passed { "PASSED" } else { "FAILED" });
+        all_passed &= rom_passed;
+    }
+    found_any && all_passed
+}
+
+// CP/M file names are upper case but they don't always stay that way once
+// they've been copied around
+fn find_file(directory: &Path, name: &str) -> Option<std::path::PathBuf> {
+    std::fs::read_dir(directory).ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| {
+            path.file_name()
+                .and_then(|file_name| file_name.to_str())
+                .is_some_and(|file_name| file_name.eq_ignore_ascii_case(name))
+        })
+}
+
+mod test {
+    #[allow(unused)] use super::*;
+
+    #[test]
+    fn test_bdos_output() {
+        let mut program = vec![
+            0x0e, 0x09, // MVI C, 9
+            0x11, 0x12, 0x01, // LXI D, 0x0112
+            0xcd, 0x05, 0x00, // CALL BDOS
+            0x0e, 0x02, // MVI C, 2
+            0x1e, 0x21, // MVI E, '!'
+            0xcd, 0x05, 0x00, // CALL BDOS
+            0xc3, 0x00, 0x00, // JMP 0
+        ];
+        program.extend_from_slice(b"HELLO$");
+        let mut machine = CpmMachine::new(&program);
+        let mut output = Vec::new();
+        machine.run(&mut output).unwrap();
+        assert_eq!(output, b"HELLO!");
+    }
+
+    #[test]
+    fn test_print_string_without_terminator() {
+        let program = vec![
+            0x0e, 0x09, // MVI C, 9
+            0x11, 0x00, 0x02, // LXI D, 0x0200
+            0xcd, 0x05, 0x00, // CALL BDOS
+            0xc3, 0x00, 0x00, // JMP 0
+        ];
+        let mut machine = CpmMachine::new(&program);
+        let mut output = Vec::new();
+        machine.run(&mut output).unwrap();
+        assert_eq!(output.len(), 0x10000);
+    }
+
+    #[test]
+    fn test_return_to_ccp() {
+        let program = vec![
+            0x3e, 0x01, // MVI A, 1
+            0xc9, // RET
+        ];
+        let mut machine = CpmMachine::new(&program);
+        let mut output = Vec::new();
+        machine.run(&mut output).unwrap();
+        assert_eq!(machine.state.a, 0x01);
+        assert_eq!(machine.state.program_counter(), WARM_BOOT);
+    }
+
+    #[test]
+    fn test_top_of_memory_for_stack() {
+        let program = vec![
+            0x2a, 0x06, 0x00, // LHLD 6
+            0xf9, // SPHL
+            0xc3, 0x00, 0x00, // JMP 0
+        ];
+        let mut machine = CpmMachine::new(&program);
+        let mut output = Vec::new();
+        machine.run(&mut output).unwrap();
+        assert_eq!(machine.state.sp, BDOS_ADDRESS);
+    }
+
+    #[test]
+    fn test_exm_groups() {
+        let output = "8080 instruction exerciser\r\n\
+            dad <b,d,h,sp>................  PASSED! crc is:14474ba6\r\n\
+            aluop nn......................  ERROR **** crc expected:9e922f9e found:12345678\r\n\
+            Tests complete\r\n";
+        assert_eq!(test_groups(&TEST_ROMS[3], output), vec![
+            TestGroup { name: "dad <b,d,h,sp>".to_string(), passed: true },
+            TestGroup { name: "aluop nn".to_string(), passed: false },
+        ]);
+    }
+
+    #[test]
+    fn test_tst8080_groups() {
+        let passed = test_groups(&TEST_ROMS[0], "MICROCOSM ASSOCIATES 8080/8085 CPU DIAGNOSTIC\r\n VERSION 1.0  (C) 1980\r\n\r\n CPU IS OPERATIONAL");
+        assert_eq!(passed, vec![TestGroup { name: "CPU IS OPERATIONAL".to_string(), passed: true }]);
+        let failed = test_groups(&TEST_ROMS[0], " CPU HAS FAILED!    ERROR EXIT=05BC");
+        assert_eq!(failed.len(), 1);
+        assert!(!failed[0].passed);
+    }
+
+    #[test]
+    fn test_verdicts_are_per_rom() {
+        // "complete" on its own isn't a pass, and EXM's verdict means nothing
+        // coming from another exerciser
+        assert_eq!(test_groups(&TEST_ROMS[3], "Tests complete\r\n"), vec![]);
+        assert_eq!(test_groups(&TEST_ROMS[0], "dad.....  PASSED!\r\n"), vec![]);
+        let passed = test_groups(&TEST_ROMS[2], "8080 Preliminary tests complete");
+        assert_eq!(passed, vec![TestGroup { name: "8080 Preliminary tests complete".to_string(), passed: true }]);
+        let passed = test_groups(&TEST_ROMS[1], "BEGIN TIMING TEST\r\nEND TIMING TEST\r\nCPU TESTS OK\r\n");
+        assert_eq!(passed, vec![TestGroup { name: "CPU TESTS OK".to_string(), passed: true }]);
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let program = vec![0xc3, 0x00, 0x01]; // JMP 0100
+        let mut machine = CpmMachine::new(&program);
+        let mut output = Vec::new();
+        assert_eq!(machine.run_for(&mut output, 1000), Err(RunError::OutOfSteps(1000)));
+
+        let program = vec![0xc3, 0x00, 0x00]; // JMP 0
+        let mut machine = CpmMachine::new(&program);
+        assert_eq!(machine.run_for(&mut output, 1000), Ok(()));
+    }
+
+    // Needs the exerciser binaries, which aren't part of this repository. Point
+    // CPU_TEST_ROMS at a directory containing them and run with --ignored.
+    #[test]
+    #[ignore]
+    fn test_cpu_exercisers() {
+        let directory = std::env::var("CPU_TEST_ROMS").expect("CPU_TEST_ROMS should be set");
+        assert!(run_test_roms(Path::new(&directory)));
+    }
+}
@@ -0,0 +1,821 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+use crate::bus::Bus;
+use crate::call_stack;
+use crate::disassembler;
+use crate::emulator;
+use crate::error::EmulatorError;
+use crate::flags::Flags;
+use crate::instruction::{Register, RegisterPair};
+use crate::io_ports::IoPorts;
+use crate::state_8080::{State8080, Variant};
+use crate::watchpoint::{WatchKind, Watchpoint};
+
+// A machine the debugger can drive. `step` runs a single instruction along
+// with whatever the machine does around it (BDOS calls, video interrupts and
+// so on), and returns false once the program has finished.
+pub trait Debuggable {
+    type Memory: Bus;
+    type Io: IoPorts;
+
+    fn state(&self) -> &State8080<Self::Memory, Self::Io>;
+    fn state_mut(&mut self) -> &mut State8080<Self::Memory, Self::Io>;
+    fn step(&mut self) -> Result<bool, EmulatorError>;
+}
+
+const HELP: &str = "\
+step [n]              run n instructions (default 1)
+continue              run until a breakpoint, an error or the program ends
+break <address>       set a breakpoint
+delete <address>      remove a breakpoint
+breakpoints           list breakpoints
+registers             show registers and flags
+set <register> <value>  change a register (A-L, M, BC, DE, HL, SP, PSW or PC)
+dump <address> [n]    show n bytes of memory (default 64)
+write <address> <byte>...  change memory
+list [address] [n]    disassemble n instructions (default 8), or around PC
+                      with no address
+watch <address>[-<end>] [read|write|change]
+                      stop when memory is accessed (default write)
+unwatch <n>           remove watchpoint n
+watchpoints           list watchpoints
+backtrace             show the calls that led to PC
+interrupt [<opcode>|cancel]
+                      request an interrupt with opcode on the data bus
+                      (usually an RST), cancel it, or show whether one is
+                      pending
+interrupt trap|rst7.5 raise the 8085's TRAP or RST 7.5 input
+interrupt nmi         raise the Z80's NMI
+interrupt rst6.5|rst5.5 on|off
+                      hold the 8085's RST 6.5 or 5.5 input high or let go
+serial [on|off]       set the 8085's SID input, or show SOD
+help                  show this
+quit                  stop debugging
+
+Addresses and values are hex. An empty line repeats the last command.";
+
+// How many instructions `list` shows before PC
+const LOOK_BACK: usize = 3;
+// Extra bytes decoded before those, to get in step with the code
+const LOOK_BACK_SLACK: usize = 8;
+
+pub struct Debugger<D: Debuggable> {
+    pub machine: D,
+    breakpoints: BTreeSet<u16>,
+    finished: bool,
+    last_command: String,
+}
+
+impl<D: Debuggable> Debugger<D> {
+    pub fn new(machine: D) -> Debugger<D> {
+        Debugger {
+            machine,
+            breakpoints: BTreeSet::new(),
+            finished: false,
+            last_command: String::new(),
+        }
+    }
+
+    // Runs commands from `input` until it runs out or says quit
+    pub fn repl<R: BufRead, W: Write>(&mut self, input: R, out: &mut W) -> io::Result<()> {
+        self.list(self.machine.state().program_counter(), 1, out)?;
+        let mut lines = input.lines();
+        loop {
+            write!(out, "(debug) ")?;
+            out.flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break,
+            };
+            if !self.execute(&line, out)? {
+                break;
+            }
+        }
+        writeln!(out)
+    }
+
+    // Returns false for quit
+    pub fn execute<W: Write>(&mut self, line: &str, out: &mut W) -> io::Result<bool> {
+        let line = if line.trim().is_empty() { self.last_command.clone() } else { line.trim().to_string() };
+        self.last_command = line.clone();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, arguments) = match words.split_first() {
+            Some((command, arguments)) => (*command, arguments),
+            None => return Ok(true),
+        };
+        let result = match command {
+            "s" | "step" => match arguments.first().map(|count| count.parse::<usize>()) {
+                None => self.step(1, out),
+                Some(Ok(count)) => self.step(count, out),
+                Some(Err(_)) => Err("step count should be a decimal number".to_string()),
+            },
+            "c" | "continue" => self.continue_running(out),
+            "b" | "break" => self.address_argument(arguments).map(|address| {
+                self.breakpoints.insert(address);
+            }),
+            "d" | "delete" => self.address_argument(arguments).and_then(|address| {
+                if self.breakpoints.remove(&address) {
+                    Ok(())
+                } else {
+                    Err(format!("no breakpoint at {:04x}", address))
+                }
+            }),
+            "breakpoints" => {
+                for address in &self.breakpoints {
+                    writeln!(out, "{:04x}", address)?;
+                }
+                Ok(())
+            },
+            "r" | "registers" => {
+                self.registers(out)?;
+                Ok(())
+            },
+            "set" => self.set(arguments),
+            "x" | "dump" => match (arguments.first().map(|a| parse_hex(a)), arguments.get(1).map(|n| n.parse::<usize>())) {
+                (Some(Ok(address)), None) => Ok(self.dump(address, 64, out)?),
+                (Some(Ok(address)), Some(Ok(count))) => Ok(self.dump(address, count, out)?),
+                _ => Err("usage: dump <address> [count]".to_string()),
+            },
+            "w" | "write" => self.write_memory(arguments),
+            "watch" => self.watch(arguments),
+            "unwatch" => match arguments {
+                [index] => match index.parse::<usize>().ok().and_then(|index| self.machine.state_mut().remove_watchpoint(index)) {
+                    Some(_) => Ok(()),
+                    None => Err(format!("no watchpoint {}", index)),
+                },
+                _ => Err("usage: unwatch <n>".to_string()),
+            },
+            "watchpoints" => {
+                for (index, watchpoint) in self.machine.state().watchpoints().iter().enumerate() {
+                    writeln!(out, "{}: {}", index, watchpoint)?;
+                }
+                Ok(())
+            },
+            "bt" | "backtrace" => {
+                call_stack::write_backtrace(out, &self.machine.state().backtrace())?;
+                Ok(())
+            },
+            "int" | "interrupt" => self.interrupt(arguments, out),
+            "serial" => self.serial(arguments, out),
+            "l" | "list" => {
+                let pc = self.machine.state().program_counter();
+                match (arguments.first().map(|a| parse_hex(a)), arguments.get(1).map(|n| n.parse::<usize>())) {
+                    (None, _) => Ok(self.list_around(pc, out)?),
+                    (Some(Ok(address)), None) => Ok(self.list(address, 8, out)?),
+                    (Some(Ok(address)), Some(Ok(count))) => Ok(self.list(address, count, out)?),
+                    _ => Err("usage: list [address] [count]".to_string()),
+                }
+            },
+            "h" | "help" => {
+                writeln!(out, "{}", HELP)?;
+                Ok(())
+            },
+            "q" | "quit" => return Ok(false),
+            _ => Err(format!("unknown command '{}', try help", command)),
+        };
+        if let Err(message) = result {
+            writeln!(out, "{}", message)?;
+        }
+        Ok(true)
+    }
+
+    fn address_argument(&self, arguments: &[&str]) -> Result<u16, String> {
+        match arguments {
+            [address] => parse_hex(address),
+            _ => Err("expected an address".to_string()),
+        }
+    }
+
+    // Runs one instruction. Errors are reported but leave the debugger
+    // running so the state can be looked at.
+    fn step_once<W: Write>(&mut self, out: &mut W) -> io::Result<bool> {
+        if self.finished {
+            writeln!(out, "The program has finished")?;
+            return Ok(false);
+        }
+        // Forget anything the debugger's own commands set off
+        self.machine.state_mut().take_watch_hit();
+        let pc = self.machine.state().program_counter();
+        let result = self.machine.step();
+        if let Some(hit) = self.machine.state_mut().take_watch_hit() {
+            writeln!(out, "Watchpoint: {} at {:04x}", hit, pc)?;
+            if let Ok(false) = result {
+                self.finished = true;
+            }
+            return Ok(false);
+        }
+        match result {
+            Ok(true) => Ok(true),
+            Ok(false) => {
+                self.finished = true;
+                writeln!(out, "The program has finished")?;
+                Ok(false)
+            },
+            Err(err) => {
+                emulator::write_error_report(out, self.machine.state(), &err)?;
+                Ok(false)
+            },
+        }
+    }
+
+    fn step<W: Write>(&mut self, count: usize, out: &mut W) -> Result<(), String> {
+        for _ in 0..count {
+            if !self.step_once(out).map_err(|err| err.to_string())? {
+                break;
+            }
+        }
+        self.list(self.machine.state().program_counter(), 1, out).map_err(|err| err.to_string())
+    }
+
+    fn continue_running<W: Write>(&mut self, out: &mut W) -> Result<(), String> {
+        loop {
+            if !self.step_once(out).map_err(|err| err.to_string())? {
+                break;
+            }
+            let pc = self.machine.state().program_counter();
+            if self.breakpoints.contains(&pc) {
+                writeln!(out, "Breakpoint at {:04x}", pc).map_err(|err| err.to_string())?;
+                break;
+            }
+        }
+        self.list(self.machine.state().program_counter(), 1, out).map_err(|err| err.to_string())
+    }
+
+    fn registers<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let state = self.machine.state();
+        writeln!(
+            out,
+            "A={:02x} B={:02x} C={:02x} D={:02x} E={:02x} H={:02x} L={:02x} SP={:04x} PC={:04x}",
+            state.a, state.b, state.c, state.d, state.e, state.h, state.l, state.sp, state.program_counter(),
+        )?;
+        let flags = state.flags();
+        let flag = |flag: Flags, name: &'static str| if flags.contains(flag) { name } else { "-" };
+        writeln!(
+            out,
+            "F={:02x} {} {} {} {} {}{}{}",
+            state.register_pair(RegisterPair::Psw) as u8,
+            flag(Flags::SIGN, "S"),
+            flag(Flags::ZERO, "Z"),
+            flag(Flags::AUX_CARRY, "AC"),
+            flag(Flags::PARITY, "P"),
+            flag(Flags::CARRY, "CY"),
+            if state.interrupt_enabled() { " interrupts enabled" } else { "" },
+            if state.halted() { " halted" } else { "" },
+        )
+    }
+
+    fn set(&mut self, arguments: &[&str]) -> Result<(), String> {
+        let (name, value) = match arguments {
+            [name, value] => (name.to_uppercase(), parse_hex(value)?),
+            _ => return Err("usage: set <register> <value>".to_string()),
+        };
+        let state = self.machine.state_mut();
+        if name == "PC" {
+            state.set_program_counter(value);
+        } else if let Some(pair) = parse_register_pair(&name) {
+            state.set_register_pair(pair, value);
+        } else if let Some(register) = parse_register(&name) {
+            if value > 0xff {
+                return Err(format!("{} only holds a byte", name));
+            }
+            state.set_register(register, value as u8);
+        } else {
+            return Err(format!("unknown register '{}'", name));
+        }
+        self.check_bus_fault()
+    }
+
+    fn dump<W: Write>(&self, address: u16, count: usize, out: &mut W) -> io::Result<()> {
+        let state = self.machine.state();
+        let bytes: Vec<Option<u8>> = (0..count)
+            .map(|offset| state.memory.read(address.wrapping_add(offset as u16)).ok())
+            .collect();
+        for (row, chunk) in bytes.chunks(16).enumerate() {
+            let hex: Vec<String> = chunk.iter()
+                .map(|byte| byte.map_or("??".to_string(), |byte| format!("{:02x}", byte)))
+                .collect();
+            let text: String = chunk.iter()
+                .map(|byte| match byte {
+                    Some(byte) if byte.is_ascii_graphic() || *byte == b' ' => *byte as char,
+                    _ => '.',
+                })
+                .collect();
+            writeln!(out, "{:04x}  {:<47}  {}", address.wrapping_add(row as u16 * 16), hex.join(" "), text)?;
+        }
+        Ok(())
+    }
+
+    fn write_memory(&mut self, arguments: &[&str]) -> Result<(), String> {
+        let (address, values) = match arguments.split_first() {
+            Some((address, values)) if !values.is_empty() => (parse_hex(address)?, values),
+            _ => return Err("usage: write <address> <byte>...".to_string()),
+        };
+        let mut bytes = Vec::new();
+        for value in values {
+            match parse_hex(value)? {
+                byte if byte <= 0xff => bytes.push(byte as u8),
+                _ => return Err(format!("{} isn't a byte", value)),
+            }
+        }
+        let state = self.machine.state_mut();
+        for (offset, byte) in bytes.into_iter().enumerate() {
+            state.write_memory(address.wrapping_add(offset as u16), byte);
+        }
+        state.take_watch_hit();
+        self.check_bus_fault()
+    }
+
+    fn watch(&mut self, arguments: &[&str]) -> Result<(), String> {
+        let usage = || "usage: watch <address>[-<end>] [read|write|change]".to_string();
+        let (range, kind) = match arguments {
+            [range] => (*range, WatchKind::Write),
+            [range, "read"] => (*range, WatchKind::Read),
+            [range, "write"] => (*range, WatchKind::Write),
+            [range, "change"] => (*range, WatchKind::Change),
+            _ => return Err(usage()),
+        };
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (parse_hex(start)?, parse_hex(end)?),
+            None => (parse_hex(range)?, parse_hex(range)?),
+        };
+        if end < start {
+            return Err(usage());
+        }
+        self.machine.state_mut().add_watchpoint(Watchpoint { start, end, kind });
+        Ok(())
+    }
+
+    fn interrupt<W: Write>(&mut self, arguments: &[&str], out: &mut W) -> Result<(), String> {
+        let variant = self.machine.state().variant();
+        let is_8085 = variant == Variant::Intel8085;
+        let state = self.machine.state_mut();
+        match arguments {
+            ["nmi"] if variant != Variant::Z80 => Err("only the Z80 has NMI".to_string()),
+            ["nmi"] => {
+                state.raise_nmi();
+                Ok(())
+            },
+            ["trap", ..] | ["rst7.5", ..] | ["rst6.5", ..] | ["rst5.5", ..] if !is_8085 => {
+                Err(format!("only the 8085 has {}", arguments[0].to_uppercase()))
+            },
+            ["trap"] => {
+                state.raise_trap();
+                Ok(())
+            },
+            ["rst7.5"] => {
+                state.raise_rst7_5();
+                Ok(())
+            },
+            ["rst6.5", level] => parse_level(level).map(|level| state.set_rst6_5(level)),
+            ["rst5.5", level] => parse_level(level).map(|level| state.set_rst5_5(level)),
+            [] => {
+                let pending = if state.interrupt_pending() { "pending" } else { "none pending" };
+                writeln!(out, "Interrupt request: {}", pending).map_err(|err| err.to_string())
+            },
+            ["cancel"] => {
+                state.cancel_interrupt();
+                Ok(())
+            },
+            [opcode] => match parse_hex(opcode)? {
+                opcode if opcode <= 0xff => {
+                    state.request_interrupt(opcode as u8);
+                    Ok(())
+                },
+                _ => Err(format!("{} isn't a byte", opcode)),
+            },
+            _ => Err("usage: interrupt [<opcode>|cancel|nmi|trap|rst7.5|rst6.5 on|off|rst5.5 on|off]".to_string()),
+        }
+    }
+
+    fn serial<W: Write>(&mut self, arguments: &[&str], out: &mut W) -> Result<(), String> {
+        if self.machine.state().variant() != Variant::Intel8085 {
+            return Err("only the 8085 has SID and SOD".to_string());
+        }
+        let state = self.machine.state_mut();
+        match arguments {
+            [] => writeln!(out, "SOD={}", state.sod() as u8).map_err(|err| err.to_string()),
+            [level] => parse_level(level).map(|level| state.set_sid(level)),
+            _ => Err("usage: serial [on|off]".to_string()),
+        }
+    }
+
+    fn check_bus_fault(&mut self) -> Result<(), String> {
+        match self.machine.state_mut().take_bus_fault() {
+            Some(reason) => Err(reason.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    fn list<W: Write>(&self, address: u16, count: usize, out: &mut W) -> io::Result<()> {
+        self.list_marking(address, count, None, out)
+    }
+
+    // A few instructions before `pc`, then `pc` (marked with a >) and what
+    // follows it
+    fn list_around<W: Write>(&self, pc: u16, out: &mut W) -> io::Result<()> {
+        let (start, before) = self.look_back(pc, LOOK_BACK);
+        self.list_marking(start, before + 8, Some(pc), out)
+    }
+
+    fn list_marking<W: Write>(&self, address: u16, count: usize, pc: Option<u16>, out: &mut W) -> io::Result<()> {
+        let state = self.machine.state();
+        let mut address = address;
+        for _ in 0..count {
+            let (description, size) = disassembler::disassemble_opcode(state.variant(), &state.memory, address as usize);
+            let marker = if pc == Some(address) {
+                ">"
+            } else if self.breakpoints.contains(&address) {
+                "*"
+            } else {
+                " "
+            };
+            writeln!(out, "{}{}", marker, description)?;
+            address = address.wrapping_add(size as u16);
+        }
+        Ok(())
+    }
+
+    // Instructions can't be decoded backwards, so this decodes forwards from
+    // a little way before `pc`, moving the start closer until the
+    // instructions line up with `pc`. Starting well back gives the decoding a
+    // chance to fall into step with the real instructions, though data just
+    // before `pc` can still fool it. Returns where the last `count`
+    // instructions before `pc` start, and how many there are.
+    fn look_back(&self, pc: u16, count: usize) -> (u16, usize) {
+        let state = self.machine.state();
+        for distance in (1..=(count * disassembler::MAX_INSTRUCTION_SIZE + LOOK_BACK_SLACK) as u16).rev() {
+            let start = pc.wrapping_sub(distance);
+            let mut addresses = Vec::new();
+            let mut offset = 0;
+            while offset < distance {
+                addresses.push(start.wrapping_add(offset));
+                let (_, size) = disassembler::disassemble_opcode(state.variant(), &state.memory, start.wrapping_add(offset) as usize);
+                offset += size as u16;
+            }
+            if offset == distance {
+                let before = addresses.len().min(count);
+                return (addresses[addresses.len() - before], before);
+            }
+        }
+        (pc, 0)
+    }
+}
+
+// Runs `machine` under the debugger on stdin and stdout
+pub fn run<D: Debuggable>(machine: D) {
+    let mut debugger = Debugger::new(machine);
+    let stdin = io::stdin();
+    if let Err(err) = debugger.repl(stdin.lock(), &mut io::stdout()) {
+        eprintln!("Error: {}", err);
+    }
+}
+
+// Hex, with or without a 0x or $ in front
+fn parse_hex(text: &str) -> Result<u16, String> {
+    let digits = text.trim_start_matches("0x").trim_start_matches('$');
+    u16::from_str_radix(digits, 16).map_err(|_| format!("'{}' isn't a hex number", text))
+}
+
+fn parse_level(text: &str) -> Result<bool, String> {
+    match text {
+        "on" | "1" => Ok(true),
+        "off" | "0" => Ok(false),
+        _ => Err(format!("'{}' should be on or off", text)),
+    }
+}
+
+fn parse_register(name: &str) -> Option<Register> {
+    match name {
+        "A" => Some(Register::A),
+        "B" => Some(Register::B),
+        "C" => Some(Register::C),
+        "D" => Some(Register::D),
+        "E" => Some(Register::E),
+        "H" => Some(Register::H),
+        "L" => Some(Register::L),
+        "M" => Some(Register::M),
+        _ => None,
+    }
+}
+
+fn parse_register_pair(name: &str) -> Option<RegisterPair> {
+    match name {
+        "BC" => Some(RegisterPair::BC),
+        "DE" => Some(RegisterPair::DE),
+        "HL" => Some(RegisterPair::HL),
+        "SP" => Some(RegisterPair::SP),
+        "PSW" => Some(RegisterPair::Psw),
+        _ => None,
+    }
+}
+
+// The GDB stub's tests use `TestMachine` too, so this is only built for tests
+#[cfg(test)]
+pub(crate) mod test {
+    #[allow(unused)] use super::*;
+    #[allow(unused)] use crate::emulator;
+
+    // Bare CPU on plain RAM, finished once it halts
+    #[allow(unused)]
+    pub struct TestMachine {
+        pub state: State8080,
+    }
+
+    impl Debuggable for TestMachine {
+        type Memory = Vec<u8>;
+        type Io = crate::io_ports::NoPorts;
+
+        fn state(&self) -> &State8080 {
+            &self.state
+        }
+
+        fn state_mut(&mut self) -> &mut State8080 {
+            &mut self.state
+        }
+
+        fn step(&mut self) -> Result<bool, EmulatorError> {
+            emulator::emulate_8080_op(&mut self.state)?;
+            Ok(!self.state.halted())
+        }
+    }
+
+    #[allow(unused)]
+    fn debugger(memory: Vec<u8>) -> Debugger<TestMachine> {
+        let mut state = State8080::empty_state();
+        state.memory = memory;
+        Debugger::new(TestMachine { state })
+    }
+
+    #[allow(unused)]
+    fn run(debugger: &mut Debugger<TestMachine>, line: &str) -> String {
+        let mut out = Vec::new();
+        debugger.execute(line, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_step() {
+        let mut debugger = debugger(vec![0x3c, 0x3c, 0x3c, 0x76]); // INR A x3, HLT
+        assert_eq!(run(&mut debugger, "step"), " 0001\tINR\tA\n");
+        assert_eq!(run(&mut debugger, "step 2"), " 0003\tHLT\n");
+        assert_eq!(debugger.machine.state.a, 3);
+        assert!(run(&mut debugger, "step").contains("finished"));
+    }
+
+    #[test]
+    fn test_empty_line_repeats() {
+        let mut debugger = debugger(vec![0x00, 0x00, 0x00, 0x76]);
+        run(&mut debugger, "s");
+        run(&mut debugger, "");
+        assert_eq!(debugger.machine.state.program_counter(), 2);
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        // Count A up in a loop and stop on the INR
+        let mut debugger = debugger(vec![0x00, 0x3c, 0xc3, 0x01, 0x00]);
+        run(&mut debugger, "break 1");
+        assert_eq!(run(&mut debugger, "continue"), "Breakpoint at 0001\n*0001\tINR\tA\n");
+        run(&mut debugger, "continue");
+        assert_eq!(debugger.machine.state.a, 1);
+        assert_eq!(run(&mut debugger, "breakpoints"), "0001\n");
+        run(&mut debugger, "delete 1");
+        assert_eq!(run(&mut debugger, "breakpoints"), "");
+        assert!(run(&mut debugger, "delete 1").contains("no breakpoint"));
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut debugger = debugger(vec![0; 4]);
+        run(&mut debugger, "set a 42");
+        run(&mut debugger, "set hl 1234");
+        run(&mut debugger, "set psw 42c3");
+        run(&mut debugger, "set pc 0002");
+        assert_eq!(
+            run(&mut debugger, "registers"),
+            "A=42 B=00 C=00 D=00 E=00 H=12 L=34 SP=0000 PC=0002\nF=c3 S Z - - CY\n",
+        );
+        assert!(run(&mut debugger, "set a 100").contains("only holds a byte"));
+        assert!(run(&mut debugger, "set q 1").contains("unknown register"));
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut debugger = debugger(vec![0; 0x20]);
+        run(&mut debugger, "write 10 48 69");
+        assert_eq!(debugger.machine.state.memory[0x10..0x12], [0x48, 0x69]);
+        assert_eq!(
+            run(&mut debugger, "dump 10 4"),
+            "0010  48 69 00 00                                      Hi..\n",
+        );
+        assert_eq!(run(&mut debugger, "dump 1f 2"), "001f  00 ??                                            ..\n");
+        assert!(run(&mut debugger, "write 20 1").contains("unmapped"));
+    }
+
+    #[test]
+    fn test_list() {
+        let mut debugger = debugger(vec![0x21, 0x34, 0x12, 0x76]);
+        assert_eq!(run(&mut debugger, "list 0 2"), " 0000\tLXI\tH,#$1234\n 0003\tHLT\n");
+    }
+
+    #[test]
+    fn test_list_around_pc() {
+        // NOPs, LXI H,1234; MVI A,05; INR A, stopped on the INR. Starting at
+        // 0007 or 0008 would line up with it too, but not with the code.
+        let mut memory = vec![0x00; 0x20];
+        memory[0x06..0x0c].copy_from_slice(&[0x21, 0x34, 0x12, 0x3e, 0x05, 0x3c]);
+        let mut debugger = debugger(memory);
+        debugger.machine.state.set_program_counter(0x000b);
+        let listing = run(&mut debugger, "list");
+        assert_eq!(
+            listing.lines().take(5).collect::<Vec<_>>(),
+            vec![" 0005\tNOP", " 0006\tLXI\tH,#$1234", " 0009\tMVI\tA,#$05", ">000b\tINR\tA", " 000c\tNOP"],
+        );
+        assert_eq!(listing.lines().count(), 11);
+    }
+
+    #[test]
+    fn test_repl() {
+        let mut debugger = debugger(vec![0x3c, 0x76]);
+        let mut out = Vec::new();
+        debugger.repl(&b"step\nquit\nstep\n"[..], &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), " 0000\tINR\tA\n(debug)  0001\tHLT\n(debug) \n");
+        assert_eq!(debugger.machine.state.a, 1);
+    }
+
+    #[test]
+    fn test_write_watchpoint() {
+        // MVI A,5; STA 0020; STA 0020; HLT
+        let mut memory = vec![0x3e, 0x05, 0x32, 0x20, 0x00, 0x32, 0x20, 0x00, 0x76];
+        memory.resize(0x30, 0);
+        let mut debugger = debugger(memory);
+        run(&mut debugger, "watch 1f-21");
+        assert_eq!(run(&mut debugger, "watchpoints"), "0: write 001f-0021\n");
+        assert_eq!(run(&mut debugger, "continue"), "Watchpoint: wrote 05 to 0020 (was 00) at 0002\n 0005\tSTA\t$0020\n");
+        assert_eq!(run(&mut debugger, "continue"), "Watchpoint: wrote 05 to 0020 (was 05) at 0005\n 0008\tHLT\n");
+        run(&mut debugger, "unwatch 0");
+        assert_eq!(run(&mut debugger, "watchpoints"), "");
+        assert!(run(&mut debugger, "unwatch 0").contains("no watchpoint"));
+    }
+
+    #[test]
+    fn test_change_watchpoint() {
+        // Same as above, but only the first store changes anything
+        let mut memory = vec![0x3e, 0x05, 0x32, 0x20, 0x00, 0x32, 0x20, 0x00, 0x76];
+        memory.resize(0x30, 0);
+        let mut debugger = debugger(memory);
+        run(&mut debugger, "watch 20 change");
+        assert!(run(&mut debugger, "continue").starts_with("Watchpoint: wrote 05 to 0020 (was 00) at 0002\n"));
+        assert!(run(&mut debugger, "continue").contains("finished"));
+    }
+
+    #[test]
+    fn test_read_watchpoint() {
+        // LDA 0010; HLT, with a watchpoint over the code too, which instruction
+        // fetches shouldn't set off
+        let mut memory = vec![0x3a, 0x10, 0x00, 0x76];
+        memory.resize(0x20, 0);
+        memory[0x10] = 0x99;
+        let mut debugger = debugger(memory);
+        run(&mut debugger, "watch 0-10 read");
+        run(&mut debugger, "write 10 98");
+        assert_eq!(run(&mut debugger, "step 5"), "Watchpoint: read 98 from 0010 at 0000\n 0003\tHLT\n");
+        assert!(run(&mut debugger, "watch 10 sideways").contains("usage"));
+    }
+
+    #[allow(unused)]
+    fn nested_calls(body: &[u8]) -> Vec<u8> {
+        // LXI SP,0040; CALL 0010; HLT, with 0010 being CALL 0020; RET
+        let mut memory = vec![0x31, 0x40, 0x00, 0xcd, 0x10, 0x00, 0x76];
+        memory.resize(0x40, 0);
+        memory[0x10..0x14].copy_from_slice(&[0xcd, 0x20, 0x00, 0xc9]);
+        memory[0x20..0x20 + body.len()].copy_from_slice(body);
+        memory
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut debugger = debugger(nested_calls(&[0x00, 0xc9])); // NOP; RET
+        run(&mut debugger, "break 21");
+        run(&mut debugger, "continue");
+        assert_eq!(run(&mut debugger, "backtrace"), "#0  0021  in 0020\n#1  0013  in 0010\n#2  0006  at top level\n");
+        run(&mut debugger, "step");
+        assert_eq!(run(&mut debugger, "bt"), "#0  0013  in 0010\n#1  0006  at top level\n");
+    }
+
+    #[test]
+    fn test_error_shows_backtrace() {
+        let mut debugger = debugger(nested_calls(&[0x3a, 0x00, 0x10])); // LDA 1000
+        assert!(run(&mut debugger, "continue").starts_with(
+            "Error: read from unmapped address 1000 (opcode 3a at 0020): 0020\tLDA\t$1000\n\
+            Backtrace:\n#0  0020  in 0020\n#1  0013  in 0010\n#2  0006  at top level\n",
+        ));
+    }
+
+    #[allow(unused)]
+    fn interrupt_program() -> Vec<u8> {
+        // LXI SP,0020; EI; NOP; HLT, with RST 1 being INR A; HLT
+        let mut memory = vec![0x31, 0x20, 0x00, 0xfb, 0x00, 0x76, 0x00, 0x00, 0x3c, 0x76];
+        memory.resize(0x20, 0);
+        memory
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut debugger = debugger(interrupt_program());
+        run(&mut debugger, "step 2");
+        assert_eq!(run(&mut debugger, "interrupt"), "Interrupt request: none pending\n");
+        run(&mut debugger, "interrupt cf");
+        assert_eq!(run(&mut debugger, "interrupt"), "Interrupt request: pending\n");
+        run(&mut debugger, "continue");
+        assert_eq!(debugger.machine.state.a, 1);
+        assert_eq!(debugger.machine.state.program_counter(), 0x0a);
+        assert!(run(&mut debugger, "interrupt 100").contains("isn't a byte"));
+    }
+
+    #[test]
+    fn test_cancel_interrupt() {
+        let mut debugger = debugger(interrupt_program());
+        run(&mut debugger, "interrupt cf");
+        run(&mut debugger, "interrupt cancel");
+        assert_eq!(run(&mut debugger, "interrupt"), "Interrupt request: none pending\n");
+        run(&mut debugger, "continue");
+        assert_eq!(debugger.machine.state.a, 0);
+        assert_eq!(debugger.machine.state.program_counter(), 0x06);
+    }
+
+    #[allow(unused)]
+    fn debugger_8085(memory: Vec<u8>) -> Debugger<TestMachine> {
+        let mut debugger = debugger(memory);
+        debugger.machine.state.set_variant(Variant::Intel8085);
+        debugger
+    }
+
+    #[test]
+    fn test_trap() {
+        // LXI SP,0040; NOP; HLT, with TRAP going to INR A; HLT
+        let mut memory = vec![0x31, 0x40, 0x00, 0x00, 0x76];
+        memory.resize(0x40, 0);
+        memory[0x24..0x26].copy_from_slice(&[0x3c, 0x76]);
+        let mut debugger = debugger_8085(memory);
+        run(&mut debugger, "step");
+        run(&mut debugger, "interrupt trap");
+        run(&mut debugger, "continue");
+        assert_eq!(debugger.machine.state.a, 1);
+        assert_eq!(debugger.machine.state.program_counter(), 0x26);
+    }
+
+    #[test]
+    fn test_rst6_5() {
+        // LXI SP,0040; MVI A,08; SIM (unmask everything); EI; NOP; HLT, with
+        // RST 6.5 going to INR A; HLT
+        let mut memory = vec![0x31, 0x40, 0x00, 0x3e, 0x08, 0x30, 0xfb, 0x00, 0x76];
+        memory.resize(0x40, 0);
+        memory[0x34..0x36].copy_from_slice(&[0x3c, 0x76]);
+        let mut debugger = debugger_8085(memory);
+        run(&mut debugger, "interrupt rst6.5 on");
+        run(&mut debugger, "continue");
+        assert_eq!(debugger.machine.state.a, 9);
+        assert_eq!(debugger.machine.state.program_counter(), 0x36);
+        assert!(run(&mut debugger, "interrupt rst5.5 maybe").contains("should be on or off"));
+    }
+
+    #[test]
+    fn test_serial() {
+        let mut debugger = debugger_8085(vec![0x3e, 0xc0, 0x30, 0x76]); // MVI A,C0; SIM; HLT
+        assert_eq!(run(&mut debugger, "serial"), "SOD=0\n");
+        run(&mut debugger, "step 2");
+        assert_eq!(run(&mut debugger, "serial"), "SOD=1\n");
+        run(&mut debugger, "serial on");
+        assert_eq!(debugger.machine.state.rim() & 0x80, 0x80);
+    }
+
+    #[test]
+    fn test_8085_pins_need_an_8085() {
+        let mut debugger = debugger(vec![0x76]);
+        assert_eq!(run(&mut debugger, "interrupt trap"), "only the 8085 has TRAP\n");
+        assert_eq!(run(&mut debugger, "serial"), "only the 8085 has SID and SOD\n");
+    }
+
+    #[test]
+    fn test_nmi() {
+        // LD SP,0080; NOP; HALT, with NMI going to INC A; HALT
+        let mut memory = vec![0x31, 0x80, 0x00, 0x00, 0x76];
+        memory.resize(0x80, 0);
+        memory[0x66..0x68].copy_from_slice(&[0x3c, 0x76]);
+        let mut debugger = debugger(memory);
+        debugger.machine.state.set_variant(Variant::Z80);
+        run(&mut debugger, "step");
+        run(&mut debugger, "interrupt nmi");
+        run(&mut debugger, "continue");
+        assert_eq!(debugger.machine.state.a, 1);
+        assert_eq!(debugger.machine.state.program_counter(), 0x68);
+    }
+
+    #[test]
+    fn test_nmi_needs_a_z80() {
+        let mut debugger = debugger(vec![0x76]);
+        assert_eq!(run(&mut debugger, "interrupt nmi"), "only the Z80 has NMI\n");
+    }
+}
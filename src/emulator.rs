@@ -1,25 +1,44 @@
-use crate::state_8080::State8080;
+use std::io::{self, Write};
+
+use crate::bus::Bus;
+use crate::call_stack;
 use crate::disassembler;
-use crate::parity::Parity;
-use crate::sign::Sign;
-
-fn unimplemented_instruction(state: &State8080) -> ! {
-    // Subtracting one from the program counter is a workaround because we
-    // increment it at the start of the `emulate_8080_op` function.
-    let actual_pc = state.program_counter() as usize - 1;
-    let (opcode_description, _) = disassembler::disassemble_opcode(&state.memory, actual_pc);
-    let opcode = state.read_memory(actual_pc);
-    eprintln!("Error: Unimplimented instruction: {} ({:02x})", opcode_description, opcode);
-    std::process::exit(1);
+use crate::error::EmulatorError;
+use crate::instruction::Instruction;
+use crate::io_ports::IoPorts;
+use crate::state_8080::{InterruptAcknowledge, State8080, Variant};
+use crate::z80;
+
+// What gets printed when a program stops on an error: the error, the failed
+// instruction and the calls that led to it
+pub fn write_error_report<M: Bus, P: IoPorts, W: Write>(out: &mut W, state: &State8080<M, P>, err: &EmulatorError) -> io::Result<()> {
+    let (opcode_description, _) = disassembler::disassemble_opcode(state.variant(), &state.memory, err.pc as usize);
+    writeln!(out, "Error: {}: {}", err, opcode_description)?;
+    // The instruction may have moved the program counter before it failed
+    let mut backtrace = state.backtrace();
+    backtrace[0].address = err.pc;
+    writeln!(out, "Backtrace:")?;
+    call_stack::write_backtrace(out, &backtrace)
 }
 
+// Runs until the CPU halts. Nothing can raise an interrupt from in here, so a
+// halted CPU would only wake up for a request that was already latched; it's
+// up to the caller to decide what happens next.
 #[allow(dead_code)]
-pub fn run(state: &mut State8080) {
-    loop {
-        emulate_8080_op(state);
+pub fn run<M: Bus, P: IoPorts>(state: &mut State8080<M, P>) -> Result<(), EmulatorError> {
+    while !state.halted() {
+        emulate_8080_op(state)?;
     }
+    Ok(())
 }
 
+// Cycles burned per call to `emulate_8080_op` while the CPU is halted. A real
+// 8080 just sits in the halt state until an interrupt comes in, so this only
+// sets how finely callers can interleave their own work with the wait.
+const HALTED_CYCLES: u32 = 4;
+
+// Conditional calls and returns are listed with their not-taken cost. Taking
+// the branch costs an extra `branch_taken_cycles` on top of that.
 const CYCLES: [u32; 256] = [
     4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4,
     4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4,
@@ -36,902 +55,301 @@ const CYCLES: [u32; 256] = [
     4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
     4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
 
-    11, 10, 10, 10, 17, 11, 7, 11, 11, 10, 10, 10, 10, 17, 7, 11,
-    11, 10, 10, 10, 17, 11, 7, 11, 11, 10, 10, 10, 10, 17, 7, 11,
-    11, 10, 10, 18, 17, 11, 7, 11, 11, 5, 10, 5, 17, 17, 7, 11,
-    11, 10, 10, 4, 17, 11, 7, 11, 11, 5, 10, 4, 17, 17, 7, 11,
+    5, 10, 10, 10, 11, 11, 7, 11, 5, 10, 10, 10, 11, 17, 7, 11,
+    5, 10, 10, 10, 11, 11, 7, 11, 5, 10, 10, 10, 11, 17, 7, 11,
+    5, 10, 10, 18, 11, 11, 7, 11, 5, 5, 10, 4, 11, 17, 7, 11,
+    5, 10, 10, 4, 11, 11, 7, 11, 5, 5, 10, 4, 11, 17, 7, 11,
 ];
 
-pub fn emulate_8080_op(state: &mut State8080) -> u32 {
-    let program_counter: usize = state.program_counter() as usize;
-    let opcode: u8 = state.read_memory(program_counter);
-    // TODO: How expensive is the following env var fetch and check? Does it
-    // need to be moved outside this function?
-    /*
-    if let Ok(val) = std::env::var("DEBUG_PRINT_INSTRUCTIONS") {
-        match val.as_ref() {
-            "1" => {
-                let (opcode_description, _) = disassembler::disassemble_opcode(&state.memory, program_counter);
-                println!("{}\t| {:#02x} | {:x?}", opcode_description, opcode, state);
-            },
-            _ => {},
-        }
-    }
-    */
+// The 8085 is quicker for some things and slower for others (INX, PUSH,
+// CALL). Its conditional jumps also cost less when they aren't taken.
+const CYCLES_8085: [u32; 256] = [
+    4, 10, 7, 6, 4, 4, 7, 4, 10, 10, 7, 6, 4, 4, 7, 4,
+    7, 10, 7, 6, 4, 4, 7, 4, 10, 10, 7, 6, 4, 4, 7, 4,
+    4, 10, 16, 6, 4, 4, 7, 4, 10, 10, 16, 6, 4, 4, 7, 4,
+    4, 10, 13, 6, 10, 10, 10, 4, 10, 10, 13, 6, 4, 4, 7, 4,
 
-    state.increment_program_counter(1);
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    7, 7, 7, 7, 7, 7, 5, 7, 4, 4, 4, 4, 4, 4, 7, 4,
 
-    match opcode {
-        0x00 => {}, // NOP
-        0x01 => { // LXI B, D16
-            state.b = state.read_memory(program_counter + 2);
-            state.c = state.read_memory(program_counter + 1);
-            state.increment_program_counter(2);
-        },
-        0x02 => { // STAX B
-            let address = state.bc();
-            state.write_memory(address as usize, state.a);
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+
+    6, 10, 7, 10, 9, 12, 7, 12, 6, 10, 7, 6, 9, 18, 7, 12,
+    6, 10, 7, 10, 9, 12, 7, 12, 6, 10, 7, 10, 9, 7, 7, 12,
+    6, 10, 7, 16, 9, 12, 7, 12, 6, 6, 7, 4, 9, 10, 7, 12,
+    6, 10, 7, 4, 9, 12, 7, 12, 6, 6, 7, 4, 9, 7, 7, 12,
+];
+
+// What taking a conditional jump, call or return costs on top of the table
+// entry. The 8080's conditional jumps take the same time either way.
+fn branch_taken_cycles(variant: Variant, opcode: u8) -> u32 {
+    match (variant, opcode & 0x07) {
+        (Variant::Intel8085, 0x00) | (Variant::Intel8085, 0x03) => 6, // Rcc, RSTV
+        (Variant::Intel8085, 0x02) | (Variant::Intel8085, 0x05) => 3, // Jcc, JNK, JK
+        (Variant::Intel8085, _) => 9, // Ccc
+        (_, 0x02) => 0, // Jcc
+        (_, _) => 6, // Ccc, Rcc
+    }
+}
+
+pub fn emulate_8080_op<M: Bus, P: IoPorts>(state: &mut State8080<M, P>) -> Result<u32, EmulatorError> {
+    // The profiler and tracer are taken out for the length of the instruction
+    // so they can look at the state around it
+    match state.take_profiler() {
+        None => traced(state),
+        Some(mut profiler) => {
+            let result = profiler.profile(state, traced);
+            state.set_profiler(Some(profiler));
+            result
         },
-        0x03 => { // INX B
-            state.set_bc(state.bc().wrapping_add(1));
+    }
+}
+
+fn traced<M: Bus, P: IoPorts>(state: &mut State8080<M, P>) -> Result<u32, EmulatorError> {
+    match state.take_tracer() {
+        None => execute(state),
+        Some(mut tracer) => {
+            let result = tracer.trace(state, execute);
+            state.set_tracer(Some(tracer));
+            result
         },
-        0x04 => { // INR B
-            State8080::increment_register(&mut state.b, &mut state.cc);
+    }
+}
+
+fn execute<M: Bus, P: IoPorts>(state: &mut State8080<M, P>) -> Result<u32, EmulatorError> {
+    if state.variant() == Variant::Z80 {
+        return z80::emulate_z80_op(state);
+    }
+    // Interrupts are acknowledged between instructions. The acknowledged
+    // instruction comes off the data bus rather than from memory, so the
+    // program counter isn't advanced for it and an RST pushes the address of
+    // the instruction that was about to run.
+    let acknowledging = match state.acknowledge_interrupt() {
+        InterruptAcknowledge::None => false,
+        InterruptAcknowledge::DataBus => true,
+        InterruptAcknowledge::Vectored(cycles) => return Ok(cycles),
+    };
+    if state.halted() {
+        return Ok(HALTED_CYCLES);
+    }
+    let program_counter: u16 = state.program_counter();
+    let opcode: u8 = state.next_byte();
+    if let Some(reason) = state.take_bus_fault() {
+        state.finish_interrupt_acknowledge();
+        state.set_program_counter(program_counter);
+        return Err(EmulatorError { pc: program_counter, opcode, reason });
+    }
+    let variant = state.variant();
+    let mut cycles = match variant {
+        Variant::Intel8085 => CYCLES_8085[opcode as usize],
+        _ => CYCLES[opcode as usize],
+    };
+    match Instruction::decode(variant, opcode, || state.next_byte()) {
+        Instruction::Nop => {},
+        Instruction::Lxi(pair, value) => state.set_register_pair(pair, value),
+        Instruction::Stax(pair) => {
+            let address = state.register_pair(pair);
+            state.write_memory(address, state.a);
         },
-        0x05 => { // DCR B
-            State8080::decrement_register(&mut state.b, &mut state.cc);
+        Instruction::Shld(address) => {
+            state.write_memory(address, state.l);
+            state.write_memory(address.wrapping_add(1), state.h);
         },
-        0x06 => { // MVI B, D8
-            state.b = state.read_memory(program_counter + 1);
-            state.increment_program_counter(1);
+        Instruction::Sta(address) => state.write_memory(address, state.a),
+        Instruction::Inx(pair) => {
+            let value = state.increment_word(state.register_pair(pair));
+            state.set_register_pair(pair, value);
         },
-        0x07 => { // RLC
+        Instruction::Inr(register) => state.inr(register),
+        Instruction::Dcr(register) => state.dcr(register),
+        Instruction::Mvi(destination, value) => state.set_register(destination, value),
+        Instruction::Rlc => {
             state.cc.cy = if (state.a & 0x80) == 0x80 { 1 } else { 0 };
             state.a = state.a.rotate_left(1);
         },
-        0x08 => {}, // -
-        0x09 => { // DAD B
-            let result: u32 = state.hl() as u32 + state.bc() as u32;
-            state.cc.cy = if result > 0xffff { 1 } else { 0 };
-            state.set_hl(result as u16);
-        },
-        0x0a => { // LDAX B
-            state.a = state.read_memory(state.bc() as usize);
-        },
-        0x0b => { // DCX B
-            state.set_bc(state.bc().wrapping_sub(1));
-        },
-        0x0c => { // INR C
-            State8080::increment_register(&mut state.c, &mut state.cc);
-        },
-        0x0d => { // DCR C
-            State8080::decrement_register(&mut state.c, &mut state.cc);
-        },
-        0x0e => { // MVI C, D8
-            state.c = state.read_memory(program_counter + 1);
-            state.increment_program_counter(1);
-        },
-        0x0f => { // RRC
+        Instruction::Rrc => {
             state.cc.cy = state.a & 0x01;
             state.a = state.a.rotate_right(1);
         },
-        0x10 => unimplemented_instruction(state), // -
-        0x11 => { // LXI D, D16
-            state.e = state.read_memory(program_counter + 1);
-            state.d = state.read_memory(program_counter + 2);
-            state.increment_program_counter(2);
-        },
-        0x12 => { // STAX D
-            let address = state.de();
-            state.write_memory(address as usize, state.a);
-        },
-        0x13 => { // INX D
-            state.set_de(state.de().wrapping_add(1));
-        }
-        0x14 => { // INR D
-            State8080::increment_register(&mut state.d, &mut state.cc);
-        },
-        0x15 => { // DCR D
-            State8080::decrement_register(&mut state.d, &mut state.cc);
-        },
-        0x16 => { // MVI D, D8
-            state.d = state.read_memory(program_counter + 1);
-            state.increment_program_counter(1);
-        },
-        0x17 => { // RAL
+        Instruction::Ral => {
             let carry = state.cc.cy;
             let register_a_high_bit = (state.a & 0x80) >> 7;
             state.cc.cy = register_a_high_bit;
             state.a = (state.a << 1) | (carry & 0x01);
         },
-        0x18 => {}, // -
-        0x19 => { // DAD D
-            let result: u32 = state.hl() as u32 + state.de() as u32;
-            state.cc.cy = if result > 0xffff { 1 } else { 0 };
-            state.set_hl(result as u16);
-        },
-        0x1a => { // LDAX D
-            state.a = state.read_memory(state.de() as usize);
-        },
-        0x1b => { // DCX D
-            state.set_de(state.de().wrapping_sub(1));
-        },
-        0x1c => { // INR E
-            State8080::increment_register(&mut state.e, &mut state.cc);
-        },
-        0x1d => { // DCR E
-            State8080::decrement_register(&mut state.e, &mut state.cc);
-        },
-        0x1e => { // MVI E, D8
-            state.e = state.read_memory(program_counter + 1);
-            state.increment_program_counter(1);
-        },
-        0x1f => { // RAR
+        Instruction::Rar => {
             let carry = state.cc.cy;
             let carry_as_high_bit = (carry << 7) & 0x80;
             let register_a_low_bit = state.a & 0x01;
             state.cc.cy = register_a_low_bit;
             state.a = ((state.a & 0x7f) >> 1) | carry_as_high_bit;
         },
-        0x20 => {}, // -
-        0x21 => { // LXI H, D16
-            state.l = state.read_memory(program_counter + 1);
-            state.h = state.read_memory(program_counter + 2);
-            state.increment_program_counter(2);
-        },
-        0x22 => { // SHLD adr
-            let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-            let low_address = state.read_memory(program_counter + 1) as u16;
-            let address: usize = (high_address | low_address) as usize;
-            state.write_memory(address, state.l);
-            state.write_memory(address + 1, state.h);
-            state.increment_program_counter(2);
-        },
-        0x23 => { // INX H
-            state.set_hl(state.hl().wrapping_add(1));
-        },
-        0x24 => { // INR H
-            State8080::increment_register(&mut state.h, &mut state.cc);
-        },
-        0x25 => { // DCR H
-            State8080::decrement_register(&mut state.h, &mut state.cc);
-        },
-        0x26 => { // MVI H, D8
-            state.h = state.read_memory(program_counter + 1);
-            state.increment_program_counter(1);
-        },
-        0x27 => { // DAA
-            let low: u8 = state.a & 0xf;
-            if low > 9 || state.cc.ac != 0 {
-                let result = low + 0x06;
-                state.cc.ac = if result > 0xf { 1 } else { 0 };
-                state.a = state.a.wrapping_add(0x06);
-            }
-            let high: u8 = (state.a & 0xf0) >> 4;
-            if high > 9 || state.cc.cy != 0 {
-                let result = high + 0x06;
-                state.cc.cy = if result > 0xf { 1 } else { 0 };
-                state.a = state.a.wrapping_add(0x60);
-            }
-        },
-        0x28 => unimplemented_instruction(state), // -
-        0x29 => { // DAD H
-            let result: u32 = state.hl() as u32 + state.hl() as u32;
+        Instruction::Dad(pair) => {
+            let result: u32 = state.hl() as u32 + state.register_pair(pair) as u32;
             state.cc.cy = if result > 0xffff { 1 } else { 0 };
             state.set_hl(result as u16);
         },
-        0x2a => { // LHLD adr
-            let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-            let low_address = state.read_memory(program_counter + 1) as u16;
-            let address: usize = (high_address | low_address) as usize;
-            state.l = state.read_memory(address);
-            state.h = state.read_memory(address + 1);
-            state.increment_program_counter(2);
-        },
-        0x2b => { // DCX H
-            state.set_hl(state.hl().wrapping_sub(1));
+        Instruction::Ldax(pair) => {
+            state.a = state.read_memory(state.register_pair(pair));
         },
-        0x2c => { // INR L
-            State8080::increment_register(&mut state.l, &mut state.cc);
-        },
-        0x2d => { // DCR L
-            State8080::decrement_register(&mut state.l, &mut state.cc);
-        },
-        0x2e => { // MVI L, D8
-            state.l = state.read_memory(program_counter + 1);
-            state.increment_program_counter(1);
-        },
-        0x2f => { // CMA
-            state.a = !state.a;
-        },
-        0x30 => {}, // -
-        0x31 => { // LXI SP, D16
-            state.sp = ((state.read_memory(program_counter + 2) as u16) << 8) | state.read_memory(program_counter + 1) as u16;
-            state.increment_program_counter(2);
-        },
-        0x32 => { // STA adr
-            let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-            let low_address = state.read_memory(program_counter + 1) as u16;
-            let address: usize = (high_address | low_address) as usize;
-            state.write_memory(address, state.a);
-            state.increment_program_counter(2);
-        },
-        0x33 => { // INX SP
-            state.sp += 1;
-        },
-        0x34 => { // INR M
-            let answer: u16 = state.m() as u16 + 1;
-            let masked_answer: u8 = (answer & 0xff) as u8;
-            state.cc.z = if masked_answer == 0 { 1 } else { 0 };
-            state.cc.s = Sign::get_sign(masked_answer);
-            state.cc.p = Parity::from(masked_answer);
-            state.set_m(masked_answer);
-        },
-        0x35 => { // DCR M
-            let minuend: u8 = state.m();
-            let answer: u8 = minuend.wrapping_sub(1);
-            state.cc.z = if answer == 0 { 1 } else { 0 };
-            state.cc.s = Sign::get_sign(answer);
-            state.cc.p = Parity::from(answer);
-            state.set_m(answer);
-        },
-        0x36 => { // MVI M, D8
-            state.set_m(state.read_memory(program_counter + 1));
-            state.increment_program_counter(1);
-        },
-        0x37 => { // STC
-            state.cc.cy = 1;
-        },
-        0x38 => {}, // -
-        0x39 => { // DAD SP
-            let result: u32 = state.hl() as u32 + state.sp as u32;
-            state.cc.cy = if result > 0xffff { 1 } else { 0 };
-            state.set_hl(result as u16);
+        Instruction::Lhld(address) => {
+            state.l = state.read_memory(address);
+            state.h = state.read_memory(address.wrapping_add(1));
         },
-        0x3a => { // LDA adr
-            let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-            let low_address = state.read_memory(program_counter + 1) as u16;
-            let address: usize = (high_address | low_address) as usize;
+        Instruction::Lda(address) => {
             state.a = state.read_memory(address);
-            state.increment_program_counter(2);
-        },
-        0x3b => { // DCX SP
-            state.sp = state.sp.wrapping_sub(1);
-        },
-        0x3c => { // INR A
-            State8080::increment_register(&mut state.a, &mut state.cc);
-        },
-        0x3d => { // DCR A
-            State8080::decrement_register(&mut state.a, &mut state.cc);
-        },
-        0x3e => { // MVI A, D8
-            state.a = state.read_memory(program_counter + 1);
-            state.increment_program_counter(1);
-        },
-        0x3f => { // CMC
-            if state.cc.cy == 0 {
-                state.cc.cy = 1;
-            } else {
-                state.cc.cy = 0;
-            }
-        },
-        0x40 => state.b = state.b, // MOV B, B
-        0x41 => state.b = state.c, // MOV B, C
-        0x42 => state.b = state.d, // MOV B, D
-        0x43 => state.b = state.e, // MOV B, E
-        0x44 => state.b = state.h, // MOV B, H
-        0x45 => state.b = state.l, // MOV B, L
-        0x46 => { state.b = state.m(); }, // MOV B, M
-        0x47 => state.b = state.a, // MOV B, A
-        0x48 => state.c = state.b, // MOV C, B
-        0x49 => state.c = state.c, // MOV C, C
-        0x4a => state.c = state.d, // MOV C, D
-        0x4b => state.c = state.e, // MOV C, E
-        0x4c => state.c = state.h, // MOV C, H
-        0x4d => state.c = state.l, // MOV C, L
-        0x4e => { state.c = state.m(); }, // MOV C, M
-        0x4f => state.c = state.a, // MOV C, A
-        0x50 => state.d = state.b, // MOV D, B
-        0x51 => state.d = state.c, // MOV D, C
-        0x52 => state.d = state.d, // MOV D, D
-        0x53 => state.d = state.e, // MOV D, E
-        0x54 => state.d = state.h, // MOV D, H
-        0x55 => state.d = state.l, // MOV D, L
-        0x56 => { state.d = state.m(); }, // MOV D, M
-        0x57 => state.d = state.a, // MOV D, A
-        0x58 => state.e = state.b, // MOV E, B
-        0x59 => state.e = state.c, // MOV E, C
-        0x5a => state.e = state.d, // MOV E, D
-        0x5b => state.e = state.e, // MOV E, E
-        0x5c => state.e = state.h, // MOV E, H
-        0x5d => state.e = state.l, // MOV E, L
-        0x5e => { state.e = state.m(); }, // MOV E, M
-        0x5f => state.e = state.a, // MOV E, A
-        0x60 => state.h = state.b, // MOV H, B
-        0x61 => state.h = state.c, // MOV H, C
-        0x62 => state.h = state.d, // MOV H, D
-        0x63 => state.h = state.e, // MOV H, E
-        0x64 => state.h = state.h, // MOV H, H
-        0x65 => state.h = state.l, // MOV H, L
-        0x66 => { state.h = state.m(); }, // MOV H, M
-        0x67 => state.h = state.a, // MOV H, A
-        0x68 => state.l = state.b, // MOV L, B
-        0x69 => state.l = state.c, // MOV L, C
-        0x6a => state.l = state.d, // MOV L, D
-        0x6b => state.l = state.e, // MOV L, E
-        0x6c => state.l = state.h, // MOV L, H
-        0x6d => state.l = state.l, // MOV L, L
-        0x6e => { state.l = state.m(); }, // MOV L, M
-        0x6f => state.l = state.a, // MOV L, A
-        0x70 => { state.set_m(state.b); } // MOV M, B
-        0x71 => { state.set_m(state.c); }, // MOV M, C
-        0x72 => { state.set_m(state.d); }, // MOV M, D
-        0x73 => { state.set_m(state.e); }, // MOV M, E
-        0x74 => { state.set_m(state.h); }, // MOV M, H
-        0x75 => { state.set_m(state.l); }, // MOV M, L
-        0x76 => { // HLT
-            // This is a bit aggressive
-            std::process::exit(0);
-        },
-        0x77 => { state.set_m(state.a); }, // MOV M, A
-        0x78 => state.a = state.b, // MOV A, B
-        0x79 => state.a = state.c, // MOV A, C
-        0x7a => state.a = state.d, // MOV A, D
-        0x7b => state.a = state.e, // MOV A, E
-        0x7c => state.a = state.h, // MOV A, H
-        0x7d => state.a = state.l, // MOV A, L
-        0x7e => { state.a = state.m(); }, // MOV A, M
-        0x7f => state.a = state.a, // MOV A, A
-        0x80 => { state.add(state.b); }, // ADD B
-        0x81 => { state.add(state.c); }, // ADD C
-        0x82 => { state.add(state.d); }, // ADD D
-        0x83 => { state.add(state.e); }, // ADD E
-        0x84 => { state.add(state.h); }, // ADD H
-        0x85 => { state.add(state.l); }, // ADD L
-        0x86 => { state.add(state.m()); }, // ADD M
-        0x87 => { state.add(state.a); }, // ADD A
-        0x88 => { state.adc(state.b); }, // ADC B
-        0x89 => { state.adc(state.c); }, // ADC C
-        0x8a => { state.adc(state.d); }, // ADC D
-        0x8b => { state.adc(state.e); }, // ADC E
-        0x8c => { state.adc(state.h); }, // ADC H
-        0x8d => { state.adc(state.l); }, // ADC L
-        0x8e => { state.adc(state.m()); }, // ADC M
-        0x8f => { state.adc(state.a); }, // ADC A
-        0x90 => { state.sub(state.b); }, // SUB B
-        0x91 => { state.sub(state.c); }, // SUB C
-        0x92 => { state.sub(state.d); }, // SUB D
-        0x93 => { state.sub(state.e); }, // SUB E
-        0x94 => { state.sub(state.h); }, // SUB H
-        0x95 => { state.sub(state.l); }, // SUB L
-        0x96 => { state.sub(state.m()); }, // SUB M
-        0x97 => { state.sub(state.a); }, // SUB A
-        0x98 => { state.sbb(state.b); }, // SBB B
-        0x99 => { state.sbb(state.c); }, // SBB C
-        0x9a => { state.sbb(state.d); }, // SBB D
-        0x9b => { state.sbb(state.e); }, // SBB E
-        0x9c => { state.sbb(state.h); }, // SBB H
-        0x9d => { state.sbb(state.l); }, // SBB L
-        0x9e => { state.sbb(state.m()); }, // SBB M
-        0x9f => { state.sbb(state.a); }, // SBB A
-        0xa0 => { state.ana(state.b); }, // ANA B
-        0xa1 => { state.ana(state.c); }, // ANA C
-        0xa2 => { state.ana(state.d); }, // ANA D
-        0xa3 => { state.ana(state.e); }, // ANA E
-        0xa4 => { state.ana(state.h); }, // ANA H
-        0xa5 => { state.ana(state.l); }, // ANA L
-        0xa6 => { state.ana(state.m()); }, // ANA M
-        0xa7 => { state.ana(state.a); }, // ANA A
-        0xa8 => { state.xra(state.b); }, // XRA B
-        0xa9 => { state.xra(state.c); }, // XRA C
-        0xaa => { state.xra(state.d); }, // XRA D
-        0xab => { state.xra(state.e); }, // XRA E
-        0xac => { state.xra(state.h); }, // XRA H
-        0xad => { state.xra(state.l); }, // XRA L
-        0xae => { state.xra(state.m()); }, // XRA M
-        0xaf => { state.xra(state.a); }, // XRA A
-        0xb0 => { state.ora(state.b); }, // ORA B
-        0xb1 => { state.ora(state.c); }, // ORA C
-        0xb2 => { state.ora(state.d); }, // ORA D
-        0xb3 => { state.ora(state.e); }, // ORA E
-        0xb4 => { state.ora(state.h); }, // ORA H
-        0xb5 => { state.ora(state.l); }, // ORA L
-        0xb6 => { state.ora(state.m()); }, // ORA M
-        0xb7 => { state.ora(state.a); }, // ORA A
-        0xb8 => { state.cmp(state.b); }, // CMP B
-        0xb9 => { state.cmp(state.c); }, // CMP C
-        0xba => { state.cmp(state.d); }, // CMP D
-        0xbb => { state.cmp(state.e); }, // CMP E
-        0xbc => { state.cmp(state.h); }, // CMP H
-        0xbd => { state.cmp(state.l); }, // CMP L
-        0xbe => { state.cmp(state.m()); }, // CMP M
-        0xbf => { state.cmp(state.a); }, // CMP A
-        0xc0 => { // RNZ
-            if state.cc.z == 0 {
-                let high_address = state.read_memory(state.sp as usize) as u16;
-                let low_address = (state.read_memory(state.sp as usize + 1) as u16) << 8;
-                state.set_program_counter(high_address | low_address);
-                state.sp += 2;
-            }
-        },
-        0xc1 => { // POP B
-            let (high, low) = state.pop();
-            state.b = high;
-            state.c = low;
-
-        },
-        0xc2 => { // JNZ adr
-            if state.cc.z == 0 {
-                let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-                let low_address = state.read_memory(program_counter + 1) as u16;
-                state.set_program_counter(high_address | low_address);
-            } else {
-                state.increment_program_counter(2);
-            }
-        },
-        0xc3 => { // JMP adr
-            let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-            let low_address = state.read_memory(program_counter + 1) as u16;
-            state.set_program_counter(high_address | low_address);
         },
-        0xc4 => { // CNZ adr
-            if state.cc.z == 0 {
-                let ret: u16 = program_counter as u16 + 2;
-                state.write_memory(state.sp as usize - 1, ((ret >> 8) & 0xff) as u8);
-                state.write_memory(state.sp as usize - 2, (ret & 0xff) as u8);
-                state.sp = state.sp - 2;
-                let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-                let low_address = state.read_memory(program_counter + 1) as u16;
-                state.set_program_counter(high_address | low_address);
-            } else {
-                state.increment_program_counter(2);
+        Instruction::Dcx(pair) => {
+            let value = state.decrement_word(state.register_pair(pair));
+            state.set_register_pair(pair, value);
+        },
+        Instruction::Daa => state.daa(),
+        Instruction::Cma => state.a = !state.a,
+        Instruction::Stc => state.cc.cy = 1,
+        Instruction::Cmc => state.cc.cy ^= 1,
+        Instruction::Mov(destination, source) => {
+            let value = state.register(source);
+            state.set_register(destination, value);
+        },
+        Instruction::Hlt => state.halt(),
+        Instruction::Add(source) => state.add(state.register(source)),
+        Instruction::Adc(source) => state.adc(state.register(source)),
+        Instruction::Sub(source) => state.sub(state.register(source)),
+        Instruction::Sbb(source) => state.sbb(state.register(source)),
+        Instruction::Ana(source) => state.ana(state.register(source)),
+        Instruction::Xra(source) => state.xra(state.register(source)),
+        Instruction::Ora(source) => state.ora(state.register(source)),
+        Instruction::Cmp(source) => state.cmp(state.register(source)),
+        Instruction::Adi(value) => state.add(value),
+        Instruction::Aci(value) => state.adc(value),
+        Instruction::Sui(value) => state.sub(value),
+        Instruction::Sbi(value) => state.sbb(value),
+        Instruction::Ani(value) => state.ana(value),
+        Instruction::Xri(value) => state.xra(value),
+        Instruction::Ori(value) => state.ora(value),
+        Instruction::Cpi(value) => state.cmp(value),
+        Instruction::ReturnIf(condition) => {
+            if state.condition(condition) {
+                state.ret();
+                cycles += branch_taken_cycles(variant, opcode);
             }
         },
-        0xc5 => { // PUSH B
-            state.push(state.b, state.c);
-        },
-        0xc6 => { // ADI D8
-            let answer: u16 = (state.a as u16) + (state.read_memory(program_counter + 1) as u16);
-            let masked_answer: u8 = (answer & 0xff) as u8;
-            state.cc.z = if masked_answer == 0 { 1 } else { 0 };
-            state.cc.s = Sign::get_sign(masked_answer);
-            state.cc.cy = if answer > 0xff { 1 } else { 0 };
-            state.cc.p = Parity::from(masked_answer);
-            state.a = masked_answer;
-            state.increment_program_counter(1);
-        },
-        0xc7 => { // RST 0
-            let ret: u16 = state.program_counter() + 2;
-            state.write_memory(state.sp as usize - 1, ((ret >> 8) & 0xff) as u8);
-            state.write_memory(state.sp as usize - 2, (ret & 0xff) as u8);
-            state.sp -= 2;
-            state.set_program_counter(0x00);
-        },
-        0xc8 => { // RZ
-            if state.cc.z != 0 {
-                let high_address = state.read_memory(state.sp as usize) as u16;
-                let low_address = (state.read_memory(state.sp as usize + 1) as u16) << 8;
-                state.set_program_counter(high_address | low_address);
-                state.sp += 2;
-            }
-        },
-        0xc9 => { // RET
-            let low_address = state.read_memory(state.sp as usize) as u16;
-            let high_address = (state.read_memory(state.sp as usize + 1) as u16) << 8;
-            state.set_program_counter(high_address | low_address);
-            state.sp += 2;
-        },
-        0xca => { // JZ adr
-            if state.cc.z != 0 {
-                let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-                let low_address = state.read_memory(program_counter + 1) as u16;
-                state.set_program_counter(high_address | low_address);
-            } else {
-                state.increment_program_counter(2);
-            }
-        },
-        0xcb => unimplemented_instruction(state), // -
-        0xcc => { // CZ adr
-            if state.cc.z != 0 {
-                let ret: u16 = program_counter as u16 + 2;
-                state.write_memory(state.sp as usize - 1, ((ret >> 8) & 0xff) as u8);
-                state.write_memory(state.sp as usize - 2, (ret & 0xff) as u8);
-                state.sp = state.sp - 2;
-                let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-                let low_address = state.read_memory(program_counter + 1) as u16;
-                state.set_program_counter(high_address | low_address);
-            } else {
-                state.increment_program_counter(2);
-            }
-        },
-        0xcd => { // CALL adr
-            let ret: u16 = program_counter as u16 + 3;
-            state.write_memory(state.sp as usize - 1, ((ret >> 8) & 0xff) as u8);
-            state.write_memory(state.sp as usize - 2, (ret & 0xff) as u8);
-            state.sp = state.sp - 2;
-            let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-            let low_address = state.read_memory(program_counter + 1) as u16;
-            state.set_program_counter(high_address | low_address);
-        },
-        0xce => { // ACI D8
-            let answer: u16 = (state.a as u16) + (state.read_memory(program_counter + 1) as u16) + state.cc.cy as u16;
-            let masked_answer: u8 = (answer & 0xff) as u8;
-            state.cc.z = if masked_answer == 0 { 1 } else { 0 };
-            state.cc.s = Sign::get_sign(masked_answer);
-            state.cc.cy = if answer > 0xff { 1 } else { 0 };
-            state.cc.p = Parity::from(masked_answer);
-            state.a = masked_answer;
-            state.increment_program_counter(1);
-        },
-        0xcf => { // RST 1
-            let ret: u16 = state.program_counter() + 2;
-            state.write_memory(state.sp as usize - 1, ((ret >> 8) & 0xff) as u8);
-            state.write_memory(state.sp as usize - 2, (ret & 0xff) as u8);
-            state.sp -= 2;
-            state.set_program_counter(0x08);
-        }
-        0xd0 => { // RNC
-            if state.cc.cy == 0 {
-                let low_address = state.read_memory(state.sp as usize) as u16;
-                let high_address = (state.read_memory(state.sp as usize + 1) as u16) << 8;
-                state.set_program_counter(high_address | low_address);
-                state.sp += 2;
-            }
-        },
-        0xd1 => { // POP D
-            let (high, low) = state.pop();
-            state.d = high;
-            state.e = low;
-        },
-        0xd2 => { // JNC adr
-            if state.cc.cy == 0 {
-                let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-                let low_address = state.read_memory(program_counter + 1) as u16;
-                state.set_program_counter(high_address | low_address);
-            } else {
-                state.increment_program_counter(2);
-            }
-        },
-        0xd3 => { // OUT D8
-            // TODO: IO
-            // This is the OUT instruction, for now just skip data byte
-            state.increment_program_counter(1);
-        },
-        0xd4 => { // CNC adr
-            if state.cc.cy == 0 {
-                let ret: u16 = program_counter as u16 + 2;
-                state.write_memory(state.sp as usize - 1, ((ret >> 8) & 0xff) as u8);
-                state.write_memory(state.sp as usize - 2, (ret & 0xff) as u8);
-                state.sp = state.sp - 2;
-                let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-                let low_address = state.read_memory(program_counter + 1) as u16;
-                state.set_program_counter(high_address | low_address);
-            } else {
-                state.increment_program_counter(2);
-            }
-        },
-        0xd5 => { // PUSH D
-            state.push(state.d, state.e);
-        },
-        0xd6 => { // SUI D8
-            let subtrahend: u8 = state.read_memory(program_counter + 1);
-            let answer: u8 = state.a.wrapping_sub(subtrahend);
-            state.cc.z = if answer == 0 { 1 } else { 0 };
-            state.cc.s = Sign::get_sign(answer);
-            state.cc.cy = if state.a < subtrahend { 1 } else { 0 };
-            state.cc.p = Parity::from(answer);
-            state.a = answer;
-            state.increment_program_counter(1);
-        },
-        0xd7 => { // RST 2
-            let ret: u16 = state.program_counter() + 2;
-            state.write_memory(state.sp as usize - 1, ((ret >> 8) & 0xff) as u8);
-            state.write_memory(state.sp as usize - 2, (ret & 0xff) as u8);
-            state.sp -= 2;
-            state.set_program_counter(0x10);
-        },
-        0xd8 => { // RC
-            if state.cc.cy != 0 {
-                let low_address = state.read_memory(state.sp as usize) as u16;
-                let high_address = (state.read_memory(state.sp as usize + 1) as u16) << 8;
-                state.set_program_counter(high_address | low_address);
-                state.sp += 2;
-            }
-        },
-        0xd9 => unimplemented_instruction(state), // -
-        0xda => { // JC adr
-            if state.cc.cy != 0 {
-                let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-                let low_address = state.read_memory(program_counter + 1) as u16;
-                state.set_program_counter(high_address | low_address);
-            } else {
-                state.increment_program_counter(2);
-            }
-        },
-        0xdb => { // IN D8
-            // TODO: IO
-            // This is the IN instruction, for now just skip data byte
-            state.increment_program_counter(1);
-        },
-        0xdc => { // CC adr
-            if state.cc.cy != 0 {
-                let ret: u16 = program_counter as u16 + 2;
-                state.write_memory(state.sp as usize - 1, ((ret >> 8) & 0xff) as u8);
-                state.write_memory(state.sp as usize - 2, (ret & 0xff) as u8);
-                state.sp = state.sp - 2;
-                let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-                let low_address = state.read_memory(program_counter + 1) as u16;
-                state.set_program_counter(high_address | low_address);
-            } else {
-                state.increment_program_counter(2);
+        Instruction::JumpIf(condition, address) => {
+            if state.condition(condition) {
+                state.set_program_counter(address);
+                cycles += branch_taken_cycles(variant, opcode);
             }
         },
-        0xdd => unimplemented_instruction(state), // -
-        0xde => { // SBI D8
-            let subtrahend: u8 = state.read_memory(program_counter + 1).wrapping_add(state.cc.cy);
-            let answer: u8 = state.a.wrapping_sub(subtrahend);
-            state.cc.z = if answer == 0 { 1 } else { 0 };
-            state.cc.s = Sign::get_sign(answer);
-            state.cc.cy = if state.a < subtrahend { 1 } else { 0 };
-            state.cc.p = Parity::from(answer);
-            state.a = answer;
-            state.increment_program_counter(1);
-        }
-        0xdf => { // RST 3
-            let ret: u16 = state.program_counter() + 2;
-            state.write_memory(state.sp as usize - 1, ((ret >> 8) & 0xff) as u8);
-            state.write_memory(state.sp as usize - 2, (ret & 0xff) as u8);
-            state.sp -= 2;
-            state.set_program_counter(0x18);
-        }
-        0xe0 => { // RPO
-            if state.cc.p == Parity::Odd {
-                let high_address = state.read_memory(state.sp as usize) as u16;
-                let low_address = (state.read_memory(state.sp as usize + 1) as u16) << 8;
-                state.set_program_counter(high_address | low_address);
-                state.sp += 2;
+        Instruction::CallIf(condition, address) => {
+            if state.condition(condition) {
+                state.call(address);
+                cycles += branch_taken_cycles(variant, opcode);
             }
         },
-        0xe1 => { // POP H
+        Instruction::Ret => state.ret(),
+        Instruction::Jmp(address) => state.set_program_counter(address),
+        Instruction::Call(address) => state.call(address),
+        Instruction::Rst(number) => state.call(number as u16 * 8),
+        Instruction::Pop(pair) => {
             let (high, low) = state.pop();
-            state.h = high;
-            state.l = low;
-        },
-        0xe2 => { // JPO adr
-            if state.cc.p == Parity::Odd {
-                let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-                let low_address = state.read_memory(program_counter + 1) as u16;
-                state.set_program_counter(high_address | low_address);
-            } else {
-                state.increment_program_counter(2);
-            }
-        },
-        0xe3 => { // XTHL
-            let new_l = state.read_memory(state.sp as usize);
-            let new_h = state.read_memory(state.sp as usize + 1);
-            state.write_memory(state.sp as usize, state.l);
-            state.write_memory(state.sp as usize + 1, state.h);
+            state.set_register_pair(pair, (high as u16) << 8 | low as u16);
+        },
+        Instruction::Push(pair) => {
+            let value = state.register_pair(pair);
+            state.push((value >> 8) as u8, value as u8);
+        },
+        Instruction::Out(port) => state.io.output(port, state.a),
+        Instruction::In(port) => state.a = state.io.input(port),
+        Instruction::Xthl => {
+            let new_l = state.read_memory(state.sp);
+            let new_h = state.read_memory(state.sp.wrapping_add(1));
+            state.write_memory(state.sp, state.l);
+            state.write_memory(state.sp.wrapping_add(1), state.h);
             state.h = new_h;
             state.l = new_l;
         },
-        0xe4 => { // CPO adr
-            if state.cc.p == Parity::Odd {
-                let ret: u16 = program_counter as u16 + 2;
-                state.write_memory(state.sp as usize - 1, ((ret >> 8) & 0xff) as u8);
-                state.write_memory(state.sp as usize - 2, (ret & 0xff) as u8);
-                state.sp = state.sp - 2;
-                let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-                let low_address = state.read_memory(program_counter + 1) as u16;
-                state.set_program_counter(high_address | low_address);
-            } else {
-                state.increment_program_counter(2);
-            }
-        },
-        0xe5 => { // PUSH H
-            state.push(state.h, state.l);
-        },
-        0xe6 => { // ANI D8
-            let answer: u8 = state.a & state.read_memory(program_counter + 1);
-            state.cc.z = if answer == 0 { 1 } else { 0 };
-            state.cc.s = Sign::get_sign(answer);
-            state.cc.cy = 0;
-            state.cc.p = Parity::from(answer);
-            state.a = answer;
-            state.increment_program_counter(1);
-        },
-        0xe7 => { // RST 4
-            let ret: u16 = state.program_counter() + 2;
-            state.write_memory(state.sp as usize - 1, ((ret >> 8) & 0xff) as u8);
-            state.write_memory(state.sp as usize - 2, (ret & 0xff) as u8);
-            state.sp -= 2;
-            state.set_program_counter(0x20);
-        }
-        0xe8 => { // RPE
-            if state.cc.p == Parity::Even {
-                let high_address = state.read_memory(state.sp as usize) as u16;
-                let low_address = (state.read_memory(state.sp as usize + 1) as u16) << 8;
-                state.set_program_counter(high_address | low_address);
-                state.sp += 2;
-            }
-        },
-        0xe9 => { // PCHL
-            state.set_program_counter(state.hl());
-        },
-        0xea => { // JPE adr
-            if state.cc.p == Parity::Even {
-                let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-                let low_address = state.read_memory(program_counter + 1) as u16;
-                state.set_program_counter(high_address | low_address);
-            } else {
-                state.increment_program_counter(2);
+        Instruction::Pchl => state.set_program_counter(state.hl()),
+        Instruction::Xchg => {
+            let hl = state.hl();
+            state.set_hl(state.de());
+            state.set_de(hl);
+        },
+        Instruction::Sphl => state.sp = state.hl(),
+        Instruction::Di => state.disable_interrupt(),
+        Instruction::Ei => state.enable_interrupt(),
+        Instruction::Rim => state.a = state.rim(),
+        Instruction::Sim => state.sim(state.a),
+        Instruction::Dsub => state.dsub(),
+        Instruction::Arhl => {
+            let hl = state.hl();
+            state.cc.cy = (hl & 0x0001) as u8;
+            state.set_hl((hl >> 1) | (hl & 0x8000));
+        },
+        Instruction::Rdel => {
+            let de = state.de();
+            let rotated = (de << 1) | state.cc.cy as u16;
+            state.cc.cy = (de >> 15) as u8;
+            state.cc.v = if (de ^ rotated) & 0x8000 != 0 { 1 } else { 0 };
+            state.set_de(rotated);
+        },
+        Instruction::Ldhi(offset) => state.set_de(state.hl().wrapping_add(offset as u16)),
+        Instruction::Ldsi(offset) => state.set_de(state.sp.wrapping_add(offset as u16)),
+        Instruction::Rstv => {
+            if state.cc.v != 0 {
+                state.call(0x40);
+                cycles += branch_taken_cycles(variant, opcode);
             }
         },
-        0xeb => { // XCHG
-            let new_h = state.d;
-            let new_l = state.e;
-            state.d = state.h;
-            state.e = state.l;
-            state.h = new_h;
-            state.l = new_l;
-        },
-        0xec => { // CPE adr
-            if state.cc.p == Parity::Even {
-                let ret: u16 = program_counter as u16 + 2;
-                state.write_memory(state.sp as usize - 1, ((ret >> 8) & 0xff) as u8);
-                state.write_memory(state.sp as usize - 2, (ret & 0xff) as u8);
-                state.sp = state.sp - 2;
-                let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-                let low_address = state.read_memory(program_counter + 1) as u16;
-                state.set_program_counter(high_address | low_address);
-            } else {
-                state.increment_program_counter(2);
-            }
-        },
-        0xed => unimplemented_instruction(state), // -
-        0xee => { // XRI D8
-            let answer: u8 = state.a ^ state.read_memory(program_counter + 1);
-            state.cc.z = if answer == 0 { 1 } else { 0 };
-            state.cc.s = Sign::get_sign(answer);
-            state.cc.cy = 0;
-            state.cc.p = Parity::from(answer);
-            state.a = answer;
-            state.increment_program_counter(1);
-        },
-        0xef => { // RST 5
-            let ret: u16 = state.program_counter() + 2;
-            state.write_memory(state.sp as usize - 1, ((ret >> 8) & 0xff) as u8);
-            state.write_memory(state.sp as usize - 2, (ret & 0xff) as u8);
-            state.sp -= 2;
-            state.set_program_counter(0x28);
-        }
-        0xf0 => { // RP
-            if state.cc.s == Sign::Positive {
-                let high_address = state.read_memory(state.sp as usize) as u16;
-                let low_address = (state.read_memory(state.sp as usize + 1) as u16) << 8;
-                state.set_program_counter(high_address | low_address);
-                state.sp += 2;
-            }
-        },
-        0xf1 => { // POP PSW
-            let (high, psw) = state.pop();
-            state.a = high;
-            state.cc.cy = if 0x01 == (psw & 0x01) { 1 } else { 0 };
-            state.cc.p = if 0x04 == (psw & 0x04) { Parity::Even } else { Parity::Odd };
-            state.cc.ac = if 0x10 == (psw & 0x10) { 1 } else { 0 };
-            state.cc.z = if 0x40 == (psw & 0x40) { 1 } else { 0 };
-            state.cc.s = Sign::get_sign(psw);
-        },
-        0xf2 => { // JP adr
-            if state.cc.s == Sign::Positive {
-                let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-                let low_address = state.read_memory(program_counter + 1) as u16;
-                state.set_program_counter(high_address | low_address);
-            } else {
-                state.increment_program_counter(2);
-            }
-        },
-        0xf3 => { // DI
-            state.disable_interrupt();
-        },
-        0xf4 => { // CP adr
-            if state.cc.s == Sign::Positive {
-                let ret: u16 = program_counter as u16 + 2;
-                state.write_memory(state.sp as usize - 1, ((ret >> 8) & 0xff) as u8);
-                state.write_memory(state.sp as usize - 2, (ret & 0xff) as u8);
-                state.sp = state.sp - 2;
-                let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-                let low_address = state.read_memory(program_counter + 1) as u16;
-                state.set_program_counter(high_address | low_address);
-            } else {
-                state.increment_program_counter(2);
-            }
-        },
-        0xf5 => { // PUSH PSW
-            let cc = &state.cc;
-            let psw: u8 = cc.cy | Into::<u8>::into(cc.p) << 2 | cc.ac << 4 | cc.z << 6 | Into::<u8>::into(cc.s) << 7;
-            state.push(state.a, psw);
-        },
-        0xf6 => { // ORI D8
-            let answer: u8 = state.a | state.read_memory(program_counter + 1);
-            state.cc.z = if answer == 0 { 1 } else { 0 };
-            state.cc.s = Sign::get_sign(answer);
-            state.cc.cy = 0;
-            state.cc.p = Parity::from(answer);
-            state.a = answer;
-            state.increment_program_counter(1);
-        },
-        0xf7 => { // RST 6
-            let ret: u16 = state.program_counter() + 2;
-            state.write_memory(state.sp as usize - 1, ((ret >> 8) & 0xff) as u8);
-            state.write_memory(state.sp as usize - 2, (ret & 0xff) as u8);
-            state.sp -= 2;
-            state.set_program_counter(0x30);
-        },
-        0xf8 => { // RM
-            if state.cc.s == Sign::Negative {
-                let high_address = state.read_memory(state.sp as usize) as u16;
-                let low_address = (state.read_memory(state.sp as usize + 1) as u16) << 8;
-                state.set_program_counter(high_address | low_address);
-                state.sp += 2;
-            }
+        Instruction::Shlx => {
+            let address = state.de();
+            state.write_memory(address, state.l);
+            state.write_memory(address.wrapping_add(1), state.h);
         },
-        0xf9 => { // SPHL
-            state.sp = state.hl();
+        Instruction::Lhlx => {
+            let address = state.de();
+            state.l = state.read_memory(address);
+            state.h = state.read_memory(address.wrapping_add(1));
         },
-        0xfa => { // JM adr
-            if state.cc.s == Sign::Negative {
-                let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-                let low_address = state.read_memory(program_counter + 1) as u16;
-                state.set_program_counter(high_address | low_address);
-            } else {
-                state.increment_program_counter(2);
+        Instruction::Jnk(address) => {
+            if state.cc.k == 0 {
+                state.set_program_counter(address);
+                cycles += branch_taken_cycles(variant, opcode);
             }
         },
-        0xfb => { // EI
-            state.enable_interrupt();
-        },
-        0xfc => { // CM adr
-            if state.cc.s == Sign::Negative {
-                let ret: u16 = program_counter as u16 + 2;
-                state.write_memory(state.sp as usize - 1, ((ret >> 8) & 0xff) as u8);
-                state.write_memory(state.sp as usize - 2, (ret & 0xff) as u8);
-                state.sp = state.sp - 2;
-                let high_address = (state.read_memory(program_counter + 2) as u16) << 8;
-                let low_address = state.read_memory(program_counter + 1) as u16;
-                state.set_program_counter(high_address | low_address);
-            } else {
-                state.increment_program_counter(2);
+        Instruction::Jk(address) => {
+            if state.cc.k != 0 {
+                state.set_program_counter(address);
+                cycles += branch_taken_cycles(variant, opcode);
             }
         },
-        0xfd => unimplemented_instruction(state), // -
-        0xfe => { // CPI D8
-            let immediate_data = state.read_memory(program_counter + 1);
-            let answer: u8 = state.a.wrapping_sub(immediate_data);
-            state.cc.z = if answer == 0 { 1 } else { 0 };
-            state.cc.s = Sign::get_sign(answer);
-            state.cc.p = Parity::from(answer);
-            state.cc.cy = if state.a < immediate_data { 1 } else { 0 };
-            state.increment_program_counter(1);
-        },
-        0xff => { // RST 7
-            let ret: u16 = state.program_counter() + 2;
-            state.write_memory(state.sp as usize - 1, ((ret >> 8) & 0xff) as u8);
-            state.write_memory(state.sp as usize - 2, (ret & 0xff) as u8);
-            state.sp -= 2;
-            state.set_program_counter(0x38);
-        },
     }
-    CYCLES[opcode as usize]
+    if acknowledging {
+        state.finish_interrupt_acknowledge();
+    }
+    // Any unmapped access during the instruction is reported after the fact,
+    // so the instruction may have partially completed.
+    if let Some(reason) = state.take_bus_fault() {
+        return Err(EmulatorError { pc: program_counter, opcode, reason });
+    }
+    Ok(cycles)
 }
 
 mod test {
     #[allow(unused)] use super::*;
+    #[allow(unused)] use crate::error::ErrorReason;
+    #[allow(unused)] use crate::parity::Parity;
+    #[allow(unused)] use crate::sign::Sign;
 
     #[test]
     fn dothething() {
         let mut state = State8080::empty_state();
         state.memory = vec![0x87];
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.cc.z, 1);
         assert_eq!(state.cc.p, Parity::Even);
     }
@@ -940,7 +358,7 @@ mod test {
     fn test_inx_d_low() {
         let mut state = State8080::empty_state();
         state.memory = vec![0x13];
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.d, 0);
         assert_eq!(state.e, 1);
     }
@@ -951,7 +369,7 @@ mod test {
         state.memory = vec![0x13];
         state.d = 0x38;
         state.e = 0xff;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.d, 0x39);
         assert_eq!(state.e, 0x00);
     }
@@ -961,7 +379,7 @@ mod test {
         let mut state = State8080::empty_state();
         state.memory = vec![0x05];
         state.b = 0x00;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.b, 0xff);
     }
 
@@ -971,7 +389,7 @@ mod test {
         state.memory = vec![0x1b];
         state.d = 0x00;
         state.e = 0x00;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.d, 0xff);
         assert_eq!(state.e, 0xff);
     }
@@ -982,7 +400,7 @@ mod test {
         state.memory = vec![0x1b];
         state.d = 0x00;
         state.e = 0x01;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.d, 0x00);
         assert_eq!(state.e, 0x00);
     }
@@ -993,7 +411,7 @@ mod test {
         state.memory = vec![0x1b];
         state.d = 0x00;
         state.e = 0xff;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.d, 0x00);
         assert_eq!(state.e, 0xfe);
     }
@@ -1004,7 +422,7 @@ mod test {
         state.memory = vec![0x1b];
         state.d = 0xff;
         state.e = 0xff;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.d, 0xff);
         assert_eq!(state.e, 0xfe);
     }
@@ -1015,7 +433,7 @@ mod test {
         state.memory = vec![0x2b];
         state.h = 0x98;
         state.l = 0x00;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.h, 0x97);
         assert_eq!(state.l, 0xff);
     }
@@ -1028,7 +446,7 @@ mod test {
         state.c = 0x9f;
         state.h = 0xa1;
         state.l = 0x7b;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.b, 0x33);
         assert_eq!(state.c, 0x9f);
         assert_eq!(state.h, 0xd5);
@@ -1044,7 +462,7 @@ mod test {
         state.c = 0xff;
         state.h = 0x00;
         state.l = 0x02;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.b, 0xff);
         assert_eq!(state.c, 0xff);
         assert_eq!(state.h, 0x00);
@@ -1057,7 +475,7 @@ mod test {
         let mut state = State8080::empty_state();
         state.memory = vec![0x26, 0x3c];
         state.h = 0;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.h, 0x3c);
     }
 
@@ -1066,7 +484,7 @@ mod test {
         let mut state = State8080::empty_state();
         state.memory = vec![0x2e, 0xf4];
         state.l = 0;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.l, 0xf4);
     }
 
@@ -1076,7 +494,7 @@ mod test {
         state.memory = vec![0x36, 0xff, 0x00];
         state.h = 0x00;
         state.l = 0x02;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.read_memory(2), 0xff);
     }
 
@@ -1086,10 +504,10 @@ mod test {
         state.memory = vec![0x06, 0xde, 0x0e, 0xad];
         state.b = 0x00;
         state.c = 0x00;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.b, 0xde);
         assert_eq!(state.c, 0x00);
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.b, 0xde);
         assert_eq!(state.c, 0xad);
     }
@@ -1101,7 +519,7 @@ mod test {
         state.a = 0x00;
         state.d = 0x00;
         state.e = 0x01;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0xde);
     }
 
@@ -1112,7 +530,7 @@ mod test {
         state.a = 0xde;
         state.h = 0x00;
         state.l = 0x01;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.read_memory(0x01), 0xde);
     }
 
@@ -1121,7 +539,7 @@ mod test {
         let mut state = State8080::empty_state();
         state.memory = vec![0xc2, 0x00, 0x00, 0x00, 0x00, 0x00];
         state.cc.z = 1;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.program_counter(), 0x03);
     }
 
@@ -1130,7 +548,7 @@ mod test {
         let mut state = State8080::empty_state();
         state.memory = vec![0xc2, 0x04, 0x00, 0x00, 0x00, 0x00];
         state.cc.z = 0;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.program_counter(), 0x04);
     }
 
@@ -1144,38 +562,38 @@ mod test {
             0xc3, 0x02, 0x00, // JMP 0x02 0x00
             0x00, // NOP
         ];
-        emulate_8080_op(&mut state); // MVI
+        emulate_8080_op(&mut state).unwrap(); // MVI
         assert_eq!(state.a, 0x03);
         assert_eq!(state.cc.z, 0x00);
-        emulate_8080_op(&mut state); // JM
+        emulate_8080_op(&mut state).unwrap(); // JM
         assert_eq!(state.program_counter(), 0x05);
-        emulate_8080_op(&mut state); // DCR A
+        emulate_8080_op(&mut state).unwrap(); // DCR A
         assert_eq!(state.a, 0x02);
         assert_eq!(state.cc.z, 0x00);
-        emulate_8080_op(&mut state); // JMP
+        emulate_8080_op(&mut state).unwrap(); // JMP
         assert_eq!(state.program_counter(), 0x02);
-        emulate_8080_op(&mut state); // JM
+        emulate_8080_op(&mut state).unwrap(); // JM
         assert_eq!(state.program_counter(), 0x05);
-        emulate_8080_op(&mut state); // DCR A
+        emulate_8080_op(&mut state).unwrap(); // DCR A
         assert_eq!(state.a, 0x01);
         assert_eq!(state.cc.z, 0x00);
-        emulate_8080_op(&mut state); // JMP
+        emulate_8080_op(&mut state).unwrap(); // JMP
         assert_eq!(state.program_counter(), 0x02);
-        emulate_8080_op(&mut state); // JM
+        emulate_8080_op(&mut state).unwrap(); // JM
         assert_eq!(state.program_counter(), 0x05);
-        emulate_8080_op(&mut state); // DCR A
+        emulate_8080_op(&mut state).unwrap(); // DCR A
         assert_eq!(state.a, 0x00);
         assert_eq!(state.cc.z, 0x01);
-        emulate_8080_op(&mut state); // JMP
+        emulate_8080_op(&mut state).unwrap(); // JMP
         assert_eq!(state.program_counter(), 0x02);
-        emulate_8080_op(&mut state); // JM
+        emulate_8080_op(&mut state).unwrap(); // JM
         assert_eq!(state.program_counter(), 0x05);
-        emulate_8080_op(&mut state); // DCR A
+        emulate_8080_op(&mut state).unwrap(); // DCR A
         assert_eq!(state.a, 0xff);
         assert_eq!(state.cc.z, 0x00);
-        emulate_8080_op(&mut state); // JMP
+        emulate_8080_op(&mut state).unwrap(); // JMP
         assert_eq!(state.program_counter(), 0x02);
-        emulate_8080_op(&mut state); // JM
+        emulate_8080_op(&mut state).unwrap(); // JM
         assert_eq!(state.program_counter(), 0x10);
     }
 
@@ -1185,7 +603,7 @@ mod test {
         state.memory = vec![0x32, 0x03, 0x00, 0x00];
         state.a = 0x09;
         state.set_program_counter(0);
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.read_memory(0x0003), 0x09);
         assert_eq!(state.program_counter(), 0x03);
     }
@@ -1196,7 +614,7 @@ mod test {
         state.memory = vec![0x3a, 0x03, 0x00, 0x09];
         state.a = 0x00;
         state.set_program_counter(0);
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0x09);
         assert_eq!(state.program_counter(), 0x03);
     }
@@ -1208,7 +626,7 @@ mod test {
         state.sp = 0x01;
         state.b = 0x00;
         state.c = 0x00;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.b, 0xff);
         assert_eq!(state.c, 0xc3);
         assert_eq!(state.sp, 0x03);
@@ -1221,7 +639,7 @@ mod test {
         state.sp = 0x01;
         state.h = 0x00;
         state.l = 0x00;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.h, 0x93);
         assert_eq!(state.l, 0x3d);
         assert_eq!(state.sp, 0x03);
@@ -1238,7 +656,7 @@ mod test {
         state.cc.ac = 0x00;
         state.cc.z = 0x00;
         state.cc.s = Sign::Positive;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0xff);
         assert_eq!(state.cc.cy, 0x01);
         assert_eq!(state.cc.p, Parity::Odd);
@@ -1259,8 +677,8 @@ mod test {
         state.cc.ac = 0x00;
         state.cc.z = 0x01;
         state.cc.s = Sign::Positive;
-        emulate_8080_op(&mut state);
-        assert_eq!(state.read_memory(0x01), 0x45);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.read_memory(0x01), 0x47);
         assert_eq!(state.read_memory(0x02), 0x47);
         assert_eq!(state.sp, 0x01);
     }
@@ -1277,12 +695,12 @@ mod test {
         state.cc.z = 0x01;
         state.cc.s = Sign::Positive;
 
-        emulate_8080_op(&mut state);
-        assert_eq!(state.read_memory(0x02), 0x45);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.read_memory(0x02), 0x47);
         assert_eq!(state.read_memory(0x03), 0x47);
         assert_eq!(state.sp, 0x02);
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0x47);
         assert_eq!(state.cc.cy, 0x01);
         assert_eq!(state.cc.p, Parity::Even);
@@ -1299,7 +717,7 @@ mod test {
         state.sp = 0x03;
         state.d = 0x8f;
         state.e = 0x9d;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.read_memory(0x02), 0x8f);
         assert_eq!(state.read_memory(0x01), 0x9d);
         assert_eq!(state.sp, 0x01);
@@ -1312,7 +730,7 @@ mod test {
         state.sp = 0x00;
         state.h = 0x50;
         state.l = 0x6c;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.sp, 0x506c);
     }
 
@@ -1323,7 +741,7 @@ mod test {
         state.sp = 0x01;
         state.h = 0x0b;
         state.l = 0x3c;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.read_memory(0x01), 0x3c);
         assert_eq!(state.read_memory(0x02), 0x0b);
         assert_eq!(state.h, 0x0d);
@@ -1337,13 +755,13 @@ mod test {
         state.memory = vec![0x82];
         state.a = 0x6c;
         state.d = 0x2e;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0x9a);
         assert_eq!(state.cc.z, 0);
         assert_eq!(state.cc.cy, 0);
         assert_eq!(state.cc.p, Parity::Even);
         assert_eq!(state.cc.s, Sign::Negative);
-        // TODO: assert_eq!(state.cc.ac, 1);
+        assert_eq!(state.cc.ac, 1);
     }
 
     #[test]
@@ -1351,7 +769,7 @@ mod test {
         let mut state = State8080::empty_state();
         state.memory = vec![0x87];
         state.a = 0x04;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0x08);
     }
 
@@ -1372,18 +790,18 @@ mod test {
             state.memory.push(0);
         }
         assert_eq!(state.b, 0x00);
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.sp, 0x1000);
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.sp, 0x0ffe);
         assert_eq!(state.program_counter(), 0x10);
         assert_eq!(state.c, 0x00);
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.c, 0x24);
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.program_counter(), 0x06);
         assert_eq!(state.b, 0x00);
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.b, 0x12);
         assert_eq!(state.sp, 0x1000);
     }
@@ -1395,7 +813,7 @@ mod test {
         state.a = 0x4a;
         state.cc.cy = 1;
         state.cc.z = 1;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.cc.cy, 0);
         assert_eq!(state.cc.z, 0);
         assert_eq!(state.program_counter(), 2);
@@ -1406,7 +824,7 @@ mod test {
         let mut state = State8080::empty_state();
         state.memory = vec![0xfe, 0x4a];
         state.a = 0x4a;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.cc.cy, 0);
         assert_eq!(state.cc.z, 1);
         assert_eq!(state.program_counter(), 2);
@@ -1417,7 +835,7 @@ mod test {
         let mut state = State8080::empty_state();
         state.memory = vec![0xfe, 0x4b];
         state.a = 0x4a;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.cc.cy, 1);
         assert_eq!(state.cc.z, 0);
         assert_eq!(state.program_counter(), 2);
@@ -1428,7 +846,7 @@ mod test {
         let mut state = State8080::empty_state();
         state.memory = vec![0xe6, 0x0f];
         state.a = 0x3a;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0x0a);
     }
 
@@ -1438,19 +856,19 @@ mod test {
         state.memory = vec![0xc6, 0x42, 0xc6, 0xbe];
         state.a = 0x14;
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0x56);
         assert_eq!(state.cc.p, Parity::Even);
         assert_eq!(state.cc.cy, 0);
-        // TODO: assert_eq!(state.cc.ac, 0);
+        assert_eq!(state.cc.ac, 0);
         assert_eq!(state.cc.z, 0);
         assert_eq!(state.cc.s, Sign::Positive);
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0x14);
         assert_eq!(state.cc.p, Parity::Even);
         assert_eq!(state.cc.cy, 1);
-        // TODO: assert_eq!(state.cc.ac, 1);
+        assert_eq!(state.cc.ac, 1);
         assert_eq!(state.cc.z, 0);
         assert_eq!(state.cc.s, Sign::Positive);
     }
@@ -1462,11 +880,11 @@ mod test {
         state.a = 0x56;
 
         assert_eq!(state.cc.cy, 0);
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0x14);
         assert_eq!(state.cc.cy, 1);
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0x57);
         assert_eq!(state.cc.cy, 0);
     }
@@ -1478,7 +896,7 @@ mod test {
         state.a = 0x00;
         state.cc.cy = 0;
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0xff);
         assert_eq!(state.cc.cy, 1);
     }
@@ -1490,13 +908,13 @@ mod test {
         state.a = 0x00;
         state.cc.cy = 0;
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0xff);
         assert_eq!(state.cc.cy, 1);
         assert_eq!(state.cc.s, Sign::Negative);
         assert_eq!(state.cc.p, Parity::Even);
         assert_eq!(state.cc.z, 0);
-        // TODO: assert_eq!(state.cc.ac, 0);
+        assert_eq!(state.cc.ac, 0);
     }
 
     #[test]
@@ -1506,13 +924,13 @@ mod test {
         state.a = 0x00;
         state.cc.cy = 1;
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0xfe);
         assert_eq!(state.cc.cy, 1);
         assert_eq!(state.cc.s, Sign::Negative);
         assert_eq!(state.cc.p, Parity::Odd);
         assert_eq!(state.cc.z, 0);
-        // TODO: assert_eq!(state.cc.ac, 0);
+        assert_eq!(state.cc.ac, 0);
     }
 
     #[test]
@@ -1521,7 +939,7 @@ mod test {
         state.memory = vec![0xf6, 0x0f];
         state.a = 0xb5;
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0xbf);
     }
 
@@ -1531,7 +949,7 @@ mod test {
         state.memory = vec![0xee, 0x81];
         state.a = 0x3b;
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0xba);
     }
 
@@ -1544,7 +962,7 @@ mod test {
         state.h = 0x56;
         state.l = 0x78;
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.d, 0x56);
         assert_eq!(state.e, 0x78);
         assert_eq!(state.h, 0x12);
@@ -1557,7 +975,7 @@ mod test {
         state.memory = vec![0x0f];
         state.a = 0xf2;
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0x79);
         assert_eq!(state.cc.cy, 0);
     }
@@ -1568,7 +986,7 @@ mod test {
         state.memory = vec![0x0f];
         state.a = 0x01;
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0x80);
         assert_eq!(state.cc.cy, 1);
     }
@@ -1579,7 +997,7 @@ mod test {
         state.memory = vec![0x07];
         state.a = 0xf2;
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0xe5);
         assert_eq!(state.cc.cy, 1);
     }
@@ -1591,7 +1009,7 @@ mod test {
         state.a = 0x6a;
         state.cc.cy = 1;
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0xb5);
         assert_eq!(state.cc.cy, 0);
     }
@@ -1604,7 +1022,7 @@ mod test {
         state.cc.cy = 0;
         state.cc.ac = 0;
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0x01);
         assert_eq!(state.cc.cy, 1);
         assert_eq!(state.cc.ac, 1);
@@ -1617,7 +1035,7 @@ mod test {
         state.h = 0xae;
         state.l = 0x29;
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.read_memory(0x03), 0x29);
         assert_eq!(state.read_memory(0x04), 0xae);
         assert_eq!(state.program_counter(), 3);
@@ -1630,7 +1048,7 @@ mod test {
         state.l = 0x00;
         state.h = 0x00;
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.l, 0xff);
         assert_eq!(state.h, 0x03);
         assert_eq!(state.program_counter(), 3);
@@ -1649,7 +1067,7 @@ mod test {
         state.l = 0x00;
         state.h = 0x00;
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.l, 0xff);
         assert_eq!(state.h, 0x03);
         assert_eq!(state.program_counter(), 3);
@@ -1662,7 +1080,7 @@ mod test {
         state.a = 0xb5;
         state.cc.cy = 0;
 
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0x6a);
         assert_eq!(state.cc.cy, 1);
     }
@@ -1672,7 +1090,7 @@ mod test {
         let mut state = State8080::empty_state();
         state.memory = vec![0x0c];
         state.c = 0x99;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.c, 0x9a);
     }
 
@@ -1681,7 +1099,7 @@ mod test {
         let mut state = State8080::empty_state();
         state.memory = vec![0x34, 0x99];
         state.set_hl(0x01);
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.read_memory(0x01), 0x9a);
     }
 
@@ -1693,7 +1111,7 @@ mod test {
         state.a = 0xde;
         state.b = 0x3f;
         state.c = 0x16;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.read_memory(0x3f16), 0xde);
     }
 
@@ -1705,7 +1123,7 @@ mod test {
         state.e = 0x05;
         state.cc.cy = 0x01;
         state.cc.z = 0x01;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0x0a);
         assert_eq!(state.e, 0x05);
         assert_eq!(state.cc.cy, 0x00);
@@ -1720,7 +1138,7 @@ mod test {
         state.e = 0x05;
         state.cc.cy = 0x00;
         state.cc.z = 0x01;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0x02);
         assert_eq!(state.e, 0x05);
         assert_eq!(state.cc.cy, 0x01);
@@ -1735,7 +1153,7 @@ mod test {
         state.e = 0x05;
         state.cc.cy = 0x01;
         state.cc.z = 0x01;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0xe5);
         assert_eq!(state.e, 0x05);
         assert_eq!(state.cc.cy, 0x00);
@@ -1747,7 +1165,7 @@ mod test {
         let mut state = State8080::empty_state();
         state.memory = vec![0x2f];
         state.a = 0x51;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.a, 0xae);
     }
 
@@ -1756,9 +1174,947 @@ mod test {
         let mut state = State8080::empty_state();
         state.memory = vec![0x3f, 0x3f];
         state.cc.cy = 0x00;
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.cc.cy, 0x01);
-        emulate_8080_op(&mut state);
+        emulate_8080_op(&mut state).unwrap();
         assert_eq!(state.cc.cy, 0x00);
     }
+
+    #[allow(unused)]
+    struct RecordingPorts {
+        input_value: u8,
+        last_input_port: Option<u8>,
+        last_output: Option<(u8, u8)>,
+    }
+
+    impl IoPorts for RecordingPorts {
+        fn input(&mut self, port: u8) -> u8 {
+            self.last_input_port = Some(port);
+            self.input_value
+        }
+
+        fn output(&mut self, port: u8, value: u8) {
+            self.last_output = Some((port, value));
+        }
+    }
+
+    #[test]
+    fn test_in() {
+        let ports = RecordingPorts { input_value: 0x5a, last_input_port: None, last_output: None };
+        let mut state = State8080::new(vec![0xdb, 0x03], ports);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x5a);
+        assert_eq!(state.io.last_input_port, Some(0x03));
+        assert_eq!(state.program_counter(), 2);
+    }
+
+    #[test]
+    fn test_out() {
+        let ports = RecordingPorts { input_value: 0, last_input_port: None, last_output: None };
+        let mut state = State8080::new(vec![0xd3, 0x04], ports);
+        state.a = 0x9c;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.io.last_output, Some((0x04, 0x9c)));
+        assert_eq!(state.program_counter(), 2);
+    }
+
+    #[test]
+    fn test_hlt() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x76, 0x00];
+        assert_eq!(emulate_8080_op(&mut state), Ok(7));
+        assert!(state.halted());
+        assert_eq!(state.program_counter(), 1);
+        assert_eq!(emulate_8080_op(&mut state), Ok(HALTED_CYCLES));
+        assert!(state.halted());
+        assert_eq!(state.program_counter(), 1);
+    }
+
+    #[test]
+    fn test_interrupt_leaves_hlt() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00; 0x20];
+        state.write_memory(0x00, 0xfb); // EI
+        state.write_memory(0x01, 0x76); // HLT
+        state.sp = 0x20;
+        emulate_8080_op(&mut state).unwrap();
+        emulate_8080_op(&mut state).unwrap();
+        assert!(state.halted());
+        state.request_interrupt(0xcf); // RST 1
+        assert_eq!(emulate_8080_op(&mut state), Ok(11));
+        assert!(!state.halted());
+        assert!(!state.interrupt_enabled());
+        assert_eq!(state.program_counter(), 0x08);
+        assert_eq!(state.read_memory(0x1e), 0x02);
+        assert_eq!(state.read_memory(0x1f), 0x00);
+    }
+
+    #[test]
+    fn test_hlt_with_interrupts_disabled() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x76, 0x00];
+        emulate_8080_op(&mut state).unwrap();
+        state.request_interrupt(0xcf);
+        assert_eq!(emulate_8080_op(&mut state), Ok(HALTED_CYCLES));
+        assert!(state.halted());
+        assert!(state.interrupt_pending());
+    }
+
+    #[test]
+    fn test_interrupt_latched_while_disabled() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00; 0x20];
+        state.write_memory(0x01, 0xfb); // EI
+        state.sp = 0x20;
+        state.request_interrupt(0xd7); // RST 2
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.program_counter(), 0x01);
+        assert!(state.interrupt_pending());
+        // EI, then one more instruction before the request is taken
+        emulate_8080_op(&mut state).unwrap();
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.program_counter(), 0x03);
+        assert!(state.interrupt_pending());
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.program_counter(), 0x10);
+        assert!(!state.interrupt_pending());
+        assert_eq!(state.read_memory(0x1e), 0x03);
+    }
+
+    #[test]
+    fn test_ei_ret_returns_before_next_interrupt() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00; 0x20];
+        state.write_memory(0x08, 0xfb); // EI
+        state.write_memory(0x09, 0xc9); // RET
+        state.set_program_counter(0x08);
+        state.sp = 0x1e;
+        state.write_memory(0x1e, 0x04);
+        state.request_interrupt(0xcf);
+        emulate_8080_op(&mut state).unwrap();
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.program_counter(), 0x04);
+        assert_eq!(state.sp, 0x20);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.program_counter(), 0x08);
+        assert_eq!(state.read_memory(0x1e), 0x04);
+    }
+
+    #[test]
+    fn test_interrupt_call_from_data_bus() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00; 0x20];
+        state.write_memory(0x00, 0xfb); // EI
+        state.sp = 0x20;
+        state.request_interrupt_instruction([0xcd, 0x34, 0x12]); // CALL 0x1234
+        emulate_8080_op(&mut state).unwrap();
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(emulate_8080_op(&mut state), Ok(17));
+        assert_eq!(state.program_counter(), 0x1234);
+        assert_eq!(state.read_memory(0x1e), 0x02);
+        assert_eq!(state.read_memory(0x1f), 0x00);
+    }
+
+    #[test]
+    fn test_di_cancels_pending_enable() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0xfb, 0xf3, 0x00, 0x00];
+        state.request_interrupt(0xcf);
+        for _ in 0..4 {
+            emulate_8080_op(&mut state).unwrap();
+        }
+        assert_eq!(state.program_counter(), 0x04);
+        assert!(state.interrupt_pending());
+    }
+
+    #[test]
+    fn test_run_stops_at_hlt() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x3e, 0x42, 0x76];
+        run(&mut state).unwrap();
+        assert!(state.halted());
+        assert_eq!(state.a, 0x42);
+        assert_eq!(state.program_counter(), 3);
+    }
+
+    #[test]
+    fn test_unmapped_read_error() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x3a, 0x00, 0x01];
+        let err = emulate_8080_op(&mut state).unwrap_err();
+        assert_eq!(err, EmulatorError { pc: 0x00, opcode: 0x3a, reason: ErrorReason::UnmappedRead(0x0100) });
+    }
+
+    #[test]
+    fn test_unmapped_write_error() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00, 0x77];
+        state.set_hl(0x2000);
+        emulate_8080_op(&mut state).unwrap();
+        let err = emulate_8080_op(&mut state).unwrap_err();
+        assert_eq!(err, EmulatorError { pc: 0x01, opcode: 0x77, reason: ErrorReason::UnmappedWrite(0x2000) });
+    }
+
+    #[test]
+    fn test_unmapped_opcode_fetch() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00];
+        emulate_8080_op(&mut state).unwrap();
+        let err = emulate_8080_op(&mut state).unwrap_err();
+        assert_eq!(err.reason, ErrorReason::UnmappedRead(0x0001));
+        assert_eq!(state.program_counter(), 0x0001);
+    }
+
+    #[test]
+    fn test_operand_fetch_wraps() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00; 0x10000];
+        state.write_memory(0xffff, 0x3e); // MVI A
+        state.write_memory(0x0000, 0x42);
+        state.set_program_counter(0xffff);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x42);
+        assert_eq!(state.program_counter(), 0x0001);
+    }
+
+    #[test]
+    fn test_jmp_address_wraps() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00; 0x10000];
+        state.write_memory(0xfffe, 0xc3); // JMP
+        state.write_memory(0xffff, 0x34);
+        state.write_memory(0x0000, 0x12);
+        state.set_program_counter(0xfffe);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.program_counter(), 0x1234);
+    }
+
+    #[test]
+    fn test_push_wraps() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00; 0x10000];
+        state.write_memory(0x0000, 0xc5); // PUSH B
+        state.set_bc(0xbeef);
+        state.sp = 0x0000;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.read_memory(0xffff), 0xbe);
+        assert_eq!(state.read_memory(0xfffe), 0xef);
+        assert_eq!(state.sp, 0xfffe);
+    }
+
+    #[test]
+    fn test_pop_wraps() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00; 0x10000];
+        state.write_memory(0x1000, 0xd1); // POP D
+        state.write_memory(0xffff, 0xcd);
+        state.write_memory(0x0000, 0xab);
+        state.set_program_counter(0x1000);
+        state.sp = 0xffff;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.e, 0xcd);
+        assert_eq!(state.d, 0xab);
+        assert_eq!(state.sp, 0x0001);
+    }
+
+    #[test]
+    fn test_call_ret_with_stack_at_zero() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00; 0x10000];
+        state.write_memory(0x0100, 0xcd); // CALL 0x0200
+        state.write_memory(0x0101, 0x00);
+        state.write_memory(0x0102, 0x02);
+        state.write_memory(0x0200, 0xc9); // RET
+        state.set_program_counter(0x0100);
+        state.sp = 0x0000;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.program_counter(), 0x0200);
+        assert_eq!(state.sp, 0xfffe);
+        assert_eq!(state.read_memory(0xffff), 0x01);
+        assert_eq!(state.read_memory(0xfffe), 0x03);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.program_counter(), 0x0103);
+        assert_eq!(state.sp, 0x0000);
+    }
+
+    #[test]
+    fn test_lhld_shld_wrap() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00; 0x10000];
+        state.write_memory(0x0100, 0x22); // SHLD 0xffff
+        state.write_memory(0x0101, 0xff);
+        state.write_memory(0x0102, 0xff);
+        state.write_memory(0x0103, 0x2a); // LHLD 0xffff
+        state.write_memory(0x0104, 0xff);
+        state.write_memory(0x0105, 0xff);
+        state.set_program_counter(0x0100);
+        state.set_hl(0x5678);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.read_memory(0xffff), 0x78);
+        assert_eq!(state.read_memory(0x0000), 0x56);
+        state.set_hl(0x0000);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.hl(), 0x5678);
+    }
+
+    #[test]
+    fn test_xthl_wraps() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00; 0x10000];
+        state.write_memory(0x0100, 0xe3);
+        state.write_memory(0xffff, 0x34);
+        state.write_memory(0x0000, 0x12);
+        state.set_program_counter(0x0100);
+        state.sp = 0xffff;
+        state.set_hl(0xabcd);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.hl(), 0x1234);
+        assert_eq!(state.read_memory(0xffff), 0xcd);
+        assert_eq!(state.read_memory(0x0000), 0xab);
+    }
+
+    #[test]
+    fn test_inx_dcx_sp_wrap() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x33, 0x3b];
+        state.sp = 0xffff;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.sp, 0x0000);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.sp, 0xffff);
+    }
+
+    #[test]
+    fn test_interrupt_with_stack_at_zero() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00; 0x10000];
+        state.set_program_counter(0x1234);
+        state.sp = 0x0000;
+        state.enable_interrupt();
+        state.acknowledge_interrupt();
+        state.request_interrupt(0xd7);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.program_counter(), 0x0010);
+        assert_eq!(state.sp, 0xfffe);
+        assert_eq!(state.read_memory(0xffff), 0x12);
+        assert_eq!(state.read_memory(0xfffe), 0x34);
+    }
+
+    #[test]
+    fn test_rst_return_address() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00; 0x100];
+        state.write_memory(0x40, 0xd7); // RST 2
+        state.set_program_counter(0x40);
+        state.sp = 0x100;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.program_counter(), 0x10);
+        assert_eq!(state.read_memory(0xff), 0x00);
+        assert_eq!(state.read_memory(0xfe), 0x41);
+    }
+
+    #[test]
+    fn test_conditional_call_return_address() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00; 0x100];
+        state.write_memory(0x40, 0xc4); // CNZ 0x0080
+        state.write_memory(0x41, 0x80);
+        state.write_memory(0x42, 0x00);
+        state.set_program_counter(0x40);
+        state.sp = 0x100;
+        state.cc.z = 0;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.program_counter(), 0x80);
+        assert_eq!(state.read_memory(0xfe), 0x43);
+    }
+
+    #[test]
+    fn test_add_auxiliary_carry() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x80, 0x80];
+        state.a = 0x08;
+        state.b = 0x08;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x10);
+        assert_eq!(state.cc.ac, 1);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x18);
+        assert_eq!(state.cc.ac, 0);
+    }
+
+    #[test]
+    fn test_adc_auxiliary_carry_from_carry_in() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x88];
+        state.a = 0x0f;
+        state.b = 0x00;
+        state.cc.cy = 1;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x10);
+        assert_eq!(state.cc.ac, 1);
+        assert_eq!(state.cc.cy, 0);
+    }
+
+    #[test]
+    fn test_sub_auxiliary_carry() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x90, 0x90];
+        // No borrow out of the low nibble, so the complement addition carries
+        state.a = 0x3e;
+        state.b = 0x3e;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x00);
+        assert_eq!(state.cc.ac, 1);
+        assert_eq!(state.cc.cy, 0);
+        assert_eq!(state.cc.z, 1);
+        // Borrow out of the low nibble
+        state.a = 0x10;
+        state.b = 0x01;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x0f);
+        assert_eq!(state.cc.ac, 0);
+        assert_eq!(state.cc.cy, 0);
+    }
+
+    #[test]
+    fn test_sbb_with_carry_from_ff() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x98];
+        state.a = 0x00;
+        state.b = 0xff;
+        state.cc.cy = 1;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x00);
+        assert_eq!(state.cc.cy, 1);
+        assert_eq!(state.cc.z, 1);
+    }
+
+    #[test]
+    fn test_cmp_auxiliary_carry() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0xfe, 0x05];
+        state.a = 0x04;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x04);
+        assert_eq!(state.cc.cy, 1);
+        assert_eq!(state.cc.ac, 0);
+    }
+
+    #[test]
+    fn test_ana_auxiliary_carry() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0xa0, 0xe6, 0x01];
+        state.a = 0x08;
+        state.b = 0x00;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x00);
+        assert_eq!(state.cc.ac, 1);
+        state.a = 0x03;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x01);
+        assert_eq!(state.cc.ac, 0);
+    }
+
+    #[test]
+    fn test_ora_xra_clear_auxiliary_carry() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0xb0, 0xa8];
+        state.cc.ac = 1;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.cc.ac, 0);
+        state.cc.ac = 1;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.cc.ac, 0);
+    }
+
+    #[test]
+    fn test_inr_dcr_auxiliary_carry() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x04, 0x05, 0x05];
+        state.b = 0x0f;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.b, 0x10);
+        assert_eq!(state.cc.ac, 1);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.b, 0x0f);
+        assert_eq!(state.cc.ac, 0);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.b, 0x0e);
+        assert_eq!(state.cc.ac, 1);
+    }
+
+    #[test]
+    fn test_inr_m_sets_flags() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x34, 0xff];
+        state.set_hl(0x01);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.read_memory(0x01), 0x00);
+        assert_eq!(state.cc.z, 1);
+        assert_eq!(state.cc.ac, 1);
+    }
+
+    #[test]
+    fn test_daa_after_bcd_add() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x80, 0x27];
+        // 38 + 45 = 83 in BCD
+        state.a = 0x38;
+        state.b = 0x45;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x7d);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x83);
+        assert_eq!(state.cc.cy, 0);
+        assert_eq!(state.cc.s, Sign::Negative);
+    }
+
+    #[test]
+    fn test_daa_keeps_carry() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x27];
+        // 99 + 99 = 198, which leaves 0x32 with carry and auxiliary carry set
+        state.a = 0x32;
+        state.cc.cy = 1;
+        state.cc.ac = 1;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x98);
+        assert_eq!(state.cc.cy, 1);
+        assert_eq!(state.cc.ac, 0);
+    }
+
+    #[test]
+    fn test_daa_zero() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x27];
+        state.a = 0x9a;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x00);
+        assert_eq!(state.cc.z, 1);
+        assert_eq!(state.cc.cy, 1);
+        assert_eq!(state.cc.p, Parity::Even);
+    }
+
+    #[test]
+    fn test_conditional_call_cycles() {
+        for &opcode in &[0xc4u8, 0xcc, 0xd4, 0xdc, 0xe4, 0xec, 0xf4, 0xfc] {
+            let mut taken = 0;
+            let mut not_taken = 0;
+            // Flip every flag the condition might look at so each one is
+            // tried both ways
+            for &flags in &[0x00u8, 0xff] {
+                let mut state = State8080::empty_state();
+                state.memory = vec![opcode, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+                state.sp = 0x08;
+                state.cc.z = flags & 0x01;
+                state.cc.cy = flags & 0x01;
+                state.cc.p = Parity::from(flags & 0x01);
+                state.cc.s = Sign::from(flags & 0x01);
+                let cycles = emulate_8080_op(&mut state).unwrap();
+                if state.program_counter() == 0x10 {
+                    taken = cycles;
+                } else {
+                    assert_eq!(state.program_counter(), 0x03);
+                    not_taken = cycles;
+                }
+            }
+            assert_eq!((taken, not_taken), (17, 11), "opcode {:02x}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_conditional_return_cycles() {
+        for &opcode in &[0xc0u8, 0xc8, 0xd0, 0xd8, 0xe0, 0xe8, 0xf0, 0xf8] {
+            let mut taken = 0;
+            let mut not_taken = 0;
+            for &flags in &[0x00u8, 0xff] {
+                let mut state = State8080::empty_state();
+                state.memory = vec![opcode, 0x00, 0x10, 0x00];
+                state.sp = 0x02;
+                state.cc.z = flags & 0x01;
+                state.cc.cy = flags & 0x01;
+                state.cc.p = Parity::from(flags & 0x01);
+                state.cc.s = Sign::from(flags & 0x01);
+                let cycles = emulate_8080_op(&mut state).unwrap();
+                if state.program_counter() == 0x10 {
+                    taken = cycles;
+                } else {
+                    assert_eq!(state.program_counter(), 0x01);
+                    not_taken = cycles;
+                }
+            }
+            assert_eq!((taken, not_taken), (11, 5), "opcode {:02x}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_unconditional_call_ret_cycles() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0xcd, 0x04, 0x00, 0x00, 0xc9, 0x00, 0x00, 0x00];
+        state.sp = 0x08;
+        assert_eq!(emulate_8080_op(&mut state), Ok(17));
+        assert_eq!(emulate_8080_op(&mut state), Ok(10));
+    }
+
+    #[test]
+    fn test_undocumented_nops() {
+        for &opcode in &[0x08u8, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38] {
+            let mut state = State8080::empty_state();
+            state.memory = vec![opcode];
+            state.a = 0x12;
+            assert_eq!(emulate_8080_op(&mut state), Ok(4));
+            assert_eq!(state.program_counter(), 0x01);
+            assert_eq!(state.a, 0x12);
+        }
+    }
+
+    #[test]
+    fn test_undocumented_jmp() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0xcb, 0x34, 0x12];
+        assert_eq!(emulate_8080_op(&mut state), Ok(10));
+        assert_eq!(state.program_counter(), 0x1234);
+    }
+
+    #[test]
+    fn test_undocumented_ret() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0xd9, 0x34, 0x12];
+        state.sp = 0x01;
+        assert_eq!(emulate_8080_op(&mut state), Ok(10));
+        assert_eq!(state.program_counter(), 0x1234);
+        assert_eq!(state.sp, 0x03);
+    }
+
+    #[test]
+    fn test_undocumented_calls() {
+        for &opcode in &[0xddu8, 0xed, 0xfd] {
+            let mut state = State8080::empty_state();
+            state.memory = vec![opcode, 0x34, 0x12, 0x00, 0x00];
+            state.sp = 0x05;
+            assert_eq!(emulate_8080_op(&mut state), Ok(17));
+            assert_eq!(state.program_counter(), 0x1234);
+            assert_eq!(state.sp, 0x03);
+            assert_eq!(state.read_memory(0x03), 0x03);
+            assert_eq!(state.read_memory(0x04), 0x00);
+        }
+    }
+
+    #[allow(unused)]
+    fn state_8085(memory: Vec<u8>) -> State8080 {
+        let mut state = State8080::empty_state();
+        state.memory = memory;
+        state.set_variant(Variant::Intel8085);
+        state
+    }
+
+    #[test]
+    fn test_8085_ana_sets_auxiliary_carry() {
+        // ANA B; ANI 01, neither of which has bit 3 set in either operand
+        let mut state = state_8085(vec![0xa0, 0xe6, 0x01]);
+        state.a = 0x03;
+        state.b = 0x01;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x01);
+        assert_eq!(state.cc.ac, 1);
+        state.cc.ac = 0;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.cc.ac, 1);
+    }
+
+    #[test]
+    fn test_8085_dsub() {
+        let mut state = state_8085(vec![0x08, 0x08]);
+        state.set_hl(0x1234);
+        state.set_bc(0x0235);
+        assert_eq!(emulate_8080_op(&mut state), Ok(10));
+        assert_eq!(state.hl(), 0x0fff);
+        assert_eq!(state.cc.cy, 0);
+        assert_eq!(state.cc.z, 0);
+        state.set_bc(0x0fff);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.hl(), 0x0000);
+        assert_eq!(state.cc.z, 1);
+    }
+
+    #[test]
+    fn test_8085_arhl() {
+        let mut state = state_8085(vec![0x10]);
+        state.set_hl(0x8003);
+        assert_eq!(emulate_8080_op(&mut state), Ok(7));
+        assert_eq!(state.hl(), 0xc001);
+        assert_eq!(state.cc.cy, 1);
+    }
+
+    #[test]
+    fn test_8085_rdel() {
+        let mut state = state_8085(vec![0x18]);
+        state.set_de(0x4001);
+        state.cc.cy = 1;
+        assert_eq!(emulate_8080_op(&mut state), Ok(10));
+        assert_eq!(state.de(), 0x8003);
+        assert_eq!(state.cc.cy, 0);
+        assert_eq!(state.cc.v, 1);
+    }
+
+    #[test]
+    fn test_8085_ldhi_ldsi() {
+        let mut state = state_8085(vec![0x28, 0x20, 0x38, 0x10]);
+        state.set_hl(0x1000);
+        state.sp = 0x2000;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.de(), 0x1020);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.de(), 0x2010);
+    }
+
+    #[test]
+    fn test_8085_shlx_lhlx() {
+        let mut state = state_8085(vec![0xd9, 0xed, 0x00, 0x00, 0x00]);
+        state.set_de(0x0003);
+        state.set_hl(0xbeef);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.read_memory(0x03), 0xef);
+        assert_eq!(state.read_memory(0x04), 0xbe);
+        state.set_hl(0x0000);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.hl(), 0xbeef);
+    }
+
+    #[test]
+    fn test_8085_jk_after_dcx() {
+        let mut state = state_8085(vec![0x0b, 0xfd, 0x34, 0x12]);
+        state.set_bc(0x0000);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.cc.k, 1);
+        assert_eq!(emulate_8080_op(&mut state), Ok(10));
+        assert_eq!(state.program_counter(), 0x1234);
+    }
+
+    #[test]
+    fn test_8085_jnk_after_inx() {
+        let mut state = state_8085(vec![0x03, 0xdd, 0x34, 0x12]);
+        state.set_bc(0x0001);
+        assert_eq!(emulate_8080_op(&mut state), Ok(6));
+        assert_eq!(state.cc.k, 0);
+        assert_eq!(emulate_8080_op(&mut state), Ok(10));
+        assert_eq!(state.program_counter(), 0x1234);
+    }
+
+    #[test]
+    fn test_8085_rstv() {
+        let mut state = state_8085(vec![0xcb, 0xcb, 0x00, 0x00]);
+        state.sp = 0x04;
+        state.cc.v = 0;
+        assert_eq!(emulate_8080_op(&mut state), Ok(6));
+        assert_eq!(state.program_counter(), 0x01);
+        state.cc.v = 1;
+        assert_eq!(emulate_8080_op(&mut state), Ok(12));
+        assert_eq!(state.program_counter(), 0x40);
+        assert_eq!(state.read_memory(0x02), 0x02);
+    }
+
+    #[test]
+    fn test_8085_overflow_flag() {
+        let mut state = state_8085(vec![0xc6, 0x01, 0xd6, 0x01]);
+        state.a = 0x7f;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x80);
+        assert_eq!(state.cc.v, 1);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x7f);
+        assert_eq!(state.cc.v, 1);
+    }
+
+    #[test]
+    fn test_8085_push_pop_psw() {
+        let mut state = state_8085(vec![0xf5, 0xf1, 0x00, 0x00]);
+        state.sp = 0x04;
+        state.cc.v = 1;
+        state.cc.k = 1;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.read_memory(0x02) & 0x22, 0x22);
+        state.cc.v = 0;
+        state.cc.k = 0;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.cc.v, 1);
+        assert_eq!(state.cc.k, 1);
+    }
+
+    #[test]
+    fn test_8080_psw_ignores_v_and_k() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0xf5, 0x00, 0x00];
+        state.sp = 0x03;
+        state.cc.v = 1;
+        state.cc.k = 1;
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.read_memory(0x01) & 0x2a, 0x02);
+    }
+
+    #[test]
+    fn test_8085_rim_sim() {
+        let mut state = state_8085(vec![
+            0x20, // RIM
+            0x3e, 0x0a, // MVI A, masks 6.5 only
+            0x30, // SIM
+            0xfb, // EI
+            0x20, // RIM
+            0x3e, 0xc0, // MVI A, SOD high
+            0x30, // SIM
+        ]);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x07);
+        for _ in 0..4 {
+            emulate_8080_op(&mut state).unwrap();
+        }
+        assert_eq!(state.a, 0x0a);
+        assert!(!state.sod());
+        emulate_8080_op(&mut state).unwrap();
+        emulate_8080_op(&mut state).unwrap();
+        assert!(state.sod());
+    }
+
+    #[test]
+    fn test_8085_rim_pending_and_sid() {
+        let mut state = state_8085(vec![0x20]);
+        state.raise_rst7_5();
+        state.set_rst5_5(true);
+        state.set_sid(true);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0xd7);
+    }
+
+    #[test]
+    fn test_8085_masked_interrupt() {
+        let mut state = state_8085(vec![0x00; 0x40]);
+        state.write_memory(0x00, 0xfb); // EI
+        state.sp = 0x40;
+        state.set_rst6_5(true);
+        emulate_8080_op(&mut state).unwrap();
+        emulate_8080_op(&mut state).unwrap();
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.program_counter(), 0x03);
+        state.sim(0x08); // Unmask everything
+        assert_eq!(emulate_8080_op(&mut state), Ok(12));
+        assert_eq!(state.program_counter(), 0x34);
+        assert!(!state.interrupt_enabled());
+        assert_eq!(state.read_memory(0x3e), 0x03);
+    }
+
+    #[test]
+    fn test_8085_interrupt_priority() {
+        let mut state = state_8085(vec![0x00; 0x40]);
+        state.sp = 0x40;
+        state.sim(0x08);
+        state.enable_interrupt();
+        emulate_8080_op(&mut state).unwrap();
+        state.set_rst5_5(true);
+        state.raise_rst7_5();
+        state.request_interrupt(0xc7);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.program_counter(), 0x3c);
+        // The 7.5 flip-flop is cleared once it's taken
+        state.enable_interrupt();
+        emulate_8080_op(&mut state).unwrap();
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.program_counter(), 0x2c);
+        assert!(state.interrupt_pending());
+    }
+
+    #[test]
+    fn test_8085_trap() {
+        let mut state = state_8085(vec![0x00; 0x40]);
+        state.write_memory(0x24, 0x20); // RIM
+        state.write_memory(0x25, 0x20); // RIM
+        state.sp = 0x40;
+        state.raise_trap();
+        // Interrupts are disabled, but TRAP can't be
+        assert_eq!(emulate_8080_op(&mut state), Ok(12));
+        assert_eq!(state.program_counter(), 0x24);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a & 0x08, 0x00);
+
+        let mut state = state_8085(vec![0x00; 0x40]);
+        state.write_memory(0x24, 0x20); // RIM
+        state.write_memory(0x25, 0x20); // RIM
+        state.sp = 0x40;
+        state.enable_interrupt();
+        emulate_8080_op(&mut state).unwrap();
+        state.raise_trap();
+        emulate_8080_op(&mut state).unwrap();
+        assert!(!state.interrupt_enabled());
+        // The first RIM reports what the TRAP disabled, the next one doesn't
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a & 0x08, 0x08);
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a & 0x08, 0x00);
+    }
+
+    #[test]
+    fn test_8085_cycles() {
+        let mut state = state_8085(vec![0xca, 0x03, 0x00, 0xca, 0x06, 0x00, 0xcd, 0x00, 0x00, 0x00, 0x00]);
+        state.sp = 0x0b;
+        state.cc.z = 0;
+        assert_eq!(emulate_8080_op(&mut state), Ok(7));
+        state.cc.z = 1;
+        assert_eq!(emulate_8080_op(&mut state), Ok(10));
+        assert_eq!(emulate_8080_op(&mut state), Ok(18));
+    }
+
+    #[test]
+    fn test_8080_ignores_8085_opcodes() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x20, 0x30];
+        state.a = 0x55;
+        emulate_8080_op(&mut state).unwrap();
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.a, 0x55);
+        assert_eq!(state.program_counter(), 2);
+    }
+
+    #[test]
+    fn test_backtrace_through_interrupt() {
+        // EI; CALL 0010 with the subroutine looping on itself, and RST 1
+        // coming in while it does
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00; 0x40];
+        state.memory[..4].copy_from_slice(&[0xfb, 0xcd, 0x10, 0x00]);
+        state.memory[0x10..0x13].copy_from_slice(&[0xc3, 0x10, 0x00]);
+        state.sp = 0x40;
+        emulate_8080_op(&mut state).unwrap();
+        emulate_8080_op(&mut state).unwrap();
+        state.request_interrupt(0xcf);
+        emulate_8080_op(&mut state).unwrap();
+        let backtrace = state.backtrace();
+        assert_eq!(backtrace.iter().map(|entry| entry.address).collect::<Vec<_>>(), vec![0x08, 0x10, 0x04]);
+        assert_eq!(backtrace[0].subroutine, Some(0x08));
+        assert!(backtrace[0].interrupt);
+        assert!(!backtrace[1].interrupt);
+    }
+
+    #[test]
+    fn test_backtrace_after_stack_tricks() {
+        // CALL 0010, which swaps its return address with XTHL, then CALL 0020,
+        // which drops its own with POP H; PCHL
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00; 0x40];
+        state.memory[..3].copy_from_slice(&[0xcd, 0x10, 0x00]);
+        state.memory[0x10..0x14].copy_from_slice(&[0xe3, 0xcd, 0x20, 0x00]);
+        state.memory[0x20..0x22].copy_from_slice(&[0xe1, 0xe9]);
+        state.sp = 0x40;
+        state.set_hl(0x0030);
+        emulate_8080_op(&mut state).unwrap();
+        emulate_8080_op(&mut state).unwrap();
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.backtrace().len(), 3);
+        emulate_8080_op(&mut state).unwrap();
+        emulate_8080_op(&mut state).unwrap();
+        assert_eq!(state.program_counter(), 0x14);
+        let backtrace = state.backtrace();
+        assert_eq!(backtrace.len(), 2);
+        assert_eq!(backtrace[0].subroutine, Some(0x10));
+        assert_eq!(backtrace[1].address, 0x30);
+        assert_eq!(backtrace[1].changed_from, Some(0x03));
+    }
 }
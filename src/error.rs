@@ -0,0 +1,34 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorReason {
+    UnmappedRead(u16),
+    UnmappedWrite(u16),
+}
+
+// Returned by `emulate_8080_op` when an instruction can't be executed. `pc`
+// and `opcode` are for the instruction that failed, not wherever the program
+// counter ended up afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmulatorError {
+    pub pc: u16,
+    pub opcode: u8,
+    pub reason: ErrorReason,
+}
+
+impl fmt::Display for ErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorReason::UnmappedRead(address) => write!(f, "read from unmapped address {:04x}", address),
+            ErrorReason::UnmappedWrite(address) => write!(f, "write to unmapped address {:04x}", address),
+        }
+    }
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (opcode {:02x} at {:04x})", self.reason, self.opcode, self.pc)
+    }
+}
+
+impl std::error::Error for EmulatorError {}
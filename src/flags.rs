@@ -0,0 +1,48 @@
+use bitflags::bitflags;
+
+bitflags! {
+    // The flags as the 8080 lays them out in the low byte of PSW
+    pub struct Flags: u8 {
+        const CARRY = 0x01;
+        const PARITY = 0x04;
+        const AUX_CARRY = 0x10;
+        const ZERO = 0x40;
+        const SIGN = 0x80;
+    }
+}
+
+impl Flags {
+    // Bit 1 is always pushed as 1 and bits 3 and 5 as 0
+    pub fn to_psw(self) -> u8 {
+        self.bits() | 0x02
+    }
+
+    // The fixed bits are dropped
+    pub fn from_psw(psw: u8) -> Flags {
+        Flags::from_bits_truncate(psw)
+    }
+}
+
+mod test {
+    #[allow(unused)] use super::*;
+
+    #[test]
+    fn test_fixed_bits() {
+        assert_eq!(Flags::empty().to_psw(), 0x02);
+        assert_eq!(Flags::all().to_psw(), 0xd7);
+    }
+
+    #[test]
+    fn test_from_psw_drops_fixed_bits() {
+        assert_eq!(Flags::from_psw(0xff), Flags::all());
+        assert_eq!(Flags::from_psw(0x2a), Flags::empty());
+        assert_eq!(Flags::from_psw(0x41), Flags::ZERO | Flags::CARRY);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for psw in 0..=0xffu8 {
+            assert_eq!(Flags::from_psw(psw).to_psw(), (psw & 0xd5) | 0x02);
+        }
+    }
+}
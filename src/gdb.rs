@@ -0,0 +1,701 @@
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::bus::Bus;
+use crate::debugger::Debuggable;
+use crate::instruction::RegisterPair;
+use crate::watchpoint::{WatchKind, Watchpoint};
+
+// A stub for GDB's remote serial protocol, so a GDB (or anything else that
+// speaks the protocol) can drive a machine the same way the built-in debugger
+// does. Packets look like `$<data>#<checksum>`, each one acknowledged with a
+// `+` unless the client has turned that off.
+//
+// GDB doesn't know the 8080, but the Z80 it does know starts with the same
+// register pairs, so the target description claims to be a Z80 with only
+// those. Every register is 16 bits and goes over the wire little endian.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>z80</architecture>
+  <feature name="org.gnu.gdb.z80.cpu">
+    <reg name="af" bitsize="16" type="int" regnum="0"/>
+    <reg name="bc" bitsize="16" type="int"/>
+    <reg name="de" bitsize="16" type="int"/>
+    <reg name="hl" bitsize="16" type="data_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// In the order of the target description. AF is the PSW.
+const REGISTERS: [Option<RegisterPair>; 6] = [
+    Some(RegisterPair::Psw),
+    Some(RegisterPair::BC),
+    Some(RegisterPair::DE),
+    Some(RegisterPair::HL),
+    Some(RegisterPair::SP),
+    None, // PC
+];
+
+// How many instructions to run between checking for the client asking to stop
+const INTERRUPT_CHECK_INTERVAL: usize = 1024;
+
+// The most bytes one `m` packet reads. Clients split longer reads up when
+// they get less than they asked for.
+const MAX_MEMORY_READ: usize = 0x1f4;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// Where the packets come from and go to
+pub trait Connection: Read + Write {
+    // Whether the client has sent a break (^C) while the machine is running.
+    // Must not block.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+impl Connection for TcpStream {
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.read(&mut byte);
+        self.set_nonblocking(false)?;
+        match result {
+            // Nothing else should turn up while running, so anything that
+            // isn't a break is dropped
+            Ok(1) => Ok(byte[0] == 0x03),
+            Ok(_) => Ok(false),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+// For `target remote | ...`. There's no way to peek at stdin without
+// blocking, so a break only gets through once the machine stops by itself.
+pub struct Stdio {
+    stdin: io::Stdin,
+    stdout: io::Stdout,
+}
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdin.read(buf)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdout.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdout.flush()
+    }
+}
+
+impl Connection for Stdio {}
+
+enum Stop {
+    Signal(u8),
+    // Reported as a software breakpoint, which qSupported says we do
+    Breakpoint,
+    Watch(&'static str, u16),
+    Exited,
+}
+
+pub struct GdbStub<D: Debuggable> {
+    pub machine: D,
+    breakpoints: BTreeSet<u16>,
+    // GDB's access watchpoints are a read and a write watchpoint on the CPU,
+    // kept here as well to report them as what GDB asked for
+    access_watchpoints: Vec<(u16, u16)>,
+    finished: bool,
+    acks: bool,
+}
+
+impl<D: Debuggable> GdbStub<D> {
+    pub fn new(machine: D) -> GdbStub<D> {
+        GdbStub {
+            machine,
+            breakpoints: BTreeSet::new(),
+            access_watchpoints: Vec::new(),
+            finished: false,
+            acks: true,
+        }
+    }
+
+    // Serves packets until the client detaches, kills the machine or hangs up
+    pub fn serve<C: Connection>(&mut self, connection: &mut C) -> io::Result<()> {
+        while let Some(packet) = self.receive(connection)? {
+            let reply = match packet.as_slice() {
+                b"k" => return Ok(()),
+                b"D" => {
+                    self.send(connection, b"OK")?;
+                    return Ok(());
+                },
+                [b'c', address @ ..] | [b's', address @ ..] => {
+                    let step = packet[0] == b's';
+                    match self.resume(connection, address, step)? {
+                        Some(stop) => stop_reply(&stop),
+                        None => b"E01".to_vec(),
+                    }
+                },
+                _ => self.handle(&packet),
+            };
+            self.send(connection, &reply)?;
+        }
+        Ok(())
+    }
+
+    // Everything other than running the machine and ending the session.
+    // Anything unsupported gets an empty reply, which is how the protocol
+    // says so.
+    fn handle(&mut self, packet: &[u8]) -> Vec<u8> {
+        let text = String::from_utf8_lossy(packet);
+        let reply = match text.as_ref() {
+            "?" => Some(stop_reply(&if self.finished { Stop::Exited } else { Stop::Signal(SIGTRAP) })),
+            "g" => Some(self.read_registers()),
+            "qAttached" => Some(b"1".to_vec()),
+            "QStartNoAckMode" => {
+                self.acks = false;
+                Some(b"OK".to_vec())
+            },
+            _ if text.starts_with("qSupported") => {
+                Some(b"PacketSize=1000;qXfer:features:read+;QStartNoAckMode+;swbreak+".to_vec())
+            },
+            _ if text.starts_with('H') => Some(b"OK".to_vec()),
+            _ => if let Some(arguments) = text.strip_prefix("qXfer:features:read:target.xml:") {
+                read_target_xml(arguments)
+            } else {
+                match packet.split_first() {
+                    Some((b'G', values)) => self.write_registers(values),
+                    Some((b'p', number)) => self.read_register(number),
+                    Some((b'P', assignment)) => self.write_register(assignment),
+                    Some((b'm', arguments)) => self.read_memory(arguments),
+                    Some((b'M', arguments)) => self.write_memory(arguments),
+                    Some((b'Z', arguments)) => self.insert_point(arguments),
+                    Some((b'z', arguments)) => self.remove_point(arguments),
+                    _ => return Vec::new(),
+                }
+            },
+        };
+        reply.unwrap_or_else(|| b"E01".to_vec())
+    }
+
+    // Runs the machine for a `c` or `s` packet, which can carry an address to
+    // resume from. Returns None if the address is garbage.
+    fn resume<C: Connection>(&mut self, connection: &mut C, address: &[u8], step: bool) -> io::Result<Option<Stop>> {
+        if !address.is_empty() {
+            match parse_hex(address) {
+                Some(address) => self.machine.state_mut().set_program_counter(address as u16),
+                None => return Ok(None),
+            }
+        }
+        let mut instructions = 0;
+        loop {
+            if let Some(stop) = self.step(connection)? {
+                return Ok(Some(stop));
+            }
+            if step {
+                return Ok(Some(Stop::Signal(SIGTRAP)));
+            }
+            if self.breakpoints.contains(&self.machine.state().program_counter()) {
+                return Ok(Some(Stop::Breakpoint));
+            }
+            instructions += 1;
+            if instructions % INTERRUPT_CHECK_INTERVAL == 0 && connection.interrupted()? {
+                return Ok(Some(Stop::Signal(SIGINT)));
+            }
+        }
+    }
+
+    // Runs one instruction, returning why the machine stopped if it did.
+    // Errors go to the client's console before the stop reply.
+    fn step<C: Connection>(&mut self, connection: &mut C) -> io::Result<Option<Stop>> {
+        if self.finished {
+            return Ok(Some(Stop::Exited));
+        }
+        self.machine.state_mut().take_watch_hit();
+        let result = self.machine.step();
+        let hit = self.machine.state_mut().take_watch_hit();
+        // An error wins over a watchpoint the instruction hit on the way to it
+        if let Err(err) = result {
+            let message = format!("Error: {}\n", err);
+            self.send(connection, &[b"O", hex(message.as_bytes()).as_bytes()].concat())?;
+            // Every error is a bus fault now that all opcodes are implemented
+            return Ok(Some(Stop::Signal(SIGSEGV)));
+        }
+        if let Some(hit) = hit {
+            self.finished = result == Ok(false);
+            let access = self.access_watchpoints.iter().any(|&(start, end)| start <= hit.address && hit.address <= end);
+            let kind = match hit.kind {
+                _ if access => "awatch",
+                WatchKind::Read => "rwatch",
+                _ => "watch",
+            };
+            return Ok(Some(Stop::Watch(kind, hit.address)));
+        }
+        if result == Ok(false) {
+            self.finished = true;
+            return Ok(Some(Stop::Exited));
+        }
+        Ok(None)
+    }
+
+    fn register(&self, number: usize) -> Option<u16> {
+        let state = self.machine.state();
+        match REGISTERS.get(number)? {
+            Some(pair) => Some(state.register_pair(*pair)),
+            None => Some(state.program_counter()),
+        }
+    }
+
+    fn set_register(&mut self, number: usize, value: u16) -> Option<()> {
+        let state = self.machine.state_mut();
+        match REGISTERS.get(number)? {
+            Some(pair) => state.set_register_pair(*pair, value),
+            None => state.set_program_counter(value),
+        }
+        Some(())
+    }
+
+    fn read_registers(&self) -> Vec<u8> {
+        let values: Vec<u8> = (0..REGISTERS.len())
+            .flat_map(|number| self.register(number).unwrap().to_le_bytes().to_vec())
+            .collect();
+        hex(&values).into_bytes()
+    }
+
+    fn write_registers(&mut self, values: &[u8]) -> Option<Vec<u8>> {
+        let values = parse_hex_bytes(values)?;
+        if values.len() != REGISTERS.len() * 2 {
+            return None;
+        }
+        for (number, value) in values.chunks(2).enumerate() {
+            self.set_register(number, u16::from_le_bytes([value[0], value[1]]))?;
+        }
+        Some(b"OK".to_vec())
+    }
+
+    fn read_register(&self, number: &[u8]) -> Option<Vec<u8>> {
+        let value = self.register(parse_hex(number)?)?;
+        Some(hex(&value.to_le_bytes()).into_bytes())
+    }
+
+    fn write_register(&mut self, assignment: &[u8]) -> Option<Vec<u8>> {
+        let (number, value) = split(assignment, b'=')?;
+        let value = parse_hex_bytes(value)?;
+        if value.len() != 2 {
+            return None;
+        }
+        self.set_register(parse_hex(number)?, u16::from_le_bytes([value[0], value[1]]))?;
+        Some(b"OK".to_vec())
+    }
+
+    // `m<address>,<length>`. Stops at the first unmapped address or the top
+    // of memory, which is what the protocol expects from a partial read.
+    fn read_memory(&self, arguments: &[u8]) -> Option<Vec<u8>> {
+        let (address, length) = split(arguments, b',')?;
+        let (address, length) = (parse_hex(address)?, parse_hex(length)?);
+        if address > 0xffff {
+            return None;
+        }
+        let end = address.saturating_add(length.min(MAX_MEMORY_READ)).min(0x10000);
+        let memory = &self.machine.state().memory;
+        let bytes: Vec<u8> = (address..end)
+            .map_while(|address| memory.read(address as u16).ok())
+            .collect();
+        if bytes.is_empty() && length > 0 {
+            return None;
+        }
+        Some(hex(&bytes).into_bytes())
+    }
+
+    // `M<address>,<length>:<bytes>`
+    fn write_memory(&mut self, arguments: &[u8]) -> Option<Vec<u8>> {
+        let (range, bytes) = split(arguments, b':')?;
+        let (address, length) = split(range, b',')?;
+        let (address, bytes) = (parse_hex(address)?, parse_hex_bytes(bytes)?);
+        if bytes.len() != parse_hex(length)? {
+            return None;
+        }
+        let state = self.machine.state_mut();
+        for (offset, byte) in bytes.into_iter().enumerate() {
+            state.write_memory(address.wrapping_add(offset) as u16, byte);
+        }
+        state.take_watch_hit();
+        match state.take_bus_fault() {
+            Some(_) => None,
+            None => Some(b"OK".to_vec()),
+        }
+    }
+
+    // `Z<type>,<address>,<kind>`: 0 is a breakpoint, 2 a write watchpoint,
+    // 3 a read one and 4 an access one. For watchpoints `kind` is the length.
+    fn insert_point(&mut self, arguments: &[u8]) -> Option<Vec<u8>> {
+        let (point_type, address, kind) = parse_point(arguments)?;
+        let state = self.machine.state_mut();
+        match point_type {
+            0 => {
+                self.breakpoints.insert(address);
+            },
+            2..=4 => {
+                let end = address.wrapping_add(kind.max(1) - 1);
+                for kind in watch_kinds(point_type) {
+                    state.add_watchpoint(Watchpoint { start: address, end, kind: *kind });
+                }
+                if point_type == 4 {
+                    self.access_watchpoints.push((address, end));
+                }
+            },
+            _ => return Some(Vec::new()),
+        }
+        Some(b"OK".to_vec())
+    }
+
+    fn remove_point(&mut self, arguments: &[u8]) -> Option<Vec<u8>> {
+        let (point_type, address, kind) = parse_point(arguments)?;
+        let state = self.machine.state_mut();
+        match point_type {
+            0 => {
+                self.breakpoints.remove(&address);
+            },
+            2..=4 => {
+                let end = address.wrapping_add(kind.max(1) - 1);
+                for kind in watch_kinds(point_type) {
+                    let watchpoint = Watchpoint { start: address, end, kind: *kind };
+                    let index = state.watchpoints().iter().position(|existing| *existing == watchpoint)?;
+                    state.remove_watchpoint(index);
+                }
+                if point_type == 4 {
+                    self.access_watchpoints.retain(|&range| range != (address, end));
+                }
+            },
+            _ => return Some(Vec::new()),
+        }
+        Some(b"OK".to_vec())
+    }
+
+    // Waits for the next packet, acknowledging it unless acks are off.
+    // Returns None once the client has gone.
+    fn receive<C: Connection>(&mut self, connection: &mut C) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match read_byte(connection)? {
+                None => return Ok(None),
+                Some(b'$') => {},
+                // A break with the machine already stopped just gets a stop
+                // reply, as if it had been running
+                Some(0x03) => {
+                    self.send(connection, &stop_reply(&Stop::Signal(SIGINT)))?;
+                    continue;
+                },
+                // Stray acks, and whatever else turns up between packets
+                Some(_) => continue,
+            }
+            let mut packet = Vec::new();
+            let mut checksum = 0u8;
+            let mut escaped = false;
+            loop {
+                let byte = match read_byte(connection)? {
+                    Some(byte) => byte,
+                    None => return Ok(None),
+                };
+                if byte == b'#' && !escaped {
+                    break;
+                }
+                checksum = checksum.wrapping_add(byte);
+                match (escaped, byte) {
+                    (false, b'}') => escaped = true,
+                    (true, _) => {
+                        packet.push(byte ^ 0x20);
+                        escaped = false;
+                    },
+                    _ => packet.push(byte),
+                }
+            }
+            let mut expected = [0; 2];
+            connection.read_exact(&mut expected)?;
+            if !self.acks {
+                return Ok(Some(packet));
+            }
+            if parse_hex(&expected) == Some(checksum as usize) {
+                connection.write_all(b"+")?;
+                connection.flush()?;
+                return Ok(Some(packet));
+            }
+            connection.write_all(b"-")?;
+            connection.flush()?;
+        }
+    }
+
+    // Sends a packet, and with acks on resends it until the client takes it
+    fn send<C: Connection>(&mut self, connection: &mut C, data: &[u8]) -> io::Result<()> {
+        let checksum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        let packet = [b"$", data, format!("#{:02x}", checksum).as_bytes()].concat();
+        loop {
+            connection.write_all(&packet)?;
+            connection.flush()?;
+            if !self.acks {
+                return Ok(());
+            }
+            match read_byte(connection)? {
+                Some(b'-') => continue,
+                Some(_) | None => return Ok(()),
+            }
+        }
+    }
+}
+
+fn stop_reply(stop: &Stop) -> Vec<u8> {
+    match stop {
+        Stop::Signal(signal) => format!("S{:02x}", signal),
+        Stop::Breakpoint => format!("T{:02x}swbreak:;", SIGTRAP),
+        Stop::Watch(kind, address) => format!("T{:02x}{}:{:04x};", SIGTRAP, kind, address),
+        Stop::Exited => "W00".to_string(),
+    }
+    .into_bytes()
+}
+
+// `<offset>,<length>` into the target description
+fn read_target_xml(arguments: &str) -> Option<Vec<u8>> {
+    let (offset, length) = split(arguments.as_bytes(), b',')?;
+    let (offset, length) = (parse_hex(offset)?, parse_hex(length)?);
+    let xml = TARGET_XML.as_bytes();
+    let start = offset.min(xml.len());
+    let end = offset.saturating_add(length).min(xml.len());
+    let marker: &[u8] = if end == xml.len() { b"l" } else { b"m" };
+    Some([marker, &xml[start..end]].concat())
+}
+
+fn watch_kinds(point_type: usize) -> &'static [WatchKind] {
+    match point_type {
+        2 => &[WatchKind::Write],
+        3 => &[WatchKind::Read],
+        _ => &[WatchKind::Read, WatchKind::Write],
+    }
+}
+
+// `<type>,<address>,<kind>`
+fn parse_point(arguments: &[u8]) -> Option<(usize, u16, u16)> {
+    let mut fields = arguments.split(|&byte| byte == b',');
+    let point_type = parse_hex(fields.next()?)?;
+    let address = parse_hex(fields.next()?)?;
+    let kind = parse_hex(fields.next()?)?;
+    if address > 0xffff || kind > 0xffff {
+        return None;
+    }
+    Some((point_type, address as u16, kind as u16))
+}
+
+fn split(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let index = bytes.iter().position(|&byte| byte == separator)?;
+    Some((&bytes[..index], &bytes[index + 1..]))
+}
+
+fn parse_hex(text: &[u8]) -> Option<usize> {
+    if text.is_empty() {
+        return None;
+    }
+    usize::from_str_radix(std::str::from_utf8(text).ok()?, 16).ok()
+}
+
+fn parse_hex_bytes(text: &[u8]) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    text.chunks(2).map(|pair| parse_hex(pair).map(|byte| byte as u8)).collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn read_byte<R: Read>(input: &mut R) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match input.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+// Serves one client, on 127.0.0.1:`port` or on stdin and stdout without a
+// port. Anything the stub has to say goes to stderr, since stdout may be the
+// connection.
+pub fn run<D: Debuggable>(machine: D, port: Option<u16>) {
+    let mut stub = GdbStub::new(machine);
+    let result = match port {
+        Some(port) => TcpListener::bind(("127.0.0.1", port)).and_then(|listener| {
+            eprintln!("Waiting for GDB on 127.0.0.1:{}", port);
+            let (mut connection, _) = listener.accept()?;
+            connection.set_nodelay(true)?;
+            stub.serve(&mut connection)
+        }),
+        None => stub.serve(&mut Stdio { stdin: io::stdin(), stdout: io::stdout() }),
+    };
+    if let Err(err) = result {
+        eprintln!("Error: {}", err);
+    }
+}
+
+// Needs the debugger's `TestMachine`, which only exists in test builds
+#[cfg(test)]
+mod test {
+    #[allow(unused)] use super::*;
+    #[allow(unused)] use crate::debugger::test::TestMachine;
+    #[allow(unused)] use crate::state_8080::State8080;
+
+    // A client that sends its packets one after the other, acknowledging
+    // each reply
+    #[allow(unused)]
+    struct ScriptedClient {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for ScriptedClient {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for ScriptedClient {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Write::write(&mut self.output, buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for ScriptedClient {}
+
+    #[allow(unused)]
+    fn packet(data: &str) -> String {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        format!("${}#{:02x}", data, checksum)
+    }
+
+    // Runs `packets` against a machine with `memory`, and returns the replies
+    #[allow(unused)]
+    fn session(memory: Vec<u8>, packets: &[&str]) -> (Vec<String>, GdbStub<TestMachine>) {
+        let mut state = State8080::empty_state();
+        state.memory = memory;
+        let mut stub = GdbStub::new(TestMachine { state });
+        let script: String = packets.iter().map(|data| packet(data) + "+").collect();
+        let mut client = ScriptedClient { input: io::Cursor::new(script.into_bytes()), output: Vec::new() };
+        stub.serve(&mut client).unwrap();
+        let output = String::from_utf8(client.output).unwrap();
+        let replies = output
+            .split('$')
+            .skip(1)
+            .map(|reply| {
+                let (data, checksum) = reply.split_at(reply.find('#').unwrap());
+                assert_eq!(&packet(data)[data.len() + 1..], &checksum[..3]);
+                data.to_string()
+            })
+            .collect();
+        (replies, stub)
+    }
+
+    #[test]
+    fn test_registers() {
+        let (replies, stub) = session(vec![0; 16], &["g", "P3=3412", "p3", "G0000000000000000fe0f0500", "p5", "p6"]);
+        assert_eq!(replies, vec!["020000000000000000000000", "OK", "3412", "OK", "0500", "E01"]);
+        assert_eq!(stub.machine.state.sp, 0x0ffe);
+        assert_eq!(stub.machine.state.program_counter(), 0x0005);
+    }
+
+    #[test]
+    fn test_memory() {
+        let (replies, stub) = session(vec![0; 16], &["M4,2:abcd", "m3,4", "me,4", "m10,1", "M10,1:00"]);
+        assert_eq!(replies, vec!["OK", "00abcd00", "0000", "E01", "E01"]);
+        assert_eq!(stub.machine.state.memory[4..6], [0xab, 0xcd]);
+    }
+
+    #[test]
+    fn test_long_memory_reads() {
+        let (replies, _) = session(vec![0; 0x10000], &["m0,1000", "mfffe,4", "m10000,1"]);
+        assert_eq!(replies[0], "00".repeat(MAX_MEMORY_READ));
+        assert_eq!(replies[1..], ["0000", "E01"]);
+    }
+
+    #[test]
+    fn test_step_and_continue() {
+        // INR A x3, HLT
+        let (replies, stub) = session(
+            vec![0x3c, 0x3c, 0x3c, 0x76],
+            &["s", "Z0,2,1", "c", "z0,2,1", "c", "c", "?"],
+        );
+        assert_eq!(replies, vec!["S05", "OK", "T05swbreak:;", "OK", "W00", "W00", "W00"]);
+        assert_eq!(stub.machine.state.a, 3);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        // MVI A,5; STA 000c; LDA 000c; HLT
+        let mut memory = vec![0x3e, 0x05, 0x32, 0x0c, 0x00, 0x3a, 0x0c, 0x00, 0x76];
+        memory.resize(16, 0);
+        let (replies, _) = session(memory.clone(), &["Z2,c,1", "Z3,c,1", "c", "c", "z2,c,1", "z3,c,1", "c"]);
+        assert_eq!(replies, vec!["OK", "OK", "T05watch:000c;", "T05rwatch:000c;", "OK", "OK", "W00"]);
+        let (replies, _) = session(memory, &["Z4,b,2", "c", "c", "z4,b,2", "c"]);
+        assert_eq!(replies, vec!["OK", "T05awatch:000c;", "T05awatch:000c;", "OK", "W00"]);
+    }
+
+    #[test]
+    fn test_errors_go_to_the_console() {
+        let (replies, _) = session(vec![0x3a, 0x00, 0x10], &["c"]); // LDA 1000
+        assert_eq!(replies.len(), 2);
+        assert!(replies[0].starts_with('O'));
+        assert_eq!(replies[1], "S0b");
+    }
+
+    #[test]
+    fn test_error_beats_watchpoint() {
+        // SHLD 000f writes the watched 000f and then faults on 0010
+        let mut memory = vec![0x22, 0x0f, 0x00];
+        memory.resize(16, 0);
+        let (replies, _) = session(memory, &["Z2,f,1", "c"]);
+        assert_eq!(replies.len(), 3);
+        assert!(replies[1].starts_with('O'));
+        assert_eq!(replies[2], "S0b");
+    }
+
+    #[test]
+    fn test_target_description() {
+        let (replies, _) = session(vec![], &["qSupported:multiprocess+", "qXfer:features:read:target.xml:0,10", "qXfer:features:read:target.xml:10,1000", "qfThreadInfo"]);
+        assert!(replies[0].contains("qXfer:features:read+"));
+        assert_eq!(replies[1], "m<?xml version=\"1");
+        assert!(replies[2].starts_with("l.0\"?>") && replies[2].ends_with("</target>\n"));
+        assert_eq!(replies[3], "");
+    }
+
+    #[test]
+    fn test_no_ack_mode_and_detach() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0; 16];
+        let mut stub = GdbStub::new(TestMachine { state });
+        let script = packet("QStartNoAckMode") + "+" + &packet("?") + &packet("D") + &packet("g");
+        let mut client = ScriptedClient { input: io::Cursor::new(script.into_bytes()), output: Vec::new() };
+        stub.serve(&mut client).unwrap();
+        assert_eq!(String::from_utf8(client.output).unwrap(), "+$OK#9a$S05#b8$OK#9a");
+    }
+
+    #[test]
+    fn test_bad_checksum() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0; 16];
+        let mut stub = GdbStub::new(TestMachine { state });
+        let script = "$?#00".to_string() + &packet("?") + "+";
+        let mut client = ScriptedClient { input: io::Cursor::new(script.into_bytes()), output: Vec::new() };
+        stub.serve(&mut client).unwrap();
+        assert_eq!(String::from_utf8(client.output).unwrap(), "-+$S05#b8");
+    }
+}
@@ -0,0 +1,605 @@
+use std::fmt;
+
+use crate::state_8080::Variant;
+
+// One decoded 8080/8085 instruction. The executor, the disassembler and
+// anything that assembles code all go through this, so the opcode map only
+// lives in `decode` and `encode`.
+//
+// Opcodes follow the usual octal layout: bits 7-6 pick a group (x), bits 5-3
+// are usually a destination register or an operation (y) and bits 2-0 a
+// source register or a sub-operation (z). Register pairs sit in bits 5-4
+// (p), with bit 3 (q) choosing between two related instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Nop,
+    Lxi(RegisterPair, u16),
+    Stax(RegisterPair),
+    Shld(u16),
+    Sta(u16),
+    Inx(RegisterPair),
+    Inr(Register),
+    Dcr(Register),
+    Mvi(Register, u8),
+    Rlc,
+    Rrc,
+    Ral,
+    Rar,
+    Dad(RegisterPair),
+    Ldax(RegisterPair),
+    Lhld(u16),
+    Lda(u16),
+    Dcx(RegisterPair),
+    Daa,
+    Cma,
+    Stc,
+    Cmc,
+    // Destination first, like the mnemonic
+    Mov(Register, Register),
+    Hlt,
+    Add(Register),
+    Adc(Register),
+    Sub(Register),
+    Sbb(Register),
+    Ana(Register),
+    Xra(Register),
+    Ora(Register),
+    Cmp(Register),
+    Adi(u8),
+    Aci(u8),
+    Sui(u8),
+    Sbi(u8),
+    Ani(u8),
+    Xri(u8),
+    Ori(u8),
+    Cpi(u8),
+    ReturnIf(Condition),
+    JumpIf(Condition, u16),
+    CallIf(Condition, u16),
+    Ret,
+    Jmp(u16),
+    Call(u16),
+    // The restart number (0-7), not the address
+    Rst(u8),
+    Pop(RegisterPair),
+    Push(RegisterPair),
+    Out(u8),
+    In(u8),
+    Xthl,
+    Pchl,
+    Xchg,
+    Sphl,
+    Di,
+    Ei,
+
+    // 8085 only. RIM and SIM are documented, the rest aren't.
+    Rim,
+    Sim,
+    Dsub,
+    Arhl,
+    Rdel,
+    Ldhi(u8),
+    Ldsi(u8),
+    Rstv,
+    Shlx,
+    Lhlx,
+    Jnk(u16),
+    Jk(u16),
+}
+
+// In encoding order. M is the byte in memory at HL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    M,
+    A,
+}
+
+// SP only goes with LXI, INX, DCX and DAD, and PSW (A and the flags) only
+// with PUSH and POP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterPair {
+    BC,
+    DE,
+    HL,
+    SP,
+    Psw,
+}
+
+// Operands an instruction can't be encoded with
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeError {
+    // The pair isn't one this instruction takes
+    Pair(RegisterPair),
+    // MOV M,M is where HLT sits
+    MovMM,
+    Rst(u8),
+}
+
+// In encoding order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    NotZero,
+    Zero,
+    NoCarry,
+    Carry,
+    ParityOdd,
+    ParityEven,
+    Plus,
+    Minus,
+}
+
+pub const REGISTERS: [Register; 8] = [
+    Register::B, Register::C, Register::D, Register::E,
+    Register::H, Register::L, Register::M, Register::A,
+];
+
+pub const CONDITIONS: [Condition; 8] = [
+    Condition::NotZero, Condition::Zero, Condition::NoCarry, Condition::Carry,
+    Condition::ParityOdd, Condition::ParityEven, Condition::Plus, Condition::Minus,
+];
+
+// Pairs as LXI, INX, DCX and DAD number them
+const PAIRS: [RegisterPair; 4] = [RegisterPair::BC, RegisterPair::DE, RegisterPair::HL, RegisterPair::SP];
+// And as PUSH and POP do
+const STACK_PAIRS: [RegisterPair; 4] = [RegisterPair::BC, RegisterPair::DE, RegisterPair::HL, RegisterPair::Psw];
+
+impl Instruction {
+    // Decodes the instruction starting with `opcode`, pulling any operand
+    // bytes from `next_byte`. On the 8080 the undocumented opcodes decode as
+    // the instruction they behave like, so they encode back to the documented
+    // opcode. On the 8085 most of those slots hold its own instructions.
+    pub fn decode<F: FnMut() -> u8>(variant: Variant, opcode: u8, mut next_byte: F) -> Instruction {
+        let intel_8085 = variant == Variant::Intel8085;
+        let mut next_word = || {
+            let low = next_byte() as u16;
+            let high = next_byte() as u16;
+            (high << 8) | low
+        };
+        let x = opcode >> 6;
+        let y = (opcode >> 3) & 0x07;
+        let z = opcode & 0x07;
+        let p = (y >> 1) as usize;
+        let q = y & 0x01;
+        let register = |r: u8| REGISTERS[r as usize];
+        match (x, z) {
+            (0, 0) if intel_8085 => match y {
+                0 => Instruction::Nop,
+                1 => Instruction::Dsub,
+                2 => Instruction::Arhl,
+                3 => Instruction::Rdel,
+                4 => Instruction::Rim,
+                5 => Instruction::Ldhi(next_byte()),
+                6 => Instruction::Sim,
+                _ => Instruction::Ldsi(next_byte()),
+            },
+            (0, 0) => Instruction::Nop,
+            (0, 1) if q == 0 => Instruction::Lxi(PAIRS[p], next_word()),
+            (0, 1) => Instruction::Dad(PAIRS[p]),
+            (0, 2) => match (q, p) {
+                (0, 2) => Instruction::Shld(next_word()),
+                (0, 3) => Instruction::Sta(next_word()),
+                (0, _) => Instruction::Stax(PAIRS[p]),
+                (_, 2) => Instruction::Lhld(next_word()),
+                (_, 3) => Instruction::Lda(next_word()),
+                (_, _) => Instruction::Ldax(PAIRS[p]),
+            },
+            (0, 3) if q == 0 => Instruction::Inx(PAIRS[p]),
+            (0, 3) => Instruction::Dcx(PAIRS[p]),
+            (0, 4) => Instruction::Inr(register(y)),
+            (0, 5) => Instruction::Dcr(register(y)),
+            (0, 6) => Instruction::Mvi(register(y), next_byte()),
+            (0, _) => [
+                Instruction::Rlc, Instruction::Rrc, Instruction::Ral, Instruction::Rar,
+                Instruction::Daa, Instruction::Cma, Instruction::Stc, Instruction::Cmc,
+            ][y as usize],
+            (1, 6) if y == 6 => Instruction::Hlt,
+            (1, _) => Instruction::Mov(register(y), register(z)),
+            (2, _) => match y {
+                0 => Instruction::Add(register(z)),
+                1 => Instruction::Adc(register(z)),
+                2 => Instruction::Sub(register(z)),
+                3 => Instruction::Sbb(register(z)),
+                4 => Instruction::Ana(register(z)),
+                5 => Instruction::Xra(register(z)),
+                6 => Instruction::Ora(register(z)),
+                _ => Instruction::Cmp(register(z)),
+            },
+            (_, 0) => Instruction::ReturnIf(CONDITIONS[y as usize]),
+            (_, 1) => match (q, p) {
+                (0, _) => Instruction::Pop(STACK_PAIRS[p]),
+                (_, 0) => Instruction::Ret,
+                (_, 1) if intel_8085 => Instruction::Shlx,
+                (_, 1) => Instruction::Ret,
+                (_, 2) => Instruction::Pchl,
+                (_, _) => Instruction::Sphl,
+            },
+            (_, 2) => Instruction::JumpIf(CONDITIONS[y as usize], next_word()),
+            (_, 3) => match y {
+                0 => Instruction::Jmp(next_word()),
+                1 if intel_8085 => Instruction::Rstv,
+                1 => Instruction::Jmp(next_word()),
+                2 => Instruction::Out(next_byte()),
+                3 => Instruction::In(next_byte()),
+                4 => Instruction::Xthl,
+                5 => Instruction::Xchg,
+                6 => Instruction::Di,
+                _ => Instruction::Ei,
+            },
+            (_, 4) => Instruction::CallIf(CONDITIONS[y as usize], next_word()),
+            (_, 5) => match (q, p) {
+                (0, _) => Instruction::Push(STACK_PAIRS[p]),
+                (_, 1) if intel_8085 => Instruction::Jnk(next_word()),
+                (_, 2) if intel_8085 => Instruction::Lhlx,
+                (_, 3) if intel_8085 => Instruction::Jk(next_word()),
+                (_, _) => Instruction::Call(next_word()),
+            },
+            (_, 6) => match y {
+                0 => Instruction::Adi(next_byte()),
+                1 => Instruction::Aci(next_byte()),
+                2 => Instruction::Sui(next_byte()),
+                3 => Instruction::Sbi(next_byte()),
+                4 => Instruction::Ani(next_byte()),
+                5 => Instruction::Xri(next_byte()),
+                6 => Instruction::Ori(next_byte()),
+                _ => Instruction::Cpi(next_byte()),
+            },
+            (_, _) => Instruction::Rst(y),
+        }
+    }
+
+    // Fails on combinations the CPU can't encode, like LXI PSW, MOV M,M
+    // (that's HLT) or RST 8. Nothing assembles yet, so only the tests use it.
+    #[cfg(test)]
+    pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        let word = |opcode: u8, value: u16| vec![opcode, value as u8, (value >> 8) as u8];
+        let bytes = match *self {
+            Instruction::Nop => vec![0x00],
+            Instruction::Lxi(pair, value) => word(0x01 | pair_bits(pair)?, value),
+            Instruction::Stax(pair) => vec![0x02 | memory_pair_bits(pair)?],
+            Instruction::Shld(address) => word(0x22, address),
+            Instruction::Sta(address) => word(0x32, address),
+            Instruction::Inx(pair) => vec![0x03 | pair_bits(pair)?],
+            Instruction::Inr(register) => vec![0x04 | register_bits(register) << 3],
+            Instruction::Dcr(register) => vec![0x05 | register_bits(register) << 3],
+            Instruction::Mvi(register, value) => vec![0x06 | register_bits(register) << 3, value],
+            Instruction::Rlc => vec![0x07],
+            Instruction::Rrc => vec![0x0f],
+            Instruction::Ral => vec![0x17],
+            Instruction::Rar => vec![0x1f],
+            Instruction::Dad(pair) => vec![0x09 | pair_bits(pair)?],
+            Instruction::Ldax(pair) => vec![0x0a | memory_pair_bits(pair)?],
+            Instruction::Lhld(address) => word(0x2a, address),
+            Instruction::Lda(address) => word(0x3a, address),
+            Instruction::Dcx(pair) => vec![0x0b | pair_bits(pair)?],
+            Instruction::Daa => vec![0x27],
+            Instruction::Cma => vec![0x2f],
+            Instruction::Stc => vec![0x37],
+            Instruction::Cmc => vec![0x3f],
+            Instruction::Mov(Register::M, Register::M) => return Err(EncodeError::MovMM),
+            Instruction::Mov(destination, source) => vec![0x40 | register_bits(destination) << 3 | register_bits(source)],
+            Instruction::Hlt => vec![0x76],
+            Instruction::Add(register) => vec![0x80 | register_bits(register)],
+            Instruction::Adc(register) => vec![0x88 | register_bits(register)],
+            Instruction::Sub(register) => vec![0x90 | register_bits(register)],
+            Instruction::Sbb(register) => vec![0x98 | register_bits(register)],
+            Instruction::Ana(register) => vec![0xa0 | register_bits(register)],
+            Instruction::Xra(register) => vec![0xa8 | register_bits(register)],
+            Instruction::Ora(register) => vec![0xb0 | register_bits(register)],
+            Instruction::Cmp(register) => vec![0xb8 | register_bits(register)],
+            Instruction::Adi(value) => vec![0xc6, value],
+            Instruction::Aci(value) => vec![0xce, value],
+            Instruction::Sui(value) => vec![0xd6, value],
+            Instruction::Sbi(value) => vec![0xde, value],
+            Instruction::Ani(value) => vec![0xe6, value],
+            Instruction::Xri(value) => vec![0xee, value],
+            Instruction::Ori(value) => vec![0xf6, value],
+            Instruction::Cpi(value) => vec![0xfe, value],
+            Instruction::ReturnIf(condition) => vec![0xc0 | condition_bits(condition)],
+            Instruction::JumpIf(condition, address) => word(0xc2 | condition_bits(condition), address),
+            Instruction::CallIf(condition, address) => word(0xc4 | condition_bits(condition), address),
+            Instruction::Ret => vec![0xc9],
+            Instruction::Jmp(address) => word(0xc3, address),
+            Instruction::Call(address) => word(0xcd, address),
+            Instruction::Rst(number) if number < 8 => vec![0xc7 | number << 3],
+            Instruction::Rst(number) => return Err(EncodeError::Rst(number)),
+            Instruction::Pop(pair) => vec![0xc1 | stack_pair_bits(pair)?],
+            Instruction::Push(pair) => vec![0xc5 | stack_pair_bits(pair)?],
+            Instruction::Out(port) => vec![0xd3, port],
+            Instruction::In(port) => vec![0xdb, port],
+            Instruction::Xthl => vec![0xe3],
+            Instruction::Pchl => vec![0xe9],
+            Instruction::Xchg => vec![0xeb],
+            Instruction::Sphl => vec![0xf9],
+            Instruction::Di => vec![0xf3],
+            Instruction::Ei => vec![0xfb],
+            Instruction::Rim => vec![0x20],
+            Instruction::Sim => vec![0x30],
+            Instruction::Dsub => vec![0x08],
+            Instruction::Arhl => vec![0x10],
+            Instruction::Rdel => vec![0x18],
+            Instruction::Ldhi(value) => vec![0x28, value],
+            Instruction::Ldsi(value) => vec![0x38, value],
+            Instruction::Rstv => vec![0xcb],
+            Instruction::Shlx => vec![0xd9],
+            Instruction::Lhlx => vec![0xed],
+            Instruction::Jnk(address) => word(0xdd, address),
+            Instruction::Jk(address) => word(0xfd, address),
+        };
+        Ok(bytes)
+    }
+
+    // Length in bytes, opcode included. Doesn't care whether the operands
+    // can be encoded.
+    pub fn size(&self) -> usize {
+        match self {
+            Instruction::Lxi(..) | Instruction::Shld(_) | Instruction::Sta(_) | Instruction::Lhld(_)
+            | Instruction::Lda(_) | Instruction::JumpIf(..) | Instruction::CallIf(..) | Instruction::Jmp(_)
+            | Instruction::Call(_) | Instruction::Jnk(_) | Instruction::Jk(_) => 3,
+            Instruction::Mvi(..) | Instruction::Adi(_) | Instruction::Aci(_) | Instruction::Sui(_)
+            | Instruction::Sbi(_) | Instruction::Ani(_) | Instruction::Xri(_) | Instruction::Ori(_)
+            | Instruction::Cpi(_) | Instruction::Out(_) | Instruction::In(_) | Instruction::Ldhi(_)
+            | Instruction::Ldsi(_) => 2,
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+fn register_bits(register: Register) -> u8 {
+    REGISTERS.iter().position(|&r| r == register).unwrap() as u8
+}
+
+#[cfg(test)]
+fn condition_bits(condition: Condition) -> u8 {
+    (CONDITIONS.iter().position(|&c| c == condition).unwrap() as u8) << 3
+}
+
+#[cfg(test)]
+fn pair_bits(pair: RegisterPair) -> Result<u8, EncodeError> {
+    match PAIRS.iter().position(|&p| p == pair) {
+        Some(index) => Ok((index as u8) << 4),
+        None => Err(EncodeError::Pair(pair)),
+    }
+}
+
+#[cfg(test)]
+fn stack_pair_bits(pair: RegisterPair) -> Result<u8, EncodeError> {
+    match STACK_PAIRS.iter().position(|&p| p == pair) {
+        Some(index) => Ok((index as u8) << 4),
+        None => Err(EncodeError::Pair(pair)),
+    }
+}
+
+// STAX and LDAX only take B and D
+#[cfg(test)]
+fn memory_pair_bits(pair: RegisterPair) -> Result<u8, EncodeError> {
+    match pair {
+        RegisterPair::BC | RegisterPair::DE => pair_bits(pair),
+        _ => Err(EncodeError::Pair(pair)),
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+// Intel names pairs after their first register
+impl fmt::Display for RegisterPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RegisterPair::BC => "B",
+            RegisterPair::DE => "D",
+            RegisterPair::HL => "H",
+            RegisterPair::SP => "SP",
+            RegisterPair::Psw => "PSW",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::Pair(pair) => write!(f, "{} can't be used here", pair),
+            EncodeError::MovMM => write!(f, "MOV M,M is HLT"),
+            EncodeError::Rst(number) => write!(f, "RST {} doesn't exist, it only goes up to 7", number),
+        }
+    }
+}
+
+#[cfg(test)]
+impl std::error::Error for EncodeError {}
+
+// The suffix used in the conditional mnemonics (JNZ, CPE, RM, ...)
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let suffix = match self {
+            Condition::NotZero => "NZ",
+            Condition::Zero => "Z",
+            Condition::NoCarry => "NC",
+            Condition::Carry => "C",
+            Condition::ParityOdd => "PO",
+            Condition::ParityEven => "PE",
+            Condition::Plus => "P",
+            Condition::Minus => "M",
+        };
+        write!(f, "{}", suffix)
+    }
+}
+
+// Intel mnemonics with a tab before the operands. Immediate values are
+// written #$xx and addresses $xxxx.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Nop => write!(f, "NOP"),
+            Instruction::Lxi(pair, value) => write!(f, "LXI\t{},#${:04x}", pair, value),
+            Instruction::Stax(pair) => write!(f, "STAX\t{}", pair),
+            Instruction::Shld(address) => write!(f, "SHLD\t${:04x}", address),
+            Instruction::Sta(address) => write!(f, "STA\t${:04x}", address),
+            Instruction::Inx(pair) => write!(f, "INX\t{}", pair),
+            Instruction::Inr(register) => write!(f, "INR\t{}", register),
+            Instruction::Dcr(register) => write!(f, "DCR\t{}", register),
+            Instruction::Mvi(register, value) => write!(f, "MVI\t{},#${:02x}", register, value),
+            Instruction::Rlc => write!(f, "RLC"),
+            Instruction::Rrc => write!(f, "RRC"),
+            Instruction::Ral => write!(f, "RAL"),
+            Instruction::Rar => write!(f, "RAR"),
+            Instruction::Dad(pair) => write!(f, "DAD\t{}", pair),
+            Instruction::Ldax(pair) => write!(f, "LDAX\t{}", pair),
+            Instruction::Lhld(address) => write!(f, "LHLD\t${:04x}", address),
+            Instruction::Lda(address) => write!(f, "LDA\t${:04x}", address),
+            Instruction::Dcx(pair) => write!(f, "DCX\t{}", pair),
+            Instruction::Daa => write!(f, "DAA"),
+            Instruction::Cma => write!(f, "CMA"),
+            Instruction::Stc => write!(f, "STC"),
+            Instruction::Cmc => write!(f, "CMC"),
+            Instruction::Mov(destination, source) => write!(f, "MOV\t{},{}", destination, source),
+            Instruction::Hlt => write!(f, "HLT"),
+            Instruction::Add(register) => write!(f, "ADD\t{}", register),
+            Instruction::Adc(register) => write!(f, "ADC\t{}", register),
+            Instruction::Sub(register) => write!(f, "SUB\t{}", register),
+            Instruction::Sbb(register) => write!(f, "SBB\t{}", register),
+            Instruction::Ana(register) => write!(f, "ANA\t{}", register),
+            Instruction::Xra(register) => write!(f, "XRA\t{}", register),
+            Instruction::Ora(register) => write!(f, "ORA\t{}", register),
+            Instruction::Cmp(register) => write!(f, "CMP\t{}", register),
+            Instruction::Adi(value) => write!(f, "ADI\t#${:02x}", value),
+            Instruction::Aci(value) => write!(f, "ACI\t#${:02x}", value),
+            Instruction::Sui(value) => write!(f, "SUI\t#${:02x}", value),
+            Instruction::Sbi(value) => write!(f, "SBI\t#${:02x}", value),
+            Instruction::Ani(value) => write!(f, "ANI\t#${:02x}", value),
+            Instruction::Xri(value) => write!(f, "XRI\t#${:02x}", value),
+            Instruction::Ori(value) => write!(f, "ORI\t#${:02x}", value),
+            Instruction::Cpi(value) => write!(f, "CPI\t#${:02x}", value),
+            Instruction::ReturnIf(condition) => write!(f, "R{}", condition),
+            Instruction::JumpIf(condition, address) => write!(f, "J{}\t${:04x}", condition, address),
+            Instruction::CallIf(condition, address) => write!(f, "C{}\t${:04x}", condition, address),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Jmp(address) => write!(f, "JMP\t${:04x}", address),
+            Instruction::Call(address) => write!(f, "CALL\t${:04x}", address),
+            Instruction::Rst(number) => write!(f, "RST\t{}", number),
+            Instruction::Pop(pair) => write!(f, "POP\t{}", pair),
+            Instruction::Push(pair) => write!(f, "PUSH\t{}", pair),
+            Instruction::Out(port) => write!(f, "OUT\t#${:02x}", port),
+            Instruction::In(port) => write!(f, "IN\t#${:02x}", port),
+            Instruction::Xthl => write!(f, "XTHL"),
+            Instruction::Pchl => write!(f, "PCHL"),
+            Instruction::Xchg => write!(f, "XCHG"),
+            Instruction::Sphl => write!(f, "SPHL"),
+            Instruction::Di => write!(f, "DI"),
+            Instruction::Ei => write!(f, "EI"),
+            Instruction::Rim => write!(f, "RIM"),
+            Instruction::Sim => write!(f, "SIM"),
+            Instruction::Dsub => write!(f, "DSUB"),
+            Instruction::Arhl => write!(f, "ARHL"),
+            Instruction::Rdel => write!(f, "RDEL"),
+            Instruction::Ldhi(value) => write!(f, "LDHI\t#${:02x}", value),
+            Instruction::Ldsi(value) => write!(f, "LDSI\t#${:02x}", value),
+            Instruction::Rstv => write!(f, "RSTV"),
+            Instruction::Shlx => write!(f, "SHLX"),
+            Instruction::Lhlx => write!(f, "LHLX"),
+            Instruction::Jnk(address) => write!(f, "JNK\t${:04x}", address),
+            Instruction::Jk(address) => write!(f, "JK\t${:04x}", address),
+        }
+    }
+}
+
+mod test {
+    #[allow(unused)] use super::*;
+
+    #[allow(unused)]
+    fn decode_bytes(variant: Variant, bytes: &[u8]) -> Instruction {
+        let mut operands = bytes[1..].iter().copied();
+        Instruction::decode(variant, bytes[0], || operands.next().unwrap_or(0))
+    }
+
+    #[test]
+    fn test_round_trip_documented_8080() {
+        let undocumented = [0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38, 0xcb, 0xd9, 0xdd, 0xed, 0xfd];
+        for opcode in 0..=0xffu8 {
+            if undocumented.contains(&opcode) {
+                continue;
+            }
+            let bytes = [opcode, 0x34, 0x12];
+            let instruction = decode_bytes(Variant::Intel8080, &bytes);
+            let encoded = instruction.encode().unwrap();
+            assert_eq!(&encoded[..], &bytes[..encoded.len()], "{}", instruction);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_8085() {
+        for opcode in 0..=0xffu8 {
+            let bytes = [opcode, 0x34, 0x12];
+            let instruction = decode_bytes(Variant::Intel8085, &bytes);
+            let encoded = instruction.encode().unwrap();
+            assert_eq!(&encoded[..], &bytes[..encoded.len()], "{}", instruction);
+        }
+    }
+
+    #[test]
+    fn test_undocumented_8080_aliases() {
+        assert_eq!(decode_bytes(Variant::Intel8080, &[0x08]), Instruction::Nop);
+        assert_eq!(decode_bytes(Variant::Intel8080, &[0xcb, 0x34, 0x12]), Instruction::Jmp(0x1234));
+        assert_eq!(decode_bytes(Variant::Intel8080, &[0xd9]), Instruction::Ret);
+        assert_eq!(decode_bytes(Variant::Intel8080, &[0xfd, 0x34, 0x12]), Instruction::Call(0x1234));
+        assert_eq!(Instruction::Jmp(0x1234).encode(), Ok(vec![0xc3, 0x34, 0x12]));
+    }
+
+    #[test]
+    fn test_operands() {
+        assert_eq!(decode_bytes(Variant::Intel8080, &[0x46]), Instruction::Mov(Register::B, Register::M));
+        assert_eq!(decode_bytes(Variant::Intel8080, &[0x36, 0x12]), Instruction::Mvi(Register::M, 0x12));
+        assert_eq!(decode_bytes(Variant::Intel8080, &[0xf5]), Instruction::Push(RegisterPair::Psw));
+        assert_eq!(decode_bytes(Variant::Intel8080, &[0x31, 0x00, 0x24]), Instruction::Lxi(RegisterPair::SP, 0x2400));
+        assert_eq!(decode_bytes(Variant::Intel8080, &[0xec, 0x00, 0x10]), Instruction::CallIf(Condition::ParityEven, 0x1000));
+        assert_eq!(decode_bytes(Variant::Intel8080, &[0xff]), Instruction::Rst(7));
+    }
+
+    #[test]
+    fn test_size() {
+        assert_eq!(Instruction::Nop.size(), 1);
+        assert_eq!(Instruction::Mvi(Register::A, 0).size(), 2);
+        assert_eq!(Instruction::JumpIf(Condition::Zero, 0).size(), 3);
+        assert_eq!(Instruction::Ldhi(0).size(), 2);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Instruction::Xthl.to_string(), "XTHL");
+        assert_eq!(Instruction::Rst(0).to_string(), "RST\t0");
+        assert_eq!(Instruction::Lxi(RegisterPair::SP, 0x2400).to_string(), "LXI\tSP,#$2400");
+        assert_eq!(Instruction::Mov(Register::M, Register::A).to_string(), "MOV\tM,A");
+        assert_eq!(Instruction::JumpIf(Condition::NotZero, 0x0abc).to_string(), "JNZ\t$0abc");
+        assert_eq!(Instruction::Push(RegisterPair::Psw).to_string(), "PUSH\tPSW");
+    }
+
+    #[test]
+    fn test_encode_invalid_operands() {
+        assert_eq!(Instruction::Lxi(RegisterPair::Psw, 0).encode(), Err(EncodeError::Pair(RegisterPair::Psw)));
+        assert_eq!(Instruction::Push(RegisterPair::SP).encode(), Err(EncodeError::Pair(RegisterPair::SP)));
+        assert_eq!(Instruction::Stax(RegisterPair::HL).encode(), Err(EncodeError::Pair(RegisterPair::HL)));
+        assert_eq!(Instruction::Mov(Register::M, Register::M).encode(), Err(EncodeError::MovMM));
+        assert_eq!(Instruction::Rst(8).encode(), Err(EncodeError::Rst(8)));
+        assert_eq!(Instruction::Rst(8).size(), 1);
+    }
+
+    #[test]
+    fn test_size_matches_encoding() {
+        for opcode in 0..=0xffu8 {
+            let instruction = decode_bytes(Variant::Intel8085, &[opcode, 0, 0]);
+            assert_eq!(instruction.size(), instruction.encode().unwrap().len(), "{}", instruction);
+        }
+    }
+}
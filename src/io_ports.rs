@@ -0,0 +1,18 @@
+// The 8080 has a separate 256 port I/O address space that is only reached
+// through the IN and OUT instructions. Machines implement this trait to hook
+// their devices up to those ports.
+pub trait IoPorts {
+    fn input(&mut self, port: u8) -> u8;
+    fn output(&mut self, port: u8, value: u8);
+}
+
+// Nothing connected to any port. Reads return 0 and writes are dropped.
+pub struct NoPorts;
+
+impl IoPorts for NoPorts {
+    fn input(&mut self, _port: u8) -> u8 {
+        0
+    }
+
+    fn output(&mut self, _port: u8, _value: u8) {}
+}
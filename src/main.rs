@@ -1,21 +1,47 @@
 use std::io::prelude::*;
 use std::fs::File;
 
+mod bus;
+mod call_stack;
+mod cpm;
+mod debugger;
 mod disassembler;
 mod emulator;
+mod error;
+mod flags;
+mod gdb;
+mod instruction;
+mod io_ports;
+mod movie;
 mod parity;
+mod profiler;
+mod rewind;
+mod savestate;
 mod sign;
 mod space_invaders;
 mod state_8080;
+mod trace;
+mod trace_diff;
+mod watchpoint;
+mod z80;
+mod z80_instruction;
 
-use state_8080::State8080;
+use state_8080::{State8080, Variant};
 
 fn main() {
     let cmd = std::env::args().nth(1).expect("First argument should be either diag or space-invaders");
     let file_name = std::env::args().nth(2).expect("Pass file name as second argument");
     match cmd.as_str() {
-        "diag" => run_diag(&file_name),
+        "diag" => run_diag(&file_name, Variant::Intel8080),
+        "diag-8085" => run_diag(&file_name, Variant::Intel8085),
+        "diag-z80" => run_diag(&file_name, Variant::Z80),
+        "test-roms" => run_test_roms(&file_name),
         "space-invaders" => run_space_invaders(&file_name),
+        // The machine comes first here, then the file
+        "debug" => run_debug(&file_name, &std::env::args().nth(3).expect("Pass file name as third argument")),
+        // Our trace comes first, then the reference and optionally a mapping
+        "trace-diff" => run_trace_diff(&file_name, &std::env::args().nth(3).expect("Pass the reference trace as third argument")),
+        "gdb" => run_gdb(&file_name, &std::env::args().nth(3).expect("Pass file name as third argument")),
         x => {
             eprintln!("Subcommand '{}' not found.", x);
             std::process::exit(1);
@@ -24,48 +50,124 @@ fn main() {
 }
 
 fn run_space_invaders(bin_file_name: &str) {
-    let mut file = File::open(&bin_file_name).expect(&format!("Unable to open file '{}'", bin_file_name));
-    let mut buffer: Vec<u8> = Vec::new();
-    let _ = file.read_to_end(&mut buffer);
-    while buffer.len() < 0x10000 {
-        buffer.push(0);
-    }
-    let mut state = State8080::empty_state();
-    state.memory = buffer;
+    let state = space_invaders_state(read_program(bin_file_name));
 
-    space_invaders::start(state);
+    let movie_mode = match (std::env::args().nth(3).as_deref(), std::env::args().nth(4)) {
+        (None, _) => space_invaders::MovieMode::Off,
+        (Some("--record"), Some(path)) => space_invaders::MovieMode::Record(path.into()),
+        (Some("--play"), Some(path)) => space_invaders::MovieMode::Play(path.into()),
+        _ => {
+            eprintln!("Expected '--record <movie>' or '--play <movie>' after the ROM");
+            std::process::exit(1);
+        }
+    };
+    let save_path = format!("{}.state", bin_file_name);
+    space_invaders::start(state, std::path::Path::new(&save_path), movie_mode);
 }
 
-fn run_diag(bin_file_name: &str) {
-    let mut file = File::open(&bin_file_name).expect(&format!("Unable to open file '{}'", bin_file_name));
-    let mut buffer: Vec<u8> = Vec::new();
+fn run_diag(bin_file_name: &str, variant: Variant) {
+    let mut machine = cpm_machine(&read_program(bin_file_name), variant);
+    let result = machine.run(&mut std::io::stdout());
+    println!();
+    if let Err(err) = result {
+        let _ = emulator::write_error_report(&mut std::io::stderr(), &machine.state, &err);
+        // Exiting skips destructors, and the profiler writes its report in one
+        drop(machine.state.take_profiler());
+        std::process::exit(1);
+    }
+}
 
-    // Load code starting at 0x100
-    while buffer.len() < 0x100 {
-        buffer.push(0);
+fn run_debug(machine_name: &str, bin_file_name: &str) {
+    match machine(machine_name, bin_file_name) {
+        Machine::Cpm(machine) => debugger::run(machine),
+        Machine::SpaceInvaders(state) => space_invaders::debug(state),
     }
-    let _ = file.read_to_end(&mut buffer);
-    // No idea how much memory this expects, hope this is enough
-    while buffer.len() < 0x10000 {
-        buffer.push(0);
+}
+
+// Like run_debug, but serves a GDB client on the port given after the file, or
+// on stdin and stdout without one
+fn run_gdb(machine_name: &str, bin_file_name: &str) {
+    let port = std::env::args().nth(4).map(|port| port.parse::<u16>().expect("Port should be a number"));
+    match machine(machine_name, bin_file_name) {
+        Machine::Cpm(machine) => match port {
+            Some(_) => gdb::run(machine, port),
+            None => gdb::run(cpm::StderrConsole(machine), port),
+        },
+        Machine::SpaceInvaders(state) => space_invaders::gdb(state, port),
     }
+}
+
+fn read_program(file_name: &str) -> Vec<u8> {
+    let mut file = File::open(file_name).unwrap_or_else(|_| panic!("Unable to open file '{}'", file_name));
+    let mut buffer: Vec<u8> = Vec::new();
+    let _ = file.read_to_end(&mut buffer);
+    buffer
+}
+
+// The machines are all traced and profiled the same way
+fn space_invaders_state(rom: Vec<u8>) -> State8080<space_invaders::SpaceInvadersBus, space_invaders::SpaceInvadersIo> {
+    let mut state = State8080::new(
+        space_invaders::SpaceInvadersBus::new(rom),
+        space_invaders::SpaceInvadersIo::new(),
+    );
+    state.set_tracer(tracer());
+    state.set_profiler(profiler::from_env().map(Box::new));
+    state
+}
 
-    let mut state = State8080::empty_state();
-    state.memory = buffer;
+fn cpm_machine(program: &[u8], variant: Variant) -> cpm::CpmMachine {
+    let mut machine = cpm::CpmMachine::new(program);
+    machine.state.set_variant(variant);
+    machine.state.set_tracer(tracer());
+    machine.state.set_profiler(profiler::from_env().map(Box::new));
+    machine
+}
 
-    // Instructions start at 0x100
-    state.set_program_counter(0x100);
+enum Machine {
+    Cpm(cpm::CpmMachine),
+    SpaceInvaders(State8080<space_invaders::SpaceInvadersBus, space_invaders::SpaceInvadersIo>),
+}
 
-    // RET to skip weird call into (seemingly) uninitialized memory?
-    state.memory[0x5] = 0xc9;
+// For the commands that take a machine name followed by a file
+fn machine(name: &str, bin_file_name: &str) -> Machine {
+    let variant = match name {
+        "diag" => Variant::Intel8080,
+        "diag-8085" => Variant::Intel8085,
+        "diag-z80" => Variant::Z80,
+        "space-invaders" => return Machine::SpaceInvaders(space_invaders_state(read_program(bin_file_name))),
+        x => {
+            eprintln!("Machine '{}' not found.", x);
+            std::process::exit(1);
+        }
+    };
+    Machine::Cpm(cpm_machine(&read_program(bin_file_name), variant))
+}
 
-    // fix stack pointer
-    state.memory[368] = 0x7;
+// Tracing is configured through the environment, see trace::from_env
+fn tracer() -> Option<Box<trace::Tracer>> {
+    match trace::from_env() {
+        Ok(tracer) => tracer.map(Box::new),
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    }
+}
 
-    // Skip DAA test
-    state.memory[0x59c] = 0xc3; // JMP
-    state.memory[0x59d] = 0xc2;
-    state.memory[0x59e] = 0x05;
+fn run_trace_diff(trace_file_name: &str, reference_file_name: &str) {
+    let mapping = std::env::args().nth(4);
+    match trace_diff::run(trace_file_name, reference_file_name, mapping.as_deref()) {
+        Ok(true) => {},
+        Ok(false) => std::process::exit(1),
+        Err(message) => {
+            eprintln!("Error: {}", message);
+            std::process::exit(1);
+        }
+    }
+}
 
-    emulator::run(&mut state);
-}
\ No newline at end of file
+fn run_test_roms(directory: &str) {
+    if !cpm::run_test_roms(std::path::Path::new(directory)) {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,207 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::error::EmulatorError;
+use crate::savestate::{SaveStateError, SnapshotReader, SnapshotWriter};
+
+// A movie is a recording of everything that was fed into a machine, so that
+// playing it back reproduces the original run exactly:
+//
+//   "8080MOVI"  magic
+//   u16         format version
+//   u64         hash of the ROM it was recorded against
+//   bytes       save state the recording started from
+//   u8          bytes of port input per frame
+//   bytes       the input for each frame, one after the other
+//
+// Input is only sampled at the start of a frame, which is the only point the
+// frontend changes it anyway, so this is all it takes for playback to be
+// bit-identical.
+const MAGIC: &[u8; 8] = b"8080MOVI";
+pub const VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum MovieError {
+    NotAMovie,
+    UnsupportedVersion(u16),
+    RomMismatch,
+    // Recorded for a machine with a different number of input bytes a frame
+    InputWidth(usize),
+    SaveState(SaveStateError),
+    Emulator(EmulatorError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::NotAMovie => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(version) => write!(f, "unsupported movie version {}", version),
+            MovieError::RomMismatch => write!(f, "movie was recorded with a different ROM"),
+            MovieError::InputWidth(width) => write!(f, "movie has {} bytes of input a frame, which doesn't fit this machine", width),
+            MovieError::SaveState(err) => write!(f, "{}", err),
+            MovieError::Emulator(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<SaveStateError> for MovieError {
+    fn from(err: SaveStateError) -> MovieError {
+        MovieError::SaveState(err)
+    }
+}
+
+impl From<std::io::Error> for MovieError {
+    fn from(err: std::io::Error) -> MovieError {
+        MovieError::SaveState(SaveStateError::Io(err))
+    }
+}
+
+impl From<EmulatorError> for MovieError {
+    fn from(err: EmulatorError) -> MovieError {
+        MovieError::Emulator(err)
+    }
+}
+
+// 64-bit FNV-1a. It's only there to catch a movie being played against the
+// wrong ROM, so it doesn't need to be anything stronger.
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    rom.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_hash: u64,
+    pub start_state: Vec<u8>,
+    input_width: usize,
+    inputs: Vec<u8>,
+}
+
+impl Movie {
+    // `start_state` is a save state of the machine taken just before the
+    // first recorded frame
+    pub fn new(rom: &[u8], start_state: Vec<u8>, input_width: usize) -> Movie {
+        Movie {
+            rom_hash: rom_hash(rom),
+            start_state,
+            input_width,
+            inputs: Vec::new(),
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        self.inputs.len().checked_div(self.input_width).unwrap_or(0)
+    }
+
+    pub fn input(&self, frame: usize) -> Option<&[u8]> {
+        let start = frame * self.input_width;
+        self.inputs.get(start..start + self.input_width)
+    }
+
+    pub fn record(&mut self, input: &[u8]) {
+        assert_eq!(input.len(), self.input_width, "wrong amount of input for this movie");
+        self.inputs.extend_from_slice(input);
+    }
+
+    // Drops everything after the first `frames` frames, for when the
+    // recording is rewound
+    pub fn truncate(&mut self, frames: usize) {
+        self.inputs.truncate(frames * self.input_width);
+    }
+
+    pub fn check_rom(&self, rom: &[u8]) -> Result<(), MovieError> {
+        if rom_hash(rom) == self.rom_hash { Ok(()) } else { Err(MovieError::RomMismatch) }
+    }
+
+    pub fn check_input_width(&self, width: usize) -> Result<(), MovieError> {
+        if self.input_width == width { Ok(()) } else { Err(MovieError::InputWidth(self.input_width)) }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = SnapshotWriter::default();
+        out.raw(MAGIC);
+        out.u16(VERSION);
+        out.u64(self.rom_hash);
+        out.bytes(&self.start_state);
+        out.u8(self.input_width as u8);
+        out.bytes(&self.inputs);
+        out.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Movie, MovieError> {
+        let mut input = SnapshotReader::new(bytes);
+        if input.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(MovieError::NotAMovie);
+        }
+        let version = input.u16()?;
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let rom_hash = input.u64()?;
+        let start_state = input.bytes()?.to_vec();
+        let input_width = input.u8()? as usize;
+        let inputs = input.bytes()?.to_vec();
+        if (input_width == 0 && !inputs.is_empty()) || (input_width != 0 && inputs.len() % input_width != 0) {
+            return Err(SaveStateError::Truncated.into());
+        }
+        Ok(Movie { rom_hash, start_state, input_width, inputs })
+    }
+
+    pub fn save_file(&self, path: &Path) -> Result<(), MovieError> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn load_file(path: &Path) -> Result<Movie, MovieError> {
+        Movie::from_bytes(&fs::read(path)?)
+    }
+}
+
+mod test {
+    #[allow(unused)] use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut movie = Movie::new(&[1, 2, 3], vec![9, 9], 2);
+        movie.record(&[0x08, 0x00]);
+        movie.record(&[0x09, 0x00]);
+        let restored = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert_eq!(restored, movie);
+        assert_eq!(restored.frames(), 2);
+        assert_eq!(restored.input(1), Some(&[0x09, 0x00][..]));
+        assert_eq!(restored.input(2), None);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut movie = Movie::new(&[], vec![], 1);
+        for frame in 0..10 {
+            movie.record(&[frame]);
+        }
+        movie.truncate(4);
+        assert_eq!(movie.frames(), 4);
+        assert_eq!(movie.input(3), Some(&[3][..]));
+    }
+
+    #[test]
+    fn test_check_rom() {
+        let movie = Movie::new(&[1, 2, 3], vec![], 1);
+        assert!(movie.check_rom(&[1, 2, 3]).is_ok());
+        match movie.check_rom(&[1, 2, 4]) {
+            Err(MovieError::RomMismatch) => {},
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_not_a_movie() {
+        match Movie::from_bytes(b"8080SNAP") {
+            Err(MovieError::NotAMovie) => {},
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+}
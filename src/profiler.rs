@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::bus::Bus;
+use crate::call_stack::Frame;
+use crate::disassembler::{self, MAX_INSTRUCTION_SIZE};
+use crate::error::EmulatorError;
+use crate::io_ports::IoPorts;
+use crate::state_8080::{State8080, Variant};
+
+// Counts instructions and cycles per address, and per subroutine, for as long
+// as it's attached to the CPU. Like the tracer it's set up once at startup
+// (see `from_env`) and sees every instruction `emulate_8080_op` runs. The
+// report goes out when the profiler is dropped, which is normally when the
+// machine is.
+//
+// Calls and returns come from the CPU's own call stack (see `call_stack`),
+// which sees CALL, Ccc, RST and interrupts going in and returns coming out.
+// After each instruction the calls open here are brought into line with it:
+// any it no longer has are closed and any new ones opened. A call unwound
+// some other way stays open until the CPU's next call or return drops it.
+
+// What an address looked like the first time it ran, for the report
+#[derive(Clone, Copy, Default)]
+struct Hits {
+    instructions: u64,
+    cycles: u64,
+    bytes: [u8; MAX_INSTRUCTION_SIZE],
+}
+
+#[derive(Clone, Copy, Default)]
+struct Subroutine {
+    calls: u64,
+    // Including everything it called. Recursive calls are only counted once.
+    inclusive: u64,
+    exclusive: u64,
+}
+
+struct OpenCall {
+    frame: Frame,
+    // Total cycles when it was called
+    entered: u64,
+}
+
+pub struct Profiler {
+    output: Option<PathBuf>,
+    variant: Variant,
+    hits: Vec<Hits>,
+    subroutines: HashMap<u16, Subroutine>,
+    calls: Vec<OpenCall>,
+    // Cycles run outside of any call we saw being made
+    top_level: u64,
+    halted: u64,
+    cycles: u64,
+}
+
+impl Profiler {
+    // Writes the report to `output` when dropped, if there is one
+    pub fn new(output: Option<PathBuf>) -> Profiler {
+        Profiler {
+            output,
+            variant: Variant::Intel8080,
+            hits: vec![Hits::default(); 0x10000],
+            subroutines: HashMap::new(),
+            calls: Vec::new(),
+            top_level: 0,
+            halted: 0,
+            cycles: 0,
+        }
+    }
+
+    // Runs one instruction through `execute` and counts it. An instruction
+    // taken from the data bus for an interrupt counts against the address it
+    // interrupted.
+    pub fn profile<M: Bus, P: IoPorts, F>(&mut self, state: &mut State8080<M, P>, execute: F) -> Result<u32, EmulatorError>
+    where
+        F: FnOnce(&mut State8080<M, P>) -> Result<u32, EmulatorError>,
+    {
+        let pc = state.program_counter();
+        let halted = state.halted();
+        if self.hits[pc as usize].instructions == 0 {
+            self.first_hit(state, pc);
+        }
+        let cycles = execute(state)? as u64;
+        self.cycles += cycles;
+        if halted && state.halted() {
+            self.halted += cycles;
+            return Ok(cycles as u32);
+        }
+
+        let hits = &mut self.hits[pc as usize];
+        hits.instructions += 1;
+        hits.cycles += cycles;
+        match self.calls.last() {
+            Some(call) => self.subroutines.get_mut(&call.frame.subroutine).unwrap().exclusive += cycles,
+            None => self.top_level += cycles,
+        }
+        self.follow(state.call_stack().open_frames());
+        Ok(cycles as u32)
+    }
+
+    fn first_hit<M: Bus, P: IoPorts>(&mut self, state: &State8080<M, P>, pc: u16) {
+        self.variant = state.variant();
+        for (offset, byte) in self.hits[pc as usize].bytes.iter_mut().enumerate() {
+            *byte = state.memory.read(pc.wrapping_add(offset as u16)).unwrap_or(0xff);
+        }
+    }
+
+    // One instruction can return from any number of calls, or drop them, and
+    // make at most one new one. The CPU also forgets its outermost calls past
+    // a certain depth, and those just stay open here.
+    fn follow(&mut self, frames: &[Frame]) {
+        if self.calls.last().map(|call| &call.frame) == frames.last() {
+            return;
+        }
+        while let Some(call) = self.calls.last() {
+            if frames.iter().rev().any(|frame| *frame == call.frame) {
+                break;
+            }
+            self.leave();
+        }
+        let new = self
+            .calls
+            .last()
+            .and_then(|call| frames.iter().rposition(|frame| *frame == call.frame))
+            .map_or(0, |outer| outer + 1);
+        for frame in &frames[new..] {
+            self.subroutines.entry(frame.subroutine).or_default().calls += 1;
+            self.calls.push(OpenCall { frame: *frame, entered: self.cycles });
+        }
+    }
+
+    fn leave(&mut self) {
+        if let Some(call) = self.calls.pop() {
+            let subroutine = call.frame.subroutine;
+            if !self.calls.iter().any(|outer| outer.frame.subroutine == subroutine) {
+                self.subroutines.get_mut(&subroutine).unwrap().inclusive += self.cycles - call.entered;
+            }
+        }
+    }
+
+    // Calls that are still open when the report is written count up to now
+    fn subroutines(&self) -> Vec<(u16, Subroutine)> {
+        let mut subroutines = self.subroutines.clone();
+        for (depth, call) in self.calls.iter().enumerate() {
+            let subroutine = call.frame.subroutine;
+            if !self.calls[..depth].iter().any(|outer| outer.frame.subroutine == subroutine) {
+                subroutines.get_mut(&subroutine).unwrap().inclusive += self.cycles - call.entered;
+            }
+        }
+        let mut subroutines: Vec<(u16, Subroutine)> = subroutines.into_iter().collect();
+        subroutines.sort_by(|(a, a_stats), (b, b_stats)| b_stats.inclusive.cmp(&a_stats.inclusive).then(a.cmp(b)));
+        subroutines
+    }
+
+    pub fn write_report<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let instructions: u64 = self.hits.iter().map(|hits| hits.instructions).sum();
+        let percent = |cycles: u64| if self.cycles == 0 { 0.0 } else { cycles as f64 * 100.0 / self.cycles as f64 };
+        writeln!(out, "{} instructions, {} cycles ({} halted)", instructions, self.cycles, self.halted)?;
+
+        writeln!(out)?;
+        writeln!(out, "Hotspots")?;
+        writeln!(out, "address  instructions        cycles        %  instruction")?;
+        let mut addresses: Vec<usize> = (0..self.hits.len()).filter(|&address| self.hits[address].instructions > 0).collect();
+        addresses.sort_by(|&a, &b| self.hits[b].cycles.cmp(&self.hits[a].cycles).then(a.cmp(&b)));
+        for address in addresses {
+            let hits = &self.hits[address];
+            let (instruction, _) = disassembler::disassemble(self.variant, address as u16, |offset| hits.bytes.get(offset).copied().unwrap_or(0));
+            writeln!(
+                out,
+                "   {:04x}  {:>12}  {:>12}  {:>6.2}%  {}",
+                address, hits.instructions, hits.cycles, percent(hits.cycles), instruction.replace('\t', " "),
+            )?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "Subroutines")?;
+        writeln!(out, "address         calls     inclusive        %     exclusive        %")?;
+        for (address, subroutine) in self.subroutines() {
+            writeln!(
+                out,
+                "   {:04x}  {:>12}  {:>12}  {:>6.2}%  {:>12}  {:>6.2}%",
+                address, subroutine.calls,
+                subroutine.inclusive, percent(subroutine.inclusive),
+                subroutine.exclusive, percent(subroutine.exclusive),
+            )?;
+        }
+        writeln!(out, "    top level                              {:>12}  {:>6.2}%", self.top_level, percent(self.top_level))
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        if let Some(path) = &self.output {
+            let result = File::create(path).and_then(|file| {
+                let mut out = BufWriter::new(file);
+                self.write_report(&mut out)?;
+                out.flush()
+            });
+            if let Err(err) = result {
+                eprintln!("Unable to write profile to '{}': {}", path.display(), err);
+            }
+        }
+    }
+}
+
+// PROFILE=<file> turns the profiler on, with the report going to that file
+pub fn from_env() -> Option<Profiler> {
+    std::env::var_os("PROFILE").map(|path| Profiler::new(Some(path.into())))
+}
+
+mod test {
+    #[allow(unused)] use super::*;
+    #[allow(unused)] use crate::emulator;
+
+    #[allow(unused)]
+    fn profiled(program: &[u8]) -> Profiler {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0; 0x20];
+        state.memory[..program.len()].copy_from_slice(program);
+        state.sp = 0x20;
+        state.set_profiler(Some(Box::new(Profiler::new(None))));
+        emulator::run(&mut state).unwrap();
+        // A couple of halted cycles, which shouldn't count against the HLT
+        emulator::emulate_8080_op(&mut state).unwrap();
+        *state.take_profiler().unwrap()
+    }
+
+    #[allow(unused)]
+    const PROGRAM: [u8; 13] = [
+        0xcd, 0x07, 0x00, // CALL 0007
+        0xcd, 0x07, 0x00, // CALL 0007
+        0x76,             // HLT
+        0x3c,             // 0007: INR A
+        0xcd, 0x0c, 0x00, // CALL 000c
+        0xc9,             // RET
+        0xc9,             // 000c: RET
+    ];
+
+    #[test]
+    fn test_hits() {
+        let profiler = profiled(&PROGRAM);
+        assert_eq!(profiler.hits[0x0007].instructions, 2);
+        assert_eq!(profiler.hits[0x0007].cycles, 10);
+        assert_eq!(profiler.hits[0x0006].instructions, 1);
+        assert_eq!(profiler.cycles, 125 + 4);
+        assert_eq!(profiler.halted, 4);
+    }
+
+    #[test]
+    fn test_subroutines() {
+        let profiler = profiled(&PROGRAM);
+        let subroutines = profiler.subroutines();
+        let summary: Vec<(u16, u64, u64, u64)> = subroutines
+            .iter()
+            .map(|(address, subroutine)| (*address, subroutine.calls, subroutine.inclusive, subroutine.exclusive))
+            .collect();
+        assert_eq!(summary, vec![(0x0007, 2, 84, 64), (0x000c, 2, 20, 20)]);
+        assert_eq!(profiler.top_level, 41);
+        assert!(profiler.calls.is_empty());
+    }
+
+    #[test]
+    fn test_recursion_and_open_calls() {
+        // 0000: CALL 0004, then 0004 calls itself forever
+        let mut state = State8080::empty_state();
+        state.memory = vec![0xcd, 0x04, 0x00, 0x00, 0xcd, 0x04, 0x00];
+        state.memory.resize(0x20, 0);
+        state.sp = 0x20;
+        state.set_profiler(Some(Box::new(Profiler::new(None))));
+        for _ in 0..4 {
+            emulator::emulate_8080_op(&mut state).unwrap();
+        }
+        let profiler = state.take_profiler().unwrap();
+        let subroutines = profiler.subroutines();
+        assert_eq!(subroutines.len(), 1);
+        assert_eq!(subroutines[0].1.calls, 4);
+        assert_eq!(subroutines[0].1.inclusive, 17 * 3);
+    }
+
+    #[test]
+    fn test_interrupts_count_as_calls() {
+        // Interrupted during the NOPs, RST 1 handler at 0008 returns
+        let mut state = State8080::empty_state();
+        state.memory = vec![0xfb, 0x00, 0x00, 0x76, 0, 0, 0, 0, 0xc9];
+        state.memory.resize(0x20, 0);
+        state.sp = 0x20;
+        state.set_profiler(Some(Box::new(Profiler::new(None))));
+        emulator::emulate_8080_op(&mut state).unwrap(); // EI
+        emulator::emulate_8080_op(&mut state).unwrap(); // NOP
+        state.request_interrupt(0xcf); // RST 1
+        emulator::run(&mut state).unwrap();
+        let profiler = state.take_profiler().unwrap();
+        assert_eq!(profiler.subroutines()[0].0, 0x0008);
+        assert_eq!(profiler.subroutines()[0].1.calls, 1);
+        assert!(profiler.calls.is_empty());
+    }
+
+    #[test]
+    fn test_stack_tricks() {
+        let profiler = profiled(&[
+            0xcd, 0x08, 0x00, // CALL 0008
+            0xcd, 0x0a, 0x00, // CALL 000a
+            0x76,             // HLT
+            0x00,
+            0xe1,             // 0008: POP H
+            0xe9,             // PCHL
+            0x21, 0x0f, 0x00, // 000a: LXI H,000f
+            0xe5,             // PUSH H
+            0xc9,             // RET, which is a jump to 000f
+            0xc9,             // 000f: RET
+        ]);
+        let summary: Vec<(u16, u64, u64, u64)> = profiler
+            .subroutines()
+            .iter()
+            .map(|(address, subroutine)| (*address, subroutine.calls, subroutine.inclusive, subroutine.exclusive))
+            .collect();
+        // 0008 stays open through the CALL that drops it
+        assert_eq!(summary, vec![(0x000a, 1, 41, 41), (0x0008, 1, 32, 32)]);
+        assert_eq!(profiler.top_level, 24);
+        assert!(profiler.calls.is_empty());
+    }
+
+    #[test]
+    fn test_report() {
+        let profiler = profiled(&PROGRAM);
+        let mut report = Vec::new();
+        profiler.write_report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("11 instructions, 129 cycles (4 halted)\n"));
+        assert!(report.contains("   0000             1            17   13.18%  CALL $0007\n"));
+        assert!(report.contains("   0007             2            84   65.12%            64   49.61%\n"));
+        assert!(report.ends_with("    top level                                        41   31.78%\n"));
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::VecDeque;
+
+use crate::savestate::{self, SaveStateError, Snapshot};
+
+// Only used to tag the snapshots kept in memory here
+const SNAPSHOT_NAME: &str = "rewind";
+
+// Recent history of a machine, one snapshot per recorded frame. Snapshots are
+// grouped behind a full keyframe, with every other frame in the group stored
+// as the byte ranges that differ from it. Most of a frame's state doesn't
+// change from one frame to the next, so the deltas stay small. The oldest
+// group is dropped as a whole once the buffer is full, so no delta outlives
+// its keyframe.
+pub struct RewindBuffer {
+    capacity: usize,
+    keyframe_interval: usize,
+    groups: VecDeque<Group>,
+    frames: usize,
+}
+
+struct Group {
+    keyframe: Vec<u8>,
+    deltas: Vec<Delta>,
+}
+
+struct Delta {
+    length: usize,
+    runs: Vec<(usize, Vec<u8>)>,
+}
+
+impl Delta {
+    fn new(keyframe: &[u8], snapshot: &[u8]) -> Delta {
+        let mut runs: Vec<(usize, Vec<u8>)> = Vec::new();
+        for (offset, &byte) in snapshot.iter().enumerate() {
+            if keyframe.get(offset) == Some(&byte) {
+                continue;
+            }
+            match runs.last_mut() {
+                Some((start, bytes)) if *start + bytes.len() == offset => bytes.push(byte),
+                _ => runs.push((offset, vec![byte])),
+            }
+        }
+        Delta { length: snapshot.len(), runs }
+    }
+
+    fn apply(&self, keyframe: &[u8]) -> Vec<u8> {
+        let mut snapshot = keyframe.to_vec();
+        snapshot.resize(self.length, 0);
+        for (offset, bytes) in &self.runs {
+            snapshot[*offset..*offset + bytes.len()].copy_from_slice(bytes);
+        }
+        snapshot
+    }
+}
+
+impl Group {
+    fn len(&self) -> usize {
+        1 + self.deltas.len()
+    }
+
+    fn newest(&self) -> Vec<u8> {
+        match self.deltas.last() {
+            Some(delta) => delta.apply(&self.keyframe),
+            None => self.keyframe.clone(),
+        }
+    }
+}
+
+impl RewindBuffer {
+    // Holds at least `capacity` frames, taking a keyframe every
+    // `keyframe_interval` of them
+    pub fn new(capacity: usize, keyframe_interval: usize) -> RewindBuffer {
+        assert!(capacity > 0 && keyframe_interval > 0, "rewind buffer needs room for at least one frame");
+        RewindBuffer {
+            capacity,
+            keyframe_interval,
+            groups: VecDeque::new(),
+            frames: 0,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.frames
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.frames == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.groups.clear();
+        self.frames = 0;
+    }
+
+    // Call once per frame, after the frame has run
+    pub fn record<S: Snapshot>(&mut self, machine: &S) {
+        let snapshot = savestate::save(machine, SNAPSHOT_NAME);
+        match self.groups.back_mut() {
+            Some(group) if group.len() < self.keyframe_interval => {
+                let delta = Delta::new(&group.keyframe, &snapshot);
+                group.deltas.push(delta);
+            },
+            _ => self.groups.push_back(Group { keyframe: snapshot, deltas: Vec::new() }),
+        }
+        self.frames += 1;
+        while self.frames - self.groups.front().map_or(0, Group::len) >= self.capacity {
+            let oldest = self.groups.pop_front().unwrap();
+            self.frames -= oldest.len();
+        }
+    }
+
+    // Puts the machine back the way it was `frames` recorded frames ago and
+    // forgets everything after that. The newest recorded frame is the
+    // machine's current state, so rewinding goes back from there and at most
+    // `len() - 1` frames. Returns how many frames it actually went back.
+    pub fn rewind<S: Snapshot>(&mut self, machine: &mut S, frames: usize) -> Result<usize, SaveStateError> {
+        let frames = frames.min(self.frames.saturating_sub(1));
+        if frames == 0 {
+            return Ok(0);
+        }
+        for _ in 0..frames {
+            let group = self.groups.back_mut().unwrap();
+            if group.deltas.pop().is_none() {
+                self.groups.pop_back();
+            }
+            self.frames -= 1;
+        }
+        let snapshot = self.groups.back().unwrap().newest();
+        savestate::load(machine, SNAPSHOT_NAME, &snapshot)?;
+        Ok(frames)
+    }
+}
+
+mod test {
+    #[allow(unused)] use super::*;
+
+    #[test]
+    fn test_rewind() {
+        let mut buffer = RewindBuffer::new(100, 4);
+        let mut memory = vec![0u8; 16];
+        for frame in 0..10 {
+            memory[frame] = frame as u8 + 1;
+            buffer.record(&memory);
+        }
+        assert_eq!(buffer.len(), 10);
+        assert_eq!(buffer.rewind(&mut memory, 3).unwrap(), 3);
+        assert_eq!(&memory[..8], &[1, 2, 3, 4, 5, 6, 7, 0]);
+        assert_eq!(buffer.len(), 7);
+        assert_eq!(buffer.rewind(&mut memory, 1).unwrap(), 1);
+        assert_eq!(&memory[..7], &[1, 2, 3, 4, 5, 6, 0]);
+    }
+
+    #[test]
+    fn test_rewind_past_the_start() {
+        let mut buffer = RewindBuffer::new(100, 4);
+        let mut memory = vec![0u8; 4];
+        for frame in 0..3 {
+            memory[frame] = 0xff;
+            buffer.record(&memory);
+        }
+        assert_eq!(buffer.rewind(&mut memory, 10).unwrap(), 2);
+        assert_eq!(memory, vec![0xff, 0, 0, 0]);
+        assert_eq!(buffer.rewind(&mut memory, 1).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_drops_oldest_group() {
+        let mut buffer = RewindBuffer::new(8, 4);
+        let mut memory = vec![0u8; 1];
+        for frame in 0..20 {
+            memory[0] = frame;
+            buffer.record(&memory);
+        }
+        assert!(buffer.len() >= 8);
+        assert!(buffer.len() < 12);
+        let frames = buffer.rewind(&mut memory, 100).unwrap();
+        assert_eq!(memory[0] as usize, 19 - frames);
+    }
+
+    #[test]
+    fn test_delta() {
+        let keyframe = vec![1, 2, 3, 4, 5];
+        let snapshot = vec![1, 9, 9, 4, 5, 6];
+        let delta = Delta::new(&keyframe, &snapshot);
+        assert_eq!(delta.runs, vec![(1, vec![9, 9]), (5, vec![6])]);
+        assert_eq!(delta.apply(&keyframe), snapshot);
+        assert_eq!(Delta::new(&snapshot, &keyframe).apply(&snapshot), keyframe);
+    }
+}
@@ -0,0 +1,283 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::io_ports::NoPorts;
+
+// Save states are a small header followed by whatever the machine writes out
+// through `Snapshot`:
+//
+//   "8080SNAP"  magic
+//   u16         format version
+//   u8 + bytes  machine name, so a Space Invaders state can't be loaded into
+//               the CP/M machine or the other way round
+//   ...         machine data
+//
+// Everything is little endian. The machine data has no field tags, so any
+// change to what a machine saves or the order it saves it in has to bump
+// `VERSION`.
+const MAGIC: &[u8; 8] = b"8080SNAP";
+pub const VERSION: u16 = 2;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    Io(io::Error),
+    NotASaveState,
+    UnsupportedVersion(u16),
+    WrongMachine(String),
+    Truncated,
+    Invalid(&'static str),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveStateError::Io(err) => write!(f, "{}", err),
+            SaveStateError::NotASaveState => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(version) => write!(f, "unsupported save state version {}", version),
+            SaveStateError::WrongMachine(name) => write!(f, "save state is for a different machine ({})", name),
+            SaveStateError::Truncated => write!(f, "save state is truncated"),
+            SaveStateError::Invalid(what) => write!(f, "invalid {} in save state", what),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+impl From<io::Error> for SaveStateError {
+    fn from(err: io::Error) -> SaveStateError {
+        SaveStateError::Io(err)
+    }
+}
+
+// Anything that makes up part of a machine: the CPU, its memory and its
+// devices. `load` reads back exactly what `save` wrote, in the same order.
+pub trait Snapshot {
+    fn save(&self, out: &mut SnapshotWriter);
+    fn load(&mut self, input: &mut SnapshotReader) -> Result<(), SaveStateError>;
+}
+
+#[derive(Default)]
+pub struct SnapshotWriter {
+    bytes: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // No length prefix, for magic numbers and the like
+    pub fn raw(&mut self, value: &[u8]) {
+        self.bytes.extend_from_slice(value);
+    }
+
+    // Length prefixed
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.bytes.extend_from_slice(value);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub struct SnapshotReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> SnapshotReader<'a> {
+        SnapshotReader { bytes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn take(&mut self, count: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.bytes.len() < count {
+            return Err(SaveStateError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, SaveStateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SaveStateError::Invalid("boolean")),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, SaveStateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SaveStateError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SaveStateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], SaveStateError> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+
+    // For fixed size memories, where a different size means the state came
+    // from some other setup
+    pub fn bytes_into(&mut self, destination: &mut [u8]) -> Result<(), SaveStateError> {
+        let bytes = self.bytes()?;
+        if bytes.len() != destination.len() {
+            return Err(SaveStateError::Invalid("memory size"));
+        }
+        destination.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+pub fn save<S: Snapshot>(machine: &S, name: &str) -> Vec<u8> {
+    let mut out = SnapshotWriter::default();
+    out.raw(MAGIC);
+    out.u16(VERSION);
+    out.u8(name.len() as u8);
+    out.raw(name.as_bytes());
+    machine.save(&mut out);
+    out.into_bytes()
+}
+
+// On an error the machine may have been partly overwritten, so callers that
+// need to carry on should load into a fresh machine and swap it in.
+pub fn load<S: Snapshot>(machine: &mut S, name: &str, bytes: &[u8]) -> Result<(), SaveStateError> {
+    let mut input = SnapshotReader::new(bytes);
+    if input.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(SaveStateError::NotASaveState);
+    }
+    let version = input.u16()?;
+    if version != VERSION {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+    let name_length = input.u8()? as usize;
+    let saved_name = String::from_utf8_lossy(input.take(name_length)?).into_owned();
+    if saved_name != name {
+        return Err(SaveStateError::WrongMachine(saved_name));
+    }
+    machine.load(&mut input)?;
+    if !input.is_empty() {
+        return Err(SaveStateError::Invalid("trailing data"));
+    }
+    Ok(())
+}
+
+pub fn save_file<S: Snapshot>(machine: &S, name: &str, path: &Path) -> Result<(), SaveStateError> {
+    fs::write(path, save(machine, name))?;
+    Ok(())
+}
+
+pub fn load_file<S: Snapshot>(machine: &mut S, name: &str, path: &Path) -> Result<(), SaveStateError> {
+    load(machine, name, &fs::read(path)?)
+}
+
+// Plain RAM, as used by the CP/M machine and the tests
+impl Snapshot for Vec<u8> {
+    fn save(&self, out: &mut SnapshotWriter) {
+        out.bytes(self);
+    }
+
+    fn load(&mut self, input: &mut SnapshotReader) -> Result<(), SaveStateError> {
+        input.bytes_into(self)
+    }
+}
+
+impl Snapshot for NoPorts {
+    fn save(&self, _out: &mut SnapshotWriter) {}
+
+    fn load(&mut self, _input: &mut SnapshotReader) -> Result<(), SaveStateError> {
+        Ok(())
+    }
+}
+
+mod test {
+    #[allow(unused)] use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let memory = vec![1, 2, 3, 4];
+        let bytes = save(&memory, "test");
+        let mut restored = vec![0; 4];
+        load(&mut restored, "test", &bytes).unwrap();
+        assert_eq!(restored, memory);
+    }
+
+    #[test]
+    fn test_rejects_other_machines() {
+        let bytes = save(&vec![0u8; 4], "test");
+        match load(&mut vec![0u8; 4], "other", &bytes) {
+            Err(SaveStateError::WrongMachine(name)) => assert_eq!(name, "test"),
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_rejects_other_versions() {
+        let mut bytes = save(&vec![0u8; 4], "test");
+        bytes[8] = 0xff;
+        match load(&mut vec![0u8; 4], "test", &bytes) {
+            Err(SaveStateError::UnsupportedVersion(_)) => {},
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_truncated() {
+        let bytes = save(&vec![0u8; 4], "test");
+        match load(&mut vec![0u8; 4], "test", &bytes[..bytes.len() - 1]) {
+            Err(SaveStateError::Truncated) => {},
+            result => panic!("unexpected {:?}", result),
+        }
+        match load(&mut vec![0u8; 4], "test", b"garbage") {
+            Err(SaveStateError::NotASaveState) => {},
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_memory_size_mismatch() {
+        let bytes = save(&vec![0u8; 4], "test");
+        match load(&mut vec![0u8; 8], "test", &bytes) {
+            Err(SaveStateError::Invalid(_)) => {},
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+}
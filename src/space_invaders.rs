@@ -1,7 +1,16 @@
+use super::bus::{Bus, UnmappedAddress};
+use super::debugger::{self, Debuggable};
+use super::gdb;
+use super::io_ports::IoPorts;
+use super::movie::{Movie, MovieError};
 use super::state_8080::State8080;
 use super::emulator;
+use super::error::EmulatorError;
+use super::rewind::RewindBuffer;
+use super::savestate::{self, SaveStateError, Snapshot, SnapshotReader, SnapshotWriter};
 
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
@@ -11,15 +20,77 @@ use sdl2::render::Canvas;
 // Display is 60Hz, clock is 2MHz
 const CYCLES_PER_FRAME: u32 = 2_000_000 / 60;
 
-struct SpaceInvadersMachine {
-    state: State8080,
+// The video hardware puts an RST on the data bus twice a frame: RST 1 when
+// the beam reaches the middle of the screen and RST 2 at the start of vblank
+const RST_1: u8 = 0xcf;
+const RST_2: u8 = 0xd7;
+
+// Written into save states so they only load into this machine
+const SAVE_STATE_NAME: &str = "space-invaders";
+// Input ports 1 and 2 are recorded for each frame of a movie
+const MOVIE_INPUT_WIDTH: usize = 2;
+
+// Ten seconds of rewind, with a keyframe every second
+const REWIND_FRAMES: usize = 60 * 10;
+const REWIND_KEYFRAME_INTERVAL: usize = 60;
 
-    last_timer: Instant,
-    next_interrupt: f64,
-    which_interrupt: i32,
+// The cabinet only decodes the low 14 address lines. The bottom 8K is the
+// program ROM and the top 8K is RAM (1K of work RAM followed by 7K of video
+// RAM), so everything from 0x4000 up is a mirror of the first 16K.
+pub struct SpaceInvadersBus {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
 
-    // emulator_timer: ???,
+impl SpaceInvadersBus {
+    pub fn new(mut rom: Vec<u8>) -> SpaceInvadersBus {
+        rom.resize(0x2000, 0);
+        SpaceInvadersBus {
+            rom,
+            ram: vec![0; 0x2000],
+        }
+    }
+
+    fn vram(&self) -> &[u8] {
+        &self.ram[0x0400..]
+    }
+}
+
+impl Bus for SpaceInvadersBus {
+    fn read(&self, address: u16) -> Result<u8, UnmappedAddress> {
+        let address = (address & 0x3fff) as usize;
+        if address < 0x2000 {
+            Ok(self.rom[address])
+        } else {
+            Ok(self.ram[address - 0x2000])
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Result<(), UnmappedAddress> {
+        let address = (address & 0x3fff) as usize;
+        // Writes to ROM are ignored
+        if address >= 0x2000 {
+            self.ram[address - 0x2000] = value;
+        }
+        Ok(())
+    }
+}
+
+// Only RAM is saved. The ROM comes from whatever file the machine was
+// started with.
+impl Snapshot for SpaceInvadersBus {
+    fn save(&self, out: &mut SnapshotWriter) {
+        out.bytes(&self.ram);
+    }
+
+    fn load(&mut self, input: &mut SnapshotReader) -> Result<(), SaveStateError> {
+        input.bytes_into(&mut self.ram)
+    }
+}
 
+// Everything hanging off the I/O ports: the inputs, the hardware shift
+// register used to draw sprites, and the sound latches.
+pub struct SpaceInvadersIo {
     shift_low: u8,
     shift_high: u8,
     shift_offset: u8,
@@ -28,17 +99,11 @@ struct SpaceInvadersMachine {
     in_port2: u8,
     out_port3: u8,
     out_port5: u8,
-    paused: bool,
 }
 
-impl SpaceInvadersMachine {
-    fn new(state: State8080) -> SpaceInvadersMachine {
-        SpaceInvadersMachine {
-            state: state,
-            last_timer: Instant::now(), // Should this be an Option<Instant>?
-            next_interrupt: 0.0,
-            which_interrupt: 1,
-            // timer?
+impl SpaceInvadersIo {
+    pub fn new() -> SpaceInvadersIo {
+        SpaceInvadersIo {
             shift_low: 0,
             shift_high: 0,
             shift_offset: 0,
@@ -46,13 +111,290 @@ impl SpaceInvadersMachine {
             in_port2: 0,
             out_port3: 0,
             out_port5: 0,
+        }
+    }
+}
+
+impl IoPorts for SpaceInvadersIo {
+    fn input(&mut self, port: u8) -> u8 {
+        match port {
+            0 => { 0xf },
+            1 => self.in_port1,
+            2 => { 0 }, // Player 2 controls and some other random stuff
+            3 => {
+                let value: u16 = ((self.shift_high as u16) << 8) | self.shift_low as u16;
+                let masked_value: u8 = ((value >> (8 - self.shift_offset)) & 0xff) as u8;
+                masked_value
+            },
+            _ => { 0 },
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            2 => {
+                self.shift_offset = value & 0x7;
+            },
+            3 => {
+                self.out_port3 = value;
+            }
+            4 => {
+                self.shift_low = self.shift_high;
+                self.shift_high = value;
+            }
+            5 => {
+                self.out_port5 = value;
+            }
+            _ => {},
+        }
+    }
+}
+
+impl Snapshot for SpaceInvadersIo {
+    fn save(&self, out: &mut SnapshotWriter) {
+        for &value in &[self.shift_low, self.shift_high, self.shift_offset, self.in_port1, self.in_port2, self.out_port3, self.out_port5] {
+            out.u8(value);
+        }
+    }
+
+    fn load(&mut self, input: &mut SnapshotReader) -> Result<(), SaveStateError> {
+        self.shift_low = input.u8()?;
+        self.shift_high = input.u8()?;
+        self.shift_offset = input.u8()?;
+        self.in_port1 = input.u8()?;
+        self.in_port2 = input.u8()?;
+        self.out_port3 = input.u8()?;
+        self.out_port5 = input.u8()?;
+        Ok(())
+    }
+}
+
+// What to do with a movie file when the game starts
+pub enum MovieMode {
+    Off,
+    Record(PathBuf),
+    Play(PathBuf),
+}
+
+// Where each frame's input comes from. Recording and playback both work on
+// the input ports as they are at the start of a frame.
+enum InputSource {
+    Keyboard,
+    Recording(Movie),
+    // The movie and the next frame to play from it
+    Playing(Movie, usize),
+}
+
+struct SpaceInvadersMachine {
+    state: State8080<SpaceInvadersBus, SpaceInvadersIo>,
+
+    paused: bool,
+    history: RewindBuffer,
+    input: InputSource,
+    // How far into the current frame the CPU is
+    frame_cycles: u32,
+    raised_mid_frame: bool,
+}
+
+impl SpaceInvadersMachine {
+    fn new(state: State8080<SpaceInvadersBus, SpaceInvadersIo>) -> SpaceInvadersMachine {
+        SpaceInvadersMachine {
+            state: state,
             paused: false,
+            history: RewindBuffer::new(REWIND_FRAMES, REWIND_KEYFRAME_INTERVAL),
+            input: InputSource::Keyboard,
+            frame_cycles: 0,
+            raised_mid_frame: false,
+        }
+    }
+
+    // Goes back up to `frames` frames, returning how many it managed. A movie
+    // being recorded or played goes back with it, so recording can carry on
+    // from the earlier point.
+    fn rewind(&mut self, frames: usize) -> Result<usize, SaveStateError> {
+        let frames = self.history.rewind(&mut self.state, frames)?;
+        // Rewind points are recorded as each frame ends
+        self.frame_cycles = 0;
+        self.raised_mid_frame = false;
+        match &mut self.input {
+            InputSource::Keyboard => {},
+            InputSource::Recording(movie) => movie.truncate(movie.frames() - frames),
+            InputSource::Playing(_, frame) => *frame -= frames,
         }
+        Ok(frames)
+    }
+
+    fn start_recording(&mut self) {
+        let start_state = savestate::save(&*self, SAVE_STATE_NAME);
+        self.input = InputSource::Recording(Movie::new(&self.state.memory.rom, start_state, MOVIE_INPUT_WIDTH));
+        self.history.clear();
+    }
+
+    // Hands back the recording, if there is one, and goes back to the keyboard
+    fn stop_recording(&mut self) -> Option<Movie> {
+        match std::mem::replace(&mut self.input, InputSource::Keyboard) {
+            InputSource::Recording(movie) => Some(movie),
+            input => {
+                self.input = input;
+                None
+            },
+        }
+    }
+
+    // Puts the machine back in the movie's starting state and plays it from
+    // the next frame on
+    fn start_playback(&mut self, movie: Movie) -> Result<(), MovieError> {
+        movie.check_rom(&self.state.memory.rom)?;
+        movie.check_input_width(MOVIE_INPUT_WIDTH)?;
+        let mut loaded = self.fresh();
+        savestate::load(&mut loaded, SAVE_STATE_NAME, &movie.start_state)?;
+        self.swap_in(loaded);
+        self.input = InputSource::Playing(movie, 0);
+        Ok(())
+    }
+
+    // An empty machine with the same ROM, to load a state into so a bad one
+    // leaves this machine alone
+    fn fresh(&self) -> SpaceInvadersMachine {
+        let bus = SpaceInvadersBus::new(self.state.memory.rom.clone());
+        SpaceInvadersMachine::new(State8080::new(bus, SpaceInvadersIo::new()))
+    }
+
+    // Takes over a loaded machine's CPU and place in the frame, keeping the
+    // tracer and profiler running
+    fn swap_in(&mut self, mut loaded: SpaceInvadersMachine) {
+        loaded.state.set_tracer(self.state.take_tracer());
+        loaded.state.set_profiler(self.state.take_profiler());
+        self.state = loaded.state;
+        self.frame_cycles = loaded.frame_cycles;
+        self.raised_mid_frame = loaded.raised_mid_frame;
+        self.history.clear();
+    }
+
+    fn playing(&self) -> bool {
+        matches!(self.input, InputSource::Playing(..))
+    }
+
+    // Records or plays back this frame's input. Playback hands control back
+    // to the keyboard once the movie runs out.
+    fn sample_input(&mut self) {
+        let io = &mut self.state.io;
+        match &mut self.input {
+            InputSource::Keyboard => {},
+            InputSource::Recording(movie) => movie.record(&[io.in_port1, io.in_port2]),
+            InputSource::Playing(movie, frame) => match movie.input(*frame) {
+                Some(input) => {
+                    io.in_port1 = input[0];
+                    io.in_port2 = input[1];
+                    *frame += 1;
+                },
+                None => self.input = InputSource::Keyboard,
+            },
+        }
+    }
+
+    // Runs one instruction, raising the two video interrupts at the points
+    // in the frame where the hardware does. Returns true when that finished
+    // the frame.
+    fn step(&mut self) -> Result<bool, EmulatorError> {
+        if self.frame_cycles == 0 {
+            self.sample_input();
+        }
+        self.frame_cycles += emulator::emulate_8080_op(&mut self.state)?;
+        if !self.raised_mid_frame && self.frame_cycles >= CYCLES_PER_FRAME / 2 {
+            self.state.request_interrupt(RST_1);
+            self.raised_mid_frame = true;
+        }
+        if self.frame_cycles < CYCLES_PER_FRAME {
+            return Ok(false);
+        }
+        self.state.request_interrupt(RST_2);
+        self.history.record(&self.state);
+        self.frame_cycles = 0;
+        self.raised_mid_frame = false;
+        Ok(true)
+    }
+
+    fn run_frame(&mut self) -> Result<(), EmulatorError> {
+        while !self.step()? {}
+        Ok(())
+    }
+}
+
+// The game never finishes on its own, so every step keeps going
+impl Debuggable for SpaceInvadersMachine {
+    type Memory = SpaceInvadersBus;
+    type Io = SpaceInvadersIo;
+
+    fn state(&self) -> &State8080<SpaceInvadersBus, SpaceInvadersIo> {
+        &self.state
     }
+
+    fn state_mut(&mut self) -> &mut State8080<SpaceInvadersBus, SpaceInvadersIo> {
+        &mut self.state
+    }
+
+    fn step(&mut self) -> Result<bool, EmulatorError> {
+        SpaceInvadersMachine::step(self)?;
+        Ok(true)
+    }
+}
+
+// Runs the game under the debugger, without a window. Inputs stay as they
+// are unless they're changed with the debugger's memory and register commands.
+pub fn debug(state: State8080<SpaceInvadersBus, SpaceInvadersIo>) {
+    debugger::run(SpaceInvadersMachine::new(state));
+}
+
+// Same again, but for a GDB client
+pub fn gdb(state: State8080<SpaceInvadersBus, SpaceInvadersIo>, port: Option<u16>) {
+    gdb::run(SpaceInvadersMachine::new(state), port);
+}
+
+// The machine can be stopped after any instruction under the debugger, so
+// how far into the frame it is gets saved too. That says which video
+// interrupt comes next; one already raised is latched in the CPU and saved
+// along with it.
+impl Snapshot for SpaceInvadersMachine {
+    fn save(&self, out: &mut SnapshotWriter) {
+        self.state.save(out);
+        out.u32(self.frame_cycles);
+        out.bool(self.raised_mid_frame);
+    }
+
+    fn load(&mut self, input: &mut SnapshotReader) -> Result<(), SaveStateError> {
+        self.state.load(input)?;
+        self.frame_cycles = input.u32()?;
+        self.raised_mid_frame = input.bool()?;
+        Ok(())
+    }
+}
+
+fn save_state(machine: &SpaceInvadersMachine, path: &Path) -> Result<(), SaveStateError> {
+    savestate::save_file(machine, SAVE_STATE_NAME, path)
+}
+
+fn restore(machine: &mut SpaceInvadersMachine, path: &Path) -> Result<(), SaveStateError> {
+    let mut restored = machine.fresh();
+    savestate::load_file(&mut restored, SAVE_STATE_NAME, path)?;
+    machine.swap_in(restored);
+    Ok(())
 }
 
-pub fn start(state: State8080) {
+// F5 saves to `save_path` and F9 loads it back. Holding backspace runs the
+// game backwards, a frame at a time.
+pub fn start(state: State8080<SpaceInvadersBus, SpaceInvadersIo>, save_path: &Path, movie_mode: MovieMode) {
     let mut machine = SpaceInvadersMachine::new(state);
+    match &movie_mode {
+        MovieMode::Off => {},
+        MovieMode::Record(_) => machine.start_recording(),
+        MovieMode::Play(path) => {
+            if let Err(err) = Movie::load_file(path).and_then(|movie| machine.start_playback(movie)) {
+                eprintln!("Unable to play movie: {}", err);
+                return;
+            }
+        },
+    }
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
 
@@ -68,6 +410,7 @@ pub fn start(state: State8080) {
     canvas.present();
     let mut color_scheme = ColorScheme::CLASSIC;
     let mut event_pump = sdl_context.event_pump().unwrap();
+    let mut rewinding = false;
     'running: loop {
         for event in event_pump.poll_iter() {
             match event {
@@ -85,6 +428,23 @@ pub fn start(state: State8080) {
                 Event::KeyDown { keycode: Some(Keycode::P), .. } => {
                     machine.paused = !machine.paused;
                 },
+                Event::KeyDown { keycode: Some(Keycode::F5), .. } => {
+                    match save_state(&machine, save_path) {
+                        Ok(()) => eprintln!("Saved state to {}", save_path.display()),
+                        Err(err) => eprintln!("Unable to save state: {}", err),
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::F9), .. } if !matches!(machine.input, InputSource::Keyboard) => {
+                    eprintln!("Can't load a state while a movie is recording or playing");
+                },
+                Event::KeyDown { keycode: Some(Keycode::F9), .. } => {
+                    match restore(&mut machine, save_path) {
+                        Ok(()) => eprintln!("Loaded state from {}", save_path.display()),
+                        Err(err) => eprintln!("Unable to load state: {}", err),
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::Backspace), .. } => rewinding = true,
+                Event::KeyUp { keycode: Some(Keycode::Backspace), .. } => rewinding = false,
                 Event::KeyDown { keycode: Some(key), .. } => machine_key_down(&mut machine, &key),
                 Event::KeyUp { keycode: Some(key), .. } => machine_key_up(&mut machine, &key),
                 _ => {}
@@ -92,85 +452,28 @@ pub fn start(state: State8080) {
         }
         draw(&machine.state, &mut canvas, color_scheme);
         canvas.present();
-        // Display is 60Hz, clock is 2MHz, this is close enough for now I guess
-        let mut cycle_count = 0;
-        while !machine.paused && (cycle_count < CYCLES_PER_FRAME) {
-            let program_counter = machine.state.program_counter() as usize;
-            let current_opcode = machine.state.memory[program_counter];
-            // Special handling for interrupts. Eventually it would be nice to
-            // have a way to do this without basically implementing instruction
-            // handlerss outside of the main CPU emulator
-            match current_opcode {
-                // Special handling for IN
-                0xdb => {
-                    let port_number = machine.state.memory[program_counter + 1];
-                    machine.state.a = handle_in(&mut machine, port_number);
-                    cycle_count += emulator::emulate_8080_op(&mut machine.state);
-                },
-                // Special handling for OUT
-                0xd3 => {
-                    let port_number = machine.state.memory[program_counter + 1];
-                    let value = machine.state.a;
-                    handle_out(&mut machine, port_number, value);
-                    cycle_count += emulator::emulate_8080_op(&mut machine.state);
-                },
-                _ => {
-                    cycle_count += emulator::emulate_8080_op(&mut machine.state);
-                },
+        if rewinding {
+            if let Err(err) = machine.rewind(1) {
+                eprintln!("Unable to rewind: {}", err);
+                rewinding = false;
             }
-            let current_time = Instant::now();
-            let time_since_last_interrupt = current_time.saturating_duration_since(machine.last_timer);
-            if time_since_last_interrupt.as_secs_f64() > 1.0/60.0 {
-                if machine.state.interrupt_enabled() {
-                    match machine.which_interrupt {
-                        1 => {
-                            machine.state.generate_interrupt(1);
-                            machine.last_timer = current_time;
-                            machine.which_interrupt = 2;
-                        }
-                        _ => {
-                            machine.state.generate_interrupt(2);
-                            machine.last_timer = current_time;
-                            machine.which_interrupt = 1;
-                        }
-                    }
-                }
+        } else if !machine.paused {
+            let was_playing = machine.playing();
+            if let Err(err) = machine.run_frame() {
+                let _ = emulator::write_error_report(&mut std::io::stderr(), &machine.state, &err);
+                break 'running;
+            }
+            if was_playing && !machine.playing() {
+                eprintln!("Movie finished");
             }
         }
         ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 60));
     }
-}
-
-fn handle_in(machine: &mut SpaceInvadersMachine, port: u8) -> u8 {
-    match port {
-        0 => { 0xf },
-        1 => machine.in_port1,
-        2 => { 0 }, // Player 2 controls and some other random stuff
-        3 => {
-            let value: u16 = ((machine.shift_high as u16) << 8) | machine.shift_low as u16;
-            let masked_value: u8 = ((value >> (8 - machine.shift_offset)) & 0xff) as u8;
-            masked_value
-        },
-        _ => { 0 },
-    }
-}
-
-fn handle_out(machine: &mut SpaceInvadersMachine, port: u8, value: u8) {
-    match port {
-        2 => {
-            machine.shift_offset = value & 0x7;
-        },
-        3 => {
-            machine.out_port3 = value;
-        }
-        4 => {
-            machine.shift_low = machine.shift_high;
-            machine.shift_high = value;
-        }
-        5 => {
-            machine.out_port5 = value;
+    if let (MovieMode::Record(path), Some(movie)) = (&movie_mode, machine.stop_recording()) {
+        match movie.save_file(path) {
+            Ok(()) => eprintln!("Saved {} frames to {}", movie.frames(), path.display()),
+            Err(err) => eprintln!("Unable to save movie: {}", err),
         }
-        _ => {},
     }
 }
 
@@ -209,10 +512,10 @@ enum PixelColor {
     WHITE = 0b11111111,
 }
 
-fn draw(state: &State8080, canvas: &mut Canvas<sdl2::video::Window>, color_scheme: ColorScheme) {
+fn draw(state: &State8080<SpaceInvadersBus, SpaceInvadersIo>, canvas: &mut Canvas<sdl2::video::Window>, color_scheme: ColorScheme) {
     let texture_creator = canvas.texture_creator();
     let mut texture = texture_creator.create_texture_target(PixelFormatEnum::RGB332, 224, 256).unwrap();
-    let vram: &[u8] = &state.memory[0x2400..0x4000];
+    let vram: &[u8] = state.memory.vram();
     let pixels: &mut [u8] = &mut [0; 224 * 256];
     for i in 0..(224 * 256 / 8) {
         let memory_x = i % 32;
@@ -237,19 +540,19 @@ fn draw(state: &State8080, canvas: &mut Canvas<sdl2::video::Window>, color_schem
 fn machine_key_down(machine: &mut SpaceInvadersMachine, key: &sdl2::keyboard::Keycode) {
     match key {
         Keycode::C => {
-            machine.in_port1 |= 0x01;
+            machine.state.io.in_port1 |= 0x01;
         },
         Keycode::Left => {
-            machine.in_port1 |= 0x20;
+            machine.state.io.in_port1 |= 0x20;
         },
         Keycode::Right => {
-            machine.in_port1 |= 0x40;
+            machine.state.io.in_port1 |= 0x40;
         },
         Keycode::Z => {
-            machine.in_port1 |= 0x10;
+            machine.state.io.in_port1 |= 0x10;
         },
         Keycode::T => {
-            machine.in_port1 |= 0x04;
+            machine.state.io.in_port1 |= 0x04;
         },
         _ => {},
     }
@@ -258,20 +561,214 @@ fn machine_key_down(machine: &mut SpaceInvadersMachine, key: &sdl2::keyboard::Ke
 fn machine_key_up(machine: &mut SpaceInvadersMachine, key: &sdl2::keyboard::Keycode) {
     match key {
         Keycode::C => {
-            machine.in_port1 &= !0x01;
+            machine.state.io.in_port1 &= !0x01;
         },
         Keycode::Left => {
-            machine.in_port1 &= !0x20;
+            machine.state.io.in_port1 &= !0x20;
         },
         Keycode::Right => {
-            machine.in_port1 &= !0x40;
+            machine.state.io.in_port1 &= !0x40;
         },
         Keycode::Z => {
-            machine.in_port1 &= !0x10;
+            machine.state.io.in_port1 &= !0x10;
         },
         Keycode::T => {
-            machine.in_port1 &= !0x04;
+            machine.state.io.in_port1 &= !0x04;
         },
         _ => {},
     }
 }
+
+mod test {
+    #[allow(unused)] use super::*;
+    #[allow(unused)] use crate::watchpoint::{WatchHit, WatchKind, Watchpoint};
+
+    #[test]
+    fn test_rom_ignores_writes() {
+        let mut bus = SpaceInvadersBus::new(vec![0xc3, 0xd4, 0x18]);
+        bus.write(0x0000, 0x00).unwrap();
+        assert_eq!(bus.read(0x0000), Ok(0xc3));
+        assert_eq!(bus.read(0x0002), Ok(0x18));
+    }
+
+    #[test]
+    fn test_ram_mirror() {
+        let mut bus = SpaceInvadersBus::new(vec![]);
+        bus.write(0x2400, 0xaa).unwrap();
+        assert_eq!(bus.read(0x6400), Ok(0xaa));
+        bus.write(0x63ff, 0x55).unwrap();
+        assert_eq!(bus.read(0x23ff), Ok(0x55));
+    }
+
+    #[test]
+    fn test_shift_register() {
+        let mut io = SpaceInvadersIo::new();
+        io.output(4, 0xab);
+        io.output(4, 0xcd);
+        io.output(2, 0);
+        assert_eq!(io.input(3), 0xcd);
+        io.output(2, 4);
+        assert_eq!(io.input(3), 0xda);
+    }
+
+    #[test]
+    fn test_frame_interrupts() {
+        // EI, then spin in place at 0x0001. The RST 1 handler just halts.
+        let rom = vec![0xfb, 0xc3, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x76];
+        let mut state = State8080::new(SpaceInvadersBus::new(rom), SpaceInvadersIo::new());
+        state.sp = 0x2400;
+        let mut machine = SpaceInvadersMachine::new(state);
+        machine.run_frame().unwrap();
+        // RST 1 was taken mid-frame, and nothing re-enabled interrupts
+        assert!(machine.state.halted());
+        assert_eq!(machine.state.program_counter(), 0x0009);
+        assert!(!machine.state.interrupt_enabled());
+        assert!(machine.state.interrupt_pending());
+    }
+
+    #[test]
+    fn test_save_state_round_trip() {
+        let rom = vec![0xfb, 0xc3, 0x01, 0x00];
+        let mut state = State8080::new(SpaceInvadersBus::new(rom.clone()), SpaceInvadersIo::new());
+        state.sp = 0x2400;
+        let mut machine = SpaceInvadersMachine::new(state);
+        machine.state.io.output(4, 0xab);
+        machine.state.io.output(2, 3);
+        machine.state.io.in_port1 = 0x29;
+        machine.run_frame().unwrap();
+        let bytes = savestate::save(&machine, SAVE_STATE_NAME);
+
+        let state = State8080::new(SpaceInvadersBus::new(rom), SpaceInvadersIo::new());
+        let mut restored = SpaceInvadersMachine::new(state);
+        savestate::load(&mut restored, SAVE_STATE_NAME, &bytes).unwrap();
+        assert_eq!(restored.state.program_counter(), machine.state.program_counter());
+        assert_eq!(restored.state.sp, 0x23fe);
+        assert_eq!(restored.state.io.input(3), machine.state.io.input(3));
+        assert_eq!(restored.state.io.in_port1, 0x29);
+        assert!(restored.state.interrupt_pending());
+
+        // Both copies carry on identically
+        machine.run_frame().unwrap();
+        restored.run_frame().unwrap();
+        assert_eq!(savestate::save(&restored, SAVE_STATE_NAME), savestate::save(&machine, SAVE_STATE_NAME));
+    }
+
+    #[test]
+    fn test_rewind() {
+        let rom = vec![0xfb, 0x3c, 0xc3, 0x01, 0x00]; // EI, then INR A in a loop
+        let mut state = State8080::new(SpaceInvadersBus::new(rom), SpaceInvadersIo::new());
+        state.sp = 0x2400;
+        let mut machine = SpaceInvadersMachine::new(state);
+        let mut saved = Vec::new();
+        for _ in 0..5 {
+            machine.run_frame().unwrap();
+            saved.push(savestate::save(&machine, SAVE_STATE_NAME));
+        }
+        assert_eq!(machine.rewind(3).unwrap(), 3);
+        assert_eq!(savestate::save(&machine, SAVE_STATE_NAME), saved[1]);
+        assert_eq!(machine.rewind(10).unwrap(), 1);
+        assert_eq!(savestate::save(&machine, SAVE_STATE_NAME), saved[0]);
+    }
+
+    // Plays a movie from start to finish without a window, returning the
+    // machine as it was at the end. The same movie always ends in the same
+    // state.
+    #[allow(unused)]
+    fn replay_movie(rom: Vec<u8>, movie: Movie) -> Result<State8080<SpaceInvadersBus, SpaceInvadersIo>, MovieError> {
+        let state = State8080::new(SpaceInvadersBus::new(rom), SpaceInvadersIo::new());
+        let mut machine = SpaceInvadersMachine::new(state);
+        machine.start_playback(movie)?;
+        while let InputSource::Playing(movie, frame) = &machine.input {
+            if *frame == movie.frames() {
+                break;
+            }
+            machine.run_frame()?;
+        }
+        Ok(machine.state)
+    }
+
+    #[test]
+    fn test_movie_replay() {
+        let rom = vec![0xfb, 0xdb, 0x01, 0x80, 0x47, 0xc3, 0x01, 0x00]; // EI, then keep adding port 1 to A and B
+        let state = State8080::new(SpaceInvadersBus::new(rom.clone()), SpaceInvadersIo::new());
+        let mut machine = SpaceInvadersMachine::new(state);
+        machine.state.sp = 0x2400;
+        machine.start_recording();
+        for frame in 0..6 {
+            machine.state.io.in_port1 = 0x08 | frame;
+            machine.run_frame().unwrap();
+        }
+        // Rewinding while recording drops the rewound frames from the movie
+        machine.rewind(2).unwrap();
+        machine.state.io.in_port1 = 0x29;
+        machine.run_frame().unwrap();
+        let expected = savestate::save(&machine.state, SAVE_STATE_NAME);
+        let movie = machine.stop_recording().unwrap();
+        assert_eq!(movie.frames(), 5);
+
+        let first = replay_movie(rom.clone(), movie.clone()).unwrap();
+        let second = replay_movie(rom, movie).unwrap();
+        assert_eq!(savestate::save(&first, SAVE_STATE_NAME), expected);
+        assert_eq!(savestate::save(&second, SAVE_STATE_NAME), expected);
+    }
+
+    #[test]
+    fn test_movie_wrong_rom() {
+        let state = State8080::new(SpaceInvadersBus::new(vec![0x00]), SpaceInvadersIo::new());
+        let mut machine = SpaceInvadersMachine::new(state);
+        machine.start_recording();
+        let movie = machine.stop_recording().unwrap();
+        match replay_movie(vec![0x01], movie) {
+            Err(MovieError::RomMismatch) => {},
+            Err(err) => panic!("unexpected {}", err),
+            Ok(_) => panic!("played against the wrong ROM"),
+        }
+    }
+
+    #[test]
+    fn test_movie_wrong_input_width() {
+        let rom = vec![0x00];
+        let state = State8080::new(SpaceInvadersBus::new(rom.clone()), SpaceInvadersIo::new());
+        let start_state = savestate::save(&state, SAVE_STATE_NAME);
+        let mut movie = Movie::new(&state.memory.rom, start_state, 1);
+        movie.record(&[0x08]);
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        match replay_movie(rom, movie) {
+            Err(MovieError::InputWidth(1)) => {},
+            Err(err) => panic!("unexpected {}", err),
+            Ok(_) => panic!("played a movie with the wrong input width"),
+        }
+    }
+
+    #[test]
+    fn test_save_state_mid_frame() {
+        // Saved between the two video interrupts, the copy still has to take
+        // RST 2 at the end of the frame rather than RST 1 again
+        let rom = vec![0xfb, 0x3c, 0xc3, 0x01, 0x00]; // EI, then INR A in a loop
+        let mut state = State8080::new(SpaceInvadersBus::new(rom), SpaceInvadersIo::new());
+        state.sp = 0x2400;
+        let mut machine = SpaceInvadersMachine::new(state);
+        while !machine.raised_mid_frame {
+            machine.step().unwrap();
+        }
+        let bytes = savestate::save(&machine, SAVE_STATE_NAME);
+        let mut restored = machine.fresh();
+        savestate::load(&mut restored, SAVE_STATE_NAME, &bytes).unwrap();
+        assert_eq!(restored.frame_cycles, machine.frame_cycles);
+        assert!(restored.raised_mid_frame);
+
+        machine.run_frame().unwrap();
+        restored.run_frame().unwrap();
+        assert_eq!(savestate::save(&restored, SAVE_STATE_NAME), savestate::save(&machine, SAVE_STATE_NAME));
+    }
+
+    #[test]
+    fn test_change_watchpoint_ignores_rom_writes() {
+        let mut state = State8080::new(SpaceInvadersBus::new(vec![0xc3]), SpaceInvadersIo::new());
+        state.add_watchpoint(Watchpoint { start: 0x0000, end: 0x2000, kind: WatchKind::Change });
+        state.write_memory(0x0000, 0x00);
+        assert_eq!(state.take_watch_hit(), None);
+        state.write_memory(0x2000, 0x42);
+        assert_eq!(state.take_watch_hit(), Some(WatchHit { kind: WatchKind::Change, address: 0x2000, value: 0x42, old_value: Some(0x00) }));
+    }
+}
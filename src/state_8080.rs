@@ -1,5 +1,17 @@
+use std::cell::Cell;
+
+use crate::bus::Bus;
+use crate::call_stack::{BacktraceEntry, CallStack, Frame};
+use crate::error::ErrorReason;
+use crate::flags::Flags;
+use crate::instruction::{Condition, Register, RegisterPair};
+use crate::io_ports::{IoPorts, NoPorts};
 use crate::parity::Parity;
+use crate::savestate::{SaveStateError, Snapshot, SnapshotReader, SnapshotWriter};
 use crate::sign::Sign;
+use crate::profiler::Profiler;
+use crate::trace::Tracer;
+use crate::watchpoint::{WatchHit, WatchKind, Watchpoint};
 
 #[derive(Debug)]
 pub struct ConditionCodes {
@@ -8,10 +20,92 @@ pub struct ConditionCodes {
     pub p: Parity,
     pub cy: u8,
     pub ac: u8,
-    pub pad: u8,
+    // Only the 8085 makes these visible, through PUSH PSW and its undocumented
+    // instructions: V is two's complement overflow and K is the underflow
+    // indicator (set when INX/DCX wrap, among other things).
+    pub v: u8,
+    pub k: u8,
+    // Z80 only: set by subtractions so its DAA knows which way to adjust
+    pub n: u8,
+}
+
+impl ConditionCodes {
+    pub fn flags(&self) -> Flags {
+        let mut flags = Flags::empty();
+        flags.set(Flags::CARRY, self.cy != 0);
+        flags.set(Flags::PARITY, self.p == Parity::Even);
+        flags.set(Flags::AUX_CARRY, self.ac != 0);
+        flags.set(Flags::ZERO, self.z != 0);
+        flags.set(Flags::SIGN, self.s == Sign::Negative);
+        flags
+    }
+
+    pub fn set_flags(&mut self, flags: Flags) {
+        self.cy = flags.contains(Flags::CARRY) as u8;
+        self.p = if flags.contains(Flags::PARITY) { Parity::Even } else { Parity::Odd };
+        self.ac = flags.contains(Flags::AUX_CARRY) as u8;
+        self.z = flags.contains(Flags::ZERO) as u8;
+        self.s = if flags.contains(Flags::SIGN) { Sign::Negative } else { Sign::Positive };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Intel8080,
+    Intel8085,
+    Z80,
+}
+
+// How the CPU took an interrupt in `acknowledge_interrupt`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptAcknowledge {
+    None,
+    // The next instruction comes from the data bus
+    DataBus,
+    // A vectored interrupt (8085 TRAP and RST 7.5/6.5/5.5, Z80 NMI and
+    // interrupt modes 1 and 2). The call has already been made, and this is
+    // how many cycles it took.
+    Vectored(u32),
+}
+
+// Registers only the Z80 has. The alternate set is kept as pairs, since the
+// only way to get at it is to exchange it with the main one.
+#[derive(Debug, Default)]
+pub struct Z80Registers {
+    pub ix: u16,
+    pub iy: u16,
+    pub i: u8,
+    pub r: u8,
+    pub af_alternate: u16,
+    pub bc_alternate: u16,
+    pub de_alternate: u16,
+    pub hl_alternate: u16,
+    pub interrupt_mode: u8,
+    // IFF1 is the usual interrupt enable. IFF2 holds on to it while an NMI is
+    // being handled so RETN can put it back.
+    pub iff2: bool,
+}
+
+// The 8085's extra interrupt inputs and serial pins, and the RST 5.5/6.5/7.5
+// masks that SIM sets. Ignored when running as an 8080.
+#[derive(Debug)]
+struct Intel8085Pins {
+    trap: bool,
+    // RST 7.5 is edge triggered, so it has a flip-flop that stays set until
+    // the interrupt is taken or SIM resets it. 6.5 and 5.5 are level inputs.
+    rst7_5: bool,
+    rst6_5: bool,
+    rst5_5: bool,
+    // M7.5, M6.5 and M5.5 in bits 2 to 0, like SIM and RIM have them
+    masks: u8,
+    sid: bool,
+    sod: bool,
+    // The first RIM after a TRAP reports whether interrupts were enabled
+    // before the TRAP disabled them, so a handler can restore them
+    enabled_before_trap: Option<bool>,
 }
 
-pub struct State8080 {
+pub struct State8080<M: Bus = Vec<u8>, P: IoPorts = NoPorts> {
     pub a: u8,
     pub b: u8,
     pub c: u8,
@@ -21,12 +115,92 @@ pub struct State8080 {
     pub l: u8,
     pub sp: u16,
     pc: u16,
-    pub memory: Vec<u8>,
+    pub memory: M,
+    pub io: P,
     pub cc: ConditionCodes,
+    pub z80: Z80Registers,
+    variant: Variant,
     int_enable: u8,
+    halted: bool,
+    pins: Intel8085Pins,
+    // Z80 NMI is edge triggered, so it's latched until it's taken
+    nmi: bool,
+    // EI only takes effect after the instruction that follows it, so that
+    // EI; RET can return from a handler before the next interrupt comes in
+    enable_interrupt_delay: bool,
+    // The instruction a device has put on the data bus for the CPU to execute
+    // when it acknowledges the interrupt. It stays latched until then, so a
+    // request made while interrupts are disabled isn't lost.
+    interrupt_request: Option<[u8; 3]>,
+    // While an interrupt is being acknowledged the instruction is fetched from
+    // here instead of from memory, without moving the program counter
+    data_bus: Option<([u8; 3], usize)>,
+    // First unmapped access made by the current instruction. Memory accessors
+    // can't fail without threading a `Result` through every instruction, so
+    // they record the problem here and `emulate_8080_op` reports it once the
+    // instruction is done.
+    bus_fault: Cell<Option<ErrorReason>>,
+    watchpoints: Vec<Watchpoint>,
+    // First watchpoint set off since the last `take_watch_hit`, recorded the
+    // same way as a bus fault
+    watch_hit: Cell<Option<WatchHit>>,
+    // Boxed so the CPU stays small when nothing is tracing or profiling it
+    tracer: Option<Box<Tracer>>,
+    profiler: Option<Box<Profiler>>,
+    call_stack: CallStack,
 }
 
-impl State8080 {
+impl<M: Bus, P: IoPorts> State8080<M, P> {
+    pub fn new(memory: M, io: P) -> State8080<M, P> {
+        State8080 {
+            a: 0,
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            h: 0,
+            l: 0,
+            cc: ConditionCodes { ac: 0, cy: 0, p: Parity::Odd, s: Sign::Positive, z: 0, v: 0, k: 0, n: 0 },
+            z80: Z80Registers::default(),
+            variant: Variant::Intel8080,
+            int_enable: 0,
+            halted: false,
+            // The vectored interrupts come out of reset masked
+            pins: Intel8085Pins {
+                trap: false,
+                rst7_5: false,
+                rst6_5: false,
+                rst5_5: false,
+                masks: 0x07,
+                sid: false,
+                sod: false,
+                enabled_before_trap: None,
+            },
+            nmi: false,
+            enable_interrupt_delay: false,
+            interrupt_request: None,
+            data_bus: None,
+            bus_fault: Cell::new(None),
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+            tracer: None,
+            profiler: None,
+            call_stack: CallStack::new(),
+            memory,
+            io,
+            sp: 0,
+            pc: 0,
+        }
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.variant = variant;
+    }
+
     pub fn program_counter(&self) -> u16 {
         self.pc
     }
@@ -36,7 +210,22 @@ impl State8080 {
     }
 
     pub fn increment_program_counter(&mut self, delta: u16) {
-        self.pc += delta;
+        self.pc = self.pc.wrapping_add(delta);
+    }
+
+    // Reads the byte at the program counter and steps past it. Instruction
+    // operands are fetched through here so they wrap around at the top of
+    // memory the same way the real program counter does.
+    pub fn next_byte(&mut self) -> u8 {
+        if let Some((instruction, index)) = &mut self.data_bus {
+            // Anything past the end of what the device supplied floats high
+            let value = instruction.get(*index).copied().unwrap_or(0xff);
+            *index += 1;
+            return value;
+        }
+        let value = self.fetch_memory(self.pc);
+        self.increment_program_counter(1);
+        value
     }
 
     pub fn bc(&self) -> u16 {
@@ -67,106 +256,277 @@ impl State8080 {
     }
 
     pub fn m(&self) -> u8 {
-        self.read_memory(self.hl() as usize)
+        self.read_memory(self.hl())
     }
 
     pub fn set_m(&mut self, value: u8) {
-        self.write_memory(self.hl() as usize, value);
+        self.write_memory(self.hl(), value);
     }
 
-    pub fn add(&mut self, value: u8) {
-        let answer: u16 = (self.a as u16) + (value as u16);
+    // M reads and writes the byte at HL, like it does in an instruction
+    pub fn register(&self, register: Register) -> u8 {
+        match register {
+            Register::B => self.b,
+            Register::C => self.c,
+            Register::D => self.d,
+            Register::E => self.e,
+            Register::H => self.h,
+            Register::L => self.l,
+            Register::M => self.m(),
+            Register::A => self.a,
+        }
+    }
+
+    pub fn set_register(&mut self, register: Register, value: u8) {
+        match register {
+            Register::B => self.b = value,
+            Register::C => self.c = value,
+            Register::D => self.d = value,
+            Register::E => self.e = value,
+            Register::H => self.h = value,
+            Register::L => self.l = value,
+            Register::M => self.set_m(value),
+            Register::A => self.a = value,
+        }
+    }
+
+    // PSW is A in the high byte and the flags in the low one, laid out the
+    // way PUSH PSW stores them. On the 8085 bits 1 and 5 hold its V and K
+    // flags instead of the 8080's fixed 1 and 0, and on the Z80 (where it's
+    // AF) bit 1 holds N.
+    pub fn register_pair(&self, pair: RegisterPair) -> u16 {
+        match pair {
+            RegisterPair::BC => self.bc(),
+            RegisterPair::DE => self.de(),
+            RegisterPair::HL => self.hl(),
+            RegisterPair::SP => self.sp,
+            RegisterPair::Psw => {
+                let psw = self.flags().to_psw() & !0x02;
+                let psw = match self.variant {
+                    Variant::Intel8080 => psw | 0x02,
+                    Variant::Intel8085 => psw | self.cc.v << 1 | self.cc.k << 5,
+                    Variant::Z80 => psw | self.cc.n << 1,
+                };
+                (self.a as u16) << 8 | psw as u16
+            },
+        }
+    }
+
+    pub fn set_register_pair(&mut self, pair: RegisterPair, value: u16) {
+        match pair {
+            RegisterPair::BC => self.set_bc(value),
+            RegisterPair::DE => self.set_de(value),
+            RegisterPair::HL => self.set_hl(value),
+            RegisterPair::SP => self.sp = value,
+            RegisterPair::Psw => {
+                let psw = value as u8;
+                self.a = (value >> 8) as u8;
+                self.set_flags(Flags::from_psw(psw));
+                match self.variant {
+                    Variant::Intel8080 => {},
+                    Variant::Intel8085 => {
+                        self.cc.v = (psw >> 1) & 0x01;
+                        self.cc.k = (psw >> 5) & 0x01;
+                    },
+                    Variant::Z80 => self.cc.n = (psw >> 1) & 0x01,
+                }
+            },
+        }
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.cc.flags()
+    }
+
+    pub fn set_flags(&mut self, flags: Flags) {
+        self.cc.set_flags(flags);
+    }
+
+    pub fn flag(&self, flag: Flags) -> bool {
+        self.flags().contains(flag)
+    }
+
+    pub fn condition(&self, condition: Condition) -> bool {
+        match condition {
+            Condition::NotZero => !self.flag(Flags::ZERO),
+            Condition::Zero => self.flag(Flags::ZERO),
+            Condition::NoCarry => !self.flag(Flags::CARRY),
+            Condition::Carry => self.flag(Flags::CARRY),
+            Condition::ParityOdd => !self.flag(Flags::PARITY),
+            Condition::ParityEven => self.flag(Flags::PARITY),
+            Condition::Plus => !self.flag(Flags::SIGN),
+            Condition::Minus => self.flag(Flags::SIGN),
+        }
+    }
+
+    fn set_zsp(&mut self, value: u8) {
+        self.cc.z = if value == 0 { 1 } else { 0 };
+        self.cc.s = Sign::get_sign(value);
+        self.cc.p = Parity::from(value);
+    }
+
+    // V and K come from the sign bits of the two numbers actually being added
+    // (so the complemented subtrahend for a subtraction) and of the result
+    fn set_vk(&mut self, x: u8, y: u8, answer: u8) {
+        self.cc.v = if (x ^ answer) & (y ^ answer) & 0x80 != 0 { 1 } else { 0 };
+        self.cc.k = if ((x & y) | (x & !answer) | (y & !answer)) & 0x80 != 0 { 1 } else { 0 };
+    }
+
+    fn addition(&mut self, value: u8, carry: u8) -> u8 {
+        let answer: u16 = (self.a as u16) + (value as u16) + (carry as u16);
         let masked_answer: u8 = (answer & 0xff) as u8;
-        self.cc.z = if masked_answer == 0 { 1 } else { 0 };
-        self.cc.s = Sign::get_sign(masked_answer);
+        self.set_zsp(masked_answer);
+        self.set_vk(self.a, value, masked_answer);
         self.cc.cy = if answer > 0xff { 1 } else { 0 };
-        self.cc.p = Parity::from(masked_answer);
-        self.a = masked_answer;
+        self.cc.ac = if (self.a & 0xf) + (value & 0xf) + carry > 0xf { 1 } else { 0 };
+        self.cc.n = 0;
+        self.set_z80_overflow();
+        masked_answer
     }
 
-    pub fn adc(&mut self, value: u8) {
-        let answer: u16 = (self.a as u16) + (value as u16) + (self.cc.cy as u16);
+    fn subtraction(&mut self, value: u8, borrow: u8) -> u8 {
+        self.subtract_bytes(self.a, value, borrow)
+    }
+
+    // The 8080 subtracts by adding the two's complement of the subtrahend and
+    // then inverting the carry, which is what decides the auxiliary carry: it
+    // is the carry out of bit 3 of x + !value + !borrow. The Z80's half carry
+    // is a proper borrow out of bit 4 instead, which is the opposite.
+    fn subtract_bytes(&mut self, x: u8, value: u8, borrow: u8) -> u8 {
+        let not_borrow = borrow ^ 0x01;
+        let answer: u16 = (x as u16) + (!value as u16) + (not_borrow as u16);
         let masked_answer: u8 = (answer & 0xff) as u8;
-        self.cc.z = if masked_answer == 0 { 1 } else { 0 };
-        self.cc.s = Sign::get_sign(masked_answer);
-        self.cc.cy = if answer > 0xff { 1 } else { 0 };
-        self.cc.p = Parity::from(masked_answer);
-        self.a = masked_answer;
+        self.set_zsp(masked_answer);
+        self.set_vk(x, !value, masked_answer);
+        self.cc.cy = if answer > 0xff { 0 } else { 1 };
+        self.cc.ac = if (x & 0xf) + (!value & 0xf) + not_borrow > 0xf { 1 } else { 0 };
+        self.cc.n = 1;
+        if self.variant == Variant::Z80 {
+            self.cc.ac ^= 0x01;
+            self.set_z80_overflow();
+        }
+        masked_answer
+    }
+
+    // Z80 arithmetic reports overflow in the parity flag instead of parity
+    fn set_z80_overflow(&mut self) {
+        if self.variant == Variant::Z80 {
+            self.cc.p = if self.cc.v != 0 { Parity::Even } else { Parity::Odd };
+        }
+    }
+
+    // 8085 DSUB: HL = HL - BC. The flags are the ones from subtracting the high
+    // bytes, apart from Z which covers all 16 bits.
+    pub fn dsub(&mut self) {
+        let low = self.subtract_bytes(self.l, self.c, 0);
+        let high = self.subtract_bytes(self.h, self.b, self.cc.cy);
+        self.cc.z = if low == 0 && high == 0 { 1 } else { 0 };
+        self.l = low;
+        self.h = high;
+    }
+
+    // INX and DCX don't touch the 8080 flags, but the 8085 sets K when the
+    // register pair wraps around
+    pub fn increment_word(&mut self, value: u16) -> u16 {
+        let answer = value.wrapping_add(1);
+        self.cc.k = if answer == 0x0000 { 1 } else { 0 };
+        answer
+    }
+
+    pub fn decrement_word(&mut self, value: u16) -> u16 {
+        let answer = value.wrapping_sub(1);
+        self.cc.k = if answer == 0xffff { 1 } else { 0 };
+        answer
+    }
+
+    pub fn add(&mut self, value: u8) {
+        self.a = self.addition(value, 0);
+    }
+
+    pub fn adc(&mut self, value: u8) {
+        self.a = self.addition(value, self.cc.cy);
     }
 
     pub fn sub(&mut self, value: u8) {
-        let answer: u8 = self.a.wrapping_sub(value);
-        self.cc.z = if answer == 0 { 1 } else { 0 };
-        self.cc.s = Sign::get_sign(answer);
-        self.cc.cy = if self.a < value { 1 } else { 0 };
-        self.cc.p = Parity::from(answer);
-        self.a = answer;
+        self.a = self.subtraction(value, 0);
     }
 
     pub fn sbb(&mut self, value: u8) {
-        let subtrahend: u8 = value + self.cc.cy;
-        let answer: u8 = self.a.wrapping_sub(subtrahend);
-        self.cc.z = if answer == 0 { 1 } else { 0 };
-        self.cc.s = Sign::get_sign(answer);
-        self.cc.cy = if self.a < subtrahend { 1 } else { 0 };
-        self.cc.p = Parity::from(answer);
-        self.a = answer;
+        self.a = self.subtraction(value, self.cc.cy);
     }
 
+    // AND is the odd one out: the 8080 sets the auxiliary carry to the OR of
+    // bit 3 of the two operands. The 8085 and the Z80 always set it.
     pub fn ana(&mut self, value: u8) {
         let answer: u8 = self.a & value;
-        self.cc.z = if answer == 0 { 1 } else { 0 };
-        self.cc.s = Sign::get_sign(answer);
+        self.set_zsp(answer);
         self.cc.cy = 0;
-        self.cc.p = Parity::from(answer);
+        self.cc.ac = match self.variant {
+            Variant::Intel8085 | Variant::Z80 => 1,
+            _ => if ((self.a | value) & 0x08) != 0 { 1 } else { 0 },
+        };
+        self.cc.n = 0;
         self.a = answer;
     }
 
     pub fn xra(&mut self, value: u8) {
         let answer: u8 = self.a ^ value;
-        self.cc.z = if answer == 0 { 1 } else { 0 };
-        self.cc.s = Sign::get_sign(answer);
+        self.set_zsp(answer);
         self.cc.cy = 0;
-        self.cc.p = Parity::from(answer);
+        self.cc.ac = 0;
+        self.cc.n = 0;
         self.a = answer;
     }
 
     pub fn ora(&mut self, value: u8) {
         let answer: u8 = self.a | value;
-        self.cc.z = if answer == 0 { 1 } else { 0 };
-        self.cc.s = Sign::get_sign(answer);
+        self.set_zsp(answer);
         self.cc.cy = 0;
-        self.cc.p = Parity::from(answer);
+        self.cc.ac = 0;
+        self.cc.n = 0;
         self.a = answer;
     }
 
     pub fn cmp(&mut self, value: u8) {
-        let answer = self.a.wrapping_sub(value);
-        self.cc.z = if answer == 0 { 1 } else { 0 };
-        self.cc.s = Sign::get_sign(answer);
-        self.cc.cy = if value > self.a { 1 } else { 0 };
-        self.cc.p = Parity::from(answer);
-    }
-
-    // I'm not sure how to implement this as a method on the state object and
-    // also use it for multiple registers without resorting to something that
-    // would add a lot of complexity (interior mutability? enum of all
-    // regitsers with a match?), which is why it's a function.
-    pub fn decrement_register(register_value: &mut u8, cc: &mut ConditionCodes) {
-        let answer: u8 = register_value.wrapping_sub(1);
-        cc.z = if answer == 0 { 1 } else { 0 };
-        cc.s = Sign::get_sign(answer);
-        cc.p = Parity::from(answer);
-        *register_value = answer;
-    }
-
-    pub fn increment_register(register_value: &mut u8, cc: &mut ConditionCodes) {
-        let answer: u16 = (register_value.clone() as u16) + 1;
-        let masked_answer: u8 = (answer & 0xff) as u8;
-        cc.z = if masked_answer == 0 { 1 } else { 0 };
-        cc.s = Sign::get_sign(masked_answer);
-        cc.p = Parity::from(masked_answer);
-        *register_value = masked_answer;
+        self.subtraction(value, 0);
+    }
+
+    pub fn daa(&mut self) {
+        let mut correction: u8 = 0;
+        let mut carry = self.cc.cy;
+        let low: u8 = self.a & 0xf;
+        let high: u8 = self.a >> 4;
+        if low > 9 || self.cc.ac != 0 {
+            correction |= 0x06;
+        }
+        // The high digit is checked after the low digit has been corrected,
+        // which can carry into it
+        if high > 9 || (high >= 9 && low > 9) || carry != 0 {
+            correction |= 0x60;
+            carry = 1;
+        }
+        self.cc.ac = if low + (correction & 0xf) > 0xf { 1 } else { 0 };
+        self.a = self.a.wrapping_add(correction);
+        self.set_zsp(self.a);
+        // DAA can set the carry but never clears it
+        self.cc.cy = carry;
+    }
+
+    pub fn dcr(&mut self, register: Register) {
+        let value = self.register(register);
+        let answer: u8 = value.wrapping_sub(1);
+        self.set_zsp(answer);
+        // Decrementing adds 0xff, which carries out of bit 3 unless the low
+        // nibble was 0
+        self.cc.ac = if (value & 0xf) != 0 { 1 } else { 0 };
+        self.set_register(register, answer);
+    }
+
+    pub fn inr(&mut self, register: Register) {
+        let answer: u8 = self.register(register).wrapping_add(1);
+        self.set_zsp(answer);
+        self.cc.ac = if (answer & 0xf) == 0 { 1 } else { 0 };
+        self.set_register(register, answer);
     }
 
     pub fn interrupt_enabled(&self) -> bool {
@@ -175,61 +535,347 @@ impl State8080 {
 
     pub fn enable_interrupt(&mut self) {
         self.int_enable = 1;
+        self.z80.iff2 = true;
+        self.enable_interrupt_delay = true;
     }
 
     pub fn disable_interrupt(&mut self) {
         self.int_enable = 0;
+        self.z80.iff2 = false;
+        self.enable_interrupt_delay = false;
+    }
+
+    // Z80 RETN: back to whatever was enabled before the NMI
+    pub fn restore_interrupt_from_iff2(&mut self) {
+        self.int_enable = self.z80.iff2 as u8;
+    }
+
+    // Asserts the interrupt line with `opcode` on the data bus, which is
+    // usually an RST. A later request replaces one that hasn't been
+    // acknowledged yet.
+    pub fn request_interrupt(&mut self, opcode: u8) {
+        self.request_interrupt_instruction([opcode, 0xff, 0xff]);
+    }
+
+    // Same, for a device that supplies a longer instruction (a CALL, for
+    // instance). Only as many bytes as the instruction needs are fetched, so
+    // the rest are ignored.
+    pub fn request_interrupt_instruction(&mut self, instruction: [u8; 3]) {
+        self.interrupt_request = Some(instruction);
+    }
+
+    pub fn cancel_interrupt(&mut self) {
+        self.interrupt_request = None;
+    }
+
+    pub fn interrupt_pending(&self) -> bool {
+        self.interrupt_request.is_some()
+    }
+
+    // Checked between instructions. If interrupts are enabled and a request is
+    // latched, interrupts are disabled, the CPU leaves HLT, and the next
+    // instruction is fetched from the data bus. In that case
+    // `finish_interrupt_acknowledge` has to be called once the instruction has
+    // executed. On the 8085, TRAP and the unmasked RST 7.5/6.5/5.5 inputs
+    // come first (in that order) and call their vectors directly. The Z80's
+    // NMI comes first in the same way, and in interrupt modes 1 and 2 the
+    // request is vectored rather than executed.
+    pub fn acknowledge_interrupt(&mut self) -> InterruptAcknowledge {
+        let delayed = std::mem::replace(&mut self.enable_interrupt_delay, false);
+        if self.variant == Variant::Intel8085 && self.pins.trap {
+            // TRAP can't be masked or disabled
+            self.pins.trap = false;
+            self.pins.enabled_before_trap = Some(self.interrupt_enabled());
+            self.take_vectored_interrupt(0x24);
+            return InterruptAcknowledge::Vectored(12);
+        }
+        if self.variant == Variant::Z80 && self.nmi {
+            // IFF2 is left alone so RETN can restore IFF1 from it
+            self.nmi = false;
+            self.int_enable = 0;
+            self.halted = false;
+            self.enter(0x66, true);
+            return InterruptAcknowledge::Vectored(11);
+        }
+        if delayed || !self.interrupt_enabled() {
+            return InterruptAcknowledge::None;
+        }
+        if self.variant == Variant::Intel8085 {
+            let masks = self.pins.masks;
+            if self.pins.rst7_5 && masks & 0x04 == 0 {
+                self.pins.rst7_5 = false;
+                self.take_vectored_interrupt(0x3c);
+                return InterruptAcknowledge::Vectored(12);
+            }
+            if self.pins.rst6_5 && masks & 0x02 == 0 {
+                self.take_vectored_interrupt(0x34);
+                return InterruptAcknowledge::Vectored(12);
+            }
+            if self.pins.rst5_5 && masks & 0x01 == 0 {
+                self.take_vectored_interrupt(0x2c);
+                return InterruptAcknowledge::Vectored(12);
+            }
+        }
+        let instruction = match self.interrupt_request.take() {
+            Some(instruction) => instruction,
+            None => return InterruptAcknowledge::None,
+        };
+        if self.variant == Variant::Z80 {
+            match self.z80.interrupt_mode {
+                1 => {
+                    self.take_vectored_interrupt(0x38);
+                    return InterruptAcknowledge::Vectored(13);
+                },
+                2 => {
+                    // The byte on the data bus picks an entry in a table of
+                    // handler addresses at I * 256
+                    let entry = ((self.z80.i as u16) << 8) | instruction[0] as u16;
+                    let low = self.read_memory(entry) as u16;
+                    let high = self.read_memory(entry.wrapping_add(1)) as u16;
+                    self.take_vectored_interrupt((high << 8) | low);
+                    return InterruptAcknowledge::Vectored(19);
+                },
+                _ => {},
+            }
+        }
+        self.int_enable = 0;
+        self.z80.iff2 = false;
+        self.halted = false;
+        self.data_bus = Some((instruction, 0));
+        InterruptAcknowledge::DataBus
+    }
+
+    fn take_vectored_interrupt(&mut self, vector: u16) {
+        self.int_enable = 0;
+        self.z80.iff2 = false;
+        self.halted = false;
+        self.enter(vector, true);
+    }
+
+    pub fn raise_nmi(&mut self) {
+        self.nmi = true;
     }
 
-    pub fn generate_interrupt(&mut self, interrupt_num: u16) {
-        let high = ((self.pc & 0xff00) >> 8) as u8;
-        let low = (self.pc & 0xff) as u8;
-        self.memory[self.sp as usize - 1] = high;
-        self.memory[self.sp as usize - 2] = low;
-        self.sp -= 2;
-        self.pc = 8 * interrupt_num;
+    // 8085 TRAP, RST 7.5 and SID are edge triggered or sampled, so these latch
+    // or set them. RST 6.5 and 5.5 are level inputs and stay asserted until
+    // the device lets go of them.
+    pub fn raise_trap(&mut self) {
+        self.pins.trap = true;
     }
 
-    pub fn read_memory(&self, address: usize) -> u8 {
-        self.memory[address]
+    pub fn raise_rst7_5(&mut self) {
+        self.pins.rst7_5 = true;
     }
 
-    pub fn write_memory(&mut self, address: usize, value: u8) {
-        self.memory[address] = value;
+    pub fn set_rst6_5(&mut self, level: bool) {
+        self.pins.rst6_5 = level;
     }
 
+    pub fn set_rst5_5(&mut self, level: bool) {
+        self.pins.rst5_5 = level;
+    }
+
+    pub fn set_sid(&mut self, level: bool) {
+        self.pins.sid = level;
+    }
+
+    pub fn sod(&self) -> bool {
+        self.pins.sod
+    }
+
+    // RIM: SID in bit 7, pending 7.5/6.5/5.5 in bits 6 to 4, interrupt enable
+    // in bit 3 and the masks in bits 2 to 0
+    pub fn rim(&mut self) -> u8 {
+        let enabled = self.pins.enabled_before_trap.take().unwrap_or_else(|| self.interrupt_enabled());
+        (self.pins.sid as u8) << 7
+            | (self.pins.rst7_5 as u8) << 6
+            | (self.pins.rst6_5 as u8) << 5
+            | (self.pins.rst5_5 as u8) << 4
+            | (enabled as u8) << 3
+            | self.pins.masks
+    }
+
+    // SIM: bit 3 enables setting the masks from bits 2 to 0, bit 4 resets the
+    // RST 7.5 flip-flop, and bit 6 enables latching bit 7 onto SOD
+    pub fn sim(&mut self, value: u8) {
+        if value & 0x08 != 0 {
+            self.pins.masks = value & 0x07;
+        }
+        if value & 0x10 != 0 {
+            self.pins.rst7_5 = false;
+        }
+        if value & 0x40 != 0 {
+            self.pins.sod = value & 0x80 != 0;
+        }
+    }
+
+    pub fn finish_interrupt_acknowledge(&mut self) {
+        self.data_bus = None;
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn halt(&mut self) {
+        self.halted = true;
+    }
+
+    // Unmapped reads float high, like an undriven data bus
+    pub fn read_memory(&self, address: u16) -> u8 {
+        let value = self.fetch_memory(address);
+        if self.watching(address, |kind| kind == WatchKind::Read) {
+            self.record_watch_hit(WatchHit { kind: WatchKind::Read, address, value, old_value: None });
+        }
+        value
+    }
+
+    // Watchpoints go by the address the CPU used, so a write through a mirror
+    // (Space Invaders RAM at 0x4000 and up, say) doesn't set off one on the
+    // address it mirrors
+    pub fn write_memory(&mut self, address: u16, value: u8) {
+        let watched = self.watching(address, |kind| kind != WatchKind::Read);
+        let old_value = if watched { self.memory.read(address).ok() } else { None };
+        if let Err(unmapped) = self.memory.write(address, value) {
+            self.record_bus_fault(ErrorReason::UnmappedWrite(unmapped.0));
+        }
+        if watched {
+            // Read back rather than trusting `value`, since the bus may have
+            // ignored the write (ROM, for one)
+            let changed = self.memory.read(address).ok() != old_value;
+            let kind = if self.watching(address, |kind| kind == WatchKind::Write) { WatchKind::Write } else { WatchKind::Change };
+            if kind == WatchKind::Write || changed {
+                self.record_watch_hit(WatchHit { kind, address, value, old_value });
+            }
+        }
+    }
+
+    // Instruction fetches come through here rather than `read_memory`, so a
+    // read watchpoint only goes off for data
+    fn fetch_memory(&self, address: u16) -> u8 {
+        match self.memory.read(address) {
+            Ok(value) => value,
+            Err(unmapped) => {
+                self.record_bus_fault(ErrorReason::UnmappedRead(unmapped.0));
+                0xff
+            },
+        }
+    }
+
+    fn watching<F: Fn(WatchKind) -> bool>(&self, address: u16, kind: F) -> bool {
+        self.watchpoints.iter().any(|watchpoint| kind(watchpoint.kind) && watchpoint.covers(address))
+    }
+
+    fn record_watch_hit(&self, hit: WatchHit) {
+        if self.watch_hit.get().is_none() {
+            self.watch_hit.set(Some(hit));
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        if index < self.watchpoints.len() {
+            Some(self.watchpoints.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    pub fn set_tracer(&mut self, tracer: Option<Box<Tracer>>) {
+        self.tracer = tracer;
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Box<Tracer>> {
+        self.tracer.take()
+    }
+
+    pub fn set_profiler(&mut self, profiler: Option<Box<Profiler>>) {
+        self.profiler = profiler;
+    }
+
+    pub fn take_profiler(&mut self) -> Option<Box<Profiler>> {
+        self.profiler.take()
+    }
+
+    pub fn call_stack(&self) -> &CallStack {
+        &self.call_stack
+    }
+
+    // Read straight from memory so looking at the stack can't set off a
+    // watchpoint or a bus fault
+    pub fn backtrace(&self) -> Vec<BacktraceEntry> {
+        self.call_stack.backtrace(self.pc, self.sp, |address| {
+            let low = self.memory.read(address).ok()?;
+            let high = self.memory.read(address.wrapping_add(1)).ok()?;
+            Some(u16::from_le_bytes([low, high]))
+        })
+    }
+
+    fn record_bus_fault(&self, reason: ErrorReason) {
+        if self.bus_fault.get().is_none() {
+            self.bus_fault.set(Some(reason));
+        }
+    }
+
+    pub fn take_bus_fault(&mut self) -> Option<ErrorReason> {
+        self.bus_fault.take()
+    }
+
+    // The stack pointer wraps around like any other address, so a stack that
+    // starts at 0x0000 grows down from 0xffff.
     pub fn push(&mut self, high: u8, low: u8) {
-        self.write_memory(self.sp as usize - 1, high);
-        self.write_memory(self.sp as usize - 2, low);
-        self.sp -= 2;
+        self.write_memory(self.sp.wrapping_sub(1), high);
+        self.write_memory(self.sp.wrapping_sub(2), low);
+        self.sp = self.sp.wrapping_sub(2);
     }
 
     pub fn pop(&mut self) -> (u8, u8) {
-        let low = self.read_memory(self.sp as usize);
-        let high = self.read_memory(self.sp as usize + 1);
-        self.sp += 2;
+        let low = self.read_memory(self.sp);
+        let high = self.read_memory(self.sp.wrapping_add(1));
+        self.sp = self.sp.wrapping_add(2);
         (high, low)
     }
 
+    // Pushes the return address (wherever the program counter is now) and
+    // jumps to `address`
+    pub fn call(&mut self, address: u16) {
+        // A call off the data bus is how an interrupt gets serviced
+        self.enter(address, self.data_bus.is_some());
+    }
+
+    fn enter(&mut self, address: u16, interrupt: bool) {
+        let ret = self.pc;
+        self.push(((ret >> 8) & 0xff) as u8, (ret & 0xff) as u8);
+        self.pc = address;
+        self.call_stack.call(Frame { subroutine: address, return_address: ret, sp: self.sp, interrupt });
+    }
+
+    pub fn ret(&mut self) {
+        self.call_stack.ret(self.sp);
+        let (high, low) = self.pop();
+        self.pc = ((high as u16) << 8) | low as u16;
+    }
+
+}
+
+impl State8080 {
     pub fn empty_state() -> State8080 {
-        State8080 {
-            a: 0,
-            b: 0,
-            c: 0,
-            d: 0,
-            e: 0,
-            h: 0,
-            l: 0,
-            cc: ConditionCodes { ac: 0, cy: 0, p: Parity::Odd, pad: 0, s: Sign::Positive, z: 0 },
-            int_enable: 0,
-            memory: Vec::new(),
-            sp: 0,
-            pc: 0,
-        }
+        State8080::new(Vec::new(), NoPorts)
     }
 }
 
-impl std::fmt::Debug for State8080 {
+impl<M: Bus, P: IoPorts> std::fmt::Debug for State8080<M, P> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("State8080")
             .field("a", &self.a)
@@ -242,6 +888,254 @@ impl std::fmt::Debug for State8080 {
             .field("sp", &self.sp)
             .field("pc", &self.pc)
             .field("cc", &self.cc)
+            .field("variant", &self.variant)
+            .field("halted", &self.halted)
             .finish()
     }
 }
+
+// Everything the CPU would need to pick up where it left off. States are only
+// taken between instructions, so the data bus and bus fault (which only live
+// for the length of one instruction) aren't included.
+impl<M: Bus + Snapshot, P: IoPorts + Snapshot> Snapshot for State8080<M, P> {
+    fn save(&self, out: &mut SnapshotWriter) {
+        out.u8(match self.variant {
+            Variant::Intel8080 => 0,
+            Variant::Intel8085 => 1,
+            Variant::Z80 => 2,
+        });
+        for &register in &[self.a, self.b, self.c, self.d, self.e, self.h, self.l] {
+            out.u8(register);
+        }
+        out.u16(self.sp);
+        out.u16(self.pc);
+        out.u8(self.flags().to_psw());
+        out.u8(self.cc.v);
+        out.u8(self.cc.k);
+        out.u8(self.cc.n);
+
+        out.u16(self.z80.ix);
+        out.u16(self.z80.iy);
+        out.u8(self.z80.i);
+        out.u8(self.z80.r);
+        out.u16(self.z80.af_alternate);
+        out.u16(self.z80.bc_alternate);
+        out.u16(self.z80.de_alternate);
+        out.u16(self.z80.hl_alternate);
+        out.u8(self.z80.interrupt_mode);
+        out.bool(self.z80.iff2);
+
+        out.u8(self.int_enable);
+        out.bool(self.halted);
+        out.bool(self.nmi);
+        out.bool(self.enable_interrupt_delay);
+        match self.interrupt_request {
+            Some(instruction) => {
+                out.bool(true);
+                instruction.iter().for_each(|&byte| out.u8(byte));
+            },
+            None => out.bool(false),
+        }
+
+        let pins = &self.pins;
+        for &pin in &[pins.trap, pins.rst7_5, pins.rst6_5, pins.rst5_5, pins.sid, pins.sod] {
+            out.bool(pin);
+        }
+        out.u8(pins.masks);
+        out.u8(match pins.enabled_before_trap {
+            None => 0,
+            Some(false) => 1,
+            Some(true) => 2,
+        });
+
+        self.memory.save(out);
+        self.io.save(out);
+    }
+
+    fn load(&mut self, input: &mut SnapshotReader) -> Result<(), SaveStateError> {
+        self.variant = match input.u8()? {
+            0 => Variant::Intel8080,
+            1 => Variant::Intel8085,
+            2 => Variant::Z80,
+            _ => return Err(SaveStateError::Invalid("CPU variant")),
+        };
+        self.a = input.u8()?;
+        self.b = input.u8()?;
+        self.c = input.u8()?;
+        self.d = input.u8()?;
+        self.e = input.u8()?;
+        self.h = input.u8()?;
+        self.l = input.u8()?;
+        self.sp = input.u16()?;
+        self.pc = input.u16()?;
+        let psw = input.u8()?;
+        self.set_flags(Flags::from_psw(psw));
+        self.cc.v = input.u8()?;
+        self.cc.k = input.u8()?;
+        self.cc.n = input.u8()?;
+
+        self.z80.ix = input.u16()?;
+        self.z80.iy = input.u16()?;
+        self.z80.i = input.u8()?;
+        self.z80.r = input.u8()?;
+        self.z80.af_alternate = input.u16()?;
+        self.z80.bc_alternate = input.u16()?;
+        self.z80.de_alternate = input.u16()?;
+        self.z80.hl_alternate = input.u16()?;
+        self.z80.interrupt_mode = input.u8()?;
+        self.z80.iff2 = input.bool()?;
+
+        self.int_enable = input.u8()?;
+        self.halted = input.bool()?;
+        self.nmi = input.bool()?;
+        self.enable_interrupt_delay = input.bool()?;
+        self.interrupt_request = if input.bool()? {
+            Some([input.u8()?, input.u8()?, input.u8()?])
+        } else {
+            None
+        };
+
+        self.pins.trap = input.bool()?;
+        self.pins.rst7_5 = input.bool()?;
+        self.pins.rst6_5 = input.bool()?;
+        self.pins.rst5_5 = input.bool()?;
+        self.pins.sid = input.bool()?;
+        self.pins.sod = input.bool()?;
+        self.pins.masks = input.u8()?;
+        self.pins.enabled_before_trap = match input.u8()? {
+            0 => None,
+            1 => Some(false),
+            2 => Some(true),
+            _ => return Err(SaveStateError::Invalid("8085 TRAP state")),
+        };
+        self.data_bus = None;
+        self.bus_fault.set(None);
+        // Savestates don't keep the shadow stack, and the old one is no use
+        // with a different stack
+        self.call_stack.clear();
+
+        self.memory.load(input)?;
+        self.io.load(input)
+    }
+}
+
+mod test {
+    #[allow(unused)] use super::*;
+
+    #[test]
+    fn test_register_m_uses_hl() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0; 0x10];
+        state.set_register_pair(RegisterPair::HL, 0x0004);
+        state.set_register(Register::M, 0x5a);
+        assert_eq!(state.memory[4], 0x5a);
+        assert_eq!(state.register(Register::M), 0x5a);
+    }
+
+    #[test]
+    fn test_register_pairs() {
+        let mut state = State8080::empty_state();
+        state.set_register_pair(RegisterPair::DE, 0x1234);
+        assert_eq!(state.register(Register::D), 0x12);
+        assert_eq!(state.register(Register::E), 0x34);
+        state.set_register_pair(RegisterPair::SP, 0x2400);
+        assert_eq!(state.sp, 0x2400);
+    }
+
+    #[test]
+    fn test_psw_8080_ignores_v_and_k() {
+        let mut state = State8080::empty_state();
+        state.set_register_pair(RegisterPair::Psw, 0x42ff);
+        assert_eq!(state.a, 0x42);
+        assert_eq!(state.cc.v, 0);
+        assert_eq!(state.register_pair(RegisterPair::Psw), 0x42d7);
+    }
+
+    #[test]
+    fn test_psw_8085_keeps_v_and_k() {
+        let mut state = State8080::empty_state();
+        state.set_variant(Variant::Intel8085);
+        state.set_register_pair(RegisterPair::Psw, 0x42ff);
+        assert_eq!(state.cc.v, 1);
+        assert_eq!(state.cc.k, 1);
+        assert_eq!(state.register_pair(RegisterPair::Psw), 0x42f7);
+    }
+
+    #[test]
+    fn test_psw_z80_keeps_n() {
+        let mut state = State8080::empty_state();
+        state.set_variant(Variant::Z80);
+        state.cc.n = 1;
+        state.set_flags(Flags::CARRY);
+        assert_eq!(state.register_pair(RegisterPair::Psw) as u8, 0x03);
+        state.set_register_pair(RegisterPair::Psw, 0x4241);
+        assert_eq!(state.cc.n, 0);
+        assert_eq!(state.register_pair(RegisterPair::Psw), 0x4241);
+        state.set_register_pair(RegisterPair::Psw, 0x42ff);
+        assert_eq!(state.cc.n, 1);
+        assert_eq!(state.register_pair(RegisterPair::Psw), 0x42d7);
+    }
+
+    #[test]
+    fn test_inr_dcr_memory() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x0f, 0x00];
+        state.inr(Register::M);
+        assert_eq!(state.memory[0], 0x10);
+        assert_eq!(state.cc.ac, 1);
+        state.set_register_pair(RegisterPair::HL, 0x0001);
+        state.dcr(Register::M);
+        assert_eq!(state.memory[1], 0xff);
+        assert_eq!(state.cc.s, Sign::Negative);
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0x00, 0x76, 0x12];
+        state.set_variant(Variant::Intel8085);
+        state.set_register_pair(RegisterPair::BC, 0x1234);
+        state.set_register_pair(RegisterPair::Psw, 0xabd7);
+        state.sp = 0x0002;
+        state.set_program_counter(0x0001);
+        state.cc.v = 1;
+        state.enable_interrupt();
+        state.request_interrupt(0xcf);
+        state.sim(0x18);
+        state.z80.ix = 0xbeef;
+
+        let bytes = crate::savestate::save(&state, "test");
+        let mut restored = State8080::empty_state();
+        restored.memory = vec![0; 3];
+        crate::savestate::load(&mut restored, "test", &bytes).unwrap();
+        assert_eq!(crate::savestate::save(&restored, "test"), bytes);
+        assert_eq!(restored.variant(), Variant::Intel8085);
+        assert_eq!(restored.register_pair(RegisterPair::BC), 0x1234);
+        assert_eq!(restored.register_pair(RegisterPair::Psw), 0xabd7);
+        assert_eq!(restored.program_counter(), 0x0001);
+        assert_eq!(restored.memory, vec![0x00, 0x76, 0x12]);
+        assert!(restored.interrupt_pending());
+        assert_eq!(restored.z80.ix, 0xbeef);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut state = State8080::empty_state();
+        state.memory = vec![0; 0x10];
+        state.add_watchpoint(Watchpoint { start: 0x04, end: 0x07, kind: WatchKind::Change });
+        state.add_watchpoint(Watchpoint { start: 0x08, end: 0x08, kind: WatchKind::Read });
+        state.write_memory(0x04, 0x00);
+        assert_eq!(state.take_watch_hit(), None);
+        state.write_memory(0x07, 0x12);
+        assert_eq!(state.take_watch_hit(), Some(WatchHit { kind: WatchKind::Change, address: 0x07, value: 0x12, old_value: Some(0x00) }));
+        state.read_memory(0x07);
+        assert_eq!(state.take_watch_hit(), None);
+        state.read_memory(0x08);
+        state.read_memory(0x08);
+        assert_eq!(state.take_watch_hit(), Some(WatchHit { kind: WatchKind::Read, address: 0x08, value: 0x00, old_value: None }));
+        assert_eq!(state.take_watch_hit(), None);
+        assert!(state.remove_watchpoint(1).is_some());
+        state.read_memory(0x08);
+        assert_eq!(state.take_watch_hit(), None);
+    }
+}
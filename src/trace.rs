@@ -0,0 +1,409 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+
+use crate::bus::Bus;
+use crate::error::EmulatorError;
+use crate::disassembler::{self, MAX_INSTRUCTION_SIZE};
+use crate::instruction::RegisterPair;
+use crate::io_ports::IoPorts;
+use crate::state_8080::{State8080, Variant};
+
+// Execution tracing. A `Tracer` is handed to the CPU once at startup (see
+// `from_env`) and from then on sees every instruction `emulate_8080_op` runs,
+// with the registers as they were just before it ran. Records can go to a
+// file, and the last few are always kept in memory so they can be dumped when
+// the CPU hits an error.
+//
+// The binary format is a header followed by fixed size records:
+//
+//   "8080TRAC"  magic
+//   u16         format version
+//
+// and then for each instruction, little endian:
+//
+//   u8          variant (0 8080, 1 8085, 2 Z80)
+//   u16         PC
+//   u8 + 4      instruction length and bytes, padded with zeros
+//   8 x u8      A, B, C, D, E, H, L and the flags
+//   u16         SP
+//   u64         cycles run before this instruction
+//
+// Mnemonics aren't stored, since they can be decoded again from the bytes.
+// A Z80 instruction behind a run of redundant DD or FD prefixes is cut short
+// at four bytes.
+const MAGIC: &[u8; 8] = b"8080TRAC";
+pub const VERSION: u16 = 2;
+pub const HEADER_SIZE: usize = 10;
+pub const RECORD_SIZE: usize = 26;
+
+// How many instructions the crash ring keeps when TRACE_RING isn't set
+const DEFAULT_RING_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,
+    Binary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub variant: Variant,
+    pub pc: u16,
+    pub length: u8,
+    pub bytes: [u8; MAX_INSTRUCTION_SIZE],
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub flags: u8,
+    pub sp: u16,
+    pub cycles: u64,
+}
+
+impl TraceRecord {
+    // The instruction at PC, read straight from the bus so that tracing
+    // doesn't set off watchpoints or bus faults of its own. An instruction
+    // taken from the data bus while acknowledging an interrupt shows up as
+    // whatever was in memory at PC.
+    pub fn capture<M: Bus, P: IoPorts>(state: &State8080<M, P>, cycles: u64) -> TraceRecord {
+        let pc = state.program_counter();
+        let mut bytes = [0; MAX_INSTRUCTION_SIZE];
+        let read = |offset: usize| state.memory.read(pc.wrapping_add(offset as u16)).unwrap_or(0xff);
+        let (_, length) = disassembler::disassemble(state.variant(), pc, read);
+        let length = length.min(MAX_INSTRUCTION_SIZE);
+        for (offset, byte) in bytes.iter_mut().enumerate().take(length) {
+            *byte = state.memory.read(pc.wrapping_add(offset as u16)).unwrap_or(0xff);
+        }
+        TraceRecord {
+            variant: state.variant(),
+            pc,
+            length: length as u8,
+            bytes,
+            a: state.a,
+            b: state.b,
+            c: state.c,
+            d: state.d,
+            e: state.e,
+            h: state.h,
+            l: state.l,
+            flags: state.register_pair(RegisterPair::Psw) as u8,
+            sp: state.sp,
+            cycles,
+        }
+    }
+
+    pub fn mnemonic(&self) -> String {
+        let (text, _) = disassembler::disassemble(self.variant, self.pc, |offset| self.bytes.get(offset).copied().unwrap_or(0));
+        text.replace('\t', " ")
+    }
+
+    pub fn write_binary<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut record = Vec::with_capacity(RECORD_SIZE);
+        record.push(match self.variant {
+            Variant::Intel8080 => 0,
+            Variant::Intel8085 => 1,
+            Variant::Z80 => 2,
+        });
+        record.extend_from_slice(&self.pc.to_le_bytes());
+        record.push(self.length);
+        record.extend_from_slice(&self.bytes);
+        record.extend_from_slice(&[self.a, self.b, self.c, self.d, self.e, self.h, self.l, self.flags]);
+        record.extend_from_slice(&self.sp.to_le_bytes());
+        record.extend_from_slice(&self.cycles.to_le_bytes());
+        out.write_all(&record)
+    }
+
+    // The next record, or None at the end of the trace
+    pub fn read_binary<R: Read>(input: &mut R) -> io::Result<Option<TraceRecord>> {
+        let mut record = [0; RECORD_SIZE];
+        match input.read_exact(&mut record) {
+            Ok(()) => {},
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let variant = match record[0] {
+            0 => Variant::Intel8080,
+            1 => Variant::Intel8085,
+            2 => Variant::Z80,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid variant in trace")),
+        };
+        let mut cycles = [0; 8];
+        cycles.copy_from_slice(&record[18..]);
+        Ok(Some(TraceRecord {
+            variant,
+            pc: u16::from_le_bytes([record[1], record[2]]),
+            length: record[3].min(MAX_INSTRUCTION_SIZE as u8),
+            bytes: [record[4], record[5], record[6], record[7]],
+            a: record[8],
+            b: record[9],
+            c: record[10],
+            d: record[11],
+            e: record[12],
+            h: record[13],
+            l: record[14],
+            flags: record[15],
+            sp: u16::from_le_bytes([record[16], record[17]]),
+            cycles: u64::from_le_bytes(cycles),
+        }))
+    }
+}
+
+// Whether `header` starts a binary trace this build can read
+pub fn is_binary_trace(header: &[u8]) -> bool {
+    header.len() >= HEADER_SIZE
+        && &header[..MAGIC.len()] == MAGIC
+        && u16::from_le_bytes([header[MAGIC.len()], header[MAGIC.len() + 1]]) == VERSION
+}
+
+// One line per instruction:
+//   0100  c3 ab 01        JMP $01ab         A=00 B=00 ... SP=fe00 F=02 CYC=0
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes[..self.length as usize].iter().map(|byte| format!("{:02x}", byte)).collect();
+        write!(
+            f,
+            "{:04x}  {:<11}  {:<16}  A={:02x} B={:02x} C={:02x} D={:02x} E={:02x} H={:02x} L={:02x} SP={:04x} F={:02x} CYC={}",
+            self.pc, bytes.join(" "), self.mnemonic(),
+            self.a, self.b, self.c, self.d, self.e, self.h, self.l, self.sp, self.flags, self.cycles,
+        )
+    }
+}
+
+pub struct Tracer {
+    output: Option<(Box<dyn Write>, TraceFormat)>,
+    ring: VecDeque<TraceRecord>,
+    ring_size: usize,
+    cycles: u64,
+}
+
+impl Tracer {
+    // Only keeps the last `ring_size` instructions for dumping on an error
+    pub fn new(ring_size: usize) -> Tracer {
+        Tracer {
+            output: None,
+            ring: VecDeque::with_capacity(ring_size),
+            ring_size,
+            cycles: 0,
+        }
+    }
+
+    // Also writes every instruction to `output`
+    pub fn with_output(ring_size: usize, mut output: Box<dyn Write>, format: TraceFormat) -> io::Result<Tracer> {
+        if format == TraceFormat::Binary {
+            output.write_all(MAGIC)?;
+            output.write_all(&VERSION.to_le_bytes())?;
+        }
+        let mut tracer = Tracer::new(ring_size);
+        tracer.output = Some((output, format));
+        Ok(tracer)
+    }
+
+    // Runs one instruction through `execute`, recording it on the way. The
+    // CPU sitting in HLT isn't recorded, only the cycles it burns. An error
+    // gets the ring dumped to stderr; reporting the error itself is up to
+    // whoever ran the instruction.
+    pub fn trace<M: Bus, P: IoPorts, F>(&mut self, state: &mut State8080<M, P>, execute: F) -> Result<u32, EmulatorError>
+    where
+        F: FnOnce(&mut State8080<M, P>) -> Result<u32, EmulatorError>,
+    {
+        let record = if state.halted() { None } else { Some(TraceRecord::capture(state, self.cycles)) };
+        let result = execute(state);
+        if let Some(record) = record {
+            self.record(record);
+        }
+        match &result {
+            Ok(cycles) => self.cycles += *cycles as u64,
+            Err(_) => {
+                let _ = self.flush();
+                let _ = self.dump_ring(&mut io::stderr());
+            },
+        }
+        result
+    }
+
+    fn record(&mut self, record: TraceRecord) {
+        if self.ring_size > 0 {
+            if self.ring.len() == self.ring_size {
+                self.ring.pop_front();
+            }
+            self.ring.push_back(record);
+        }
+        if let Some((output, format)) = &mut self.output {
+            let result = match format {
+                TraceFormat::Text => writeln!(output, "{}", record),
+                TraceFormat::Binary => record.write_binary(output),
+            };
+            // Giving up on the file is better than failing the program over it
+            if let Err(err) = result {
+                eprintln!("Error writing trace, tracing to file stopped: {}", err);
+                self.output = None;
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn ring(&self) -> impl Iterator<Item = &TraceRecord> {
+        self.ring.iter()
+    }
+
+    pub fn dump_ring<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        if self.ring.is_empty() {
+            return Ok(());
+        }
+        writeln!(out, "Last {} instructions, oldest first:", self.ring.len())?;
+        for record in &self.ring {
+            writeln!(out, "{}", record)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.output {
+            Some((output, _)) => output.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+// Buffered output goes out when the tracer does, unless the process exits
+// without dropping it, which is why errors flush first
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+// Sets tracing up from the environment, once at startup:
+//   TRACE_FILE    write every instruction to this file
+//   TRACE_FORMAT  "text" (the default) or "binary"
+//   TRACE_RING    how many instructions to dump on an error (default 64).
+//                 Setting this on its own turns on just the dump.
+// Returns None if neither TRACE_FILE nor TRACE_RING is set.
+pub fn from_env() -> Result<Option<Tracer>, String> {
+    let file = std::env::var("TRACE_FILE").ok();
+    let ring = std::env::var("TRACE_RING").ok();
+    if file.is_none() && ring.is_none() {
+        return Ok(None);
+    }
+    let ring_size = match ring {
+        Some(ring) => ring.parse::<usize>().map_err(|_| format!("TRACE_RING should be a number, not '{}'", ring))?,
+        None => DEFAULT_RING_SIZE,
+    };
+    let file = match file {
+        Some(file) => file,
+        None => return Ok(Some(Tracer::new(ring_size))),
+    };
+    let format = match std::env::var("TRACE_FORMAT").as_deref() {
+        Err(_) | Ok("text") => TraceFormat::Text,
+        Ok("binary") => TraceFormat::Binary,
+        Ok(format) => return Err(format!("TRACE_FORMAT should be text or binary, not '{}'", format)),
+    };
+    let output = File::create(&file).map_err(|err| format!("Unable to create trace file '{}': {}", file, err))?;
+    Tracer::with_output(ring_size, Box::new(BufWriter::new(output)), format)
+        .map(Some)
+        .map_err(|err| err.to_string())
+}
+
+mod test {
+    #[allow(unused)] use super::*;
+    #[allow(unused)] use crate::emulator;
+    #[allow(unused)] use std::cell::RefCell;
+    #[allow(unused)] use std::rc::Rc;
+
+    // Somewhere to write a trace that the test can still get at afterwards
+    #[allow(unused)]
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[allow(unused)]
+    fn traced_state(memory: Vec<u8>, tracer: Tracer) -> State8080 {
+        let mut state = State8080::empty_state();
+        state.memory = memory;
+        state.set_tracer(Some(Box::new(tracer)));
+        state
+    }
+
+    #[test]
+    fn test_text_trace() {
+        let buffer = SharedBuffer::default();
+        let tracer = Tracer::with_output(4, Box::new(buffer.clone()), TraceFormat::Text).unwrap();
+        // LXI H,1234; INR A; HLT
+        let mut state = traced_state(vec![0x21, 0x34, 0x12, 0x3c, 0x76], tracer);
+        emulator::run(&mut state).unwrap();
+        emulator::emulate_8080_op(&mut state).unwrap();
+        let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert_eq!(
+            trace,
+            "0000  21 34 12     LXI H,#$1234      A=00 B=00 C=00 D=00 E=00 H=00 L=00 SP=0000 F=02 CYC=0\n\
+             0003  3c           INR A             A=00 B=00 C=00 D=00 E=00 H=12 L=34 SP=0000 F=02 CYC=10\n\
+             0004  76           HLT               A=01 B=00 C=00 D=00 E=00 H=12 L=34 SP=0000 F=02 CYC=15\n",
+        );
+    }
+
+    #[test]
+    fn test_binary_trace() {
+        let buffer = SharedBuffer::default();
+        let tracer = Tracer::with_output(0, Box::new(buffer.clone()), TraceFormat::Binary).unwrap();
+        let mut state = traced_state(vec![0x3c, 0x76], tracer);
+        emulator::run(&mut state).unwrap();
+        let trace = buffer.0.borrow().clone();
+        assert!(is_binary_trace(&trace));
+        assert_eq!(trace.len(), 10 + 2 * RECORD_SIZE);
+        let second = &trace[10 + RECORD_SIZE..];
+        assert_eq!(second[..8], [0, 0x01, 0x00, 1, 0x76, 0, 0, 0]);
+        assert_eq!(second[8], 1); // A
+        assert_eq!(second[18..], 5u64.to_le_bytes());
+        let mut input = &trace[HEADER_SIZE..];
+        let first = TraceRecord::read_binary(&mut input).unwrap().unwrap();
+        assert_eq!(first.to_string(), "0000  3c           INR A             A=00 B=00 C=00 D=00 E=00 H=00 L=00 SP=0000 F=02 CYC=0");
+        assert_eq!(TraceRecord::read_binary(&mut input).unwrap().unwrap().pc, 1);
+        assert_eq!(TraceRecord::read_binary(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn test_ring() {
+        // Four INR As and then an LDA from unmapped memory
+        let mut state = traced_state(vec![0x3c, 0x3c, 0x3c, 0x3c, 0x3a, 0x00, 0x10], Tracer::new(3));
+        assert!(emulator::run(&mut state).is_err());
+        let tracer = state.take_tracer().unwrap();
+        let pcs: Vec<u16> = tracer.ring().map(|record| record.pc).collect();
+        assert_eq!(pcs, vec![0x0002, 0x0003, 0x0004]);
+        let mut dump = Vec::new();
+        tracer.dump_ring(&mut dump).unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+        assert!(dump.starts_with("Last 3 instructions, oldest first:\n0002  3c"));
+        assert!(dump.ends_with("LDA $1000         A=04 B=00 C=00 D=00 E=00 H=00 L=00 SP=0000 F=02 CYC=20\n"));
+    }
+
+    #[test]
+    fn test_z80_record() {
+        // LD (IX+2),$42 and then JR back to it
+        let mut state = State8080::empty_state();
+        state.set_variant(Variant::Z80);
+        state.memory = vec![0xdd, 0x36, 0x02, 0x42, 0x18, 0xfa];
+        let record = TraceRecord::capture(&state, 0);
+        assert_eq!((record.length, record.bytes), (4, [0xdd, 0x36, 0x02, 0x42]));
+        assert_eq!(record.mnemonic(), "LD (IX+$02),#$42");
+        let mut binary = Vec::new();
+        record.write_binary(&mut binary).unwrap();
+        assert_eq!(TraceRecord::read_binary(&mut &binary[..]).unwrap(), Some(record));
+        state.set_program_counter(4);
+        assert_eq!(TraceRecord::capture(&state, 0).mnemonic(), "JR $0000");
+    }
+}
@@ -0,0 +1,399 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+use crate::trace::{self, TraceRecord};
+
+// Lines up one of our traces with a reference trace, instruction by
+// instruction, and reports the first place they disagree. The reference can
+// come from anywhere that logs registers before each instruction; a mapping
+// says where in each line to find them:
+//
+//   pc:0,af:AF:&ffd7,bc:BC:,de:DE:,hl:HL:,sp:SP:
+//
+// Each entry is `<field>:<source>`, optionally followed by `&<mask>`. The
+// fields are pc, a, b, c, d, e, h, l, f, sp and cyc, or the pairs af, bc, de
+// and hl. A source that's a number is a whitespace separated column, counting
+// from 0. Anything else is a label, and the value is the rest of the first
+// column starting with it, or the column after if that's all there is. Values
+// are hex, with or without a leading $ or 0x, except for cyc which is decimal
+// like cycle counts usually are. A mask is applied to that field
+// on both sides before comparing, for flag bits the two emulators don't
+// agree on.
+//
+// Lines without all of the mapped fields (headers, program output) are
+// skipped, and fields only one side has aren't compared.
+
+// The mapping for our own text traces, also used for the reference if none is
+// given (say, a trace from an earlier build)
+const DEFAULT_MAPPING: &str = "pc:0,a:A=,b:B=,c:C=,d:D=,e:E=,h:H=,l:L=,sp:SP=,f:F=,cyc:CYC=";
+
+const FIELD_NAMES: [&str; 11] = ["PC", "A", "B", "C", "D", "E", "H", "L", "F", "SP", "CYC"];
+const PC: usize = 0;
+const A: usize = 1;
+const B: usize = 2;
+const C: usize = 3;
+const D: usize = 4;
+const E: usize = 5;
+const H: usize = 6;
+const L: usize = 7;
+const F: usize = 8;
+const SP: usize = 9;
+const CYC: usize = 10;
+
+// One instruction from either trace, with the line it came from for reporting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    values: [Option<u64>; 11],
+    text: String,
+}
+
+impl Entry {
+    fn from_record(record: &TraceRecord) -> Entry {
+        let mut values = [None; 11];
+        for (field, value) in [
+            (PC, record.pc as u64),
+            (A, record.a as u64),
+            (B, record.b as u64),
+            (C, record.c as u64),
+            (D, record.d as u64),
+            (E, record.e as u64),
+            (H, record.h as u64),
+            (L, record.l as u64),
+            (F, record.flags as u64),
+            (SP, record.sp as u64),
+            (CYC, record.cycles),
+        ] {
+            values[field] = Some(value);
+        }
+        Entry { values, text: record.to_string() }
+    }
+}
+
+enum Source {
+    Column(usize),
+    Label(String),
+}
+
+// A field, or a pair of them split high byte then low byte
+struct Column {
+    fields: Vec<usize>,
+    source: Source,
+}
+
+pub struct Mapping {
+    columns: Vec<Column>,
+    masks: [u64; 11],
+}
+
+impl Mapping {
+    pub fn parse(spec: &str) -> Result<Mapping, String> {
+        let mut columns = Vec::new();
+        let mut masks = [u64::MAX; 11];
+        for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (field, source) = entry.split_once(':').ok_or_else(|| format!("expected <field>:<source> in '{}'", entry))?;
+            let fields = match field.to_lowercase().as_str() {
+                "af" => vec![A, F],
+                "bc" => vec![B, C],
+                "de" => vec![D, E],
+                "hl" => vec![H, L],
+                name => match FIELD_NAMES.iter().position(|field| field.to_lowercase() == name) {
+                    Some(field) => vec![field],
+                    None => return Err(format!("unknown field '{}'", field)),
+                },
+            };
+            let (source, mask) = match source.rsplit_once('&') {
+                Some((source, mask)) => {
+                    let mask = parse_hex(mask).ok_or_else(|| format!("mask '{}' isn't hex", mask))?;
+                    (source, Some(mask))
+                },
+                None => (source, None),
+            };
+            if let Some(mask) = mask {
+                // Split the same way the value will be
+                match fields.as_slice() {
+                    [field] => masks[*field] = mask,
+                    [high, low] => {
+                        masks[*high] = (mask >> 8) & 0xff;
+                        masks[*low] = mask & 0xff;
+                    },
+                    _ => unreachable!(),
+                }
+            }
+            let source = match source.parse::<usize>() {
+                Ok(column) => Source::Column(column),
+                Err(_) if source.is_empty() => return Err(format!("no source for '{}'", field)),
+                Err(_) => Source::Label(source.to_string()),
+            };
+            columns.push(Column { fields, source });
+        }
+        if columns.is_empty() {
+            return Err("mapping is empty".to_string());
+        }
+        Ok(Mapping { columns, masks })
+    }
+
+    // None if the line is missing any of the mapped fields
+    pub fn entry(&self, line: &str) -> Option<Entry> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let mut values = [None; 11];
+        for column in &self.columns {
+            let text = match &column.source {
+                Source::Column(index) => *tokens.get(*index)?,
+                Source::Label(label) => {
+                    let index = tokens.iter().position(|token| token.starts_with(label.as_str()))?;
+                    match &tokens[index][label.len()..] {
+                        "" => *tokens.get(index + 1)?,
+                        rest => rest,
+                    }
+                },
+            };
+            let value = if column.fields == [CYC] { parse_decimal(text)? } else { parse_hex(text)? };
+            match column.fields.as_slice() {
+                [field] => values[*field] = Some(value),
+                [high, low] => {
+                    values[*high] = Some((value >> 8) & 0xff);
+                    values[*low] = Some(value & 0xff);
+                },
+                _ => unreachable!(),
+            }
+        }
+        Some(Entry { values, text: line.to_string() })
+    }
+}
+
+// Leading hex digits, after an optional $ or 0x. Whatever follows them (a
+// comma, say) is ignored.
+fn parse_hex(text: &str) -> Option<u64> {
+    let text = text.trim_start_matches('$');
+    let text = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    let end = text.find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(text.len());
+    u64::from_str_radix(&text[..end], 16).ok()
+}
+
+fn parse_decimal(text: &str) -> Option<u64> {
+    let end = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    text[..end].parse().ok()
+}
+
+// Reads one of our traces, whichever format it's in
+pub fn read_trace<'a, R: BufRead + 'a>(mut input: R) -> io::Result<Box<dyn Iterator<Item = io::Result<Entry>> + 'a>> {
+    let header = input.fill_buf()?;
+    if trace::is_binary_trace(header) {
+        input.consume(trace::HEADER_SIZE);
+        let records = std::iter::from_fn(move || TraceRecord::read_binary(&mut input).transpose());
+        return Ok(Box::new(records.map(|record| record.map(|record| Entry::from_record(&record)))));
+    }
+    Ok(read_lines(input, Mapping::parse(DEFAULT_MAPPING).unwrap()))
+}
+
+// Reads a text trace through `mapping`, skipping lines that don't fit it
+pub fn read_lines<'a, R: BufRead + 'a>(input: R, mapping: Mapping) -> Box<dyn Iterator<Item = io::Result<Entry>> + 'a> {
+    Box::new(input.lines().filter_map(move |line| match line {
+        Ok(line) => mapping.entry(&line).map(Ok),
+        Err(err) => Some(Err(err)),
+    }))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Comparison {
+    // Both traces ran to the end together
+    Matched(usize),
+    // One trace ends after this many instructions, all of which matched
+    OursEnded(usize),
+    ReferenceEnded(usize),
+    Diverged(Box<Divergence>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Divergence {
+    // Counting from 1
+    pub instruction: usize,
+    pub previous: Option<Entry>,
+    pub ours: Entry,
+    pub reference: Entry,
+    // The fields that differ, with our value and then the reference's
+    pub differences: Vec<(&'static str, u64, u64)>,
+}
+
+pub fn compare<O, R>(ours: O, reference: R, masks: &[u64; 11]) -> io::Result<Comparison>
+where
+    O: Iterator<Item = io::Result<Entry>>,
+    R: Iterator<Item = io::Result<Entry>>,
+{
+    let mut ours = ours;
+    let mut reference = reference;
+    let mut previous = None;
+    let mut count = 0;
+    loop {
+        let (ours_entry, reference_entry) = match (ours.next().transpose()?, reference.next().transpose()?) {
+            (None, None) => return Ok(Comparison::Matched(count)),
+            (None, Some(_)) => return Ok(Comparison::OursEnded(count)),
+            (Some(_), None) => return Ok(Comparison::ReferenceEnded(count)),
+            (Some(ours_entry), Some(reference_entry)) => (ours_entry, reference_entry),
+        };
+        count += 1;
+        let differences: Vec<(&'static str, u64, u64)> = (0..FIELD_NAMES.len())
+            .filter_map(|field| match (ours_entry.values[field], reference_entry.values[field]) {
+                (Some(ours_value), Some(reference_value))
+                    if ours_value & masks[field] != reference_value & masks[field] =>
+                {
+                    Some((FIELD_NAMES[field], ours_value, reference_value))
+                },
+                _ => None,
+            })
+            .collect();
+        if !differences.is_empty() {
+            return Ok(Comparison::Diverged(Box::new(Divergence {
+                instruction: count,
+                previous,
+                ours: ours_entry,
+                reference: reference_entry,
+                differences,
+            })));
+        }
+        previous = Some(ours_entry);
+    }
+}
+
+pub fn report<W: Write>(comparison: &Comparison, out: &mut W) -> io::Result<()> {
+    match comparison {
+        Comparison::Matched(count) => writeln!(out, "Traces match ({} instructions)", count),
+        Comparison::OursEnded(count) => writeln!(out, "Traces match until ours ends after {} instructions", count),
+        Comparison::ReferenceEnded(count) => {
+            writeln!(out, "Traces match until the reference ends after {} instructions", count)
+        },
+        Comparison::Diverged(divergence) => {
+            writeln!(out, "Traces diverge at instruction {}", divergence.instruction)?;
+            if let Some(previous) = &divergence.previous {
+                writeln!(out, "  last matching:  {}", previous.text)?;
+            }
+            writeln!(out, "  ours:           {}", divergence.ours.text)?;
+            writeln!(out, "  reference:      {}", divergence.reference.text)?;
+            for (field, ours_value, reference_value) in &divergence.differences {
+                writeln!(out, "  {:<3} {:x} here, {:x} in the reference", field, ours_value, reference_value)?;
+            }
+            Ok(())
+        },
+    }
+}
+
+// Compares the trace at `ours` with the one at `reference` and prints the
+// result. Returns whether they matched.
+pub fn run(ours: &str, reference: &str, mapping: Option<&str>) -> Result<bool, String> {
+    let open = |path: &str| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|err| format!("Unable to open trace '{}': {}", path, err))
+    };
+    let ours_trace = read_trace(open(ours)?).map_err(|err| err.to_string())?;
+    let mapping = Mapping::parse(mapping.unwrap_or(DEFAULT_MAPPING))?;
+    let masks = mapping.masks;
+    // An earlier build's binary trace works as a reference too
+    let reference_trace = if is_binary_file(reference) {
+        read_trace(open(reference)?).map_err(|err| err.to_string())?
+    } else {
+        read_lines(open(reference)?, mapping)
+    };
+    let comparison = compare(ours_trace, reference_trace, &masks).map_err(|err| err.to_string())?;
+    report(&comparison, &mut io::stdout()).map_err(|err| err.to_string())?;
+    Ok(matches!(comparison, Comparison::Matched(_)))
+}
+
+fn is_binary_file(path: &str) -> bool {
+    let mut header = [0; trace::HEADER_SIZE];
+    File::open(path).and_then(|mut file| file.read_exact(&mut header)).is_ok() && trace::is_binary_trace(&header)
+}
+
+mod test {
+    #[allow(unused)] use super::*;
+
+    #[allow(unused)]
+    fn entries(text: &str, mapping: &str) -> Vec<Entry> {
+        read_lines(text.as_bytes(), Mapping::parse(mapping).unwrap()).map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn test_mapping() {
+        let mapping = Mapping::parse("pc:0,af:AF:&ffd7,hl:HL:,sp:SP=").unwrap();
+        let entry = mapping.entry("0100 JMP $0150   AF: 12c7, HL: $beef SP=0x3ffe").unwrap();
+        assert_eq!(entry.values[PC], Some(0x0100));
+        assert_eq!(entry.values[A], Some(0x12));
+        assert_eq!(entry.values[F], Some(0xc7));
+        assert_eq!(entry.values[H], Some(0xbe));
+        assert_eq!(entry.values[L], Some(0xef));
+        assert_eq!(entry.values[SP], Some(0x3ffe));
+        assert_eq!(entry.values[B], None);
+        assert_eq!(mapping.masks[A], 0xff);
+        assert_eq!(mapping.masks[F], 0xd7);
+        assert_eq!(mapping.entry("Running test 1..."), None);
+        assert!(Mapping::parse("ix:0").is_err());
+        assert!(Mapping::parse("pc").is_err());
+    }
+
+    #[test]
+    fn test_default_mapping_reads_our_text_traces() {
+        let line = "0003  3c           INR A             A=00 B=01 C=02 D=03 E=04 H=12 L=34 SP=fffe F=02 CYC=10";
+        let entry = Mapping::parse(DEFAULT_MAPPING).unwrap().entry(line).unwrap();
+        let values: Vec<u64> = entry.values.iter().map(|value| value.unwrap()).collect();
+        assert_eq!(values, vec![0x03, 0x00, 0x01, 0x02, 0x03, 0x04, 0x12, 0x34, 0x02, 0xfffe, 10]);
+    }
+
+    #[test]
+    fn test_divergence() {
+        let ours = "0000 A=00 F=02\n0001 A=01 F=02\n0002 A=02 F=02\n";
+        let reference = "pc=0000 a=00 f=02\nsome output\npc=0001 a=01 f=02\npc=0002 a=03 f=06\n";
+        let mapping = Mapping::parse("pc:pc=,a:a=,f:f=").unwrap();
+        let comparison = compare(
+            entries(ours, "pc:0,a:A=,f:F=").into_iter().map(Ok),
+            read_lines(reference.as_bytes(), mapping),
+            &[u64::MAX; 11],
+        )
+        .unwrap();
+        match &comparison {
+            Comparison::Diverged(divergence) => {
+                assert_eq!(divergence.instruction, 3);
+                assert_eq!(divergence.previous.as_ref().unwrap().text, "0001 A=01 F=02");
+                assert_eq!(divergence.differences, vec![("A", 0x02, 0x03), ("F", 0x02, 0x06)]);
+            },
+            _ => panic!("unexpected {:?}", comparison),
+        }
+        let mut out = Vec::new();
+        report(&comparison, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Traces diverge at instruction 3\n  last matching:  0001 A=01 F=02\n  ours:           0002 A=02 F=02\n  reference:      pc=0002 a=03 f=06\n  A   2 here, 3 in the reference\n  F   2 here, 6 in the reference\n",
+        );
+    }
+
+    #[test]
+    fn test_masks_and_lengths() {
+        let ours = entries("0000 F=02\n0001 F=02\n", "pc:0,f:F=");
+        let reference = entries("0000 F=00\n", "pc:0,f:F=");
+        let mut masks = [u64::MAX; 11];
+        masks[F] = 0xd5;
+        let comparison = compare(ours.into_iter().map(Ok), reference.into_iter().map(Ok), &masks).unwrap();
+        assert_eq!(comparison, Comparison::ReferenceEnded(1));
+    }
+
+    #[test]
+    fn test_binary_trace() {
+        let record = TraceRecord {
+            variant: crate::state_8080::Variant::Intel8080,
+            pc: 0x0100,
+            length: 1,
+            bytes: [0x3c, 0, 0, 0],
+            a: 1, b: 2, c: 3, d: 4, e: 5, h: 6, l: 7,
+            flags: 0x02,
+            sp: 0xfffe,
+            cycles: 42,
+        };
+        let mut bytes = b"8080TRAC".to_vec();
+        bytes.extend_from_slice(&trace::VERSION.to_le_bytes());
+        record.write_binary(&mut bytes).unwrap();
+        let from_binary: Vec<Entry> = read_trace(&bytes[..]).unwrap().map(Result::unwrap).collect();
+        let text = format!("{}\n", record);
+        let from_text: Vec<Entry> = read_trace(text.as_bytes()).unwrap().map(Result::unwrap).collect();
+        assert_eq!(from_binary.len(), 1);
+        assert_eq!(from_binary[0].values, from_text[0].values);
+    }
+}
@@ -0,0 +1,64 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // Only writes that store a different value from what was there
+    Change,
+}
+
+// Watches every address from `start` to `end`, both included
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    pub fn covers(&self, address: u16) -> bool {
+        self.start <= address && address <= self.end
+    }
+}
+
+// A memory access that set off a watchpoint. `old_value` is only there for
+// writes, and is `None` if the address couldn't be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub kind: WatchKind,
+    pub address: u16,
+    pub value: u8,
+    pub old_value: Option<u8>,
+}
+
+impl fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Change => "change",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{} {:04x}", self.kind, self.start)
+        } else {
+            write!(f, "{} {:04x}-{:04x}", self.kind, self.start, self.end)
+        }
+    }
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.kind, self.old_value) {
+            (WatchKind::Read, _) => write!(f, "read {:02x} from {:04x}", self.value, self.address),
+            (_, Some(old_value)) => write!(f, "wrote {:02x} to {:04x} (was {:02x})", self.value, self.address, old_value),
+            (_, None) => write!(f, "wrote {:02x} to {:04x}", self.value, self.address),
+        }
+    }
+}
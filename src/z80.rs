@@ -0,0 +1,1100 @@
+use crate::bus::Bus;
+use crate::error::EmulatorError;
+use crate::instruction::{Register, RegisterPair};
+use crate::io_ports::IoPorts;
+use crate::parity::Parity;
+use crate::sign::Sign;
+use crate::state_8080::{InterruptAcknowledge, State8080};
+use crate::z80_instruction::{Alu, Block, Index, Operand, Operation, Pair, Rotate, Table, Z80Instruction};
+
+// The Z80 runs 8080 code as it is, apart from a couple of flag differences
+// (parity doubles as overflow after arithmetic, and there's a subtract flag
+// for DAA). So this shares the 8080's state and 8-bit arithmetic, and adds the
+// alternate registers, IX/IY, the CB/ED/DD/FD prefixed instructions and the
+// interrupt modes on top. The undocumented flag bits 3 and 5 aren't modelled.
+//
+// Decoding is left to `Z80Instruction`, which the disassembler uses too.
+
+// Unprefixed opcodes. Like the 8080 table, conditional jumps, calls and
+// returns are listed with their not-taken cost; taking them costs
+// `JR_TAKEN_CYCLES`, `CALL_TAKEN_CYCLES` or `RET_TAKEN_CYCLES` on top. The
+// prefixes are zero here because they're counted by the prefixed tables.
+const CYCLES: [u32; 256] = [
+    4, 10, 7, 6, 4, 4, 7, 4, 4, 11, 7, 6, 4, 4, 7, 4,
+    8, 10, 7, 6, 4, 4, 7, 4, 12, 11, 7, 6, 4, 4, 7, 4,
+    7, 10, 16, 6, 4, 4, 7, 4, 7, 11, 16, 6, 4, 4, 7, 4,
+    7, 10, 13, 6, 11, 11, 10, 4, 7, 11, 13, 6, 4, 4, 7, 4,
+
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    7, 7, 7, 7, 7, 7, 4, 7, 4, 4, 4, 4, 4, 4, 7, 4,
+
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+
+    5, 10, 10, 10, 10, 11, 7, 11, 5, 10, 10, 0, 10, 17, 7, 11,
+    5, 10, 10, 11, 10, 11, 7, 11, 5, 4, 10, 11, 10, 0, 7, 11,
+    5, 10, 10, 19, 10, 11, 7, 11, 5, 4, 10, 4, 10, 0, 7, 11,
+    5, 10, 10, 4, 10, 11, 7, 11, 5, 6, 10, 4, 10, 0, 7, 11,
+];
+
+const JR_TAKEN_CYCLES: u32 = 5;
+const CALL_TAKEN_CYCLES: u32 = 7;
+const RET_TAKEN_CYCLES: u32 = 6;
+
+// A DD or FD prefix costs this much on top of the instruction it modifies
+const PREFIX_CYCLES: u32 = 4;
+// And an (IX+d) or (IY+d) operand costs this much more than (HL) did
+const DISPLACEMENT_CYCLES: u32 = 8;
+
+// A repeating block instruction that goes round again costs this much more
+const BLOCK_REPEAT_CYCLES: u32 = 5;
+
+// LD (IX+d),n costs this much more than LD (HL),n
+const DISPLACED_IMMEDIATE_CYCLES: u32 = 5;
+
+// A halted Z80 keeps executing NOPs until an interrupt comes in
+const HALTED_CYCLES: u32 = 4;
+
+pub fn emulate_z80_op<M: Bus, P: IoPorts>(state: &mut State8080<M, P>) -> Result<u32, EmulatorError> {
+    // Interrupt mode 0 works like the 8080, executing whatever is on the data
+    // bus. Modes 1 and 2 and NMI are vectored.
+    let acknowledging = match state.acknowledge_interrupt() {
+        InterruptAcknowledge::None => false,
+        InterruptAcknowledge::DataBus => true,
+        InterruptAcknowledge::Vectored(cycles) => return Ok(cycles),
+    };
+    if state.halted() {
+        increment_refresh(state);
+        return Ok(HALTED_CYCLES);
+    }
+    let program_counter = state.program_counter();
+    let opcode = state.next_byte();
+    if let Some(reason) = state.take_bus_fault() {
+        increment_refresh(state);
+        state.finish_interrupt_acknowledge();
+        state.set_program_counter(program_counter);
+        return Err(EmulatorError { pc: program_counter, opcode, reason });
+    }
+    let instruction = Z80Instruction::decode(program_counter, opcode, || state.next_byte());
+    for _ in 0..instruction.opcode_fetches() {
+        increment_refresh(state);
+    }
+    let mut cycles = instruction.prefixes as u32 * PREFIX_CYCLES;
+    cycles += match instruction.table {
+        Table::Main => CYCLES[instruction.opcode as usize],
+        Table::Cb => cb_cycles(instruction.operation),
+        Table::Ed => ed_cycles(instruction.operation),
+    };
+    cycles += execute(state, instruction.operation);
+
+    if acknowledging {
+        state.finish_interrupt_acknowledge();
+    }
+    // Any unmapped access during the instruction is reported after the fact,
+    // so the instruction may have partially completed.
+    if let Some(reason) = state.take_bus_fault() {
+        return Err(EmulatorError { pc: program_counter, opcode, reason });
+    }
+    Ok(cycles)
+}
+
+// R counts opcode fetches in its low 7 bits. Bit 7 only changes with LD R,A.
+fn increment_refresh<M: Bus, P: IoPorts>(state: &mut State8080<M, P>) {
+    let r = state.z80.r;
+    state.z80.r = (r & 0x80) | (r.wrapping_add(1) & 0x7f);
+}
+
+// The prefixed tables don't have timing tables of their own
+fn cb_cycles(operation: Operation) -> u32 {
+    let (operand, bit) = match operation {
+        Operation::Bit(_, operand) => (operand, true),
+        Operation::Rotate(_, operand, _) | Operation::Res(_, operand, _) | Operation::Set(_, operand, _) => (operand, false),
+        _ => unreachable!("not a CB instruction"),
+    };
+    match (operand, bit) {
+        (Operand::Memory(Index::HL, _), true) => 12,
+        (Operand::Memory(Index::HL, _), false) => 15,
+        (Operand::Memory(..), true) => 20 - PREFIX_CYCLES,
+        (Operand::Memory(..), false) => 23 - PREFIX_CYCLES,
+        _ => 8,
+    }
+}
+
+fn ed_cycles(operation: Operation) -> u32 {
+    match operation {
+        Operation::InC(_) | Operation::OutC(_) => 12,
+        Operation::SbcHl(_) | Operation::AdcHl(_) => 15,
+        Operation::StorePair(..) | Operation::LoadPair(..) => 20,
+        Operation::Retn | Operation::Reti => 14,
+        Operation::LdIA | Operation::LdRA | Operation::LdAI | Operation::LdAR => 9,
+        Operation::Rrd | Operation::Rld => 18,
+        Operation::Block(..) => 16,
+        _ => 8,
+    }
+}
+
+// Runs the operation and returns any cycles it takes on top of its table
+// entry: taken branches, (IX+d) operands and repeats
+fn execute<M: Bus, P: IoPorts>(state: &mut State8080<M, P>, operation: Operation) -> u32 {
+    match operation {
+        Operation::Nop | Operation::Undefined => {},
+        Operation::ExAf => {
+            let af = state.register_pair(RegisterPair::Psw);
+            state.set_register_pair(RegisterPair::Psw, state.z80.af_alternate);
+            state.z80.af_alternate = af;
+        },
+        Operation::Djnz(offset) => {
+            state.b = state.b.wrapping_sub(1);
+            if state.b != 0 {
+                jump_relative(state, offset);
+                return JR_TAKEN_CYCLES;
+            }
+        },
+        Operation::Jr(offset) => jump_relative(state, offset),
+        Operation::JrIf(condition, offset) => {
+            if state.condition(condition) {
+                jump_relative(state, offset);
+                return JR_TAKEN_CYCLES;
+            }
+        },
+        Operation::LdPair(pair, value) => set_pair(state, pair, value),
+        Operation::AddPair(index, pair) => {
+            let value = pair_value(state, pair);
+            add_hl(state, index, value);
+        },
+        Operation::StoreA(pair) => state.write_memory(pair_value(state, pair), state.a),
+        Operation::LoadA(pair) => state.a = state.read_memory(pair_value(state, pair)),
+        Operation::StorePair(address, pair) => {
+            let value = pair_value(state, pair);
+            write_word(state, address, value);
+        },
+        Operation::LoadPair(pair, address) => {
+            let value = read_word(state, address);
+            set_pair(state, pair, value);
+        },
+        Operation::StoreAbsoluteA(address) => state.write_memory(address, state.a),
+        Operation::LoadAbsoluteA(address) => state.a = state.read_memory(address),
+        // No flags, not even the 8085's K
+        Operation::IncPair(pair) => set_pair(state, pair, pair_value(state, pair).wrapping_add(1)),
+        Operation::DecPair(pair) => set_pair(state, pair, pair_value(state, pair).wrapping_sub(1)),
+        Operation::Inc(operand) | Operation::Dec(operand) => {
+            let increment = matches!(operation, Operation::Inc(_));
+            let value = read_operand(state, operand);
+            let answer = increment_decrement(state, value, increment);
+            write_operand(state, operand, answer);
+            return displacement_cycles(operand);
+        },
+        Operation::LdImmediate(operand, value) => {
+            write_operand(state, operand, value);
+            if displacement_cycles(operand) > 0 {
+                return DISPLACED_IMMEDIATE_CYCLES;
+            }
+        },
+        Operation::Rlca | Operation::Rrca | Operation::Rla | Operation::Rra
+        | Operation::Daa | Operation::Cpl | Operation::Scf | Operation::Ccf => accumulator_op(state, operation),
+        Operation::Halt => state.halt(),
+        Operation::Ld(destination, source) => {
+            let value = read_operand(state, source);
+            write_operand(state, destination, value);
+            return displacement_cycles(destination) + displacement_cycles(source);
+        },
+        Operation::Alu(alu, operand) => {
+            let value = read_operand(state, operand);
+            arithmetic(state, alu, value);
+            return displacement_cycles(operand);
+        },
+        Operation::AluImmediate(alu, value) => arithmetic(state, alu, value),
+        Operation::RetIf(condition) => {
+            if state.condition(condition) {
+                state.ret();
+                return RET_TAKEN_CYCLES;
+            }
+        },
+        Operation::Pop(pair) => {
+            let (high, low) = state.pop();
+            set_pair(state, pair, ((high as u16) << 8) | low as u16);
+        },
+        Operation::Push(pair) => {
+            let value = pair_value(state, pair);
+            state.push((value >> 8) as u8, value as u8);
+        },
+        Operation::Ret => state.ret(),
+        Operation::Exx => exchange_alternates(state),
+        Operation::JpIndirect(index) => state.set_program_counter(index_register(state, index)),
+        Operation::LdSp(index) => state.sp = index_register(state, index),
+        Operation::JpIf(condition, address) => {
+            if state.condition(condition) {
+                state.set_program_counter(address);
+            }
+        },
+        Operation::Jp(address) => state.set_program_counter(address),
+        Operation::Out(port) => state.io.output(port, state.a),
+        Operation::In(port) => state.a = state.io.input(port),
+        Operation::ExSp(index) => {
+            let value = read_word(state, state.sp);
+            let register = index_register(state, index);
+            write_word(state, state.sp, register);
+            set_index_register(state, index, value);
+        },
+        Operation::ExDeHl => {
+            let de = state.de();
+            state.set_de(state.hl());
+            state.set_hl(de);
+        },
+        Operation::Di => state.disable_interrupt(),
+        Operation::Ei => state.enable_interrupt(),
+        Operation::CallIf(condition, address) => {
+            if state.condition(condition) {
+                state.call(address);
+                return CALL_TAKEN_CYCLES;
+            }
+        },
+        Operation::Call(address) => state.call(address),
+        Operation::Rst(number) => state.call(number as u16 * 8),
+        Operation::Rotate(rotate, operand, copy) => {
+            let value = read_operand(state, operand);
+            let result = rotate_shift(state, rotate, value);
+            write_bit_result(state, operand, copy, result);
+        },
+        Operation::Bit(bit, operand) => {
+            let set = read_operand(state, operand) & (1 << bit) != 0;
+            state.cc.z = if set { 0 } else { 1 };
+            state.cc.p = overflow_flag(!set);
+            state.cc.s = if bit == 7 && set { Sign::Negative } else { Sign::Positive };
+            state.cc.ac = 1;
+            state.cc.n = 0;
+        },
+        Operation::Res(bit, operand, copy) => {
+            let result = read_operand(state, operand) & !(1 << bit);
+            write_bit_result(state, operand, copy, result);
+        },
+        Operation::Set(bit, operand, copy) => {
+            let result = read_operand(state, operand) | (1 << bit);
+            write_bit_result(state, operand, copy, result);
+        },
+        Operation::InC(register) => {
+            let value = state.io.input(state.c);
+            set_szp(state, value);
+            state.cc.ac = 0;
+            state.cc.n = 0;
+            if let Some(register) = register {
+                state.set_register(register, value);
+            }
+        },
+        Operation::OutC(register) => {
+            let value = register.map_or(0, |register| state.register(register));
+            state.io.output(state.c, value);
+        },
+        Operation::SbcHl(pair) => sbc_hl(state, pair_value(state, pair)),
+        Operation::AdcHl(pair) => adc_hl(state, pair_value(state, pair)),
+        Operation::Neg => {
+            let value = state.a;
+            state.a = 0;
+            state.sub(value);
+        },
+        Operation::Retn | Operation::Reti => {
+            state.restore_interrupt_from_iff2();
+            state.ret();
+        },
+        Operation::Im(mode) => state.z80.interrupt_mode = mode,
+        Operation::LdIA => state.z80.i = state.a,
+        Operation::LdRA => state.z80.r = state.a,
+        Operation::LdAI | Operation::LdAR => {
+            let value = if operation == Operation::LdAI { state.z80.i } else { state.z80.r };
+            state.a = value;
+            state.cc.z = if value == 0 { 1 } else { 0 };
+            state.cc.s = Sign::get_sign(value);
+            state.cc.ac = 0;
+            state.cc.n = 0;
+            state.cc.p = overflow_flag(state.z80.iff2);
+        },
+        Operation::Rrd | Operation::Rld => {
+            let address = state.hl();
+            let memory = state.read_memory(address);
+            let a = state.a;
+            let (memory, a) = if operation == Operation::Rrd {
+                ((a << 4) | (memory >> 4), (a & 0xf0) | (memory & 0x0f))
+            } else {
+                ((memory << 4) | (a & 0x0f), (a & 0xf0) | (memory >> 4))
+            };
+            state.write_memory(address, memory);
+            state.a = a;
+            set_szp(state, a);
+            state.cc.ac = 0;
+            state.cc.n = 0;
+        },
+        Operation::Block(block, decrement, repeat) => return block_op(state, block, decrement, repeat),
+    }
+    0
+}
+
+// What an (IX+d) or (IY+d) operand costs over (HL)
+fn displacement_cycles(operand: Operand) -> u32 {
+    match operand {
+        Operand::Memory(Index::HL, _) => 0,
+        Operand::Memory(..) => DISPLACEMENT_CYCLES,
+        _ => 0,
+    }
+}
+
+// RLCA, RRCA, RLA, RRA, DAA, CPL, SCF and CCF
+fn accumulator_op<M: Bus, P: IoPorts>(state: &mut State8080<M, P>, operation: Operation) {
+    let a = state.a;
+    match operation {
+        Operation::Rlca => {
+            state.a = a.rotate_left(1);
+            state.cc.cy = a >> 7;
+        },
+        Operation::Rrca => {
+            state.a = a.rotate_right(1);
+            state.cc.cy = a & 0x01;
+        },
+        Operation::Rla => {
+            state.a = (a << 1) | state.cc.cy;
+            state.cc.cy = a >> 7;
+        },
+        Operation::Rra => {
+            state.a = (a >> 1) | (state.cc.cy << 7);
+            state.cc.cy = a & 0x01;
+        },
+        Operation::Daa => {
+            daa(state);
+            return;
+        },
+        Operation::Cpl => {
+            state.a = !a;
+            state.cc.ac = 1;
+            state.cc.n = 1;
+            return;
+        },
+        Operation::Scf => {
+            state.cc.cy = 1;
+        },
+        _ => { // CCF
+            state.cc.ac = state.cc.cy;
+            state.cc.cy ^= 0x01;
+            state.cc.n = 0;
+            return;
+        },
+    }
+    state.cc.ac = 0;
+    state.cc.n = 0;
+}
+
+// Unlike the 8080's, the Z80's DAA also works after a subtraction. N says
+// which way to adjust.
+fn daa<M: Bus, P: IoPorts>(state: &mut State8080<M, P>) {
+    let a = state.a;
+    let low = a & 0x0f;
+    let mut correction: u8 = 0;
+    let mut carry = state.cc.cy;
+    if low > 9 || state.cc.ac != 0 {
+        correction |= 0x06;
+    }
+    if a > 0x99 || carry != 0 {
+        correction |= 0x60;
+        carry = 1;
+    }
+    if state.cc.n != 0 {
+        state.cc.ac = if state.cc.ac != 0 && low < 6 { 1 } else { 0 };
+        state.a = a.wrapping_sub(correction);
+    } else {
+        state.cc.ac = if low > 9 { 1 } else { 0 };
+        state.a = a.wrapping_add(correction);
+    }
+    state.cc.cy = carry;
+    set_szp(state, state.a);
+}
+
+fn arithmetic<M: Bus, P: IoPorts>(state: &mut State8080<M, P>, alu: Alu, value: u8) {
+    match alu {
+        Alu::Add => state.add(value),
+        Alu::Adc => state.adc(value),
+        Alu::Sub => state.sub(value),
+        Alu::Sbc => state.sbb(value),
+        Alu::And => state.ana(value),
+        Alu::Xor => state.xra(value),
+        Alu::Or => state.ora(value),
+        Alu::Cp => state.cmp(value),
+    }
+}
+
+fn increment_decrement<M: Bus, P: IoPorts>(state: &mut State8080<M, P>, value: u8, increment: bool) -> u8 {
+    let answer = if increment { value.wrapping_add(1) } else { value.wrapping_sub(1) };
+    state.cc.z = if answer == 0 { 1 } else { 0 };
+    state.cc.s = Sign::get_sign(answer);
+    let (half_carry, overflow) = if increment {
+        ((answer & 0x0f) == 0x00, answer == 0x80)
+    } else {
+        ((answer & 0x0f) == 0x0f, answer == 0x7f)
+    };
+    state.cc.ac = half_carry as u8;
+    state.cc.p = overflow_flag(overflow);
+    state.cc.n = if increment { 0 } else { 1 };
+    answer
+}
+
+fn add_hl<M: Bus, P: IoPorts>(state: &mut State8080<M, P>, index: Index, value: u16) {
+    let hl = index_register(state, index);
+    let answer = hl as u32 + value as u32;
+    state.cc.ac = if (hl & 0x0fff) + (value & 0x0fff) > 0x0fff { 1 } else { 0 };
+    state.cc.cy = if answer > 0xffff { 1 } else { 0 };
+    state.cc.n = 0;
+    set_index_register(state, index, answer as u16);
+}
+
+fn adc_hl<M: Bus, P: IoPorts>(state: &mut State8080<M, P>, value: u16) {
+    let hl = state.hl();
+    let carry = state.cc.cy as u32;
+    let answer = hl as u32 + value as u32 + carry;
+    let result = answer as u16;
+    state.cc.ac = if (hl & 0x0fff) as u32 + (value & 0x0fff) as u32 + carry > 0x0fff { 1 } else { 0 };
+    state.cc.p = overflow_flag(!(hl ^ value) & (hl ^ result) & 0x8000 != 0);
+    state.cc.n = 0;
+    set_word_flags(state, result, answer > 0xffff);
+}
+
+fn sbc_hl<M: Bus, P: IoPorts>(state: &mut State8080<M, P>, value: u16) {
+    let hl = state.hl();
+    let borrow = state.cc.cy as u32;
+    let answer = (hl as u32).wrapping_sub(value as u32).wrapping_sub(borrow);
+    let result = answer as u16;
+    state.cc.ac = if ((hl & 0x0fff) as u32) < (value & 0x0fff) as u32 + borrow { 1 } else { 0 };
+    state.cc.p = overflow_flag((hl ^ value) & (hl ^ result) & 0x8000 != 0);
+    state.cc.n = 1;
+    set_word_flags(state, result, answer > 0xffff);
+}
+
+fn set_word_flags<M: Bus, P: IoPorts>(state: &mut State8080<M, P>, result: u16, carry: bool) {
+    state.cc.z = if result == 0 { 1 } else { 0 };
+    state.cc.s = Sign::get_sign((result >> 8) as u8);
+    state.cc.cy = carry as u8;
+    state.set_hl(result);
+}
+
+// RLC, RRC, RL, RR, SLA, SRA, SLL (undocumented) and SRL
+fn rotate_shift<M: Bus, P: IoPorts>(state: &mut State8080<M, P>, rotate: Rotate, value: u8) -> u8 {
+    let carry = state.cc.cy;
+    let (result, carry_out) = match rotate {
+        Rotate::Rlc => (value.rotate_left(1), value >> 7),
+        Rotate::Rrc => (value.rotate_right(1), value & 0x01),
+        Rotate::Rl => ((value << 1) | carry, value >> 7),
+        Rotate::Rr => ((value >> 1) | (carry << 7), value & 0x01),
+        Rotate::Sla => (value << 1, value >> 7),
+        Rotate::Sra => ((value >> 1) | (value & 0x80), value & 0x01),
+        Rotate::Sll => ((value << 1) | 0x01, value >> 7),
+        Rotate::Srl => (value >> 1, value & 0x01),
+    };
+    state.cc.cy = carry_out;
+    state.cc.ac = 0;
+    state.cc.n = 0;
+    set_szp(state, result);
+    result
+}
+
+// LDI, CPI, INI, OUTI and their decrementing and repeating versions. The
+// repeating ones run one step per call, going back over the instruction
+// until they're done, so interrupts can still come in part way through.
+fn block_op<M: Bus, P: IoPorts>(state: &mut State8080<M, P>, block: Block, decrement: bool, repeat: bool) -> u32 {
+    let step: u16 = if decrement { 0xffff } else { 0x0001 };
+    let hl = state.hl();
+    let more = match block {
+        Block::Load => { // LDI
+            let value = state.read_memory(hl);
+            state.write_memory(state.de(), value);
+            state.set_de(state.de().wrapping_add(step));
+            state.set_bc(state.bc().wrapping_sub(1));
+            state.cc.ac = 0;
+            state.cc.n = 0;
+            state.cc.p = overflow_flag(state.bc() != 0);
+            state.bc() != 0
+        },
+        Block::Compare => { // CPI
+            let value = state.read_memory(hl);
+            let carry = state.cc.cy;
+            state.cmp(value);
+            state.cc.cy = carry;
+            state.set_bc(state.bc().wrapping_sub(1));
+            state.cc.p = overflow_flag(state.bc() != 0);
+            state.bc() != 0 && state.cc.z == 0
+        },
+        Block::In => { // INI
+            let value = state.io.input(state.c);
+            state.write_memory(hl, value);
+            state.b = state.b.wrapping_sub(1);
+            state.cc.z = if state.b == 0 { 1 } else { 0 };
+            state.cc.n = 1;
+            state.b != 0
+        },
+        Block::Out => { // OUTI
+            let value = state.read_memory(hl);
+            state.b = state.b.wrapping_sub(1);
+            state.io.output(state.c, value);
+            state.cc.z = if state.b == 0 { 1 } else { 0 };
+            state.cc.n = 1;
+            state.b != 0
+        },
+    };
+    state.set_hl(hl.wrapping_add(step));
+    if repeat && more {
+        let pc = state.program_counter();
+        state.set_program_counter(pc.wrapping_sub(2));
+        BLOCK_REPEAT_CYCLES
+    } else {
+        0
+    }
+}
+
+fn exchange_alternates<M: Bus, P: IoPorts>(state: &mut State8080<M, P>) {
+    let (bc, de, hl) = (state.bc(), state.de(), state.hl());
+    state.set_bc(state.z80.bc_alternate);
+    state.set_de(state.z80.de_alternate);
+    state.set_hl(state.z80.hl_alternate);
+    state.z80.bc_alternate = bc;
+    state.z80.de_alternate = de;
+    state.z80.hl_alternate = hl;
+}
+
+fn jump_relative<M: Bus, P: IoPorts>(state: &mut State8080<M, P>, offset: i8) {
+    let pc = state.program_counter();
+    state.set_program_counter(pc.wrapping_add(offset as u16));
+}
+
+// The parity flag doubles as P/V, with "even" meaning set
+fn overflow_flag(set: bool) -> Parity {
+    if set { Parity::Even } else { Parity::Odd }
+}
+
+fn set_szp<M: Bus, P: IoPorts>(state: &mut State8080<M, P>, value: u8) {
+    state.cc.z = if value == 0 { 1 } else { 0 };
+    state.cc.s = Sign::get_sign(value);
+    state.cc.p = Parity::from(value);
+}
+
+fn index_register<M: Bus, P: IoPorts>(state: &State8080<M, P>, index: Index) -> u16 {
+    match index {
+        Index::HL => state.hl(),
+        Index::IX => state.z80.ix,
+        Index::IY => state.z80.iy,
+    }
+}
+
+fn set_index_register<M: Bus, P: IoPorts>(state: &mut State8080<M, P>, index: Index, value: u16) {
+    match index {
+        Index::HL => state.set_hl(value),
+        Index::IX => state.z80.ix = value,
+        Index::IY => state.z80.iy = value,
+    }
+}
+
+fn read_operand<M: Bus, P: IoPorts>(state: &State8080<M, P>, operand: Operand) -> u8 {
+    match operand {
+        Operand::Register(register) => state.register(register),
+        Operand::High(index) => (index_register(state, index) >> 8) as u8,
+        Operand::Low(index) => index_register(state, index) as u8,
+        Operand::Memory(index, displacement) => state.read_memory(operand_address(state, index, displacement)),
+    }
+}
+
+fn write_operand<M: Bus, P: IoPorts>(state: &mut State8080<M, P>, operand: Operand, value: u8) {
+    match operand {
+        Operand::Register(register) => state.set_register(register, value),
+        Operand::High(index) => {
+            let low = index_register(state, index) & 0x00ff;
+            set_index_register(state, index, ((value as u16) << 8) | low);
+        },
+        Operand::Low(index) => {
+            let high = index_register(state, index) & 0xff00;
+            set_index_register(state, index, high | value as u16);
+        },
+        Operand::Memory(index, displacement) => {
+            let address = operand_address(state, index, displacement);
+            state.write_memory(address, value);
+        },
+    }
+}
+
+// Undocumented: DD CB d op with a register other than (HL) also copies the
+// result into that register
+fn write_bit_result<M: Bus, P: IoPorts>(state: &mut State8080<M, P>, operand: Operand, copy: Option<Register>, value: u8) {
+    write_operand(state, operand, value);
+    if let Some(register) = copy {
+        state.set_register(register, value);
+    }
+}
+
+fn pair_value<M: Bus, P: IoPorts>(state: &State8080<M, P>, pair: Pair) -> u16 {
+    match pair {
+        Pair::BC => state.bc(),
+        Pair::DE => state.de(),
+        Pair::Index(index) => index_register(state, index),
+        Pair::SP => state.sp,
+        Pair::AF => state.register_pair(RegisterPair::Psw),
+    }
+}
+
+fn set_pair<M: Bus, P: IoPorts>(state: &mut State8080<M, P>, pair: Pair, value: u16) {
+    match pair {
+        Pair::BC => state.set_bc(value),
+        Pair::DE => state.set_de(value),
+        Pair::Index(index) => set_index_register(state, index, value),
+        Pair::SP => state.sp = value,
+        Pair::AF => state.set_register_pair(RegisterPair::Psw, value),
+    }
+}
+
+// Where an (HL), (IX+d) or (IY+d) operand is
+fn operand_address<M: Bus, P: IoPorts>(state: &State8080<M, P>, index: Index, displacement: i8) -> u16 {
+    index_register(state, index).wrapping_add(displacement as u16)
+}
+
+fn read_word<M: Bus, P: IoPorts>(state: &State8080<M, P>, address: u16) -> u16 {
+    let low = state.read_memory(address) as u16;
+    let high = state.read_memory(address.wrapping_add(1)) as u16;
+    (high << 8) | low
+}
+
+fn write_word<M: Bus, P: IoPorts>(state: &mut State8080<M, P>, address: u16, value: u16) {
+    state.write_memory(address, value as u8);
+    state.write_memory(address.wrapping_add(1), (value >> 8) as u8);
+}
+
+mod test {
+    #[allow(unused)] use super::*;
+    #[allow(unused)] use crate::state_8080::Variant;
+
+    #[allow(unused)]
+    fn z80_state(memory: Vec<u8>) -> State8080 {
+        let mut state = State8080::empty_state();
+        state.memory = memory;
+        state.set_variant(Variant::Z80);
+        state
+    }
+
+    #[allow(unused)]
+    fn step(state: &mut State8080) -> u32 {
+        crate::emulator::emulate_8080_op(state).unwrap()
+    }
+
+    #[test]
+    fn test_indexed_memory() {
+        let mut memory = vec![
+            0xdd, 0x21, 0x00, 0x10, // LD IX, 0x1000
+            0xdd, 0x36, 0x05, 0x42, // LD (IX+5), 0x42
+            0xdd, 0x34, 0x05, // INC (IX+5)
+            0xdd, 0x7e, 0x05, // LD A, (IX+5)
+            0xfd, 0x21, 0x00, 0x10, // LD IY, 0x1000
+            0xfd, 0x77, 0xff, // LD (IY-1), A
+        ];
+        memory.resize(0x1010, 0);
+        let mut state = z80_state(memory);
+        assert_eq!(step(&mut state), 14);
+        assert_eq!(state.z80.ix, 0x1000);
+        assert_eq!(step(&mut state), 19);
+        assert_eq!(state.read_memory(0x1005), 0x42);
+        assert_eq!(step(&mut state), 23);
+        assert_eq!(state.read_memory(0x1005), 0x43);
+        assert_eq!(step(&mut state), 19);
+        assert_eq!(state.a, 0x43);
+        step(&mut state);
+        assert_eq!(step(&mut state), 19);
+        assert_eq!(state.read_memory(0x0fff), 0x43);
+        assert_eq!(state.hl(), 0x0000);
+    }
+
+    #[test]
+    fn test_index_register_halves() {
+        let mut state = z80_state(vec![
+            0xdd, 0x26, 0x12, // LD IXH, 0x12
+            0xdd, 0x2e, 0x34, // LD IXL, 0x34
+            0xdd, 0x7c, // LD A, IXH
+            0xdd, 0x66, 0x00, // LD H, (IX+0)
+        ]);
+        step(&mut state);
+        step(&mut state);
+        assert_eq!(state.z80.ix, 0x1234);
+        assert_eq!(step(&mut state), 8);
+        assert_eq!(state.a, 0x12);
+        state.z80.ix = 0x0001;
+        step(&mut state);
+        // (IX+d) forms use the real H and L
+        assert_eq!(state.h, 0x26);
+        assert_eq!(state.z80.ix, 0x0001);
+    }
+
+    #[test]
+    fn test_exchanges() {
+        let mut state = z80_state(vec![0x08, 0xd9, 0xeb]);
+        state.a = 0x12;
+        state.cc.cy = 1;
+        state.z80.af_alternate = 0x3440;
+        state.set_bc(0x1111);
+        state.set_de(0x2222);
+        state.set_hl(0x3333);
+        state.z80.hl_alternate = 0x4444;
+        step(&mut state);
+        assert_eq!(state.a, 0x34);
+        assert_eq!(state.cc.cy, 0);
+        assert_eq!(state.cc.z, 1);
+        assert_eq!(state.z80.af_alternate, 0x1201);
+        step(&mut state);
+        assert_eq!(state.hl(), 0x4444);
+        assert_eq!(state.z80.bc_alternate, 0x1111);
+        assert_eq!(state.z80.de_alternate, 0x2222);
+        assert_eq!(state.z80.hl_alternate, 0x3333);
+        state.set_de(0x5555);
+        step(&mut state);
+        assert_eq!(state.hl(), 0x5555);
+        assert_eq!(state.de(), 0x4444);
+    }
+
+    #[test]
+    fn test_djnz() {
+        let mut state = z80_state(vec![
+            0x06, 0x03, // LD B, 3
+            0x3c, // INC A
+            0x10, 0xfd, // DJNZ -3
+            0x76, // HALT
+        ]);
+        step(&mut state);
+        step(&mut state);
+        assert_eq!(step(&mut state), 13);
+        while !state.halted() {
+            step(&mut state);
+        }
+        assert_eq!(state.a, 3);
+        assert_eq!(state.b, 0);
+    }
+
+    #[test]
+    fn test_jr_cycles() {
+        let mut state = z80_state(vec![0x20, 0x02, 0x28, 0xfe]);
+        state.cc.z = 1;
+        assert_eq!(step(&mut state), 7);
+        assert_eq!(state.program_counter(), 0x02);
+        assert_eq!(step(&mut state), 12);
+        assert_eq!(state.program_counter(), 0x02);
+    }
+
+    #[test]
+    fn test_overflow_and_subtract_flags() {
+        let mut state = z80_state(vec![0xd6, 0x01, 0xc6, 0x01, 0xe6, 0xff]);
+        state.a = 0x80;
+        step(&mut state);
+        assert_eq!(state.a, 0x7f);
+        assert_eq!(state.cc.p, Parity::Even);
+        assert_eq!(state.cc.n, 1);
+        assert_eq!(state.cc.ac, 1);
+        step(&mut state);
+        assert_eq!(state.a, 0x80);
+        assert_eq!(state.cc.p, Parity::Even);
+        assert_eq!(state.cc.n, 0);
+        // Logical operations still set parity, and AND sets the half carry
+        step(&mut state);
+        assert_eq!(state.cc.p, Parity::Odd);
+        assert_eq!(state.cc.ac, 1);
+    }
+
+    #[test]
+    fn test_daa_after_subtraction() {
+        let mut state = z80_state(vec![0xd6, 0x06, 0x27]);
+        state.a = 0x15;
+        step(&mut state);
+        step(&mut state);
+        assert_eq!(state.a, 0x09);
+        assert_eq!(state.cc.cy, 0);
+    }
+
+    #[test]
+    fn test_inc_dec_flags() {
+        let mut state = z80_state(vec![0x3c, 0x05]);
+        state.a = 0x7f;
+        state.b = 0x80;
+        state.cc.cy = 1;
+        step(&mut state);
+        assert_eq!(state.a, 0x80);
+        assert_eq!(state.cc.p, Parity::Even);
+        assert_eq!(state.cc.ac, 1);
+        assert_eq!(state.cc.n, 0);
+        step(&mut state);
+        assert_eq!(state.b, 0x7f);
+        assert_eq!(state.cc.p, Parity::Even);
+        assert_eq!(state.cc.ac, 1);
+        assert_eq!(state.cc.n, 1);
+        assert_eq!(state.cc.cy, 1);
+    }
+
+    #[test]
+    fn test_cb_register_ops() {
+        let mut state = z80_state(vec![
+            0xcb, 0x00, // RLC B
+            0xcb, 0x78, // BIT 7, B
+            0xcb, 0xf8, // SET 7, B
+            0xcb, 0x80, // RES 0, B
+            0xcb, 0x38, // SRL B
+        ]);
+        state.b = 0x81;
+        assert_eq!(step(&mut state), 8);
+        assert_eq!(state.b, 0x03);
+        assert_eq!(state.cc.cy, 1);
+        step(&mut state);
+        assert_eq!(state.cc.z, 1);
+        assert_eq!(state.cc.ac, 1);
+        step(&mut state);
+        assert_eq!(state.b, 0x83);
+        step(&mut state);
+        assert_eq!(state.b, 0x82);
+        step(&mut state);
+        assert_eq!(state.b, 0x41);
+        assert_eq!(state.cc.cy, 0);
+    }
+
+    #[test]
+    fn test_cb_memory_ops() {
+        let mut state = z80_state(vec![
+            0xcb, 0x16, // RL (HL)
+            0xcb, 0x46, // BIT 0, (HL)
+            0x00, 0x40,
+        ]);
+        state.set_hl(0x0005);
+        state.cc.cy = 1;
+        assert_eq!(step(&mut state), 15);
+        assert_eq!(state.read_memory(0x05), 0x81);
+        assert_eq!(step(&mut state), 12);
+        assert_eq!(state.cc.z, 0);
+    }
+
+    #[test]
+    fn test_indexed_bit_ops() {
+        let mut state = z80_state(vec![
+            0xdd, 0xcb, 0x02, 0xde, // SET 3, (IX+2)
+            0xdd, 0xcb, 0x02, 0x5e, // BIT 3, (IX+2)
+            0xdd, 0xcb, 0x02, 0x00, // RLC (IX+2), B
+            0x00, 0x00, 0x01,
+        ]);
+        state.z80.ix = 0x000c;
+        assert_eq!(step(&mut state), 23);
+        assert_eq!(state.read_memory(0x0e), 0x09);
+        assert_eq!(step(&mut state), 20);
+        assert_eq!(state.cc.z, 0);
+        step(&mut state);
+        assert_eq!(state.read_memory(0x0e), 0x12);
+        assert_eq!(state.b, 0x12);
+    }
+
+    #[test]
+    fn test_ldir() {
+        let mut state = z80_state(vec![0xed, 0xb0, 0x00, 0xaa, 0xbb, 0xcc, 0x00, 0x00, 0x00]);
+        state.set_hl(0x0003);
+        state.set_de(0x0006);
+        state.set_bc(0x0003);
+        assert_eq!(step(&mut state), 21);
+        assert_eq!(state.program_counter(), 0x00);
+        assert_eq!(step(&mut state), 21);
+        assert_eq!(step(&mut state), 16);
+        assert_eq!(state.program_counter(), 0x02);
+        assert_eq!(&state.memory[6..9], &[0xaa, 0xbb, 0xcc]);
+        assert_eq!(state.bc(), 0);
+        assert_eq!(state.hl(), 0x0006);
+        assert_eq!(state.de(), 0x0009);
+        assert_eq!(state.cc.p, Parity::Odd);
+    }
+
+    #[test]
+    fn test_cpir() {
+        let mut state = z80_state(vec![0xed, 0xb1, 0x00, 0x11, 0x22, 0x33, 0x44]);
+        state.set_hl(0x0003);
+        state.set_bc(0x0004);
+        state.a = 0x33;
+        while state.program_counter() == 0x00 {
+            step(&mut state);
+        }
+        assert_eq!(state.cc.z, 1);
+        assert_eq!(state.hl(), 0x0006);
+        assert_eq!(state.bc(), 0x0001);
+        assert_eq!(state.cc.p, Parity::Even);
+    }
+
+    #[test]
+    fn test_16_bit_arithmetic() {
+        let mut state = z80_state(vec![0xed, 0x4a, 0xed, 0x42, 0x09]);
+        state.set_hl(0x7fff);
+        state.set_bc(0x0001);
+        assert_eq!(step(&mut state), 15);
+        assert_eq!(state.hl(), 0x8000);
+        assert_eq!(state.cc.p, Parity::Even);
+        assert_eq!(state.cc.s, Sign::Negative);
+        assert_eq!(state.cc.ac, 1);
+        step(&mut state);
+        assert_eq!(state.hl(), 0x7fff);
+        assert_eq!(state.cc.p, Parity::Even);
+        assert_eq!(state.cc.n, 1);
+        assert_eq!(state.cc.cy, 0);
+        state.set_bc(0x8001);
+        assert_eq!(step(&mut state), 11);
+        assert_eq!(state.hl(), 0x0000);
+        assert_eq!(state.cc.cy, 1);
+        assert_eq!(state.cc.n, 0);
+    }
+
+    #[test]
+    fn test_neg() {
+        let mut state = z80_state(vec![0xed, 0x44]);
+        state.a = 0x01;
+        assert_eq!(step(&mut state), 8);
+        assert_eq!(state.a, 0xff);
+        assert_eq!(state.cc.cy, 1);
+        assert_eq!(state.cc.n, 1);
+    }
+
+    #[test]
+    fn test_ld_register_pair_memory() {
+        let mut state = z80_state(vec![0xed, 0x43, 0x08, 0x00, 0xed, 0x5b, 0x08, 0x00, 0x00, 0x00]);
+        state.set_bc(0xbeef);
+        assert_eq!(step(&mut state), 20);
+        assert_eq!(state.read_memory(0x08), 0xef);
+        assert_eq!(state.read_memory(0x09), 0xbe);
+        step(&mut state);
+        assert_eq!(state.de(), 0xbeef);
+    }
+
+    #[test]
+    fn test_rld_rrd() {
+        let mut state = z80_state(vec![0xed, 0x6f, 0xed, 0x67, 0x34]);
+        state.set_hl(0x0004);
+        state.a = 0x12;
+        assert_eq!(step(&mut state), 18);
+        assert_eq!(state.a, 0x13);
+        assert_eq!(state.read_memory(0x04), 0x42);
+        step(&mut state);
+        assert_eq!(state.a, 0x12);
+        assert_eq!(state.read_memory(0x04), 0x34);
+    }
+
+    #[test]
+    fn test_in_c() {
+        let mut state = z80_state(vec![0xed, 0x40]);
+        state.b = 0x55;
+        assert_eq!(step(&mut state), 12);
+        assert_eq!(state.b, 0x00);
+        assert_eq!(state.cc.z, 1);
+    }
+
+    #[test]
+    fn test_interrupt_mode_0() {
+        let mut state = z80_state(vec![0x00; 0x40]);
+        state.write_memory(0x00, 0xfb); // EI
+        state.sp = 0x40;
+        step(&mut state);
+        step(&mut state);
+        state.request_interrupt(0xef); // RST 28h
+        step(&mut state);
+        assert_eq!(state.program_counter(), 0x28);
+        assert!(!state.interrupt_enabled());
+    }
+
+    #[test]
+    fn test_interrupt_mode_1() {
+        let mut state = z80_state(vec![0x00; 0x40]);
+        state.memory[..3].copy_from_slice(&[0xed, 0x56, 0xfb]); // IM 1, EI
+        state.sp = 0x40;
+        for _ in 0..3 {
+            step(&mut state);
+        }
+        state.request_interrupt(0x00);
+        assert_eq!(step(&mut state), 13);
+        assert_eq!(state.program_counter(), 0x38);
+        assert_eq!(state.read_memory(0x3e), 0x04);
+    }
+
+    #[test]
+    fn test_interrupt_mode_2() {
+        let mut state = z80_state(vec![0x00; 0x120]);
+        state.memory[..8].copy_from_slice(&[
+            0x3e, 0x01, // LD A, 1
+            0xed, 0x47, // LD I, A
+            0xed, 0x5e, // IM 2
+            0xfb, // EI
+            0x00,
+        ]);
+        state.write_memory(0x0110, 0x34);
+        state.write_memory(0x0111, 0x12);
+        state.sp = 0x100;
+        for _ in 0..5 {
+            step(&mut state);
+        }
+        state.request_interrupt(0x10);
+        assert_eq!(step(&mut state), 19);
+        assert_eq!(state.program_counter(), 0x1234);
+    }
+
+    #[test]
+    fn test_nmi_and_retn() {
+        let mut state = z80_state(vec![0x00; 0x80]);
+        state.write_memory(0x00, 0xfb); // EI
+        state.write_memory(0x66, 0xed); // RETN
+        state.write_memory(0x67, 0x45);
+        state.sp = 0x80;
+        step(&mut state);
+        step(&mut state);
+        state.raise_nmi();
+        assert_eq!(step(&mut state), 11);
+        assert_eq!(state.program_counter(), 0x66);
+        assert!(!state.interrupt_enabled());
+        assert!(state.z80.iff2);
+        assert_eq!(step(&mut state), 14);
+        assert_eq!(state.program_counter(), 0x02);
+        assert!(state.interrupt_enabled());
+    }
+
+    #[test]
+    fn test_ld_a_i_reports_iff2() {
+        let mut state = z80_state(vec![0xfb, 0xed, 0x57]);
+        state.z80.i = 0x80;
+        step(&mut state);
+        step(&mut state);
+        assert_eq!(state.a, 0x80);
+        assert_eq!(state.cc.s, Sign::Negative);
+        assert_eq!(state.cc.p, Parity::Even);
+    }
+
+    #[test]
+    fn test_refresh_register() {
+        let mut state = z80_state(vec![0x00, 0xdd, 0x23, 0xcb, 0x00]);
+        state.z80.r = 0xff;
+        step(&mut state);
+        assert_eq!(state.z80.r, 0x80);
+        step(&mut state);
+        step(&mut state);
+        assert_eq!(state.z80.r, 0x84);
+    }
+
+    #[test]
+    fn test_push_pop_af() {
+        let mut state = z80_state(vec![0xf5, 0xf1, 0x00, 0x00]);
+        state.sp = 0x04;
+        state.a = 0x12;
+        state.cc.n = 1;
+        state.cc.cy = 1;
+        step(&mut state);
+        assert_eq!(state.read_memory(0x02) & 0x03, 0x03);
+        state.cc.n = 0;
+        state.cc.cy = 0;
+        step(&mut state);
+        assert_eq!(state.cc.n, 1);
+        assert_eq!(state.cc.cy, 1);
+    }
+
+    #[test]
+    fn test_halt() {
+        let mut state = z80_state(vec![0x76, 0x00]);
+        assert_eq!(step(&mut state), 4);
+        assert!(state.halted());
+        assert_eq!(step(&mut state), HALTED_CYCLES);
+        assert_eq!(state.program_counter(), 0x01);
+    }
+}
@@ -0,0 +1,617 @@
+use std::fmt;
+
+use crate::instruction::{Condition, Register, CONDITIONS, REGISTERS};
+
+// One decoded Z80 instruction. The Z80 core and the disassembler both go
+// through this, the way the 8080 and 8085 share `Instruction`, so the Z80's
+// opcode map only lives in `decode`.
+//
+// Opcodes split into the same fields as the 8080's (x = bits 7-6, y = bits
+// 5-3, z = bits 2-0, with y split again into p = bits 5-4 and q = bit 3). CB
+// opcodes are the bit operations and ED opcodes a mixed bag of extras. A DD
+// or FD prefix makes the instruction after it use IX or IY where it would
+// have used HL; any number of them can be stacked up, and only the last one
+// counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Z80Instruction {
+    pub operation: Operation,
+    // Where it was decoded from, so relative jumps can show their target
+    pub address: u16,
+    // How many DD and FD prefixes came first
+    pub prefixes: u8,
+    pub table: Table,
+    // The opcode within its table, after any prefixes
+    pub opcode: u8,
+    size: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Table {
+    Main,
+    Cb,
+    Ed,
+}
+
+// Which register stands in for HL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Index {
+    HL,
+    IX,
+    IY,
+}
+
+// An 8-bit operand
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    // B, C, D, E, H, L or A, never M
+    Register(Register),
+    // The halves of IX and IY (undocumented, but widely used)
+    High(Index),
+    Low(Index),
+    // (HL), or (IX+d) and (IY+d) with a signed displacement
+    Memory(Index, i8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pair {
+    BC,
+    DE,
+    Index(Index),
+    SP,
+    AF,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alu {
+    Add,
+    Adc,
+    Sub,
+    Sbc,
+    And,
+    Xor,
+    Or,
+    Cp,
+}
+
+// SLL is undocumented
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotate {
+    Rlc,
+    Rrc,
+    Rl,
+    Rr,
+    Sla,
+    Sra,
+    Sll,
+    Srl,
+}
+
+// LDI, CPI, INI and OUTI
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Block {
+    Load,
+    Compare,
+    In,
+    Out,
+}
+
+// Destinations come first, like the mnemonics. Relative jumps keep their
+// offset from the end of the instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Nop,
+    ExAf,
+    Djnz(i8),
+    Jr(i8),
+    JrIf(Condition, i8),
+    LdPair(Pair, u16),
+    AddPair(Index, Pair),
+    // LD (BC),A and LD (DE),A
+    StoreA(Pair),
+    LoadA(Pair),
+    // LD (nn),rr
+    StorePair(u16, Pair),
+    LoadPair(Pair, u16),
+    // LD (nn),A
+    StoreAbsoluteA(u16),
+    LoadAbsoluteA(u16),
+    IncPair(Pair),
+    DecPair(Pair),
+    Inc(Operand),
+    Dec(Operand),
+    LdImmediate(Operand, u8),
+    Rlca,
+    Rrca,
+    Rla,
+    Rra,
+    Daa,
+    Cpl,
+    Scf,
+    Ccf,
+    Halt,
+    Ld(Operand, Operand),
+    Alu(Alu, Operand),
+    AluImmediate(Alu, u8),
+    RetIf(Condition),
+    Pop(Pair),
+    Push(Pair),
+    Ret,
+    Exx,
+    // JP (HL)
+    JpIndirect(Index),
+    // LD SP,HL
+    LdSp(Index),
+    JpIf(Condition, u16),
+    Jp(u16),
+    // OUT (n),A and IN A,(n)
+    Out(u8),
+    In(u8),
+    // EX (SP),HL
+    ExSp(Index),
+    ExDeHl,
+    Di,
+    Ei,
+    CallIf(Condition, u16),
+    Call(u16),
+    // The restart number (0-7), not the address
+    Rst(u8),
+
+    // CB. Under a prefix the ones that write (IX+d) back also copy the
+    // result into the register, if the opcode names one (undocumented).
+    Rotate(Rotate, Operand, Option<Register>),
+    Bit(u8, Operand),
+    Res(u8, Operand, Option<Register>),
+    Set(u8, Operand, Option<Register>),
+
+    // ED. IN (C) only sets the flags and OUT (C),0 writes zero.
+    InC(Option<Register>),
+    OutC(Option<Register>),
+    SbcHl(Pair),
+    AdcHl(Pair),
+    Neg,
+    Retn,
+    Reti,
+    Im(u8),
+    LdIA,
+    LdRA,
+    LdAI,
+    LdAR,
+    Rrd,
+    Rld,
+    // Which one, whether it counts down, and whether it repeats
+    Block(Block, bool, bool),
+    // The rest of the ED table does nothing
+    Undefined,
+}
+
+const ALUS: [Alu; 8] = [Alu::Add, Alu::Adc, Alu::Sub, Alu::Sbc, Alu::And, Alu::Xor, Alu::Or, Alu::Cp];
+
+const ROTATES: [Rotate; 8] = [
+    Rotate::Rlc, Rotate::Rrc, Rotate::Rl, Rotate::Rr,
+    Rotate::Sla, Rotate::Sra, Rotate::Sll, Rotate::Srl,
+];
+
+const ACCUMULATOR_OPERATIONS: [Operation; 8] = [
+    Operation::Rlca, Operation::Rrca, Operation::Rla, Operation::Rra,
+    Operation::Daa, Operation::Cpl, Operation::Scf, Operation::Ccf,
+];
+
+const BLOCKS: [Block; 4] = [Block::Load, Block::Compare, Block::In, Block::Out];
+
+// Counts the bytes as they go by
+struct Reader<F> {
+    next_byte: F,
+    size: u8,
+}
+
+impl<F: FnMut() -> u8> Reader<F> {
+    fn byte(&mut self) -> u8 {
+        self.size = self.size.saturating_add(1);
+        (self.next_byte)()
+    }
+
+    fn word(&mut self) -> u16 {
+        let low = self.byte() as u16;
+        let high = self.byte() as u16;
+        (high << 8) | low
+    }
+}
+
+impl Z80Instruction {
+    // Decodes the instruction at `address` starting with `opcode`, pulling
+    // the rest of its bytes (prefixed opcodes included) from `next_byte`
+    pub fn decode<F: FnMut() -> u8>(address: u16, opcode: u8, next_byte: F) -> Z80Instruction {
+        let mut reader = Reader { next_byte, size: 1 };
+        let mut opcode = opcode;
+        let mut index = Index::HL;
+        let mut prefixes: u8 = 0;
+        while opcode == 0xdd || opcode == 0xfd {
+            index = if opcode == 0xdd { Index::IX } else { Index::IY };
+            prefixes = prefixes.saturating_add(1);
+            opcode = reader.byte();
+        }
+        let (table, opcode, operation) = match opcode {
+            0xcb => {
+                let (opcode, operation) = decode_cb(&mut reader, index);
+                (Table::Cb, opcode, operation)
+            },
+            // ED ignores any DD or FD in front of it
+            0xed => {
+                let opcode = reader.byte();
+                (Table::Ed, opcode, decode_ed(&mut reader, opcode))
+            },
+            _ => (Table::Main, opcode, decode_main(&mut reader, opcode, index)),
+        };
+        Z80Instruction { operation, address, prefixes, table, opcode, size: reader.size }
+    }
+
+    // Length in bytes, prefixes included
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    // Opcode fetches, which are what R counts: every prefix and the opcode,
+    // apart from the opcode of DD CB d op, which is read like an operand
+    pub fn opcode_fetches(&self) -> u8 {
+        match self.table {
+            Table::Main => self.prefixes.saturating_add(1),
+            Table::Cb if self.prefixes > 0 => self.prefixes.saturating_add(1),
+            _ => self.prefixes.saturating_add(2),
+        }
+    }
+
+    fn target(&self, offset: i8) -> u16 {
+        self.address.wrapping_add(self.size as u16).wrapping_add(offset as u16)
+    }
+}
+
+fn decode_main<F: FnMut() -> u8>(reader: &mut Reader<F>, opcode: u8, index: Index) -> Operation {
+    let x = opcode >> 6;
+    let y = (opcode >> 3) & 0x07;
+    let z = opcode & 0x07;
+    let p = y >> 1;
+    let q = y & 0x01;
+    // An instruction that uses (IX+d) still means H and L by H and L. The
+    // displacement comes straight after the opcode, before any immediate.
+    let uses_memory = match x {
+        0 => y == 6 && (4..=6).contains(&z),
+        1 => (y == 6) != (z == 6),
+        2 => z == 6,
+        _ => false,
+    };
+    let memory = match index {
+        Index::HL => Operand::Memory(Index::HL, 0),
+        _ if uses_memory => Operand::Memory(index, reader.byte() as i8),
+        _ => Operand::Memory(index, 0),
+    };
+    let operand = |r: u8| match r {
+        6 => memory,
+        4 if index != Index::HL && !uses_memory => Operand::High(index),
+        5 if index != Index::HL && !uses_memory => Operand::Low(index),
+        _ => Operand::Register(REGISTERS[r as usize]),
+    };
+    let pair = |p: u8| match p {
+        0 => Pair::BC,
+        1 => Pair::DE,
+        2 => Pair::Index(index),
+        _ => Pair::SP,
+    };
+    let stack_pair = |p: u8| if p == 3 { Pair::AF } else { pair(p) };
+    let condition = |y: u8| CONDITIONS[y as usize];
+    match (x, z) {
+        (0, 0) => match y {
+            0 => Operation::Nop,
+            1 => Operation::ExAf,
+            2 => Operation::Djnz(reader.byte() as i8),
+            3 => Operation::Jr(reader.byte() as i8),
+            _ => Operation::JrIf(condition(y - 4), reader.byte() as i8),
+        },
+        (0, 1) if q == 0 => Operation::LdPair(pair(p), reader.word()),
+        (0, 1) => Operation::AddPair(index, pair(p)),
+        (0, 2) => match (q, p) {
+            (0, 2) => Operation::StorePair(reader.word(), Pair::Index(index)),
+            (0, 3) => Operation::StoreAbsoluteA(reader.word()),
+            (0, _) => Operation::StoreA(pair(p)),
+            (_, 2) => Operation::LoadPair(Pair::Index(index), reader.word()),
+            (_, 3) => Operation::LoadAbsoluteA(reader.word()),
+            (_, _) => Operation::LoadA(pair(p)),
+        },
+        (0, 3) if q == 0 => Operation::IncPair(pair(p)),
+        (0, 3) => Operation::DecPair(pair(p)),
+        (0, 4) => Operation::Inc(operand(y)),
+        (0, 5) => Operation::Dec(operand(y)),
+        (0, 6) => Operation::LdImmediate(operand(y), reader.byte()),
+        (0, _) => ACCUMULATOR_OPERATIONS[y as usize],
+        (1, 6) if y == 6 => Operation::Halt,
+        (1, _) => Operation::Ld(operand(y), operand(z)),
+        (2, _) => Operation::Alu(ALUS[y as usize], operand(z)),
+        (_, 0) => Operation::RetIf(condition(y)),
+        (_, 1) => match (q, p) {
+            (0, _) => Operation::Pop(stack_pair(p)),
+            (_, 0) => Operation::Ret,
+            (_, 1) => Operation::Exx,
+            (_, 2) => Operation::JpIndirect(index),
+            (_, _) => Operation::LdSp(index),
+        },
+        (_, 2) => Operation::JpIf(condition(y), reader.word()),
+        (_, 3) => match y {
+            0 => Operation::Jp(reader.word()),
+            1 => unreachable!("CB prefix is decoded separately"),
+            2 => Operation::Out(reader.byte()),
+            3 => Operation::In(reader.byte()),
+            4 => Operation::ExSp(index),
+            // Never IX or IY
+            5 => Operation::ExDeHl,
+            6 => Operation::Di,
+            _ => Operation::Ei,
+        },
+        (_, 4) => Operation::CallIf(condition(y), reader.word()),
+        (_, 5) => match (q, p) {
+            (0, _) => Operation::Push(stack_pair(p)),
+            (_, 0) => Operation::Call(reader.word()),
+            _ => unreachable!("DD, ED and FD prefixes are decoded separately"),
+        },
+        (_, 6) => Operation::AluImmediate(ALUS[y as usize], reader.byte()),
+        (_, _) => Operation::Rst(y),
+    }
+}
+
+// Under a prefix the displacement comes before the opcode (DD CB d op)
+fn decode_cb<F: FnMut() -> u8>(reader: &mut Reader<F>, index: Index) -> (u8, Operation) {
+    let displacement = match index {
+        Index::HL => None,
+        _ => Some(reader.byte() as i8),
+    };
+    let opcode = reader.byte();
+    let x = opcode >> 6;
+    let y = (opcode >> 3) & 0x07;
+    let z = opcode & 0x07;
+    let register = REGISTERS[z as usize];
+    let (operand, copy) = match displacement {
+        Some(displacement) if z == 6 => (Operand::Memory(index, displacement), None),
+        Some(displacement) => (Operand::Memory(index, displacement), Some(register)),
+        None if z == 6 => (Operand::Memory(Index::HL, 0), None),
+        None => (Operand::Register(register), None),
+    };
+    let operation = match x {
+        0 => Operation::Rotate(ROTATES[y as usize], operand, copy),
+        1 => Operation::Bit(y, operand),
+        2 => Operation::Res(y, operand, copy),
+        _ => Operation::Set(y, operand, copy),
+    };
+    (opcode, operation)
+}
+
+fn decode_ed<F: FnMut() -> u8>(reader: &mut Reader<F>, opcode: u8) -> Operation {
+    let x = opcode >> 6;
+    let y = (opcode >> 3) & 0x07;
+    let z = opcode & 0x07;
+    let p = y >> 1;
+    let q = y & 0x01;
+    let register = if y == 6 { None } else { Some(REGISTERS[y as usize]) };
+    let pair = [Pair::BC, Pair::DE, Pair::Index(Index::HL), Pair::SP][p as usize];
+    match (x, z) {
+        (1, 0) => Operation::InC(register),
+        (1, 1) => Operation::OutC(register),
+        (1, 2) if q == 0 => Operation::SbcHl(pair),
+        (1, 2) => Operation::AdcHl(pair),
+        (1, 3) if q == 0 => Operation::StorePair(reader.word(), pair),
+        (1, 3) => Operation::LoadPair(pair, reader.word()),
+        (1, 4) => Operation::Neg,
+        (1, 5) if y == 1 => Operation::Reti,
+        (1, 5) => Operation::Retn,
+        (1, 6) => Operation::Im([0, 0, 1, 2][(y & 0x03) as usize]),
+        (1, 7) => match y {
+            0 => Operation::LdIA,
+            1 => Operation::LdRA,
+            2 => Operation::LdAI,
+            3 => Operation::LdAR,
+            4 => Operation::Rrd,
+            5 => Operation::Rld,
+            _ => Operation::Undefined,
+        },
+        (2, 0..=3) if y >= 4 => Operation::Block(BLOCKS[z as usize], y & 0x01 != 0, y >= 6),
+        _ => Operation::Undefined,
+    }
+}
+
+impl fmt::Display for Index {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Operand::Register(register) => write!(f, "{}", register),
+            Operand::High(index) => write!(f, "{}H", index),
+            Operand::Low(index) => write!(f, "{}L", index),
+            Operand::Memory(Index::HL, _) => write!(f, "(HL)"),
+            Operand::Memory(index, displacement) if displacement < 0 => {
+                write!(f, "({}-${:02x})", index, -(displacement as i16))
+            },
+            Operand::Memory(index, displacement) => write!(f, "({}+${:02x})", index, displacement),
+        }
+    }
+}
+
+impl fmt::Display for Pair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pair::Index(index) => write!(f, "{}", index),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+// The part of the mnemonic before the operand
+impl fmt::Display for Alu {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mnemonic = match self {
+            Alu::Add => "ADD\tA,",
+            Alu::Adc => "ADC\tA,",
+            Alu::Sub => "SUB\t",
+            Alu::Sbc => "SBC\tA,",
+            Alu::And => "AND\t",
+            Alu::Xor => "XOR\t",
+            Alu::Or => "OR\t",
+            Alu::Cp => "CP\t",
+        };
+        write!(f, "{}", mnemonic)
+    }
+}
+
+impl fmt::Display for Rotate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_uppercase())
+    }
+}
+
+// Zilog mnemonics, written like the 8080's: a tab before the operands,
+// immediate values as #$xx and addresses as $xxxx
+impl fmt::Display for Z80Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let copy = |copy: Option<Register>| copy.map(|register| format!(",{}", register)).unwrap_or_default();
+        match self.operation {
+            Operation::Nop => write!(f, "NOP"),
+            Operation::ExAf => write!(f, "EX\tAF,AF'"),
+            Operation::Djnz(offset) => write!(f, "DJNZ\t${:04x}", self.target(offset)),
+            Operation::Jr(offset) => write!(f, "JR\t${:04x}", self.target(offset)),
+            Operation::JrIf(condition, offset) => write!(f, "JR\t{},${:04x}", condition, self.target(offset)),
+            Operation::LdPair(pair, value) => write!(f, "LD\t{},#${:04x}", pair, value),
+            Operation::AddPair(index, pair) => write!(f, "ADD\t{},{}", index, pair),
+            Operation::StoreA(pair) => write!(f, "LD\t({}),A", pair),
+            Operation::LoadA(pair) => write!(f, "LD\tA,({})", pair),
+            Operation::StorePair(address, pair) => write!(f, "LD\t(${:04x}),{}", address, pair),
+            Operation::LoadPair(pair, address) => write!(f, "LD\t{},(${:04x})", pair, address),
+            Operation::StoreAbsoluteA(address) => write!(f, "LD\t(${:04x}),A", address),
+            Operation::LoadAbsoluteA(address) => write!(f, "LD\tA,(${:04x})", address),
+            Operation::IncPair(pair) => write!(f, "INC\t{}", pair),
+            Operation::DecPair(pair) => write!(f, "DEC\t{}", pair),
+            Operation::Inc(operand) => write!(f, "INC\t{}", operand),
+            Operation::Dec(operand) => write!(f, "DEC\t{}", operand),
+            Operation::LdImmediate(operand, value) => write!(f, "LD\t{},#${:02x}", operand, value),
+            Operation::Rlca => write!(f, "RLCA"),
+            Operation::Rrca => write!(f, "RRCA"),
+            Operation::Rla => write!(f, "RLA"),
+            Operation::Rra => write!(f, "RRA"),
+            Operation::Daa => write!(f, "DAA"),
+            Operation::Cpl => write!(f, "CPL"),
+            Operation::Scf => write!(f, "SCF"),
+            Operation::Ccf => write!(f, "CCF"),
+            Operation::Halt => write!(f, "HALT"),
+            Operation::Ld(destination, source) => write!(f, "LD\t{},{}", destination, source),
+            Operation::Alu(alu, operand) => write!(f, "{}{}", alu, operand),
+            Operation::AluImmediate(alu, value) => write!(f, "{}#${:02x}", alu, value),
+            Operation::RetIf(condition) => write!(f, "RET\t{}", condition),
+            Operation::Pop(pair) => write!(f, "POP\t{}", pair),
+            Operation::Push(pair) => write!(f, "PUSH\t{}", pair),
+            Operation::Ret => write!(f, "RET"),
+            Operation::Exx => write!(f, "EXX"),
+            Operation::JpIndirect(index) => write!(f, "JP\t({})", index),
+            Operation::LdSp(index) => write!(f, "LD\tSP,{}", index),
+            Operation::JpIf(condition, address) => write!(f, "JP\t{},${:04x}", condition, address),
+            Operation::Jp(address) => write!(f, "JP\t${:04x}", address),
+            Operation::Out(port) => write!(f, "OUT\t(${:02x}),A", port),
+            Operation::In(port) => write!(f, "IN\tA,(${:02x})", port),
+            Operation::ExSp(index) => write!(f, "EX\t(SP),{}", index),
+            Operation::ExDeHl => write!(f, "EX\tDE,HL"),
+            Operation::Di => write!(f, "DI"),
+            Operation::Ei => write!(f, "EI"),
+            Operation::CallIf(condition, address) => write!(f, "CALL\t{},${:04x}", condition, address),
+            Operation::Call(address) => write!(f, "CALL\t${:04x}", address),
+            Operation::Rst(number) => write!(f, "RST\t${:02x}", number * 8),
+            Operation::Rotate(rotate, operand, register) => write!(f, "{}\t{}{}", rotate, operand, copy(register)),
+            Operation::Bit(bit, operand) => write!(f, "BIT\t{},{}", bit, operand),
+            Operation::Res(bit, operand, register) => write!(f, "RES\t{},{}{}", bit, operand, copy(register)),
+            Operation::Set(bit, operand, register) => write!(f, "SET\t{},{}{}", bit, operand, copy(register)),
+            Operation::InC(None) => write!(f, "IN\t(C)"),
+            Operation::InC(Some(register)) => write!(f, "IN\t{},(C)", register),
+            Operation::OutC(None) => write!(f, "OUT\t(C),0"),
+            Operation::OutC(Some(register)) => write!(f, "OUT\t(C),{}", register),
+            Operation::SbcHl(pair) => write!(f, "SBC\tHL,{}", pair),
+            Operation::AdcHl(pair) => write!(f, "ADC\tHL,{}", pair),
+            Operation::Neg => write!(f, "NEG"),
+            Operation::Retn => write!(f, "RETN"),
+            Operation::Reti => write!(f, "RETI"),
+            Operation::Im(mode) => write!(f, "IM\t{}", mode),
+            Operation::LdIA => write!(f, "LD\tI,A"),
+            Operation::LdRA => write!(f, "LD\tR,A"),
+            Operation::LdAI => write!(f, "LD\tA,I"),
+            Operation::LdAR => write!(f, "LD\tA,R"),
+            Operation::Rrd => write!(f, "RRD"),
+            Operation::Rld => write!(f, "RLD"),
+            Operation::Block(block, decrement, repeat) => {
+                // OTIR and OTDR lose a letter
+                let mnemonic = match block {
+                    Block::Load => "LD",
+                    Block::Compare => "CP",
+                    Block::In => "IN",
+                    Block::Out if repeat => "OT",
+                    Block::Out => "OUT",
+                };
+                let direction = if decrement { "D" } else { "I" };
+                write!(f, "{}{}{}", mnemonic, direction, if repeat { "R" } else { "" })
+            },
+            Operation::Undefined => write!(f, "DB\t$ed,${:02x}", self.opcode),
+        }
+    }
+}
+
+mod test {
+    #[allow(unused)] use super::*;
+
+    #[allow(unused)]
+    fn decode_bytes(bytes: &[u8]) -> Z80Instruction {
+        let mut rest = bytes[1..].iter().copied();
+        Z80Instruction::decode(0x0100, bytes[0], || rest.next().unwrap_or(0))
+    }
+
+    #[test]
+    fn test_prefixes() {
+        let instruction = decode_bytes(&[0xdd, 0xfd, 0x21, 0x34, 0x12]);
+        assert_eq!(instruction.operation, Operation::LdPair(Pair::Index(Index::IY), 0x1234));
+        assert_eq!((instruction.prefixes, instruction.size(), instruction.opcode_fetches()), (2, 5, 3));
+        // ED ignores the prefix, apart from it taking a fetch
+        let instruction = decode_bytes(&[0xdd, 0xed, 0x63, 0x34, 0x12]);
+        assert_eq!(instruction.operation, Operation::StorePair(0x1234, Pair::Index(Index::HL)));
+        assert_eq!(instruction.opcode_fetches(), 3);
+    }
+
+    #[test]
+    fn test_index_halves_and_memory() {
+        // With (IX+d) in the instruction, H and L are themselves
+        assert_eq!(
+            decode_bytes(&[0xdd, 0x74, 0xfe]).operation,
+            Operation::Ld(Operand::Memory(Index::IX, -2), Operand::Register(Register::H)),
+        );
+        assert_eq!(
+            decode_bytes(&[0xfd, 0x6c]).operation,
+            Operation::Ld(Operand::Low(Index::IY), Operand::High(Index::IY)),
+        );
+        // EX DE,HL is never indexed
+        assert_eq!(decode_bytes(&[0xdd, 0xeb]).operation, Operation::ExDeHl);
+    }
+
+    #[test]
+    fn test_indexed_bit_operations() {
+        let instruction = decode_bytes(&[0xfd, 0xcb, 0x03, 0x10]);
+        assert_eq!(instruction.operation, Operation::Rotate(Rotate::Rl, Operand::Memory(Index::IY, 3), Some(Register::B)));
+        assert_eq!((instruction.opcode, instruction.size(), instruction.opcode_fetches()), (0x10, 4, 2));
+        assert_eq!(instruction.to_string(), "RL\t(IY+$03),B");
+    }
+
+    #[test]
+    fn test_relative_jumps() {
+        let instruction = decode_bytes(&[0x38, 0x80]);
+        assert_eq!(instruction.operation, Operation::JrIf(Condition::Carry, -128));
+        assert_eq!(instruction.to_string(), "JR\tC,$0082");
+    }
+
+    #[test]
+    fn test_block_mnemonics() {
+        let mnemonics: Vec<String> = [0xa0, 0xa9, 0xb2, 0xbb].iter().map(|&opcode| decode_bytes(&[0xed, opcode]).to_string()).collect();
+        assert_eq!(mnemonics, vec!["LDI", "CPD", "INIR", "OTDR"]);
+    }
+}